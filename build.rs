@@ -0,0 +1,16 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    grpc();
+}
+
+/// Generates the gRPC control API from `proto/control.proto`, without needing `protoc`.
+#[cfg(feature = "grpc")]
+fn grpc() {
+    println!("cargo:rerun-if-changed=proto");
+    let descriptors =
+        protox::compile(["control.proto"], ["proto"]).expect("Failed to compile control.proto");
+    tonic_build::configure()
+        .build_client(false)
+        .compile_fds(descriptors)
+        .expect("Failed to generate the gRPC control API");
+}
@@ -1,5 +1,20 @@
-use crate::core::Protocol;
-use clap::{Parser, Subcommand};
+use crate::core::{
+    Protocol, ProtocolChoice,
+    balance::Strategy,
+    datagram::OversizedPolicy,
+    mapping::{Mapping, parse_network},
+    qos::Dscp,
+    ticket::Ticket,
+};
+use crate::utils::config::{Role, RuntimeSettings, StoreKind};
+use crate::utils::format::parse_duration;
+use crate::utils::import::ImportSource;
+use crate::utils::output::Verbosity;
+use crate::utils::prompt::PromptMode;
+use clap::{Parser, Subcommand, ValueHint};
+use ipnet::IpNet;
+use iroh::{NodeId, RelayUrl};
+use std::net::IpAddr;
 use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
@@ -12,34 +27,398 @@ pub struct Opts {
     #[clap(short, long, global = true)]
     pub ephemeral: bool,
 
-    /// Path to the private key file
+    /// Path to the private key file, or `-` to read the key from stdin
     #[clap(short, long, global = true)]
     pub private_key: Option<PathBuf>,
 
+    /// Keep configs, state and the private key in this directory (defaults to ~/.punch or
+    /// the XDG base directories)
+    #[clap(long, global = true, env = "PUNCH_CONFIG_DIR")]
+    pub config_dir: Option<PathBuf>,
+
     /// Force the regeneration of the private key
     #[clap(short, long, global = true)]
     pub regenerate: bool,
+
+    /// Only use this relay, e.g. when the firewall lets no other through (overrides the host's)
+    #[clap(long, global = true)]
+    pub relay_url: Option<RelayUrl>,
+
+    /// Never prompt and use default answers, implied when stdin isn't a terminal
+    #[clap(long, global = true)]
+    pub non_interactive: bool,
+
+    /// Never prompt and accept confirmations, such as saving a new host or regenerating the key
+    #[clap(short = 'y', long, global = true)]
+    pub yes: bool,
+
+    /// Print without colors, which is also the case with `NO_COLOR` or when piping the output
+    #[clap(long, global = true)]
+    pub no_color: bool,
+
+    /// Print errors on stderr, and the addresses tunnels listen on, as JSON for scripts
+    #[clap(long, global = true)]
+    pub json: bool,
+
+    /// Only print warnings and errors
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Log what punch does, -vv and -vvv for details (overrides PUNCH_LOG)
+    #[clap(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Threads running the tunnels (overrides runtime.worker_threads, defaults to one per
+    /// CPU core)
+    #[clap(long, global = true, value_parser = clap::value_parser!(u16).range(1..))]
+    pub worker_threads: Option<u16>,
+
+    /// Threads for blocking work such as file access (overrides runtime.max_blocking_threads)
+    #[clap(long, global = true, value_parser = clap::value_parser!(u16).range(1..))]
+    pub max_blocking_threads: Option<u16>,
+
+    /// Run everything on the main thread, e.g. on a single-core box (overrides
+    /// runtime.current_thread)
+    #[clap(long, global = true, conflicts_with = "worker_threads")]
+    pub current_thread: bool,
+
+    /// Key from `PUNCH_SECRET_KEY`, kept here once taken out of the environment
+    #[clap(skip)]
+    pub env_secret_key: Option<String>,
+}
+
+impl Opts {
+    pub fn prompt_mode(&self) -> PromptMode {
+        PromptMode::new(self.non_interactive, self.yes)
+    }
+
+    pub fn verbosity(&self) -> Verbosity {
+        Verbosity::from_flags(self.quiet, self.verbose)
+    }
+
+    /// Applies the runtime flags over the settings from the config.
+    pub fn runtime_settings(&self, mut settings: RuntimeSettings) -> RuntimeSettings {
+        if let Some(threads) = self.worker_threads {
+            settings.worker_threads = Some(threads.into());
+            settings.current_thread = false;
+        }
+        if let Some(threads) = self.max_blocking_threads {
+            settings.max_blocking_threads = Some(threads.into());
+        }
+        settings.current_thread |= self.current_thread;
+        settings
+    }
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Command {
     /// Start the iroh tunnel server
     #[command(visible_alias = "s")]
-    Server {},
+    Server {
+        #[clap(subcommand)]
+        command: Option<ServerCommand>,
+
+        /// Ask for confirmation in the terminal before accepting a node for the first time
+        #[clap(long)]
+        confirm: bool,
+
+        /// Remember confirmed nodes in the server config
+        #[clap(long, requires = "confirm")]
+        remember: bool,
+    },
+
+    /// Share a directory over HTTP with authorized keys, published as a service (server)
+    Serve {
+        /// Directory to share
+        #[clap(default_value = ".")]
+        dir: PathBuf,
+
+        /// Name of the service clients connect to
+        #[clap(short, long, default_value = "files")]
+        name: String,
+
+        /// Local port of the HTTP server, which clients also use by default (defaults to a
+        /// free one)
+        #[clap(long, default_value = "0")]
+        port: u16,
+    },
 
     /// Start the iroh tunnel client
     #[command(visible_alias = "c")]
     Client {
         /// Identifier of the host to connect to (Node ID or name)
+        #[clap(required_unless_present = "ticket", value_hint = ValueHint::Hostname)]
+        to: Option<String>,
+
+        /// Port mapping in the format "[bind:]local:remote", or "port" for the same port on both
+        /// sides. A local port of 0 picks a free one
+        #[clap(required_unless_present_any = ["service", "list_services", "ticket", "mappings_file", "dns"])]
+        mapping: Option<Mapping>,
+
+        /// Open every tunnel listed in a TOML file, each with its own protocol and bind
+        /// address, instead of a single mapping. The tunnels share one connection
+        #[clap(long, conflicts_with_all = ["mapping", "ticket", "protocol", "service", "list_services", "local_port"])]
+        mappings_file: Option<PathBuf>,
+
+        /// Connect using a ticket from `punch ticket create` instead of a host and mapping
+        #[clap(long, conflicts_with_all = ["to", "mapping", "protocol", "service", "list_services"])]
+        ticket: Option<Ticket>,
+
+        /// Protocol to forward: tcp, udp, or both over two tunnels sharing the local port
+        #[clap(short = 'P', long, default_value = "tcp")]
+        protocol: ProtocolChoice,
+
+        /// Connect to a service defined by the server instead of a port
+        #[clap(long, conflicts_with_all = ["mapping", "protocol"])]
+        service: Option<String>,
+
+        /// Local port for --service or --ticket (defaults to the remote port)
+        #[clap(long, conflicts_with = "mapping")]
+        local_port: Option<u16>,
+
+        /// Forward DNS over UDP and TCP to the server's resolver from this local port (53 when
+        /// omitted), so that names on its network resolve
+        #[clap(long, num_args = 0..=1, default_missing_value = "53", conflicts_with_all = ["mapping", "mappings_file", "ticket", "protocol", "service", "list_services", "local_port"])]
+        dns: Option<u16>,
+
+        /// Point the system resolver at the --dns listener until exit, which needs port 53
+        /// (Linux only, restores /etc/resolv.conf on exit)
+        #[clap(long, requires = "dns")]
+        set_resolver: bool,
+
+        /// List the services the server exposes to you and exit
+        #[clap(long, conflicts_with_all = ["mapping", "service"])]
+        list_services: bool,
+
+        /// Address to bind the local listener to (defaults to 127.0.0.1)
+        #[clap(short, long)]
+        bind: Option<IpAddr>,
+
+        /// Only accept local connections from this address or network (repeatable)
+        #[clap(long = "allow-from", value_parser = parse_network)]
+        allow_from: Vec<IpNet>,
+
+        /// Host the server should forward to (defaults to the server's loopback interface)
+        #[clap(long)]
+        remote_host: Option<String>,
+
+        /// Carry UDP packets in unreliable QUIC datagrams instead of a stream
+        #[clap(long)]
+        datagrams: bool,
+
+        /// What to do with UDP packets too large for a datagram: drop, fragment or stream
+        #[clap(long, default_value = "fragment", requires = "datagrams")]
+        oversized: OversizedPolicy,
+
+        /// Parallel connections to spread TCP streams over (defaults to settings.connections)
+        #[clap(long, value_parser = clap::value_parser!(u8).range(1..))]
+        connections: Option<u8>,
+
+        /// Close the tunnel and exit once no traffic went through for this long, e.g. 30m
+        #[clap(long, value_parser = parse_duration)]
+        idle_exit: Option<u64>,
+
+        /// Mark the tunnel's packets on the local sockets and the server's sockets to the
+        /// target with this DSCP: 0-63, or a name such as ef (VoIP), af41 or cs6
+        #[clap(long)]
+        dscp: Option<Dscp>,
+
+        /// Socket priority (SO_PRIORITY) of the same sockets, Linux only. Servers ignore
+        /// values above 6
+        #[clap(long)]
+        priority: Option<u8>,
+    },
+
+    /// Run a command while a tunnel is up, e.g. `punch run db 0:5432 -- ./migrate.sh`
+    ///
+    /// The command sees PUNCH_LOCAL_HOST, PUNCH_LOCAL_PORT, PUNCH_LOCAL_ADDR,
+    /// PUNCH_REMOTE_PORT, PUNCH_PROTOCOL and PUNCH_NODE_ID. A local port of 0 picks a free one.
+    Run {
+        /// Identifier of the host to connect to (Node ID or name)
+        #[clap(value_hint = ValueHint::Hostname)]
         to: String,
 
-        /// Port mapping in the format "local:remote"
-        #[clap(value_parser = parse_mapping)]
-        mapping: (u16, u16),
+        /// Port mapping in the format "[bind:]local:remote", or "port" for the same port on both
+        /// sides
+        mapping: Mapping,
 
         /// Protocol to use for the connection
         #[clap(short = 'P', long, default_value = "tcp")]
         protocol: Protocol,
+
+        /// Address to bind the local listener to (defaults to 127.0.0.1)
+        #[clap(short, long)]
+        bind: Option<IpAddr>,
+
+        /// Host the server should forward to (defaults to the server's loopback interface)
+        #[clap(long)]
+        remote_host: Option<String>,
+
+        /// Command to run, after `--`
+        #[clap(last = true, required = true)]
+        command: Vec<String>,
+    },
+
+    /// Spread the connections of a local port over several hosts exposing the same port
+    Balance {
+        /// Port mapping in the format "[bind:]local:remote", or "port" for the same port on both
+        /// sides. A local port of 0 picks a free one
+        mapping: Mapping,
+
+        /// Hosts to spread connections over (Node IDs or names)
+        #[clap(required = true, num_args = 1.., value_hint = ValueHint::Hostname)]
+        hosts: Vec<String>,
+
+        /// How connections pick a host: round-robin or least-loaded
+        #[clap(short, long, default_value = "round-robin")]
+        strategy: Strategy,
+
+        /// Address to bind the local listener to (defaults to 127.0.0.1)
+        #[clap(short, long)]
+        bind: Option<IpAddr>,
+
+        /// Only accept local connections from this address or network (repeatable)
+        #[clap(long = "allow-from", value_parser = parse_network)]
+        allow_from: Vec<IpNet>,
+    },
+
+    /// Connect stdin/stdout to a remote port, e.g. `ProxyCommand punch stdio myserver 22`
+    Stdio {
+        /// Identifier of the host to connect to (Node ID or name)
+        #[clap(value_hint = ValueHint::Hostname)]
+        to: String,
+
+        /// Remote port to connect to
+        port: u16,
+
+        /// Host the server should forward to (defaults to the server's loopback interface)
+        #[clap(long)]
+        remote_host: Option<String>,
+    },
+
+    /// Pick a known host and connect to it with its saved mapping
+    Connect {
+        /// Name or Node ID of the host, picked from the known hosts if omitted
+        #[clap(value_hint = ValueHint::Hostname)]
+        to: Option<String>,
+
+        /// Port mapping in the format "[bind:]local:remote", or "port" for the same port on both
+        /// sides. A local port of 0 picks a free one (defaults to the host's mapping)
+        mapping: Option<Mapping>,
+    },
+
+    /// Measure upload and download goodput, latency and jitter to a server, and whether the
+    /// path is direct or relayed
+    #[command(visible_alias = "speedtest")]
+    Bench {
+        /// Identifier of the host to benchmark (Node ID or name)
+        #[clap(value_hint = ValueHint::Hostname)]
+        to: String,
+
+        /// Seconds to run each throughput test for
+        #[clap(short, long, default_value = "5")]
+        duration: u64,
+
+        /// Number of round trips used to measure latency
+        #[clap(short = 'n', long, default_value = "20")]
+        pings: usize,
+    },
+
+    /// Ask a server whether one of its ports accepts connections, before opening a tunnel to
+    /// it (exit code 0: open, 1: closed, filtered or not allowed)
+    Probe {
+        /// Identifier of the host to ask (Node ID or name)
+        #[clap(value_hint = ValueHint::Hostname)]
+        to: String,
+
+        /// Port to check on the server's side
+        port: u16,
+
+        /// Host the server should check instead of its loopback interface
+        #[clap(long)]
+        remote_host: Option<String>,
+    },
+
+    /// Join the VPN of a server, bringing up a TUN interface that routes its network (needs
+    /// root and a build with the vpn feature)
+    Vpn {
+        /// Identifier of the host to join (Node ID or name)
+        #[clap(value_hint = ValueHint::Hostname)]
+        to: String,
+
+        /// Name of the interface to bring up, which must be `utunN` on macOS
+        #[clap(long, default_value = "punch0")]
+        interface: String,
+    },
+
+    /// Send files to a node running `punch receive`, resuming interrupted transfers
+    Send {
+        /// Files to send
+        #[clap(required = true)]
+        files: Vec<PathBuf>,
+
+        /// Identifier of the host to send them to (Node ID or name)
+        #[clap(long, value_hint = ValueHint::Hostname)]
+        to: String,
+    },
+
+    /// Receive files sent with `punch send` by authorized keys
+    Receive {
+        /// Directory to save the files in
+        #[clap(short, long, default_value = ".")]
+        dir: PathBuf,
+
+        /// Keep receiving after the first sender is done
+        #[clap(long)]
+        keep_open: bool,
+    },
+
+    /// Exchange hosts with another of your nodes, which lists you in `sync_peers`
+    Sync {
+        #[clap(subcommand)]
+        command: SyncCommand,
+    },
+
+    /// Create tickets that let clients connect with a single argument (server)
+    Ticket {
+        #[clap(subcommand)]
+        command: TicketCommand,
+    },
+
+    /// List punch nodes on the local network and offer to save them as hosts
+    Discover {
+        /// Seconds to listen for announcements
+        #[clap(short, long, default_value = "5")]
+        timeout: u64,
+
+        /// Also list iroh nodes that aren't running punch
+        #[clap(short, long)]
+        all: bool,
+    },
+
+    /// Report on NAT behavior and relay reachability, to explain relayed connections
+    Netcheck {
+        /// Seconds to wait for the report
+        #[clap(short, long, default_value = "10")]
+        timeout: u64,
+    },
+
+    /// Show past client sessions
+    History {
+        /// Only show sessions with this host, by name or Node ID prefix
+        host: Option<String>,
+
+        /// Only show sessions started within this age, e.g. 12h or 7d
+        #[clap(long, value_parser = parse_duration)]
+        since: Option<u64>,
+
+        /// Only show sessions that ended before this age
+        #[clap(long, value_parser = parse_duration)]
+        until: Option<u64>,
+
+        /// Number of most recent sessions to show
+        #[clap(short = 'n', long, default_value = "20")]
+        lines: usize,
     },
 
     /// Display our Node ID
@@ -56,6 +435,12 @@ pub enum Command {
         command: HostCommand,
     },
 
+    /// Manage the key identifying this node
+    Key {
+        #[clap(subcommand)]
+        command: KeyCommand,
+    },
+
     /// Manage authorization (server)
     #[command(visible_alias = "a")]
     Auth {
@@ -63,11 +448,99 @@ pub enum Command {
         command: AuthCommand,
     },
 
+    /// Check the health of the running server (exit code 0: healthy, 1: degraded, 2: down),
+    /// only reporting through the exit code with -q
+    Healthcheck {
+        /// Seconds to wait for the server to answer
+        #[clap(short, long, default_value = "5")]
+        timeout: u64,
+    },
+
+    /// Replace punch with the latest release, after checking its checksum
+    Upgrade {
+        /// Only report whether a newer release is available
+        #[clap(long)]
+        check: bool,
+    },
+
+    /// Print the completion script of a shell, e.g. `punch completions zsh > _punch`
+    Completions {
+        /// Shell to complete in: bash, elvish, fish, powershell or zsh
+        shell: clap_complete::Shell,
+    },
+
+    /// Write the man pages of punch and its subcommands
+    Manpages {
+        /// Directory to write them to
+        #[clap(long, default_value = ".")]
+        out_dir: PathBuf,
+    },
+
     /// Show configuration information
     Config {
+        #[clap(subcommand)]
+        command: Option<ConfigCommand>,
+
         /// Show the configuration directory path
         #[clap(short, long)]
         show_path: bool,
+
+        /// Move the configuration to another store: toml or sqlite
+        #[clap(long)]
+        store: Option<StoreKind>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ServerCommand {
+    /// Show the connection audit log
+    Log {
+        /// Number of most recent entries to show
+        #[clap(short = 'n', long, default_value = "20")]
+        lines: usize,
+
+        /// Keep printing new entries as they are written
+        #[clap(short = 'f', long)]
+        tail: bool,
+    },
+
+    /// Show the keys that used the server the most
+    Usage {
+        /// How far back to count, e.g. 7d or 12h (counters are kept by day)
+        #[clap(long, value_parser = parse_duration, default_value = "30d")]
+        since: u64,
+
+        /// Number of keys to show
+        #[clap(short = 'n', long, default_value = "10")]
+        top: usize,
+    },
+
+    /// List the active tunnels of the running server
+    #[command(visible_alias = "ls")]
+    Connections,
+
+    /// Close the tunnels of a node
+    Kick {
+        /// Node ID, or a unique prefix of it
+        node_id: String,
+
+        /// Only close the tunnel with this ID, as shown by `connections`
+        #[clap(long)]
+        id: Option<usize>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TicketCommand {
+    /// Print a ticket for a port of this node
+    Create {
+        /// Port clients will connect to
+        #[clap(long)]
+        port: u16,
+
+        /// Protocol clients will use
+        #[clap(short = 'P', long, default_value = "tcp")]
+        protocol: Protocol,
     },
 }
 
@@ -78,13 +551,36 @@ pub enum HostCommand {
         /// Name of the host
         name: String,
         /// Node ID of the host
-        id: String,
+        #[clap(required_unless_present = "dns")]
+        id: Option<String>,
+
+        /// Domain publishing the node ID in a `_punch.<domain>` TXT record, looked up on
+        /// every connection
+        #[clap(long, conflicts_with = "id")]
+        dns: Option<String>,
+
+        /// Relay to always use for this host
+        #[clap(long)]
+        relay_url: Option<RelayUrl>,
+
+        /// Node ID to fail over to when the host can't be reached, tried in order (repeatable)
+        #[clap(long = "backup")]
+        backups: Vec<NodeId>,
+
+        /// Mapping used by `punch connect` for this host
+        #[clap(short, long)]
+        mapping: Option<Mapping>,
+
+        /// Protocol used by `punch connect` for this host
+        #[clap(short = 'P', long, requires = "mapping")]
+        protocol: Option<Protocol>,
     },
 
     /// Remove a host by name or ID
     #[command(visible_alias = "rm")]
     Remove {
         /// Name or Node ID of the host to remove
+        #[clap(value_hint = ValueHint::Hostname)]
         identifier: String,
     },
 
@@ -94,6 +590,24 @@ pub enum HostCommand {
         /// Show the full Node ID
         #[clap(short, long)]
         full: bool,
+
+        /// Only print the names, one per line, as used by shell completions
+        #[clap(long, conflicts_with_all = ["full", "stats"])]
+        plain: bool,
+
+        /// Show connection totals for each host
+        #[clap(short, long)]
+        stats: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum KeyCommand {
+    /// Use an OpenSSH ed25519 private key as the node's key, so that the Node ID is its
+    /// public key, keeping the previous key in a backup
+    Import {
+        /// Private key to import, e.g. ~/.ssh/id_ed25519
+        path: PathBuf,
     },
 }
 
@@ -101,12 +615,35 @@ pub enum HostCommand {
 pub enum AuthCommand {
     /// List authorized keys
     #[command(visible_alias = "ls")]
-    List,
+    List {
+        /// Only list keys that opened no session for this long, e.g. 90d (needs the SQLite store)
+        #[clap(long, value_parser = parse_duration)]
+        unused: Option<u64>,
+    },
 
     /// Add an authorized key
     Add {
         /// Public key to authorize
         key: String,
+
+        /// Role of the key: admin, user or guest
+        #[clap(long)]
+        role: Option<Role>,
+    },
+
+    /// Change the role of a key: admin, user or guest
+    Role {
+        /// Public key to change the role of
+        key: String,
+
+        role: Role,
+    },
+
+    /// Authorize many keys at once, from a file or the SSH keys of a GitHub user
+    Import {
+        /// File with one key per line, or gh:<username> for the ed25519 SSH keys of a
+        /// GitHub user
+        source: ImportSource,
     },
 
     /// Remove an authorized key
@@ -119,18 +656,94 @@ pub enum AuthCommand {
     /// Show your public key
     #[command(name = "my-key")]
     MyKey,
+
+    /// Ask a server to authorize your key
+    Request {
+        /// Node ID or host name of the server
+        to: String,
+
+        /// Ports you need access to, can be repeated
+        #[clap(long = "port")]
+        ports: Vec<u16>,
+
+        /// Message shown to the server administrator
+        #[clap(long)]
+        reason: Option<String>,
+    },
+
+    /// Review access requests sent to this server
+    Requests {
+        #[clap(subcommand)]
+        command: AccessRequestCommand,
+    },
 }
 
-fn parse_mapping(s: &str) -> Result<(u16, u16), String> {
-    let parts: Vec<&str> = s.split(':').collect();
-    if parts.len() != 2 {
-        return Err("Mapping must be in the format 'local_port:remote_port'".to_string());
-    }
-    let local_port = parts[0]
-        .parse::<u16>()
-        .map_err(|_| "Invalid local port".to_string())?;
-    let remote_port = parts[1]
-        .parse::<u16>()
-        .map_err(|_| "Invalid remote port".to_string())?;
-    Ok((local_port, remote_port))
+#[derive(Debug, Subcommand)]
+pub enum AccessRequestCommand {
+    /// List pending access requests
+    #[command(visible_alias = "ls")]
+    List,
+
+    /// Authorize the key of a pending request
+    Approve {
+        /// Public key of the requesting node
+        key: String,
+    },
+
+    /// Discard a pending request
+    Deny {
+        /// Public key of the requesting node
+        key: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Check both config files and report every problem found
+    Validate,
+
+    /// Open a config in $EDITOR, saving it only once it is valid
+    Edit {
+        /// Edit the server config instead of the client one
+        #[clap(long)]
+        server: bool,
+    },
+
+    /// Write the key and configs to a passphrase-encrypted file
+    Backup {
+        /// File to write the backup to
+        #[clap(short, long, default_value = "punch-backup.age")]
+        output: PathBuf,
+    },
+
+    /// Restore the key and configs from a file written by `punch config backup`
+    Restore {
+        /// Backup to restore
+        input: PathBuf,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SyncCommand {
+    /// Merge our hosts into those of another node
+    Push {
+        /// Identifier of the node to sync with (Node ID or name)
+        #[clap(value_hint = ValueHint::Hostname)]
+        to: String,
+
+        /// Also authorize our authorized keys on that node, after confirming
+        #[clap(long)]
+        authorized_keys: bool,
+    },
+
+    /// Merge the hosts of another node into ours
+    Pull {
+        /// Identifier of the node to sync with (Node ID or name)
+        #[clap(value_hint = ValueHint::Hostname)]
+        to: String,
+
+        /// Also authorize the keys authorized on that node, after confirming
+        #[clap(long)]
+        authorized_keys: bool,
+    },
 }
@@ -0,0 +1,242 @@
+use crate::Result;
+use crate::utils::{
+    access::{AccessRequest, AccessRequests, SubmitOutcome},
+    config::{AuthorizationManager, ConfigCache, ServerConfig},
+    constants::{
+        ACCESS_ALPN, ACCESS_REQUEST_BURST, ACCESS_REQUEST_INTERVAL, HANDSHAKE_TIMEOUT,
+        MAX_ACCESS_REASON_LEN, MAX_ACCESS_REQUEST_SIZE,
+    },
+    notifications::{self, NotificationEvent},
+    reduced_node_id,
+};
+use iroh::{
+    Endpoint, NodeId,
+    endpoint::{Connection, RecvStream, SendStream},
+    protocol::ProtocolHandler,
+};
+use n0_future::boxed::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::Instant;
+
+/// Most ports a single request may list
+const MAX_REQUESTED_PORTS: usize = 64;
+
+/// What the server did with an access request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AccessStatus {
+    Submitted = 0,
+    Updated = 1,
+    AlreadyAuthorized = 2,
+    /// Too many requests came in lately. Older servers sent it when their list was full
+    Throttled = 3,
+    Disabled = 4,
+}
+
+impl TryFrom<u8> for AccessStatus {
+    type Error = crate::utils::error::PunchError;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(AccessStatus::Submitted),
+            1 => Ok(AccessStatus::Updated),
+            2 => Ok(AccessStatus::AlreadyAuthorized),
+            3 => Ok(AccessStatus::Throttled),
+            4 => Ok(AccessStatus::Disabled),
+            _ => Err(crate::error!("Unknown access request status: {}", value)),
+        }
+    }
+}
+
+impl From<SubmitOutcome> for AccessStatus {
+    fn from(outcome: SubmitOutcome) -> Self {
+        match outcome {
+            SubmitOutcome::Stored | SubmitOutcome::Evicted => AccessStatus::Submitted,
+            SubmitOutcome::Updated => AccessStatus::Updated,
+        }
+    }
+}
+
+/// Request body as sent on the wire, the node ID comes from the connection itself.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RequestBody {
+    #[serde(default)]
+    ports: Vec<u16>,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+/// Asks `node_id` to add us to its authorized keys.
+pub async fn request(
+    endpoint: &Endpoint,
+    node_id: NodeId,
+    ports: Vec<u16>,
+    reason: Option<String>,
+) -> Result<AccessStatus> {
+    let conn = endpoint.connect(node_id, ACCESS_ALPN).await?;
+    let (mut send, mut recv) = conn.open_bi().await?;
+
+    let body =
+        serde_json::to_vec(&RequestBody { ports, reason }).map_err(|e| crate::error!("{}", e))?;
+    AsyncWriteExt::write_all(&mut send, &body).await?;
+    send.finish()
+        .map_err(|e| crate::error!("Failed to finish stream: {}", e))?;
+
+    let status = AccessStatus::try_from(recv.read_u8().await?)?;
+    conn.close(0u8.into(), b"done");
+    Ok(status)
+}
+
+/// Stores access requests from nodes that aren't authorized yet.
+#[derive(Debug, Clone)]
+pub struct AccessService {
+    config: Arc<ConfigCache<ServerConfig>>,
+    auth_manager: Arc<AuthorizationManager>,
+    requests: Arc<AccessRequests>,
+    throttle: Arc<Mutex<Throttle>>,
+}
+
+/// Caps how fast requests come in across all nodes, as node IDs cost nothing to make.
+#[derive(Debug)]
+struct Throttle {
+    /// Requests that can come in right away
+    allowance: f64,
+    updated: Instant,
+}
+
+impl Throttle {
+    fn new() -> Self {
+        Self {
+            allowance: ACCESS_REQUEST_BURST as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Takes a request from the allowance, `false` when there is none left.
+    fn allow(&mut self) -> bool {
+        let now = Instant::now();
+        let refill =
+            now.duration_since(self.updated).as_secs_f64() / ACCESS_REQUEST_INTERVAL.as_secs_f64();
+        self.allowance = (self.allowance + refill).min(ACCESS_REQUEST_BURST as f64);
+        self.updated = now;
+
+        if self.allowance < 1.0 {
+            return false;
+        }
+        self.allowance -= 1.0;
+        true
+    }
+}
+
+impl AccessService {
+    pub fn new(
+        config: Arc<ConfigCache<ServerConfig>>,
+        auth_manager: Arc<AuthorizationManager>,
+    ) -> Self {
+        let requests = Arc::new(AccessRequests::new(config.manager().access_requests_path()));
+        Self {
+            config,
+            auth_manager,
+            requests,
+            throttle: Arc::new(Mutex::new(Throttle::new())),
+        }
+    }
+
+    async fn handle(
+        &self,
+        node_id: NodeId,
+        mut send: SendStream,
+        mut recv: RecvStream,
+    ) -> Result<()> {
+        let status = self.submit(node_id, &mut recv).await?;
+        send.write_u8(status as u8).await?;
+        send.finish()
+            .map_err(|e| crate::error!("Failed to finish stream: {}", e))?;
+        send.stopped().await.ok();
+        Ok(())
+    }
+
+    async fn submit(&self, node_id: NodeId, recv: &mut RecvStream) -> Result<AccessStatus> {
+        let config = self.config.get();
+        if !config.settings.access_requests {
+            return Ok(AccessStatus::Disabled);
+        }
+        if self.auth_manager.is_authorized(&node_id).await? {
+            return Ok(AccessStatus::AlreadyAuthorized);
+        }
+        if !self.throttle.lock().unwrap().allow() {
+            tracing::debug!("Throttled the access request from {}", node_id);
+            return Ok(AccessStatus::Throttled);
+        }
+
+        let body =
+            tokio::time::timeout(HANDSHAKE_TIMEOUT, recv.read_to_end(MAX_ACCESS_REQUEST_SIZE))
+                .await
+                .map_err(|_| crate::error!("Timed out reading access request"))?
+                .map_err(|e| crate::error!("Failed to read access request: {}", e))?;
+        let body: RequestBody = serde_json::from_slice(&body)
+            .map_err(|e| crate::error!("Invalid access request: {}", e))?;
+
+        let mut ports = body.ports;
+        ports.sort_unstable();
+        ports.dedup();
+        ports.truncate(MAX_REQUESTED_PORTS);
+
+        let request = AccessRequest {
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            node_id,
+            ports,
+            reason: body.reason.as_deref().and_then(sanitize_reason),
+        };
+
+        let outcome = self.requests.submit(request).await?;
+        crate::info!(
+            "Access request from node {}, review it with `punch auth requests list`",
+            reduced_node_id(&node_id)
+        );
+        if outcome == SubmitOutcome::Evicted {
+            crate::warning!("Too many pending access requests, dropped the oldest one");
+        }
+        // A node updating its request has already been announced
+        if outcome != SubmitOutcome::Updated {
+            notifications::notify(
+                &config.notifications,
+                NotificationEvent::NewKey,
+                format!("{} asks for access", node_id),
+            );
+        }
+        Ok(outcome.into())
+    }
+}
+
+/// Strips control characters so that the reason is safe to print in the admin's terminal.
+fn sanitize_reason(reason: &str) -> Option<String> {
+    let reason: String = reason
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .take(MAX_ACCESS_REASON_LEN)
+        .collect();
+    let reason = reason.trim();
+    (!reason.is_empty()).then(|| reason.to_string())
+}
+
+impl ProtocolHandler for AccessService {
+    fn accept(&self, conn: Connection) -> BoxFuture<anyhow::Result<()>> {
+        let service = self.clone();
+
+        Box::pin(async move {
+            let node_id = conn.remote_node_id()?;
+            let (send, recv) = tokio::time::timeout(HANDSHAKE_TIMEOUT, conn.accept_bi()).await??;
+
+            if let Err(e) = service.handle(node_id, send, recv).await {
+                tracing::debug!("Access request from {} failed: {}", node_id, e);
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,130 @@
+use crate::core::TunnelConnection;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// How a local connection picks the host it goes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strategy {
+    /// Each host in turn
+    #[default]
+    RoundRobin,
+    /// The host with the fewest connections in flight
+    LeastLoaded,
+}
+
+impl std::str::FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "round-robin" | "rr" => Ok(Strategy::RoundRobin),
+            "least-loaded" | "ll" => Ok(Strategy::LeastLoaded),
+            _ => Err("Invalid strategy. Use 'round-robin' or 'least-loaded'.".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Strategy::RoundRobin => write!(f, "round-robin"),
+            Strategy::LeastLoaded => write!(f, "least-loaded"),
+        }
+    }
+}
+
+/// A tunnel to one of the hosts behind a balanced listener.
+pub struct Backend {
+    pub name: String,
+    pub tunnel: TunnelConnection,
+    active: AtomicUsize,
+}
+
+impl Backend {
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+}
+
+/// Spreads the connections of a local listener over tunnels to several hosts. Hosts whose
+/// tunnel closed are skipped, the others keep serving.
+pub struct Balancer {
+    backends: Vec<Arc<Backend>>,
+    strategy: Strategy,
+    next: AtomicUsize,
+}
+
+impl Balancer {
+    pub fn new(strategy: Strategy) -> Self {
+        Self {
+            backends: Vec::new(),
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn with_backend(mut self, name: String, tunnel: TunnelConnection) -> Self {
+        self.backends.push(Arc::new(Backend {
+            name,
+            tunnel,
+            active: AtomicUsize::new(0),
+        }));
+        self
+    }
+
+    pub fn backends(&self) -> &[Arc<Backend>] {
+        &self.backends
+    }
+
+    /// Picks the host for a new connection, `None` once every tunnel closed. The host
+    /// counts the connection as in flight until the lease is dropped.
+    pub fn pick(&self) -> Option<Lease> {
+        let open: Vec<_> = self
+            .backends
+            .iter()
+            .filter(|backend| !backend.tunnel.is_closed())
+            .collect();
+        if open.is_empty() {
+            return None;
+        }
+
+        // Starting from a rotating offset spreads ties between idle hosts too
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let backend = match self.strategy {
+            Strategy::RoundRobin => open[start % open.len()],
+            Strategy::LeastLoaded => (0..open.len())
+                .map(|offset| open[(start + offset) % open.len()])
+                .min_by_key(|backend| backend.active())?,
+        };
+
+        backend.active.fetch_add(1, Ordering::Relaxed);
+        Some(Lease(Arc::clone(backend)))
+    }
+
+    /// Resolves once the tunnels to all hosts closed.
+    pub async fn wait_closed(&self) {
+        n0_future::join_all(
+            self.backends
+                .iter()
+                .map(|backend| backend.tunnel.wait_closed()),
+        )
+        .await;
+    }
+}
+
+/// A connection in flight on a backend.
+pub struct Lease(Arc<Backend>);
+
+impl std::ops::Deref for Lease {
+    type Target = Backend;
+
+    fn deref(&self) -> &Backend {
+        &self.0
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,257 @@
+use crate::Result;
+use crate::core::limit::StreamLimit;
+use crate::utils::config::AuthorizationManager;
+use crate::utils::constants::{BENCH_ALPN, DEFAULT_MAX_STREAMS_PER_CONNECTION, MAX_BENCH_DURATION};
+use crate::{CloseReason, utils::reduced_node_id};
+use iroh::{
+    Endpoint, NodeId,
+    endpoint::{Connection, ConnectionType, RecvStream, SendStream},
+    protocol::ProtocolHandler,
+};
+use n0_future::boxed::BoxFuture;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const KIND_PING: u8 = 0x0;
+const KIND_UPLOAD: u8 = 0x1;
+const KIND_DOWNLOAD: u8 = 0x2;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Length of each throughput test
+    pub duration: Duration,
+    /// Number of round trips used to measure latency
+    pub pings: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Throughput {
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl Throughput {
+    pub fn bits_per_second(&self) -> f64 {
+        self.bytes as f64 * 8.0 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    pub path: ConnectionType,
+    /// Round trip times, sorted
+    pub rtts: Vec<Duration>,
+    /// Mean difference between consecutive round trips, `None` with fewer than two
+    pub jitter: Option<Duration>,
+    pub upload: Throughput,
+    pub download: Throughput,
+}
+
+impl BenchReport {
+    /// Returns the `p`th percentile (0-100) of the measured round trip times.
+    pub fn rtt_percentile(&self, p: f64) -> Option<Duration> {
+        if self.rtts.is_empty() {
+            return None;
+        }
+        let index = ((p / 100.0) * (self.rtts.len() - 1) as f64).round() as usize;
+        self.rtts.get(index).copied()
+    }
+}
+
+/// Runs latency, upload and download tests against `node_id`'s bench service.
+pub async fn run(
+    endpoint: &Endpoint,
+    node_id: NodeId,
+    options: &BenchOptions,
+) -> Result<BenchReport> {
+    let conn = endpoint.connect(node_id, BENCH_ALPN).await?;
+
+    let mut rtts = ping(&conn, options.pings).await?;
+    let jitter = jitter(&rtts);
+    rtts.sort();
+    let upload = upload(&conn, options.duration).await?;
+    let download = download(&conn, options.duration).await?;
+
+    let path = endpoint
+        .conn_type(node_id)?
+        .get()
+        .unwrap_or(ConnectionType::None);
+    conn.close(0u8.into(), b"done");
+
+    Ok(BenchReport {
+        path,
+        rtts,
+        jitter,
+        upload,
+        download,
+    })
+}
+
+/// Takes the round trips in the order they were measured.
+fn jitter(rtts: &[Duration]) -> Option<Duration> {
+    if rtts.len() < 2 {
+        return None;
+    }
+    let total: Duration = rtts.windows(2).map(|pair| pair[0].abs_diff(pair[1])).sum();
+    Some(total / (rtts.len() - 1) as u32)
+}
+
+async fn ping(conn: &Connection, count: usize) -> Result<Vec<Duration>> {
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send.write_u8(KIND_PING).await?;
+
+    let mut rtts = Vec::with_capacity(count);
+    for seq in 0..count as u64 {
+        let started_at = Instant::now();
+        send.write_u64(seq).await?;
+        if recv.read_u64().await? != seq {
+            return Err(crate::error!("Unexpected ping reply"));
+        }
+        rtts.push(started_at.elapsed());
+    }
+
+    finish(&mut send)?;
+    Ok(rtts)
+}
+
+async fn upload(conn: &Connection, duration: Duration) -> Result<Throughput> {
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send.write_u8(KIND_UPLOAD).await?;
+
+    let chunk = vec![0u8; CHUNK_SIZE];
+    let started_at = Instant::now();
+    while started_at.elapsed() < duration {
+        AsyncWriteExt::write_all(&mut send, &chunk).await?;
+    }
+    finish(&mut send)?;
+
+    // The server answers once it has read everything, so the time includes draining
+    let bytes = recv.read_u64().await?;
+    Ok(Throughput {
+        bytes,
+        elapsed: started_at.elapsed(),
+    })
+}
+
+async fn download(conn: &Connection, duration: Duration) -> Result<Throughput> {
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send.write_u8(KIND_DOWNLOAD).await?;
+    send.write_u32(duration.as_millis() as u32).await?;
+    finish(&mut send)?;
+
+    let started_at = Instant::now();
+    let bytes = drain(&mut recv).await?;
+    Ok(Throughput {
+        bytes,
+        elapsed: started_at.elapsed(),
+    })
+}
+
+async fn drain(recv: &mut RecvStream) -> Result<u64> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut total = 0;
+    loop {
+        match AsyncReadExt::read(recv, &mut buf).await? {
+            0 => return Ok(total),
+            n => total += n as u64,
+        }
+    }
+}
+
+fn finish(send: &mut SendStream) -> Result<()> {
+    send.finish()
+        .map_err(|e| crate::error!("Failed to finish stream: {}", e))
+}
+
+/// Serves bench requests from authorized nodes.
+#[derive(Debug, Clone)]
+pub struct BenchService {
+    auth_manager: Arc<AuthorizationManager>,
+}
+
+impl BenchService {
+    pub fn new(auth_manager: Arc<AuthorizationManager>) -> Self {
+        Self { auth_manager }
+    }
+
+    async fn serve(conn: Connection) -> Result<()> {
+        let streams = StreamLimit::new(DEFAULT_MAX_STREAMS_PER_CONNECTION, None, 0);
+        loop {
+            let permit = streams.acquire().await;
+            let (send, recv) = match conn.accept_bi().await {
+                Ok(streams) => streams,
+                Err(_) => return Ok(()),
+            };
+
+            tokio::spawn(async move {
+                let _permit = permit;
+                if let Err(e) = Self::serve_stream(send, recv).await {
+                    tracing::debug!("Bench stream failed: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn serve_stream(mut send: SendStream, mut recv: RecvStream) -> Result<()> {
+        match recv.read_u8().await? {
+            KIND_PING => {
+                while let Ok(seq) = recv.read_u64().await {
+                    send.write_u64(seq).await?;
+                }
+            }
+            KIND_UPLOAD => {
+                let bytes = drain(&mut recv).await?;
+                send.write_u64(bytes).await?;
+            }
+            KIND_DOWNLOAD => {
+                let millis = recv.read_u32().await?;
+                let duration = Duration::from_millis(millis as u64).min(MAX_BENCH_DURATION);
+
+                let chunk = vec![0u8; CHUNK_SIZE];
+                let started_at = Instant::now();
+                while started_at.elapsed() < duration {
+                    AsyncWriteExt::write_all(&mut send, &chunk).await?;
+                }
+            }
+            kind => return Err(crate::error!("Unknown bench request: {}", kind)),
+        }
+
+        finish(&mut send)?;
+        send.stopped().await.ok();
+        Ok(())
+    }
+}
+
+impl ProtocolHandler for BenchService {
+    fn on_connecting(
+        &self,
+        connecting: iroh::endpoint::Connecting,
+    ) -> BoxFuture<anyhow::Result<Connection>> {
+        let auth_manager = Arc::clone(&self.auth_manager);
+
+        Box::pin(async move {
+            let conn = connecting.await?;
+            let node_id = conn.remote_node_id()?;
+
+            if !auth_manager.is_authorized(&node_id).await? {
+                CloseReason::Unauthorized.execute(&conn);
+                anyhow::bail!("Unauthorized bench request from {}", node_id);
+            }
+
+            Ok(conn)
+        })
+    }
+
+    fn accept(&self, conn: Connection) -> BoxFuture<anyhow::Result<()>> {
+        Box::pin(async move {
+            let node_id = conn.remote_node_id()?;
+            tracing::info!("Running bench for node: {}", reduced_node_id(&node_id));
+
+            Self::serve(conn).await?;
+            Ok(())
+        })
+    }
+}
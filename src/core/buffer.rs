@@ -0,0 +1,309 @@
+use crate::ResetReason;
+use crate::core::TrafficStats;
+use crate::utils::config::BufferSettings;
+use crate::utils::constants::MAX_WRITE_CHUNKS;
+use crate::utils::constants::{DEFAULT_BUFFER_POOL_CAPACITY, DEFAULT_BUFFER_SIZE};
+use bytes::Bytes;
+use iroh::endpoint::{RecvStream, SendStream};
+use std::io::IoSlice;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Fixed-size buffers shared by all streams of a process, so that opening a stream
+/// doesn't allocate once the pool is warm.
+#[derive(Debug)]
+pub struct BufferPool {
+    buffer_size: usize,
+    capacity: usize,
+    buffers: Mutex<Vec<Box<[u8]>>>,
+}
+
+impl BufferPool {
+    /// Creates a pool handing out `buffer_size` byte buffers, keeping at most `capacity`
+    /// idle buffers around.
+    pub fn new(buffer_size: usize, capacity: usize) -> Self {
+        Self {
+            buffer_size,
+            capacity,
+            buffers: Mutex::new(Vec::with_capacity(capacity)),
+        }
+    }
+
+    pub fn from_settings(settings: &BufferSettings) -> Self {
+        Self::new(
+            settings.size.unwrap_or(DEFAULT_BUFFER_SIZE),
+            settings
+                .pool_capacity
+                .unwrap_or(DEFAULT_BUFFER_POOL_CAPACITY),
+        )
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    pub fn get(self: &Arc<Self>) -> PooledBuffer {
+        let buffer = self
+            .buffers
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| vec![0u8; self.buffer_size].into_boxed_slice());
+
+        PooledBuffer {
+            buffer: Some(buffer),
+            pool: Arc::clone(self),
+        }
+    }
+
+    fn put(&self, buffer: Box<[u8]>) {
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.capacity {
+            buffers.push(buffer);
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_BUFFER_SIZE, DEFAULT_BUFFER_POOL_CAPACITY)
+    }
+}
+
+/// A buffer borrowed from a [`BufferPool`], returned to it when dropped.
+pub struct PooledBuffer {
+    buffer: Option<Box<[u8]>>,
+    pool: Arc<BufferPool>,
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.buffer.as_deref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.buffer.as_deref_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            self.pool.put(buffer);
+        }
+    }
+}
+
+/// Copies both directions between the tunnel and a local stream until both reach EOF,
+/// counting bytes into `stats` as they flow. Returns `(bytes_in, bytes_out)`.
+///
+/// Data from the local stream goes through a buffer of `pool`, while data from the tunnel is
+/// written straight from the chunks quinn received it in, several per write.
+///
+/// Each direction ends on its own: EOF on one side shuts down the write half of the other
+/// while the opposite direction keeps flowing, so half-closing protocols see every byte.
+/// A failure ends both, and resets the tunnel stream instead of finishing it, so that the
+/// peer can tell an aborted stream from a complete one.
+pub async fn bridge(
+    tunnel: (RecvStream, SendStream),
+    local: (impl AsyncRead + Unpin, impl AsyncWrite + Unpin),
+    pool: &Arc<BufferPool>,
+    stats: &TrafficStats,
+) -> std::io::Result<(u64, u64)> {
+    let (mut tunnel_recv, mut tunnel_send) = tunnel;
+    let (mut local_read, mut local_write) = local;
+
+    let mut finished = false;
+    let outbound = async {
+        let total = copy(
+            &mut local_read,
+            &mut tunnel_send,
+            pool,
+            stats,
+            &stats.bytes_out,
+        )
+        .await?;
+        finished = true;
+        Ok(total)
+    };
+    let result = tokio::try_join!(
+        copy_chunks(&mut tunnel_recv, &mut local_write, stats, &stats.bytes_in),
+        outbound,
+    );
+
+    // Dropping the send stream would finish it, passing what was sent so far off as the
+    // whole stream
+    if result.is_err() && !finished {
+        tunnel_send.reset((&ResetReason::Aborted).into()).ok();
+    }
+    result
+}
+
+async fn copy(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    pool: &Arc<BufferPool>,
+    stats: &TrafficStats,
+    counter: &AtomicU64,
+) -> std::io::Result<u64> {
+    let mut buffer = pool.get();
+    let mut total = 0;
+
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            writer.shutdown().await?;
+            return Ok(total);
+        }
+
+        stats.throttle(read as u64).await;
+        writer.write_all(&buffer[..read]).await?;
+        total += read as u64;
+        counter.fetch_add(read as u64, Ordering::Relaxed);
+    }
+}
+
+/// Copies what `recv` receives to `writer` without going through a buffer: the chunks
+/// ready at once are written with a single vectored write where `writer` supports it.
+async fn copy_chunks(
+    recv: &mut RecvStream,
+    writer: &mut (impl AsyncWrite + Unpin),
+    stats: &TrafficStats,
+    counter: &AtomicU64,
+) -> std::io::Result<u64> {
+    let mut chunks = vec![Bytes::new(); MAX_WRITE_CHUNKS];
+    let mut total = 0;
+
+    loop {
+        let Some(count) = recv.read_chunks(&mut chunks).await? else {
+            writer.shutdown().await?;
+            return Ok(total);
+        };
+        let chunks = &mut chunks[..count];
+        let read = chunks.iter().map(Bytes::len).sum::<usize>() as u64;
+
+        stats.throttle(read).await;
+        write_all_vectored(writer, chunks).await?;
+        total += read;
+        counter.fetch_add(read, Ordering::Relaxed);
+    }
+}
+
+/// Writes every chunk, emptying them as it goes. Writers without vectored writes take one
+/// chunk per write.
+async fn write_all_vectored(
+    writer: &mut (impl AsyncWrite + Unpin),
+    chunks: &mut [Bytes],
+) -> std::io::Result<()> {
+    let mut first = 0;
+    loop {
+        while chunks.get(first).is_some_and(Bytes::is_empty) {
+            first += 1;
+        }
+        if first == chunks.len() {
+            return Ok(());
+        }
+
+        let mut slices = [IoSlice::new(&[]); MAX_WRITE_CHUNKS];
+        let pending = &chunks[first..];
+        for (slice, chunk) in slices.iter_mut().zip(pending) {
+            *slice = IoSlice::new(chunk);
+        }
+        let mut written = writer.write_vectored(&slices[..pending.len()]).await?;
+        if written == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+
+        while written > 0 {
+            let chunk = &mut chunks[first];
+            let advance = written.min(chunk.len());
+            bytes::Buf::advance(chunk, advance);
+            written -= advance;
+            if chunk.is_empty() {
+                first += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Takes at most `limit` bytes per write, across chunks
+    struct ShortWriter {
+        limit: usize,
+        written: Vec<u8>,
+        writes: usize,
+    }
+
+    impl AsyncWrite for ShortWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+        }
+
+        fn poll_write_vectored(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            bufs: &[IoSlice<'_>],
+        ) -> Poll<std::io::Result<usize>> {
+            let mut taken = 0;
+            for buf in bufs {
+                let take = buf.len().min(self.limit - taken);
+                self.written.extend_from_slice(&buf[..take]);
+                taken += take;
+            }
+            self.writes += 1;
+            Poll::Ready(Ok(taken))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            true
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_every_chunk_across_short_writes() {
+        let cases = [
+            (usize::MAX, vec!["hello", " ", "world"], 1),
+            (4, vec!["hello", " ", "world"], 3),
+            (1, vec!["ab", "c"], 3),
+            (8, vec!["", "hello", "", "world", ""], 2),
+            (8, vec!["", ""], 0),
+        ];
+
+        for (limit, chunks, writes) in cases {
+            let mut writer = ShortWriter {
+                limit,
+                written: Vec::new(),
+                writes: 0,
+            };
+            let mut buffers: Vec<Bytes> = chunks.iter().map(|c| Bytes::from(*c)).collect();
+            write_all_vectored(&mut writer, &mut buffers).await.unwrap();
+
+            assert_eq!(writer.written, chunks.concat().as_bytes(), "{:?}", chunks);
+            assert_eq!(writer.writes, writes, "{:?} by {}", chunks, limit);
+            assert!(buffers.iter().all(Bytes::is_empty));
+        }
+    }
+}
@@ -1,56 +1,609 @@
-use crate::core::{Protocol, TunnelConnection};
-use crate::utils::config::{ClientConfig, Host, load_config, save_config};
-use crate::utils::constants::{ALPN, MAX_RETRIES};
-use crate::utils::reduced_node_id;
-use crate::{CloseReason, PunchError, Result};
+use crate::core::{
+    Protocol, TrafficStats, TunnelConnection, UdpMode,
+    balance::{Balancer, Strategy},
+    buffer::BufferPool,
+    datagram::OversizedPolicy,
+    dns::SystemResolver,
+    handshake::{self, Handshake},
+    mapping::{Forward, Mapping, SourceFilter},
+    net,
+    qos::Qos,
+};
+use crate::utils::backoff::Backoff;
+use crate::utils::config::{ClientConfig, ConfigManager, Host, HostManager, load_config};
+use crate::utils::constants::ALPN;
+use crate::utils::history::{History, HistoryRecord};
+use crate::utils::hooks::{self, HookContext, HookEvent};
+use crate::utils::notifications;
+use crate::utils::{
+    format::{format_elapsed, format_path},
+    output,
+    prompt::PromptMode,
+    reduced_node_id,
+};
+use crate::{CloseDetails, CloseReason, PunchError, Result};
 use inquire::validator::Validation;
-use iroh::{Endpoint, NodeId};
-use std::net::SocketAddr;
+use iroh::{
+    Endpoint, NodeAddr, NodeId, RelayUrl,
+    endpoint::{Connection, ConnectionError, ConnectionType},
+};
+use n0_future::{StreamExt, task::AbortOnDropHandle};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::net::{TcpListener, UdpSocket};
-use tokio::time::{Duration, sleep};
+use tokio::task::JoinSet;
+use tokio::time::{Duration, Instant, sleep};
+use tracing::Instrument;
+
+/// How long to wait for the server to answer a UDP mode request before assuming it
+/// predates the negotiation.
+const UDP_MODE_TIMEOUT: Duration = Duration::from_secs(3);
+const SERVICE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Default)]
+pub struct ClientOptions {
+    /// Address to bind local listeners to when the mapping doesn't specify one
+    pub bind: Option<IpAddr>,
+    pub allowed_sources: SourceFilter,
+    /// Host the server should forward to instead of its own loopback interface
+    pub remote_host: Option<String>,
+    /// Requested transport for UDP packets, servers may fall back to streams
+    pub udp_mode: UdpMode,
+    pub oversized: OversizedPolicy,
+    /// Service to ask the server for, which then decides the protocol and remote port
+    pub service: Option<String>,
+    pub prompt: PromptMode,
+    /// Relay the server is reached through, without waiting for discovery to find it
+    pub relay_url: Option<RelayUrl>,
+    /// Overrides the number of parallel connections from the settings
+    pub connections: Option<usize>,
+    /// Close the tunnel once it carried no traffic for this long
+    pub idle_exit: Option<Duration>,
+    /// Point the system resolver at the first local listener while the tunnels are up, for
+    /// `--dns`
+    pub set_resolver: bool,
+    /// Marking of the tunnels' sockets, unless the mapping has its own
+    pub qos: Qos,
+}
 
 pub struct Client {
     endpoint: Endpoint,
     config: ClientConfig,
+    options: ClientOptions,
+    /// Report path changes for as long as the client lives, one per tunnel
+    path_watchers: Vec<AbortOnDropHandle<()>>,
 }
 
 impl Client {
-    pub async fn new(endpoint: Endpoint) -> Result<Self> {
+    pub async fn new(endpoint: Endpoint, options: ClientOptions) -> Result<Self> {
         Ok(Self {
             endpoint,
             config: load_config().await?,
+            options,
+            path_watchers: Vec::new(),
         })
     }
 
     pub async fn connect(
         mut self,
         target: String,
-        local_port: u16,
-        remote_port: u16,
+        mapping: Mapping,
         protocol: Protocol,
     ) -> Result<()> {
+        let (tunnel, mapping) = self.open_tunnel(&target, mapping, protocol).await?;
+        let protocol = tunnel.protocol();
+        let local = LocalSocket::bind(
+            mapping.local_addr(self.options.bind),
+            protocol,
+            self.config.settings.acceptors,
+        )?;
+        // Recorded with the port actually picked for a local port of 0
+        let mapping = mapping.with_local_port(local.local_addr()?.port());
+
+        let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+        if let Some(idle) = self.options.idle_exit {
+            let stats = Arc::clone(tunnel.stats());
+            let shutdown_tx = shutdown_tx.clone();
+            tokio::spawn(async move {
+                stats.wait_idle(idle).await;
+                crate::info!(
+                    "No traffic for {}, closing the tunnel",
+                    format_elapsed(idle.as_secs())
+                );
+                let _ = shutdown_tx.send(true);
+            });
+        }
+        tokio::spawn(async move {
+            tokio::signal::ctrl_c().await.ok();
+            let _ = shutdown_tx.send(true);
+        });
+
+        let node_id = tunnel.remote_node_id()?;
+        let session = Session::start(&tunnel, node_id, &mapping);
+        let result = self
+            .handle_local_connections(tunnel, local, shutdown_rx)
+            .await;
+        let reason = session.close_reason(result.as_ref().err());
+
+        // Let the server know the session is over instead of waiting for the idle timeout
+        self.endpoint.close().await;
+        self.record_session(session, reason).await;
+        self.trigger_hook(
+            HookEvent::Disconnect,
+            node_id,
+            protocol,
+            mapping.remote_port,
+        );
+        result
+    }
+
+    /// Forwards several mappings to the same node, e.g. TCP and UDP on one port or a mappings
+    /// file. They are multiplexed over a single connection, each stream naming its target,
+    /// unless they need something only a connection of their own carries. Losing the
+    /// connection closes them all.
+    pub async fn connect_many(mut self, target: String, forwards: Vec<Forward>) -> Result<()> {
         let node_id = self.resolve_node_id(&target).await?;
+        crate::info!("Connecting to node {}", reduced_node_id(&node_id));
+        let tunnels = match self.open_multiplexed(node_id, &forwards).await? {
+            Some(tunnels) => tunnels,
+            None => {
+                let mut tunnels = Vec::with_capacity(forwards.len());
+                for forward in forwards {
+                    let (tunnel, _) = self
+                        .open_tunnel_to(
+                            node_id,
+                            forward.mapping,
+                            forward.protocol,
+                            forward.qos.or(self.options.qos),
+                        )
+                        .await?;
+                    tunnels.push((tunnel, forward.mapping));
+                }
+                tunnels
+            }
+        };
+
+        // A local port of 0 is only picked once per mapping, so that TCP and UDP share it
+        let mut picked: Vec<(Mapping, u16)> = Vec::new();
+        let mut bound = Vec::with_capacity(tunnels.len());
+        for (tunnel, requested) in tunnels {
+            let mapping = picked
+                .iter()
+                .find(|(mapping, _)| *mapping == requested)
+                .map_or(requested, |&(_, port)| requested.with_local_port(port));
+            let local = LocalSocket::bind(
+                mapping.local_addr(self.options.bind),
+                tunnel.protocol(),
+                self.config.settings.acceptors,
+            )?;
+            let port = local.local_addr()?.port();
+            if requested.local_port == 0 {
+                picked.push((requested, port));
+            }
+            bound.push((tunnel, local, requested.with_local_port(port)));
+        }
+        // Only once listening, names had to resolve for the connection to the server
+        let _resolver = match bound.first() {
+            Some((_, local, _)) if self.options.set_resolver => {
+                Some(SystemResolver::install(local.local_addr()?.ip())?)
+            }
+            _ => None,
+        };
+
+        let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+        if let Some(idle) = self.options.idle_exit {
+            let stats: Vec<_> = bound
+                .iter()
+                .map(|(tunnel, ..)| Arc::clone(tunnel.stats()))
+                .collect();
+            let shutdown_tx = shutdown_tx.clone();
+            tokio::spawn(async move {
+                let stats: Vec<&TrafficStats> = stats.iter().map(Arc::as_ref).collect();
+                TrafficStats::wait_all_idle(&stats, idle).await;
+                crate::info!(
+                    "No traffic for {}, closing the tunnels",
+                    format_elapsed(idle.as_secs())
+                );
+                let _ = shutdown_tx.send(true);
+            });
+        }
+        {
+            let shutdown_tx = shutdown_tx.clone();
+            tokio::spawn(async move {
+                tokio::signal::ctrl_c().await.ok();
+                let _ = shutdown_tx.send(true);
+            });
+        }
+
+        let sessions: Vec<_> = bound
+            .iter()
+            .map(|(tunnel, _, mapping)| (Session::start(tunnel, node_id, mapping), *mapping))
+            .collect();
+        let this = &self;
+        let shutdown_tx = &shutdown_tx;
+        let serving = bound.into_iter().map(|(tunnel, local, _)| {
+            let shutdown_rx = shutdown_rx.clone();
+            async move {
+                let result = this
+                    .handle_local_connections(tunnel, local, shutdown_rx)
+                    .await;
+                let _ = shutdown_tx.send(true);
+                result
+            }
+        });
+        let result = n0_future::join_all(serving)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<()>>>()
+            .map(drop);
+        let reasons: Vec<_> = sessions
+            .iter()
+            .map(|(session, _)| session.close_reason(result.as_ref().err()))
+            .collect();
+
+        self.endpoint.close().await;
+        for ((session, mapping), reason) in sessions.into_iter().zip(reasons) {
+            let protocol = session.protocol;
+            self.record_session(session, reason).await;
+            self.trigger_hook(
+                HookEvent::Disconnect,
+                node_id,
+                protocol,
+                mapping.remote_port,
+            );
+        }
+        result
+    }
+
+    /// Serves one local TCP port from several hosts exposing the same service, each local
+    /// connection going to one of them. Hosts that can't be reached are skipped.
+    pub async fn balance(
+        mut self,
+        targets: Vec<String>,
+        mapping: Mapping,
+        strategy: Strategy,
+    ) -> Result<()> {
+        let mut balancer = Balancer::new(strategy);
+        let mut sessions = Vec::new();
+        for target in targets {
+            let (tunnel, _) = match self.open_tunnel(&target, mapping, Protocol::Tcp).await {
+                Ok(opened) => opened,
+                Err(e) => {
+                    crate::warning!("Skipping {}: {}", target.bold(), e);
+                    continue;
+                }
+            };
+            if tunnel.protocol() != Protocol::Tcp {
+                crate::warning!("Skipping {}: only TCP can be balanced", target.bold());
+                continue;
+            }
+            sessions.push(Session::start(&tunnel, tunnel.remote_node_id()?, &mapping));
+            balancer = balancer.with_backend(target, tunnel);
+        }
+        if balancer.backends().is_empty() {
+            return Err(crate::error!("None of the hosts could be reached"));
+        }
+
+        let listeners = net::bind_tcp_listeners(
+            mapping.local_addr(self.options.bind),
+            self.config.settings.acceptors,
+        )?;
+        self.warn_if_exposed(listeners[0].local_addr()?);
+        crate::info!(
+            "Balancing over {} host(s) ({})",
+            balancer.backends().len(),
+            strategy
+        );
+
+        let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+        tokio::spawn(async move {
+            tokio::signal::ctrl_c().await.ok();
+            let _ = shutdown_tx.send(true);
+        });
+
+        let result = self
+            .handle_tcp_connections_with_shutdown(Arc::new(balancer), listeners, shutdown_rx)
+            .await;
+        let reasons: Vec<_> = sessions
+            .iter()
+            .map(|session| session.close_reason(result.as_ref().err()))
+            .collect();
+
+        self.endpoint.close().await;
+        for (session, reason) in sessions.into_iter().zip(reasons) {
+            let node_id = session.node_id;
+            self.record_session(session, reason).await;
+            self.trigger_hook(
+                HookEvent::Disconnect,
+                node_id,
+                Protocol::Tcp,
+                mapping.remote_port,
+            );
+        }
+        result
+    }
+
+    /// Runs `command` once the tunnel is up, with the mapping exported as `PUNCH_*`
+    /// environment variables, and tears the tunnel down when it exits. Returns the
+    /// command's exit code.
+    pub async fn run(
+        mut self,
+        target: String,
+        mapping: Mapping,
+        protocol: Protocol,
+        command: Vec<String>,
+    ) -> Result<i32> {
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| crate::error!("No command to run"))?;
+
+        let (tunnel, mapping) = self.open_tunnel(&target, mapping, protocol).await?;
+        let protocol = tunnel.protocol();
+        let node_id = tunnel.remote_node_id()?;
+        let local = LocalSocket::bind(
+            mapping.local_addr(self.options.bind),
+            protocol,
+            self.config.settings.acceptors,
+        )?;
+        let local_addr = local.local_addr()?;
+        let mapping = mapping.with_local_port(local_addr.port());
+
+        let mut child = tokio::process::Command::new(program)
+            .args(args)
+            .env("PUNCH_LOCAL_HOST", local_addr.ip().to_string())
+            .env("PUNCH_LOCAL_PORT", local_addr.port().to_string())
+            .env("PUNCH_LOCAL_ADDR", local_addr.to_string())
+            .env("PUNCH_REMOTE_PORT", mapping.remote_port.to_string())
+            .env("PUNCH_PROTOCOL", protocol.to_string().to_lowercase())
+            .env("PUNCH_NODE_ID", node_id.to_string())
+            .spawn()
+            .map_err(|e| crate::error!("Failed to run {}: {}", program, e))?;
+
+        let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+        let session = Session::start(&tunnel, node_id, &mapping);
+        let serve = self.handle_local_connections(tunnel, local, shutdown_rx);
+        tokio::pin!(serve);
+
+        let status = tokio::select! {
+            status = child.wait() => status?,
+            result = &mut serve => {
+                // The tunnel went away before the command finished
+                child.kill().await.ok();
+                result?;
+                child.wait().await?
+            }
+        };
 
+        let _ = shutdown_tx.send(true);
+        let reason = session.close_reason(None);
+        self.endpoint.close().await;
+        self.record_session(session, reason).await;
+        self.trigger_hook(
+            HookEvent::Disconnect,
+            node_id,
+            protocol,
+            mapping.remote_port,
+        );
+
+        Ok(status.code().unwrap_or(1))
+    }
+
+    /// Connects to `target` and negotiates the tunnel. When a service was requested, the
+    /// returned mapping carries the remote port it resolved to, and its local port too if
+    /// none was given.
+    async fn open_tunnel(
+        &mut self,
+        target: &str,
+        mapping: Mapping,
+        protocol: Protocol,
+    ) -> Result<(TunnelConnection, Mapping)> {
+        let node_id = self.resolve_node_id(target).await?;
         crate::info!("Connecting to node {}", reduced_node_id(&node_id));
+        self.open_tunnel_to(node_id, mapping, protocol, self.options.qos)
+            .await
+    }
+
+    async fn open_tunnel_to(
+        &mut self,
+        node_id: NodeId,
+        mut mapping: Mapping,
+        mut protocol: Protocol,
+        qos: Qos,
+    ) -> Result<(TunnelConnection, Mapping)> {
+        let candidates = self.failover_candidates(node_id);
+        let (connection, node_id, event) = self
+            .establish_connection(
+                &candidates,
+                &self.handshake(protocol, mapping.remote_port, qos),
+            )
+            .await?;
+
+        if let Some(service) = &self.options.service {
+            let (service_protocol, port) =
+                handshake::recv_service(&connection, SERVICE_TIMEOUT).await?;
+            tracing::debug!("Service {} is {} port {}", service, service_protocol, port);
+            protocol = service_protocol;
+            mapping.remote_port = port;
+            if mapping.local_port == 0 {
+                mapping.local_port = port;
+            }
+        }
+        let udp_mode = self.negotiate_udp_mode(&connection, protocol).await?;
+        self.trigger_hook(event, node_id, protocol, mapping.remote_port);
+        self.mark_connected(&node_id).await;
+
+        match &self.options.service {
+            Some(service) => crate::success!(
+                "Connected to service {} on node {} (remote port {})",
+                service.green().bold(),
+                reduced_node_id(&node_id),
+                mapping.remote_port
+            ),
+            None => crate::success!(
+                "Connected to node {} on remote port {}",
+                reduced_node_id(&node_id),
+                mapping.remote_port.green().bold()
+            ),
+        }
+        self.watch_path(node_id)?;
+
+        let buffers = BufferPool::from_settings(&self.config.network.buffers);
+        // UDP peers are tied to the connection their packets arrive on, only TCP is spread out
+        let striped = match protocol {
+            Protocol::Tcp => {
+                self.open_striped_connections(node_id, mapping.remote_port, qos)
+                    .await
+            }
+            Protocol::Udp => Vec::new(),
+        };
+        let tunnel = TunnelConnection::new(connection, protocol)
+            .with_udp_mode(udp_mode)
+            .with_oversized_policy(self.options.oversized)
+            .with_buffers(Arc::new(buffers))
+            .with_remote_port(mapping.remote_port)
+            .with_striped_connections(striped)
+            .with_qos(qos)
+            .with_tcp_settings(self.config.network.tcp);
+        Ok((tunnel, mapping))
+    }
+
+    /// Opens one connection for all of `forwards`, each of their streams starting with the
+    /// request of its mapping. `None` when they need connections of their own: a service
+    /// names no port for the streams to give, datagrams have no stream to carry the request,
+    /// and servers predating multiplexing only serve the first mapping.
+    async fn open_multiplexed(
+        &mut self,
+        node_id: NodeId,
+        forwards: &[Forward],
+    ) -> Result<Option<Vec<(TunnelConnection, Mapping)>>> {
+        let [first, ..] = forwards else {
+            return Ok(None);
+        };
+        let udp = forwards.iter().any(|f| f.protocol == Protocol::Udp);
+        if self.options.service.is_some() || (udp && self.options.udp_mode != UdpMode::Stream) {
+            return Ok(None);
+        }
 
-        let connection = self
-            .establish_connection(node_id, remote_port, protocol)
+        let candidates = self.failover_candidates(node_id);
+        let request = |forward: &Forward| {
+            let qos = forward.qos.or(self.options.qos);
+            self.handshake(forward.protocol, forward.mapping.remote_port, qos)
+                .with_udp_mode(None)
+                .with_stream_priority(forward.stream_priority)
+        };
+        let (connection, node_id, event) = self
+            .establish_connection(&candidates, &request(first).with_multiplexed(true))
             .await?;
+        if !handshake::recv_multiplexed(&connection, UDP_MODE_TIMEOUT).await? {
+            tracing::info!("Server doesn't multiplex mappings, opening a connection per mapping");
+            connection.close(0u32.into(), b"");
+            return Ok(None);
+        }
 
+        let buffers = Arc::new(BufferPool::from_settings(&self.config.network.buffers));
+        let mut tunnels = Vec::with_capacity(forwards.len());
+        for forward in forwards {
+            let header = handshake::stream_header(&request(forward))?;
+            let tunnel = TunnelConnection::new(connection.clone(), forward.protocol)
+                .with_udp_mode((forward.protocol == Protocol::Udp).then_some(UdpMode::Stream))
+                .with_stream_header(header)
+                .with_stream_priority(forward.stream_priority)
+                .with_buffers(Arc::clone(&buffers))
+                .with_remote_port(forward.mapping.remote_port)
+                .with_qos(forward.qos.or(self.options.qos))
+                .with_tcp_settings(self.config.network.tcp);
+            self.trigger_hook(
+                event,
+                node_id,
+                forward.protocol,
+                forward.mapping.remote_port,
+            );
+            tunnels.push((tunnel, forward.mapping));
+        }
+        self.mark_connected(&node_id).await;
         crate::success!(
-            "Connected to node {} on remote port {}",
+            "Connected to node {} with {} mappings",
             reduced_node_id(&node_id),
-            remote_port.green().bold()
+            forwards.len().green().bold()
+        );
+        self.watch_path(node_id)?;
+
+        Ok(Some(tunnels))
+    }
+
+    /// Bridges a single TCP stream with stdin/stdout, e.g. as an SSH `ProxyCommand`.
+    /// Stdout carries the tunneled data, so nothing else is printed to it.
+    pub async fn stdio(mut self, target: String, remote_port: u16) -> Result<()> {
+        let node_id = self
+            .config
+            .resolve_host(self.endpoint.dns_resolver(), &target)
+            .await?;
+
+        let candidates = self.failover_candidates(node_id);
+        let (connection, node_id, event) = self
+            .establish_connection(
+                &candidates,
+                &self.handshake(Protocol::Tcp, remote_port, self.options.qos),
+            )
+            .await?;
+        self.trigger_hook(event, node_id, Protocol::Tcp, remote_port);
+        tracing::info!(
+            "Connected to node {} on remote port {}",
+            node_id,
+            remote_port
         );
 
-        let tunnel = TunnelConnection::new(connection, protocol);
-        self.handle_local_connections(tunnel, local_port).await
+        let buffers = BufferPool::from_settings(&self.config.network.buffers);
+        let tunnel = TunnelConnection::new(connection, Protocol::Tcp)
+            .with_buffers(Arc::new(buffers))
+            .with_remote_port(remote_port);
+        let result = tunnel
+            .handle_local_io(tokio::io::stdin(), tokio::io::stdout())
+            .await;
+
+        self.endpoint.close().await;
+        self.trigger_hook(HookEvent::Disconnect, node_id, Protocol::Tcp, remote_port);
+        result
+    }
+
+    async fn negotiate_udp_mode(
+        &self,
+        conn: &iroh::endpoint::Connection,
+        protocol: Protocol,
+    ) -> Result<Option<UdpMode>> {
+        if protocol != Protocol::Udp {
+            return Ok(None);
+        }
+        let requested = self.options.udp_mode;
+
+        let mode = handshake::recv_udp_mode(conn, UDP_MODE_TIMEOUT).await?;
+        match mode {
+            Some(mode) if mode != requested => crate::warning!(
+                "Server doesn't support {} mode, falling back to {} mode",
+                requested,
+                mode
+            ),
+            None => crate::warning!(
+                "Server doesn't negotiate UDP modes, packet boundaries may not be preserved"
+            ),
+            _ => {}
+        }
+        Ok(mode)
+    }
+
+    /// Services may turn out to be UDP, so the mode is requested for them as well.
+    fn requested_udp_mode(&self, protocol: Protocol) -> Option<UdpMode> {
+        (protocol == Protocol::Udp || self.options.service.is_some())
+            .then_some(self.options.udp_mode)
     }
 
     async fn resolve_node_id(&mut self, target: &str) -> Result<NodeId> {
-        if let Some(host) = self.config.hosts.iter().find(|h| h.name == target) {
-            return Ok(host.id);
+        if self.config.hosts.iter().any(|h| h.name == target) {
+            return self
+                .config
+                .resolve_host(self.endpoint.dns_resolver(), target)
+                .await;
         }
 
         if let Ok(node_id) = target.parse::<NodeId>() {
@@ -67,17 +620,38 @@ impl Client {
         Err(anyhow::anyhow!("Invalid node ID or host name: {}", target).into())
     }
 
+    /// Without a terminal, unknown nodes are only saved when confirmations are accepted.
     async fn prompt_add_host(&self, node_id: &NodeId) -> Result<bool> {
-        inquire::Confirm::new(&format!(
-            "Connecting to node ID {}. Add it to known hosts?",
-            reduced_node_id(node_id)
-        ))
-        .with_default(true)
-        .prompt()
-        .map_err(Into::into)
+        let default = self.options.prompt.is_interactive();
+        self.options.prompt.confirm(
+            &format!(
+                "Connecting to node ID {}. Add it to known hosts?",
+                reduced_node_id(node_id)
+            ),
+            default,
+        )
     }
 
     async fn add_host_interactive(&mut self, node_id: NodeId) -> Result<()> {
+        let name = if self.options.prompt.is_interactive() {
+            self.prompt_host_name()?
+        } else {
+            self.default_host_name(&node_id)
+        };
+
+        self.config = ConfigManager::new()?
+            .update(|config: &mut ClientConfig| {
+                // Another process may have added the node since the config was loaded
+                if !config.hosts.iter().any(|h| h.id == node_id) {
+                    config.hosts.push(Host::new(name, node_id));
+                }
+                Ok(config.clone())
+            })
+            .await?;
+        Ok(())
+    }
+
+    fn prompt_host_name(&self) -> Result<String> {
         let hosts = self.config.hosts.clone();
         let name = inquire::Text::new("Enter a name for this host:")
             .with_validator(move |input: &str| {
@@ -92,129 +666,400 @@ impl Client {
                 }
             })
             .prompt()?;
+        Ok(name)
+    }
 
-        let new_host = Host {
-            name,
-            id: node_id,
-            added_at: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
-            description: None,
-            last_connected: None,
-        };
-        self.config.hosts.push(new_host);
-        save_config(&self.config).await?;
+    /// Named after the node ID, suffixed if that name is already taken.
+    fn default_host_name(&self, node_id: &NodeId) -> String {
+        let base = node_id.fmt_short();
+        let taken = |name: &str| self.config.hosts.iter().any(|h| h.name == name);
+        if !taken(&base) {
+            return base;
+        }
+        (2..)
+            .map(|i| format!("{}-{}", base, i))
+            .find(|name| !taken(name))
+            .unwrap()
+    }
+
+    /// Prints the current path to `node_id`, then every upgrade or downgrade of it.
+    fn watch_path(&mut self, node_id: NodeId) -> Result<()> {
+        let watcher = self.endpoint.conn_type(node_id)?;
+        let mut current = watcher.get().unwrap_or_default();
+        crate::info!("Path: {}", format_path(&current));
+
+        self.path_watchers
+            .push(AbortOnDropHandle::new(tokio::spawn(async move {
+                let mut updates = watcher.stream_updates_only();
+                while let Some(path) = updates.next().await {
+                    let change = match path_rank(&path).cmp(&path_rank(&current)) {
+                        std::cmp::Ordering::Greater => "upgraded",
+                        std::cmp::Ordering::Less => "downgraded",
+                        std::cmp::Ordering::Equal => "changed",
+                    };
+                    crate::info!(
+                        "Path {}: {} -> {}",
+                        change,
+                        format_path(&current),
+                        format_path(&path)
+                    );
+                    current = path;
+                }
+            })));
         Ok(())
     }
 
+    /// Remembers when we last reached a known host, which isn't worth failing the tunnel over.
+    async fn mark_connected(&mut self, node_id: &NodeId) {
+        let Some(host) = self.config.hosts.iter_mut().find(|h| h.has_node(node_id)) else {
+            return;
+        };
+        host.mark_connected();
+        let result = async {
+            HostManager::new(ConfigManager::new()?)
+                .mark_host_connected(node_id)
+                .await
+        };
+        if let Err(e) = result.await {
+            tracing::warn!("Failed to save the last connection time: {}", e);
+        }
+    }
+
+    /// Adds a finished session to the host's stats and to the history. The stored config is
+    /// updated rather than overwritten so that tunnels running in other processes don't
+    /// lose each other's totals.
+    async fn record_session(&self, session: Session, reason: String) {
+        let duration = session.started.elapsed();
+        // Bytes in come from the tunnel, so they are what we downloaded
+        let (bytes_down, bytes_up) = session.stats.totals();
+
+        let result = async {
+            ConfigManager::new()?
+                .update(|config: &mut ClientConfig| {
+                    let host = config
+                        .hosts
+                        .iter_mut()
+                        .find(|h| h.has_node(&session.node_id));
+                    Ok(host.map(|host| {
+                        host.stats.record_session(duration, bytes_up, bytes_down);
+                        host.name.clone()
+                    }))
+                })
+                .await
+        };
+        let host_name = match result.await {
+            Ok(host_name) => host_name,
+            Err(e) => {
+                tracing::warn!("Failed to save the host stats: {}", e);
+                None
+            }
+        };
+
+        let ended = SystemTime::now();
+        let record = HistoryRecord {
+            started: unix_secs(ended - duration),
+            ended: unix_secs(ended),
+            node_id: session.node_id.to_string(),
+            host: host_name,
+            protocol: session.protocol.to_string().to_lowercase(),
+            mapping: session.mapping,
+            bytes_up,
+            bytes_down,
+            reason,
+        };
+        let result = async {
+            History::new(ConfigManager::new()?.history_path())
+                .append(&record)
+                .await
+        };
+        if let Err(e) = result.await {
+            tracing::warn!("Failed to write the connection history: {}", e);
+        }
+    }
+
+    /// Runs the hook for `event`, and shows its notification.
+    fn trigger_hook(&self, event: HookEvent, node_id: NodeId, protocol: Protocol, port: u16) {
+        let context = HookContext {
+            peer: node_id,
+            protocol: protocol.to_string().to_lowercase(),
+            port,
+            target: None,
+        };
+        hooks::trigger(&self.config.hooks, event, &context);
+        notifications::notify(
+            &self.config.notifications,
+            event.into(),
+            format!("{} port {} on {}", protocol, port, node_id.fmt_short()),
+        );
+    }
+
+    /// The node IDs to try for `node_id`, followed by the backups of the host it belongs to.
+    fn failover_candidates(&self, node_id: NodeId) -> Vec<NodeId> {
+        let backups = self
+            .config
+            .hosts
+            .iter()
+            .find(|h| h.id == node_id)
+            .map(|h| h.backups.as_slice())
+            .unwrap_or_default();
+        std::iter::once(node_id)
+            .chain(backups.iter().copied().filter(|id| *id != node_id))
+            .collect()
+    }
+
+    /// Connects to the first of `candidates` that accepts us, trying them in order on every
+    /// attempt. Returns the node we reached and the hook event to fire once the tunnel is
+    /// set up.
     async fn establish_connection(
         &self,
-        node_id: NodeId,
-        remote_port: u16,
-        protocol: Protocol,
-    ) -> Result<iroh::endpoint::Connection> {
-        let mut retries = 0;
+        candidates: &[NodeId],
+        handshake: &Handshake,
+    ) -> Result<(iroh::endpoint::Connection, NodeId, HookEvent)> {
+        let mut backoff = Backoff::from_settings(&self.config.settings);
 
         loop {
-            match self.try_connect(node_id, remote_port, protocol).await {
-                Ok(conn) => return Ok(conn),
-                Err(PunchError::ConnectionClosed { reason }) => {
-                    tracing::error!("Connection closed by remote peer: {}", reason);
-                    return Err(PunchError::ConnectionClosed { reason });
-                }
-                Err(e) if retries < MAX_RETRIES => {
-                    retries += 1;
-                    tracing::warn!("Connection failed, retrying... ({})", e);
-                    sleep(Duration::from_secs(1)).await;
+            let mut errors = Vec::new();
+            for (index, &node_id) in candidates.iter().enumerate() {
+                match self.try_connect(node_id, handshake).await {
+                    Ok(conn) => {
+                        if index > 0 {
+                            crate::warning!(
+                                "Failed over to backup node {}",
+                                reduced_node_id(&node_id)
+                            );
+                        }
+                        let event = if backoff.retries() > 0 {
+                            HookEvent::Reconnect
+                        } else {
+                            HookEvent::Connect
+                        };
+                        return Ok((conn, node_id, event));
+                    }
+                    Err(e) => {
+                        if candidates.len() > 1 {
+                            tracing::warn!("Failed to reach {}: {}", node_id.fmt_short(), e);
+                        }
+                        errors.push(e);
+                    }
                 }
-                Err(e) => return Err(e),
             }
+            // Keep retrying as long as one of the candidates may still accept us
+            let retryable = errors.iter().position(PunchError::is_retryable);
+            let error = errors.swap_remove(retryable.unwrap_or(0));
+
+            // The server may tell us when it will accept us again
+            let requested = match &error {
+                PunchError::ConnectionClosed { details, .. } => details.retry_after,
+                _ => None,
+            };
+            let delay = match requested {
+                _ if !error.is_retryable() => None,
+                Some(seconds) => backoff.next_after(Duration::from_secs(seconds)),
+                None => backoff.next_delay(),
+            };
+            let Some(delay) = delay else {
+                if let PunchError::ConnectionClosed { reason, details } = &error {
+                    tracing::error!(
+                        "Connection closed by remote peer: {}",
+                        details.describe(reason)
+                    );
+                }
+                return Err(error);
+            };
+
+            tracing::warn!(
+                "Connection failed, retrying in {:.1?}... ({})",
+                delay,
+                error
+            );
+            sleep(delay).await;
         }
     }
 
-    async fn try_connect(
+    /// Opens the extra connections streams get spread over. The tunnel works without them,
+    /// so failures only shrink the pool.
+    async fn open_striped_connections(
         &self,
         node_id: NodeId,
         remote_port: u16,
-        protocol: Protocol,
-    ) -> Result<iroh::endpoint::Connection> {
-        let conn = self.endpoint.connect(node_id, ALPN).await?;
+        qos: Qos,
+    ) -> Vec<iroh::endpoint::Connection> {
+        let count = self
+            .options
+            .connections
+            .unwrap_or(self.config.settings.connections);
+        let handshake = self.handshake(Protocol::Tcp, remote_port, qos);
+        let attempts = (1..count).map(|_| self.try_connect(node_id, &handshake));
 
-        conn.send_datagram(bytes::Bytes::from(vec![protocol as u8]))?;
-        conn.send_datagram(bytes::Bytes::copy_from_slice(&remote_port.to_be_bytes()))?;
+        let mut connections = Vec::new();
+        for result in n0_future::join_all(attempts).await {
+            match result {
+                Ok(conn) => connections.push(conn),
+                Err(e) => tracing::warn!("Failed to open a parallel connection: {}", e),
+            }
+        }
+        if count > 1 {
+            tracing::info!(
+                "Spreading streams over {} connections",
+                connections.len() + 1
+            );
+        }
+        connections
+    }
 
-        tokio::select! {
-            _ = tokio::time::sleep(Duration::from_millis(100)) => {
+    /// The tunnel request for `remote_port`, with what the options ask of every tunnel.
+    fn handshake(&self, protocol: Protocol, remote_port: u16, qos: Qos) -> Handshake {
+        Handshake::new(protocol, remote_port)
+            .with_host(self.options.remote_host.clone())
+            .with_udp_mode(self.requested_udp_mode(protocol))
+            .with_service(self.options.service.clone())
+            .with_qos(qos)
+    }
 
-                Ok(conn)
+    async fn try_connect(
+        &self,
+        node_id: NodeId,
+        handshake: &Handshake,
+    ) -> Result<iroh::endpoint::Connection> {
+        let span = tracing::info_span!("handshake", peer = %node_id.fmt_short());
+        async {
+            let mut addr = NodeAddr::new(node_id);
+            if let Some(relay_url) = &self.options.relay_url {
+                addr = addr.with_relay_url(relay_url.clone());
             }
-            _ = conn.closed() => {
-                match conn.close_reason() {
-                    Some(iroh::endpoint::ConnectionError::ApplicationClosed(e)) => {
-                        let close_reason: CloseReason = e.error_code.into();
-                        Err(PunchError::ConnectionClosed { reason: close_reason })
+            // Without a bound, an unreachable node would hold up failing over to the next one
+            let timeout = Duration::from_secs(self.config.settings.connection_timeout);
+            let conn = tokio::time::timeout(timeout, self.endpoint.connect(addr, ALPN))
+                .await
+                .map_err(|_| PunchError::ConnectTimeout {
+                    node: node_id.fmt_short(),
+                    timeout,
+                })??;
+
+            conn.send_datagram(handshake.encode()?)?;
 
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_millis(100)) => {
+
+                    Ok(conn)
+                }
+                _ = conn.closed() => {
+                    match conn.close_reason() {
+                        Some(iroh::endpoint::ConnectionError::ApplicationClosed(close)) => {
+                            Err((&close).into())
+                        }
+                        Some(e) => Err(crate::error!("Connection closed unexpectedly: {}", e)),
+                        None => Err(PunchError::ConnectionClosed {
+                            reason: CloseReason::Unknown,
+                            details: CloseDetails::default(),
+                        }),
                     }
-                    Some(e) => Err(crate::error!("Connection closed unexpectedly: {}", e)),
-                    None => Err(PunchError::ConnectionClosed {
-                        reason: CloseReason::Unknown,
-                    }),
                 }
             }
         }
+        .instrument(span)
+        .await
     }
 
     async fn handle_local_connections(
         &self,
         tunnel: TunnelConnection,
-        local_port: u16,
+        local: LocalSocket,
+        shutdown_rx: tokio::sync::watch::Receiver<bool>,
     ) -> Result<()> {
-        let local_addr: SocketAddr = ([127, 0, 0, 1], local_port).into();
-
-        let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
-
-        let shutdown_signal = shutdown_tx.clone();
-        tokio::spawn(async move {
-            tokio::signal::ctrl_c().await.ok();
-            let _ = shutdown_signal.send(true);
-        });
+        self.warn_if_exposed(local.local_addr()?);
 
-        match tunnel.protocol() {
-            Protocol::Tcp => {
-                self.handle_tcp_connections_with_shutdown(tunnel, local_addr, shutdown_rx)
-                    .await
+        match local {
+            LocalSocket::Tcp(listeners) => {
+                let balancer =
+                    Balancer::new(Strategy::default()).with_backend(String::new(), tunnel);
+                self.handle_tcp_connections_with_shutdown(
+                    Arc::new(balancer),
+                    listeners,
+                    shutdown_rx,
+                )
+                .await
             }
-            Protocol::Udp => {
-                self.handle_udp_connections_with_shutdown(tunnel, local_addr, shutdown_rx)
+            LocalSocket::Udp(socket) => {
+                self.handle_udp_connections_with_shutdown(tunnel, socket, shutdown_rx)
                     .await
             }
         }
     }
 
+    fn warn_if_exposed(&self, local_addr: SocketAddr) {
+        if !local_addr.ip().is_loopback() && self.options.allowed_sources.is_empty() {
+            crate::warning!(
+                "Listening on {} without --allow-from, anyone who can reach it can use the tunnel",
+                local_addr.ip().bold()
+            );
+        }
+    }
+
     async fn handle_tcp_connections_with_shutdown(
         &self,
-        tunnel: TunnelConnection,
-        local_addr: SocketAddr,
+        balancer: Arc<Balancer>,
+        listeners: Vec<TcpListener>,
         mut shutdown_rx: tokio::sync::watch::Receiver<bool>,
     ) -> Result<()> {
-        let listener = TcpListener::bind(local_addr).await?;
-
+        let local_addr = listeners[0].local_addr()?;
         crate::info!(
             "Listening for TCP connections on {}",
             format!("{}", local_addr.green()).bold()
         );
+        output::report_listening("tcp", local_addr);
+        if listeners.len() > 1 {
+            tracing::debug!("Accepting on {} listeners", listeners.len());
+        }
 
-        let tunnel = Arc::new(tunnel);
         let (tunnel_shutdown_tx, mut tunnel_shutdown_rx) = tokio::sync::watch::channel(false);
 
-        let tunnel_monitor = Arc::clone(&tunnel);
+        let tunnel_monitor = Arc::clone(&balancer);
         let shutdown_monitor = tunnel_shutdown_tx.clone();
         tokio::spawn(async move {
             tunnel_monitor.wait_closed().await;
             let _ = shutdown_monitor.send(true);
         });
 
+        // With several hosts, losing one only shrinks the pool. The monitors stop with the
+        // listener so that shutting down doesn't report every tunnel as lost.
+        let mut backend_monitors = Vec::new();
+        let backends = balancer.backends();
+        if backends.len() > 1 {
+            for backend in backends {
+                let backend = Arc::clone(backend);
+                let balancer = Arc::clone(&balancer);
+                let monitor = tokio::spawn(async move {
+                    backend.tunnel.wait_closed().await;
+                    let left = balancer
+                        .backends()
+                        .iter()
+                        .filter(|backend| !backend.tunnel.is_closed())
+                        .count();
+                    if left > 0 {
+                        crate::warning!(
+                            "Tunnel to {} closed, {} host(s) left",
+                            backend.name.bold(),
+                            left
+                        );
+                    }
+                });
+                backend_monitors.push(AbortOnDropHandle::new(monitor));
+            }
+        }
+
+        // Each listener accepts from its own task, all of them sharing the balancer
+        let mut acceptors = JoinSet::new();
+        for listener in listeners {
+            acceptors.spawn(accept_connections(
+                listener,
+                Arc::clone(&balancer),
+                self.options.allowed_sources.clone(),
+                shutdown_rx.clone(),
+                tunnel_shutdown_rx.clone(),
+            ));
+        }
+
         loop {
             tokio::select! {
 
@@ -228,43 +1073,17 @@ impl Client {
 
                 _ = tunnel_shutdown_rx.changed() => {
                     if *tunnel_shutdown_rx.borrow() {
-                        crate::warning!("Tunnel connection closed");
+                        match balancer.backends() {
+                            [backend] => crate::warning!("{}", closed_message(backend.tunnel.connection())),
+                            _ => crate::warning!("Tunnel connection closed"),
+                        }
                         break;
                     }
                 }
 
 
-                accept_result = listener.accept() => {
-                    match accept_result {
-                        Ok((stream, client_addr)) => {
-                            let tunnel = Arc::clone(&tunnel);
-                            let mut shutdown_rx = shutdown_rx.clone();
-                            let mut tunnel_shutdown_rx = tunnel_shutdown_rx.clone();
-
-                            tokio::spawn(async move {
-                                tracing::debug!("Accepted connection from {}", client_addr);
-
-                                tokio::select! {
-                                    result = tunnel.handle_tcp_stream(stream) => {
-                                        if let Err(e) = result {
-                                            tracing::error!("Error handling TCP stream: {}", e);
-                                        }
-                                    }
-                                    _ = shutdown_rx.changed() => {
-                                        tracing::debug!("Closing TCP stream due to shutdown");
-                                    }
-                                    _ = tunnel_shutdown_rx.changed() => {
-                                        tracing::debug!("Closing TCP stream due to tunnel shutdown");
-                                    }
-                                }
-                            });
-                        }
-                        Err(e) => {
-                            tracing::error!("Failed to accept connection: {}", e);
-                            break;
-                        }
-                    }
-                }
+                // An acceptor only stops once its listener fails or no host is left
+                Some(_) = acceptors.join_next() => break,
             }
         }
 
@@ -274,22 +1093,21 @@ impl Client {
     async fn handle_udp_connections_with_shutdown(
         &self,
         tunnel: TunnelConnection,
-        local_addr: SocketAddr,
+        socket: UdpSocket,
         mut shutdown_rx: tokio::sync::watch::Receiver<bool>,
     ) -> Result<()> {
-        let socket = UdpSocket::bind(local_addr).await?;
-
         crate::info!(
             "Listening for UDP packets on {}",
-            format!("{}", local_addr.green()).bold()
+            format!("{}", socket.local_addr()?.green()).bold()
         );
+        output::report_listening("udp", socket.local_addr()?);
 
         tokio::select! {
-            result = tunnel.handle_udp_socket(socket) => {
+            result = tunnel.handle_udp_socket(socket, &self.options.allowed_sources) => {
                 Ok(result?)
             }
             _ = tunnel.wait_closed() => {
-                crate::warning!("Tunnel connection closed");
+                crate::warning!("{}", closed_message(tunnel.connection()));
                 Ok(())
             }
             _ = shutdown_rx.changed() => {
@@ -304,14 +1122,171 @@ impl Client {
     }
 }
 
+/// Accepts local connections on `listener` and sends each to a host picked by `balancer`,
+/// until shutting down, losing the tunnels or failing to accept.
+async fn accept_connections(
+    listener: TcpListener,
+    balancer: Arc<Balancer>,
+    allowed_sources: SourceFilter,
+    mut shutdown_rx: tokio::sync::watch::Receiver<bool>,
+    mut tunnel_shutdown_rx: tokio::sync::watch::Receiver<bool>,
+) {
+    loop {
+        let (stream, client_addr) = tokio::select! {
+            Ok(_) = shutdown_rx.wait_for(|&down| down) => break,
+            Ok(_) = tunnel_shutdown_rx.wait_for(|&down| down) => break,
+
+            accept_result = listener.accept() => match accept_result {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::error!("Failed to accept connection: {}", e);
+                    break;
+                }
+            },
+        };
+
+        if !allowed_sources.allows(&client_addr.ip()) {
+            crate::warning!("Rejected connection from {}", client_addr);
+            continue;
+        }
+
+        let Some(backend) = balancer.pick() else {
+            break;
+        };
+        if balancer.backends().len() > 1 {
+            tracing::debug!("Sending {} to {}", client_addr, backend.name);
+        }
+        let mut shutdown_rx = shutdown_rx.clone();
+        let mut tunnel_shutdown_rx = tunnel_shutdown_rx.clone();
+        let span = backend.tunnel.span().clone();
+
+        tokio::spawn(
+            async move {
+                tracing::debug!("Accepted connection from {}", client_addr);
+
+                tokio::select! {
+                    result = backend.tunnel.handle_tcp_stream(stream) => {
+                        match result {
+                            Err(e @ PunchError::StreamReset { .. }) => crate::warning!("{}", e),
+                            Err(e) => tracing::error!("Error handling TCP stream: {}", e),
+                            Ok(()) => {}
+                        }
+                    }
+                    _ = shutdown_rx.changed() => {
+                        tracing::debug!("Closing TCP stream due to shutdown");
+                    }
+                    _ = tunnel_shutdown_rx.changed() => {
+                        tracing::debug!("Closing TCP stream due to tunnel shutdown");
+                    }
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// The local end of a mapping, bound before the tunnel starts serving it.
+enum LocalSocket {
+    Tcp(Vec<TcpListener>),
+    Udp(UdpSocket),
+}
+
+impl LocalSocket {
+    /// TCP ports get `acceptors` listeners, see [`net::bind_tcp_listeners`].
+    fn bind(addr: SocketAddr, protocol: Protocol, acceptors: usize) -> Result<Self> {
+        let socket = match protocol {
+            Protocol::Tcp => net::bind_tcp_listeners(addr, acceptors).map(LocalSocket::Tcp),
+            Protocol::Udp => net::bind_udp_socket(addr).map(LocalSocket::Udp),
+        };
+        socket.map_err(|e| match e {
+            PunchError::Io(e)
+                if e.kind() == std::io::ErrorKind::PermissionDenied && addr.port() < 1024 =>
+            {
+                crate::error!(
+                    "Binding port {} needs root or CAP_NET_BIND_SERVICE, e.g. `sudo setcap cap_net_bind_service=+ep $(which punch)`",
+                    addr.port()
+                )
+            }
+            e => e,
+        })
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(match self {
+            LocalSocket::Tcp(listeners) => listeners[0].local_addr()?,
+            LocalSocket::Udp(socket) => socket.local_addr()?,
+        })
+    }
+}
+
+/// What gets recorded about a tunnel once it closes.
+struct Session {
+    node_id: NodeId,
+    protocol: Protocol,
+    mapping: String,
+    stats: Arc<TrafficStats>,
+    conn: Connection,
+    started: Instant,
+}
+
+impl Session {
+    fn start(tunnel: &TunnelConnection, node_id: NodeId, mapping: &Mapping) -> Self {
+        Self {
+            node_id,
+            protocol: tunnel.protocol(),
+            mapping: mapping.to_string(),
+            stats: Arc::clone(tunnel.stats()),
+            conn: tunnel.connection().clone(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Must be called before closing the endpoint, which would hide why the server left.
+    fn close_reason(&self, error: Option<&PunchError>) -> String {
+        match (self.conn.close_reason(), error) {
+            (Some(ConnectionError::ApplicationClosed(close)), _) => {
+                PunchError::from(&close).to_string()
+            }
+            (Some(ConnectionError::LocallyClosed), None) | (None, None) => {
+                "Closed locally".to_string()
+            }
+            (_, Some(e)) => e.to_string(),
+            (Some(e), None) => e.to_string(),
+        }
+    }
+}
+
+/// Tells why the tunnel went away when the server gave a reason, like an expired session.
+fn closed_message(conn: &Connection) -> String {
+    match conn.close_reason() {
+        Some(ConnectionError::ApplicationClosed(close)) => PunchError::from(&close).to_string(),
+        _ => "Tunnel connection closed".to_string(),
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Orders paths from worst to best, direct connections being the goal.
+fn path_rank(path: &ConnectionType) -> u8 {
+    match path {
+        ConnectionType::None => 0,
+        ConnectionType::Relay(_) => 1,
+        ConnectionType::Mixed(..) => 2,
+        ConnectionType::Direct(_) => 3,
+    }
+}
+
 pub async fn client(
     endpoint: Endpoint,
     connect_to: String,
-    (local_port, remote_port): (u16, u16),
+    mapping: Mapping,
     protocol: Protocol,
+    options: ClientOptions,
 ) -> Result<()> {
-    let client = Client::new(endpoint).await?;
-    client
-        .connect(connect_to, local_port, remote_port, protocol)
-        .await
+    let client = Client::new(endpoint, options).await?;
+    client.connect(connect_to, mapping, protocol).await
 }
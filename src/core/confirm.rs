@@ -0,0 +1,78 @@
+use crate::Result;
+use crate::utils::{config::AuthorizationManager, constants::CONFIRM_TIMEOUT};
+use anstream::{print, println};
+use dashmap::DashSet;
+use iroh::NodeId;
+use owo_colors::OwoColorize;
+use std::io::Write;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines, Stdin};
+use tokio::sync::Mutex;
+
+/// Asks the operator in the server terminal before letting an authorized node connect for
+/// the first time.
+#[derive(Debug)]
+pub struct Confirmer {
+    auth_manager: Arc<AuthorizationManager>,
+    /// Persist approvals to the server config instead of only remembering them until exit
+    remember: bool,
+    approved: DashSet<NodeId>,
+    /// Held for the whole prompt so that concurrent connections are asked about one at a time
+    stdin: Mutex<Lines<BufReader<Stdin>>>,
+}
+
+impl Confirmer {
+    pub fn new(auth_manager: Arc<AuthorizationManager>, remember: bool) -> Self {
+        Self {
+            auth_manager,
+            remember,
+            approved: DashSet::new(),
+            stdin: Mutex::new(BufReader::new(tokio::io::stdin()).lines()),
+        }
+    }
+
+    /// Returns whether `node_id` may connect, prompting the operator if it hasn't been
+    /// approved yet. No answer within [`CONFIRM_TIMEOUT`] counts as a refusal.
+    pub async fn confirm(&self, node_id: NodeId, request: &str) -> Result<bool> {
+        if self.is_approved(&node_id).await? {
+            return Ok(true);
+        }
+
+        let mut stdin = self.stdin.lock().await;
+        // Another prompt may have approved this node while we were waiting for our turn
+        if self.is_approved(&node_id).await? {
+            return Ok(true);
+        }
+
+        print!(
+            "{} New node {} wants to connect to {}. Allow? [y/N] ",
+            "?".yellow(),
+            node_id.to_string().bold(),
+            request
+        );
+        std::io::stdout().flush()?;
+
+        let answer = match tokio::time::timeout(CONFIRM_TIMEOUT, stdin.next_line()).await {
+            Ok(line) => line?.unwrap_or_default(),
+            Err(_) => {
+                println!();
+                crate::warning!("No answer, rejecting node {}", node_id);
+                return Ok(false);
+            }
+        };
+
+        if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+            return Ok(false);
+        }
+
+        self.approved.insert(node_id);
+        if self.remember {
+            self.auth_manager.remember_confirmed(node_id).await?;
+        }
+        Ok(true)
+    }
+
+    async fn is_approved(&self, node_id: &NodeId) -> Result<bool> {
+        Ok(self.approved.contains(node_id) || self.auth_manager.is_confirmed(node_id).await?)
+    }
+}
@@ -0,0 +1,228 @@
+use crate::Result;
+use crate::utils::hooks::HookEvent;
+use n0_future::boxed::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    Health,
+    Connections,
+    /// Closes the tunnels of a node, or a single one of them if `id` is set
+    Kick {
+        node_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<usize>,
+    },
+    /// Removes a key from the authorized keys and closes its tunnels
+    Revoke {
+        node_id: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlResponse {
+    Health(HealthReport),
+    Connections { connections: Vec<ConnectionInfo> },
+    Kicked { count: usize },
+    Revoked { removed: bool },
+    Error { message: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    Healthy,
+    Degraded,
+    Down,
+}
+
+impl HealthStatus {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            HealthStatus::Healthy => 0,
+            HealthStatus::Degraded => 1,
+            HealthStatus::Down => 2,
+        }
+    }
+}
+
+impl std::fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HealthStatus::Healthy => write!(f, "healthy"),
+            HealthStatus::Degraded => write!(f, "degraded"),
+            HealthStatus::Down => write!(f, "down"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    pub node_id: String,
+    pub uptime: u64,
+    pub active_connections: usize,
+    pub max_connections: usize,
+    pub home_relay: Option<String>,
+    #[serde(default)]
+    pub issues: Vec<String>,
+}
+
+/// An active tunnel, as listed by `punch server connections`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    pub id: usize,
+    pub node_id: String,
+    pub protocol: String,
+    pub port: u16,
+    pub target: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<String>,
+    /// Seconds since the tunnel was opened
+    pub duration: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+/// A tunnel opened or closed, as streamed by the gRPC control API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionEvent {
+    pub event: HookEvent,
+    pub connection: ConnectionInfo,
+}
+
+/// Answers requests received on the local control socket of a running node.
+pub trait ControlHandler: Clone + Send + Sync + 'static {
+    fn handle(&self, request: ControlRequest) -> BoxFuture<ControlResponse>;
+
+    /// Subscribes to the tunnels opened and closed from now on, if the node reports them.
+    fn events(&self) -> Option<tokio::sync::broadcast::Receiver<ConnectionEvent>> {
+        None
+    }
+}
+
+/// Listens on the control socket until dropped, removing the socket file afterwards.
+pub struct ControlServer {
+    path: PathBuf,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl ControlServer {
+    #[cfg(unix)]
+    pub async fn spawn<H: ControlHandler>(path: PathBuf, handler: H) -> Result<Self> {
+        use tokio::net::{UnixListener, UnixStream};
+
+        if path.exists() {
+            if UnixStream::connect(&path).await.is_ok() {
+                return Err(crate::error!(
+                    "Another server is already listening on {}",
+                    path.display()
+                ));
+            }
+            tokio::fs::remove_file(&path).await?;
+        }
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        {
+            use std::os::unix::fs::PermissionsExt;
+            tokio::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).await?;
+        }
+
+        let task = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let handler = handler.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = serve_stream(stream, handler).await {
+                                tracing::debug!("Control client error: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to accept control connection: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Self { path, task })
+    }
+
+    #[cfg(not(unix))]
+    pub async fn spawn<H: ControlHandler>(path: PathBuf, _handler: H) -> Result<Self> {
+        Err(crate::error!(
+            "Control sockets are not supported on this platform ({})",
+            path.display()
+        ))
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+async fn serve_stream<H: ControlHandler>(stream: tokio::net::UnixStream, handler: H) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => handler.handle(request).await,
+            Err(e) => ControlResponse::Error {
+                message: format!("Invalid control request: {}", e),
+            },
+        };
+
+        let mut payload = serde_json::to_vec(&response).map_err(|e| crate::error!("{}", e))?;
+        payload.push(b'\n');
+        write.write_all(&payload).await?;
+    }
+
+    Ok(())
+}
+
+/// Sends a single request to the control socket at `path` and waits for the response.
+#[cfg(unix)]
+pub async fn request(path: &Path, request: &ControlRequest) -> Result<ControlResponse> {
+    let stream = tokio::net::UnixStream::connect(path).await.map_err(|e| {
+        crate::error!(
+            source = e,
+            "Could not reach control socket at {}",
+            path.display()
+        )
+    })?;
+    let (read, mut write) = stream.into_split();
+
+    let mut payload = serde_json::to_vec(request).map_err(|e| crate::error!("{}", e))?;
+    payload.push(b'\n');
+    write.write_all(&payload).await?;
+
+    let line = BufReader::new(read)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| crate::error!("Control socket closed without a response"))?;
+
+    serde_json::from_str(&line).map_err(|e| crate::error!("Invalid control response: {}", e))
+}
+
+#[cfg(not(unix))]
+pub async fn request(path: &Path, _request: &ControlRequest) -> Result<ControlResponse> {
+    Err(crate::error!(
+        "Control sockets are not supported on this platform ({})",
+        path.display()
+    ))
+}
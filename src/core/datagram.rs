@@ -0,0 +1,590 @@
+use crate::Result;
+use crate::core::{
+    TrafficStats, buffer::BufferPool, framing::PeerStreams, mapping::SourceFilter, net,
+    net::TargetSocket, qos::Qos, udp, udp::LastSeen,
+};
+use crate::utils::constants::{MAX_UDP_SESSIONS, UDP_BATCH_SIZE, UDP_SESSION_IDLE_TIMEOUT};
+use bytes::{BufMut, Bytes, BytesMut};
+use iroh::endpoint::Connection;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::task::{AbortHandle, JoinSet};
+use tracing::Instrument;
+
+/// Every datagram starts with `[session: u32 BE]`, identifying the local peer that sent
+/// the packet so replies can find their way back.
+pub const HEADER_LEN: usize = 4;
+
+/// Set in the session field of fragments, which carry `[packet: u16 BE][index: u8][count: u8]`
+/// after the session.
+const FRAGMENT_FLAG: u32 = 1 << 31;
+const FRAGMENT_HEADER_LEN: usize = 4;
+
+/// Incomplete packets are forgotten after this long.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Packets being reassembled at once, fragments of new packets are dropped past this.
+const MAX_PENDING_PACKETS: usize = 256;
+
+/// What to do with UDP packets that don't fit in a single datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OversizedPolicy {
+    /// Drop them with a warning
+    Drop,
+    /// Split them across several datagrams, reassembled on the other side
+    #[default]
+    Fragment,
+    /// Send them on a reliable stream instead
+    Stream,
+}
+
+impl std::str::FromStr for OversizedPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "drop" => Ok(OversizedPolicy::Drop),
+            "fragment" => Ok(OversizedPolicy::Fragment),
+            "stream" => Ok(OversizedPolicy::Stream),
+            _ => Err("Invalid policy. Use 'drop', 'fragment' or 'stream'.".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for OversizedPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OversizedPolicy::Drop => write!(f, "drop"),
+            OversizedPolicy::Fragment => write!(f, "fragment"),
+            OversizedPolicy::Stream => write!(f, "stream"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Datagram {
+    Packet {
+        session: u32,
+        payload: Bytes,
+    },
+    Fragment {
+        session: u32,
+        packet: u16,
+        index: u8,
+        count: u8,
+        payload: Bytes,
+    },
+}
+
+pub fn encode(session: u32, payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(HEADER_LEN + payload.len());
+    buf.put_u32(session);
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+/// Splits `payload` into datagrams of at most `max_size` bytes, or returns `None` if it
+/// would take more than 255 fragments.
+pub fn encode_fragments(
+    session: u32,
+    packet: u16,
+    payload: &[u8],
+    max_size: usize,
+) -> Option<Vec<Bytes>> {
+    let chunk_size = max_size.checked_sub(HEADER_LEN + FRAGMENT_HEADER_LEN)?;
+    if chunk_size == 0 {
+        return None;
+    }
+    let count = u8::try_from(payload.len().div_ceil(chunk_size)).ok()?;
+
+    let fragments = payload
+        .chunks(chunk_size)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut buf = BytesMut::with_capacity(HEADER_LEN + FRAGMENT_HEADER_LEN + chunk.len());
+            buf.put_u32(session | FRAGMENT_FLAG);
+            buf.put_u16(packet);
+            buf.put_u8(index as u8);
+            buf.put_u8(count);
+            buf.put_slice(chunk);
+            buf.freeze()
+        })
+        .collect();
+
+    Some(fragments)
+}
+
+pub fn decode(mut datagram: Bytes) -> Option<Datagram> {
+    if datagram.len() < HEADER_LEN {
+        return None;
+    }
+    let header = datagram.split_to(HEADER_LEN);
+    let session = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+
+    if session & FRAGMENT_FLAG == 0 {
+        return Some(Datagram::Packet {
+            session,
+            payload: datagram,
+        });
+    }
+
+    if datagram.len() < FRAGMENT_HEADER_LEN {
+        return None;
+    }
+    let header = datagram.split_to(FRAGMENT_HEADER_LEN);
+    Some(Datagram::Fragment {
+        session: session & !FRAGMENT_FLAG,
+        packet: u16::from_be_bytes([header[0], header[1]]),
+        index: header[2],
+        count: header[3],
+        payload: datagram,
+    })
+}
+
+/// Collects fragments until their packet is complete.
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    pending: HashMap<(u32, u16), PendingPacket>,
+}
+
+#[derive(Debug)]
+struct PendingPacket {
+    parts: Vec<Option<Bytes>>,
+    received: usize,
+    started_at: Instant,
+}
+
+impl Reassembler {
+    /// Turns a datagram into a whole packet, returning `None` while fragments are missing.
+    pub fn push(&mut self, datagram: Datagram) -> Option<(u32, Bytes)> {
+        let (session, packet, index, count, payload) = match datagram {
+            Datagram::Packet { session, payload } => return Some((session, payload)),
+            Datagram::Fragment {
+                session,
+                packet,
+                index,
+                count,
+                payload,
+            } => (session, packet, index as usize, count as usize, payload),
+        };
+
+        if index >= count {
+            return None;
+        }
+
+        self.pending
+            .retain(|_, pending| pending.started_at.elapsed() < REASSEMBLY_TIMEOUT);
+        if self.pending.len() >= MAX_PENDING_PACKETS
+            && !self.pending.contains_key(&(session, packet))
+        {
+            tracing::debug!("Too many incomplete UDP packets, dropped fragment");
+            return None;
+        }
+
+        let pending = self
+            .pending
+            .entry((session, packet))
+            .or_insert_with(|| PendingPacket {
+                parts: vec![None; count],
+                received: 0,
+                started_at: Instant::now(),
+            });
+        if pending.parts.len() != count {
+            return None;
+        }
+        if pending.parts[index].replace(payload).is_none() {
+            pending.received += 1;
+        }
+        if pending.received < count {
+            return None;
+        }
+
+        let pending = self.pending.remove(&(session, packet))?;
+        let mut buf = BytesMut::new();
+        for part in pending.parts.into_iter().flatten() {
+            buf.put_slice(&part);
+        }
+        Some((session, buf.freeze()))
+    }
+}
+
+/// Sends a packet as a single datagram or, if it doesn't fit, as fragments. Returns
+/// `false` when the packet can't be sent as datagrams at all.
+fn send_packet(
+    conn: &Connection,
+    session: u32,
+    packet: &mut u16,
+    payload: &[u8],
+    fragment: bool,
+) -> Result<bool> {
+    let Some(max_size) = conn.max_datagram_size() else {
+        return Ok(false);
+    };
+
+    if HEADER_LEN + payload.len() <= max_size {
+        conn.send_datagram(encode(session, payload))?;
+        return Ok(true);
+    }
+    if !fragment {
+        return Ok(false);
+    }
+
+    let Some(fragments) = encode_fragments(session, *packet, payload, max_size) else {
+        return Ok(false);
+    };
+    *packet = packet.wrapping_add(1);
+    for datagram in fragments {
+        conn.send_datagram(datagram)?;
+    }
+    Ok(true)
+}
+
+/// Local peers of a client tunnel, by the session they are known as to the server. Sessions
+/// idle for [`UDP_SESSION_IDLE_TIMEOUT`] make room for new peers, the server having
+/// forgotten them by then as well.
+#[derive(Debug, Default)]
+struct PeerSessions {
+    sessions: HashMap<SocketAddr, u32>,
+    peers: HashMap<u32, (SocketAddr, Instant)>,
+    next_session: u32,
+    /// Whether new peers were turned away since the last one was let in
+    full: bool,
+}
+
+impl PeerSessions {
+    /// The session of `peer`, opened on first use, `None` while too many peers are active.
+    fn session(&mut self, peer: SocketAddr) -> Option<u32> {
+        if let Some(&session) = self.sessions.get(&peer) {
+            self.peers.insert(session, (peer, Instant::now()));
+            return Some(session);
+        }
+
+        if self.sessions.len() >= MAX_UDP_SESSIONS {
+            self.expire();
+        }
+        if self.sessions.len() >= MAX_UDP_SESSIONS {
+            if !self.full {
+                crate::warning!(
+                    "Too many UDP peers ({}), dropping packets from new ones such as {}",
+                    MAX_UDP_SESSIONS,
+                    peer
+                );
+                self.full = true;
+            }
+            return None;
+        }
+        self.full = false;
+
+        // Sessions aren't reused for as long as the server could still know them
+        let session = self.next_session;
+        self.next_session = session.wrapping_add(1) & !FRAGMENT_FLAG;
+        self.sessions.insert(peer, session);
+        self.peers.insert(session, (peer, Instant::now()));
+        Some(session)
+    }
+
+    /// The peer a reply for `session` goes to.
+    fn peer(&mut self, session: u32) -> Option<SocketAddr> {
+        let (peer, seen) = self.peers.get_mut(&session)?;
+        *seen = Instant::now();
+        Some(*peer)
+    }
+
+    fn expire(&mut self) {
+        let Self {
+            sessions, peers, ..
+        } = self;
+        peers.retain(|_, (peer, seen)| {
+            let active = seen.elapsed() < UDP_SESSION_IDLE_TIMEOUT;
+            if !active {
+                sessions.remove(peer);
+            }
+            active
+        });
+    }
+}
+
+/// Client side: sends packets received on the local `socket` as datagrams and delivers
+/// replies back to the peer that sent the matching request.
+pub async fn forward_local_socket(
+    conn: &Connection,
+    socket: Arc<UdpSocket>,
+    filter: &SourceFilter,
+    oversized: OversizedPolicy,
+    buffers: &Arc<BufferPool>,
+    stats: &Arc<TrafficStats>,
+) -> Result<()> {
+    let mut sessions = PeerSessions::default();
+    let mut streams = PeerStreams::new(
+        conn.clone(),
+        Arc::clone(&socket),
+        Arc::clone(buffers),
+        Arc::clone(stats),
+    );
+    let mut reassembler = Reassembler::default();
+    let mut next_packet = 0u16;
+    let mut batch = Vec::new();
+    let mut datagrams = Vec::new();
+
+    loop {
+        tokio::select! {
+            result = udp::recv_batch(&socket, buffers, &mut batch) => {
+                result?;
+                for packet in &batch {
+                    let (peer, payload) = (packet.from, packet.payload());
+                    if !filter.allows(&peer.ip()) {
+                        tracing::warn!("Dropped UDP packet from {}", peer);
+                        continue;
+                    }
+
+                    let Some(session) = sessions.session(peer) else {
+                        continue;
+                    };
+
+                    let fragment = oversized == OversizedPolicy::Fragment;
+                    if send_packet(conn, session, &mut next_packet, payload, fragment)? {
+                        stats.record(0, payload.len() as u64);
+                    } else if oversized == OversizedPolicy::Stream {
+                        streams.send(peer, payload);
+                    } else {
+                        tracing::warn!(
+                            "Dropped {} byte packet from {}, larger than the tunnel's datagram size ({})",
+                            payload.len(),
+                            peer,
+                            conn.max_datagram_size().unwrap_or_default()
+                        );
+                    }
+                }
+            }
+
+            open = read_datagrams(conn, &mut datagrams) => {
+                if !open {
+                    tracing::debug!("UDP tunnel connection closed");
+                    return Ok(());
+                }
+
+                let mut replies = Vec::new();
+                for datagram in datagrams.drain(..) {
+                    let Some(datagram) = decode(datagram) else {
+                        tracing::debug!("Dropped malformed datagram");
+                        continue;
+                    };
+                    let Some((session, payload)) = reassembler.push(datagram) else {
+                        continue;
+                    };
+                    let Some(peer) = sessions.peer(session) else {
+                        tracing::debug!("Dropped datagram for unknown session {}", session);
+                        continue;
+                    };
+                    replies.push((payload, Some(peer)));
+                }
+
+                let replies: Vec<(&[u8], Option<SocketAddr>)> = replies
+                    .iter()
+                    .map(|(payload, peer)| (&payload[..], *peer))
+                    .collect();
+                let bytes = udp::send_batch(&socket, &replies).await?;
+                stats.record(bytes, 0);
+            }
+        }
+    }
+}
+
+/// Waits for a datagram and takes in those already queued behind it, up to
+/// `UDP_BATCH_SIZE`, returning `false` once the connection is closed.
+async fn read_datagrams(conn: &Connection, datagrams: &mut Vec<Bytes>) -> bool {
+    datagrams.clear();
+    let Ok(datagram) = conn.read_datagram().await else {
+        return false;
+    };
+    datagrams.push(datagram);
+    while datagrams.len() < UDP_BATCH_SIZE {
+        match n0_future::future::poll_once(conn.read_datagram()).await {
+            Some(Ok(datagram)) => datagrams.push(datagram),
+            _ => break,
+        }
+    }
+    true
+}
+
+/// Server side: forwards datagrams to `target` from one socket per session, and sends the
+/// target's replies back tagged with the same session.
+pub async fn forward_to_target(
+    conn: &Connection,
+    target: SocketAddr,
+    qos: Qos,
+    buffers: &Arc<BufferPool>,
+    stats: &Arc<TrafficStats>,
+) -> Result<()> {
+    let mut sessions: HashMap<u32, TargetSession> = HashMap::new();
+    let mut replies = JoinSet::new();
+    let mut reassembler = Reassembler::default();
+    let mut full = false;
+    let mut expiry = tokio::time::interval(UDP_SESSION_IDLE_TIMEOUT / 4);
+
+    let mut datagrams = Vec::new();
+    loop {
+        tokio::select! {
+            open = read_datagrams(conn, &mut datagrams) => {
+                if !open {
+                    break;
+                }
+            }
+            _ = expiry.tick() => {
+                sessions.retain(|_, session| session.last_seen.idle() < UDP_SESSION_IDLE_TIMEOUT);
+                while replies.try_join_next().is_some() {}
+                continue;
+            }
+        }
+
+        let mut packets: Vec<(Arc<TargetSocket>, Bytes)> = Vec::new();
+        for datagram in datagrams.drain(..) {
+            let Some(datagram) = decode(datagram) else {
+                tracing::debug!("Dropped malformed datagram");
+                continue;
+            };
+            let Some((session, payload)) = reassembler.push(datagram) else {
+                continue;
+            };
+
+            if !sessions.contains_key(&session) {
+                if sessions.len() >= MAX_UDP_SESSIONS {
+                    sessions
+                        .retain(|_, session| session.last_seen.idle() < UDP_SESSION_IDLE_TIMEOUT);
+                }
+                if sessions.len() >= MAX_UDP_SESSIONS {
+                    if !full {
+                        tracing::warn!(
+                            "Too many UDP sessions ({}) for {}, dropping packets of new ones",
+                            MAX_UDP_SESSIONS,
+                            target
+                        );
+                        full = true;
+                    }
+                    continue;
+                }
+                full = false;
+
+                let socket = Arc::new(net::connect_udp_socket(target).await?);
+                socket.set_qos(qos);
+                let last_seen = Arc::new(LastSeen::new());
+                let forwarding = replies.spawn(
+                    forward_replies(
+                        conn.clone(),
+                        session,
+                        Arc::clone(&socket),
+                        Arc::clone(&last_seen),
+                        Arc::clone(buffers),
+                        Arc::clone(stats),
+                    )
+                    .in_current_span(),
+                );
+                sessions.insert(
+                    session,
+                    TargetSession {
+                        socket,
+                        last_seen,
+                        forwarding,
+                    },
+                );
+            }
+            let session = &sessions[&session];
+            session.last_seen.touch();
+            packets.push((Arc::clone(&session.socket), payload));
+        }
+
+        // Consecutive packets of a session go out in a single call
+        for run in packets.chunk_by(|(a, _), (b, _)| Arc::ptr_eq(a, b)) {
+            let payloads: Vec<&[u8]> = run.iter().map(|(_, payload)| &payload[..]).collect();
+            stats
+                .throttle(payloads.iter().map(|payload| payload.len() as u64).sum())
+                .await;
+            match run[0].0.send_batch(&payloads).await {
+                Ok(bytes) => stats.record(bytes, 0),
+                Err(e) => tracing::debug!("Failed to send UDP packets to {}: {}", target, e),
+            }
+        }
+    }
+
+    tracing::info!("UDP datagram tunnel for {} closed", target);
+    Ok(())
+}
+
+/// The socket of a session to the target, closed once the session expires.
+struct TargetSession {
+    socket: Arc<TargetSocket>,
+    last_seen: Arc<LastSeen>,
+    forwarding: AbortHandle,
+}
+
+impl Drop for TargetSession {
+    fn drop(&mut self) {
+        self.forwarding.abort();
+    }
+}
+
+/// Replies that don't fit in a datagram are always fragmented, the client reassembles
+/// them whatever its own policy is.
+async fn forward_replies(
+    conn: Connection,
+    session: u32,
+    socket: Arc<TargetSocket>,
+    last_seen: Arc<LastSeen>,
+    buffers: Arc<BufferPool>,
+    stats: Arc<TrafficStats>,
+) {
+    let mut batch = Vec::new();
+    let mut next_packet = 0u16;
+
+    while socket.recv_batch(&buffers, &mut batch).await.is_ok() {
+        last_seen.touch();
+        for packet in &batch {
+            let size = packet.payload().len();
+            stats.throttle(size as u64).await;
+            match send_packet(&conn, session, &mut next_packet, packet.payload(), true) {
+                Ok(true) => stats.record(0, size as u64),
+                Ok(false) => tracing::warn!("Dropped {} byte reply, too large to fragment", size),
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn idle_sessions_make_room_for_new_peers() {
+        let mut sessions = PeerSessions::default();
+        for port in 0..MAX_UDP_SESSIONS as u16 {
+            assert_eq!(sessions.session(peer(port)), Some(port as u32));
+        }
+        assert_eq!(sessions.session(peer(0)), Some(0));
+        assert_eq!(sessions.session(peer(u16::MAX)), None);
+
+        let long_ago = Instant::now() - UDP_SESSION_IDLE_TIMEOUT;
+        sessions.peers.get_mut(&7).unwrap().1 = long_ago;
+        let session = sessions.session(peer(u16::MAX)).unwrap();
+        assert_eq!(session, MAX_UDP_SESSIONS as u32);
+        assert_eq!(sessions.peer(session), Some(peer(u16::MAX)));
+        assert_eq!(sessions.peer(7), None);
+        assert_eq!(sessions.sessions.get(&peer(7)), None);
+    }
+
+    #[test]
+    fn sessions_never_look_like_fragments() {
+        let mut sessions = PeerSessions {
+            next_session: FRAGMENT_FLAG - 1,
+            ..Default::default()
+        };
+        assert_eq!(sessions.session(peer(1)), Some(FRAGMENT_FLAG - 1));
+        assert_eq!(sessions.session(peer(2)), Some(0));
+    }
+}
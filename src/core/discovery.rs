@@ -0,0 +1,106 @@
+use crate::Result;
+use crate::utils::constants::DNS_TIMEOUT;
+use iroh::{Endpoint, NodeId, discovery::UserData, dns::DnsResolver};
+use n0_future::StreamExt;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Subdomain whose TXT record holds the node ID of a server
+const DNS_PREFIX: &str = "_punch.";
+
+/// Marks punch nodes among the iroh nodes we discover, optionally followed by `:<name>`
+const USER_DATA_PREFIX: &str = "punch";
+
+/// What a punch node advertises about itself.
+pub fn user_data(name: Option<&str>) -> Option<UserData> {
+    let data = match name {
+        Some(name) => format!("{}:{}", USER_DATA_PREFIX, name),
+        None => USER_DATA_PREFIX.to_string(),
+    };
+    data.parse().ok()
+}
+
+/// Returns the advertised name if the user data comes from a punch node.
+fn parse_user_data(data: &UserData) -> Option<Option<String>> {
+    let rest = data.as_ref().strip_prefix(USER_DATA_PREFIX)?;
+    match rest.strip_prefix(':') {
+        Some(name) => Some(Some(name.to_string())),
+        None if rest.is_empty() => Some(None),
+        None => None,
+    }
+}
+
+/// A node found on the local network.
+#[derive(Debug, Clone)]
+pub struct DiscoveredPeer {
+    pub node_id: NodeId,
+    pub addrs: BTreeSet<SocketAddr>,
+    pub name: Option<String>,
+    /// Whether it advertises itself as a punch node
+    pub is_punch: bool,
+}
+
+/// Collects the nodes announced over mDNS during `duration`, ignoring nodes that aren't
+/// running punch unless `all` is set.
+pub async fn discover(endpoint: &Endpoint, duration: Duration, all: bool) -> Vec<DiscoveredPeer> {
+    let mut stream = endpoint.discovery_stream();
+    let mut peers = BTreeMap::new();
+    let deadline = tokio::time::Instant::now() + duration;
+
+    while let Ok(Some(item)) = tokio::time::timeout_at(deadline, stream.next()).await {
+        let Ok(item) = item else {
+            // Lagged behind, later announcements will fill in what we missed
+            continue;
+        };
+        if item.provenance() != iroh::discovery::mdns::NAME || item.node_id() == endpoint.node_id()
+        {
+            continue;
+        }
+
+        let punch = item.user_data().as_ref().and_then(parse_user_data);
+        if punch.is_none() && !all {
+            continue;
+        }
+
+        let peer = peers
+            .entry(item.node_id())
+            .or_insert_with(|| DiscoveredPeer {
+                node_id: item.node_id(),
+                addrs: BTreeSet::new(),
+                name: None,
+                is_punch: punch.is_some(),
+            });
+        peer.addrs
+            .extend(item.node_info().direct_addresses().iter().copied());
+        if let Some(Some(name)) = punch {
+            peer.name = Some(name);
+        }
+    }
+
+    peers.into_values().collect()
+}
+
+/// Looks up the node ID published in the `_punch.<name>` TXT record, so that servers can
+/// change keys without clients editing their config.
+pub async fn resolve_dns(resolver: &DnsResolver, name: &str) -> Result<NodeId> {
+    let name = name.trim_end_matches('.');
+    let record = match name.starts_with(DNS_PREFIX) {
+        true => name.to_string(),
+        false => format!("{}{}", DNS_PREFIX, name),
+    };
+
+    let lookup = resolver
+        .lookup_txt(&record, DNS_TIMEOUT)
+        .await
+        .map_err(|e| crate::error!("Failed to look up {}: {}", record, e))?;
+
+    lookup
+        .into_iter()
+        .filter_map(|txt| {
+            let data: Vec<u8> = txt.txt_data().concat();
+            std::str::from_utf8(&data).ok()?.trim().parse().ok()
+        })
+        .next()
+        .ok_or_else(|| crate::error!("No node ID in the TXT records of {}", record))
+}
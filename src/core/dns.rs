@@ -0,0 +1,83 @@
+use crate::Result;
+use crate::utils::constants::RESOLV_CONF_PATH;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// The nameserver `punch client --dns` reaches on the server: `configured`, or else the first
+/// one in `/etc/resolv.conf`.
+pub async fn server_resolver(configured: Option<&str>) -> Option<String> {
+    if let Some(resolver) = configured {
+        return Some(resolver.to_string());
+    }
+    let resolv_conf = tokio::fs::read_to_string(RESOLV_CONF_PATH).await.ok()?;
+    nameservers(&resolv_conf).next().map(str::to_string)
+}
+
+fn nameservers(resolv_conf: &str) -> impl Iterator<Item = &str> {
+    resolv_conf.lines().filter_map(|line| {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some("nameserver"), Some(address)) => Some(address),
+            _ => None,
+        }
+    })
+}
+
+/// Points the system at a local DNS listener until dropped, restoring `/etc/resolv.conf`
+/// then. A killed client leaves it in place.
+#[derive(Debug)]
+pub struct SystemResolver {
+    previous: String,
+}
+
+impl SystemResolver {
+    /// Makes `address` the only nameserver, keeping the search domains and options. The
+    /// listener has to be on port 53, resolv.conf has no say in the port.
+    pub fn install(address: IpAddr) -> Result<Self> {
+        if !cfg!(target_os = "linux") {
+            return Err(crate::error!(
+                "Setting the system resolver is only supported on Linux"
+            ));
+        }
+        // Listening on every interface, the loopback one included
+        let address = match address {
+            address if address.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            address => address,
+        };
+
+        // A link means systemd-resolved or NetworkManager owns the file, writing through it
+        // would clobber theirs and they may rewrite it under us anyway
+        let metadata = std::fs::symlink_metadata(RESOLV_CONF_PATH)
+            .map_err(|e| crate::error!(source = e, "Failed to read {}", RESOLV_CONF_PATH))?;
+        if metadata.file_type().is_symlink() {
+            return Err(crate::error!(
+                "{} is a link managed by another resolver, point that one at {} instead",
+                RESOLV_CONF_PATH,
+                address
+            ));
+        }
+        // Restoring an empty file would leave the system without any nameserver
+        let previous = std::fs::read_to_string(RESOLV_CONF_PATH)
+            .map_err(|e| crate::error!(source = e, "Failed to read {}", RESOLV_CONF_PATH))?;
+        let mut resolv_conf =
+            format!("# Set by punch client --dns, restored when it exits\nnameserver {address}\n");
+        for line in previous.lines() {
+            if !line.trim_start().starts_with("nameserver") && !line.trim_start().starts_with('#') {
+                resolv_conf.push_str(line);
+                resolv_conf.push('\n');
+            }
+        }
+        std::fs::write(RESOLV_CONF_PATH, resolv_conf)
+            .map_err(|e| crate::error!(source = e, "Failed to write {}", RESOLV_CONF_PATH))?;
+        crate::info!("System resolver set to {}", address);
+        Ok(Self { previous })
+    }
+}
+
+impl Drop for SystemResolver {
+    fn drop(&mut self) {
+        match std::fs::write(RESOLV_CONF_PATH, &self.previous) {
+            Ok(()) => crate::info!("Restored {}", RESOLV_CONF_PATH),
+            Err(e) => crate::warning!("Failed to restore {}: {}", RESOLV_CONF_PATH, e),
+        }
+    }
+}
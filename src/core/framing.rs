@@ -0,0 +1,214 @@
+use crate::ResetReason;
+use crate::core::{StreamSetup, TrafficStats, buffer::BufferPool, stream_span, udp::LastSeen};
+use crate::utils::constants::{MAX_UDP_SESSIONS, UDP_SESSION_IDLE_TIMEOUT};
+use bytes::Bytes;
+use iroh::endpoint::Connection;
+use std::collections::HashMap;
+use std::io::IoSlice;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::Instrument;
+
+/// Packets of a peer waiting for its stream to open or to accept more data
+const PEER_QUEUE_SIZE: usize = 64;
+
+/// Writes `payload` as a `[len: u16 BE][payload]` frame, keeping UDP packet boundaries
+/// intact over a byte stream.
+pub async fn write_frame(
+    writer: &mut (impl AsyncWrite + Unpin),
+    payload: &[u8],
+) -> std::io::Result<()> {
+    let len = u16::try_from(payload.len()).map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "UDP packet too large")
+    })?;
+    let header = len.to_be_bytes();
+
+    let mut written = 0;
+    let total = header.len() + payload.len();
+    while written < total {
+        let n = if written < header.len() {
+            let slices = [IoSlice::new(&header[written..]), IoSlice::new(payload)];
+            writer.write_vectored(&slices).await?
+        } else {
+            writer.write(&payload[written - header.len()..]).await?
+        };
+        if n == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        written += n;
+    }
+
+    Ok(())
+}
+
+/// Reads the next frame into `buf`, returning its length, or `None` at the end of the stream.
+pub async fn read_frame(
+    reader: &mut (impl AsyncRead + Unpin),
+    buf: &mut [u8],
+) -> std::io::Result<Option<usize>> {
+    let len = match reader.read_u16().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    if len > buf.len() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Frame of {} bytes exceeds the buffer size", len),
+        ));
+    }
+
+    reader.read_exact(&mut buf[..len]).await?;
+    Ok(Some(len))
+}
+
+/// Client-side framed streams, one per local UDP peer so that replies can be delivered
+/// back to the right address. Each peer has a task of its own, so that opening its stream,
+/// which waits while the connection is out of stream credit, doesn't hold up the others.
+pub struct PeerStreams {
+    conn: Connection,
+    socket: Arc<UdpSocket>,
+    buffers: Arc<BufferPool>,
+    stats: Arc<TrafficStats>,
+    setup: StreamSetup,
+    peers: HashMap<SocketAddr, mpsc::Sender<Bytes>>,
+    tasks: JoinSet<()>,
+}
+
+impl PeerStreams {
+    pub fn new(
+        conn: Connection,
+        socket: Arc<UdpSocket>,
+        buffers: Arc<BufferPool>,
+        stats: Arc<TrafficStats>,
+    ) -> Self {
+        Self {
+            conn,
+            socket,
+            buffers,
+            stats,
+            setup: StreamSetup::default(),
+            peers: HashMap::new(),
+            tasks: JoinSet::new(),
+        }
+    }
+
+    pub fn with_setup(mut self, setup: StreamSetup) -> Self {
+        self.setup = setup;
+        self
+    }
+
+    /// Queues a packet from `peer` for its stream, opening it on first use. Packets are
+    /// dropped while the queue of the peer is full, as a congested link would.
+    pub fn send(&mut self, peer: SocketAddr, payload: &[u8]) {
+        if self.peers.get(&peer).is_none_or(mpsc::Sender::is_closed) {
+            self.peers.remove(&peer);
+            if self.peers.len() >= MAX_UDP_SESSIONS {
+                // Peers that went idle leave their slot behind
+                self.peers.retain(|_, packets| !packets.is_closed());
+                while self.tasks.try_join_next().is_some() {}
+            }
+            if self.peers.len() >= MAX_UDP_SESSIONS {
+                tracing::warn!("Too many UDP peers, dropped packet from {}", peer);
+                return;
+            }
+
+            let (packets, queued) = mpsc::channel(PEER_QUEUE_SIZE);
+            let span = tracing::debug_span!("peer", %peer);
+            self.tasks.spawn(
+                run_peer(
+                    self.conn.clone(),
+                    self.setup.clone(),
+                    peer,
+                    queued,
+                    Arc::clone(&self.socket),
+                    Arc::clone(&self.buffers),
+                    Arc::clone(&self.stats),
+                )
+                .instrument(span),
+            );
+            self.peers.insert(peer, packets);
+        }
+
+        let packets = &self.peers[&peer];
+        if packets.try_send(Bytes::copy_from_slice(payload)).is_err() {
+            tracing::debug!("UDP stream of {} is congested, dropped packet", peer);
+        }
+    }
+}
+
+/// Carries the packets of `peer` over a stream of its own and delivers the replies back to
+/// it, until the peer goes idle for [`UDP_SESSION_IDLE_TIMEOUT`]. Finishing the stream then
+/// lets the server release its side.
+async fn run_peer(
+    conn: Connection,
+    setup: StreamSetup,
+    peer: SocketAddr,
+    mut packets: mpsc::Receiver<Bytes>,
+    socket: Arc<UdpSocket>,
+    buffers: Arc<BufferPool>,
+    stats: Arc<TrafficStats>,
+) {
+    let (mut send, mut recv) = match conn.open_bi().await {
+        Ok(streams) => streams,
+        Err(e) => {
+            tracing::debug!("Failed to open UDP stream for {}: {}", peer, e);
+            return;
+        }
+    };
+    let span = stream_span(send.id());
+    if let Err(e) = setup.start(&mut send).await {
+        tracing::debug!("Failed to send stream header for {}: {}", peer, e);
+        return;
+    }
+
+    let last_seen = LastSeen::new();
+
+    let requests = async {
+        while let Some(payload) = packets.recv().await {
+            write_frame(&mut send, &payload).await?;
+            last_seen.touch();
+            stats.record(0, payload.len() as u64);
+        }
+        Ok::<_, std::io::Error>(())
+    };
+
+    let replies = async {
+        let mut buf = buffers.get();
+        while let Some(size) = read_frame(&mut recv, &mut buf).await? {
+            last_seen.touch();
+            if let Err(e) = socket.send_to(&buf[..size], peer).await {
+                tracing::debug!("Failed to deliver UDP reply to {}: {}", peer, e);
+                continue;
+            }
+            stats.record(size as u64, 0);
+        }
+        Ok::<_, std::io::Error>(())
+    };
+
+    let result = async {
+        tokio::select! {
+            result = requests => result,
+            result = replies => result,
+            () = last_seen.idle_for(UDP_SESSION_IDLE_TIMEOUT) => {
+                tracing::debug!("UDP peer {} went idle", peer);
+                Ok(())
+            }
+        }
+    }
+    .instrument(span)
+    .await;
+    match result {
+        Err(e) if ResetReason::from_io_error(&e) == Some(ResetReason::Forbidden) => {
+            tracing::warn!("Server refused the UDP stream of {}", peer);
+        }
+        Err(e) => tracing::debug!("UDP stream of {} ended: {}", peer, e),
+        Ok(()) => {}
+    }
+    send.finish().ok();
+}
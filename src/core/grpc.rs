@@ -0,0 +1,252 @@
+use crate::Result;
+use crate::core::control::{self, ControlHandler, ControlRequest, ControlResponse};
+use crate::utils::hooks::HookEvent;
+use std::net::SocketAddr;
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+use tokio_stream::{StreamExt, wrappers::BroadcastStream};
+use tonic::{Request, Response, Status};
+
+mod proto {
+    tonic::include_proto!("punch.control.v0");
+}
+
+use proto::control_server::{Control, ControlServer};
+
+/// Serves the control API over gRPC until dropped.
+pub struct GrpcServer {
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl GrpcServer {
+    /// Clients must send `authorization: Bearer <token>`, with the token of the file at
+    /// `token_path`. Loopback is open to every local user, the token file only to us.
+    pub async fn spawn<H: ControlHandler>(
+        address: SocketAddr,
+        token_path: &Path,
+        handler: H,
+    ) -> Result<Self> {
+        let expected = format!("Bearer {}", load_token(token_path).await?);
+        let authenticate = move |request: Request<()>| match request.metadata().get("authorization")
+        {
+            Some(given) if constant_time_eq(given.as_bytes(), expected.as_bytes()) => Ok(request),
+            _ => Err(Status::unauthenticated("Missing or invalid bearer token")),
+        };
+
+        let listener = tokio::net::TcpListener::bind(address)
+            .await
+            .map_err(|e| crate::error!(source = e, "Failed to listen for gRPC on {}", address))?;
+        let incoming = tokio_stream::wrappers::TcpListenerStream::new(listener);
+
+        let task = tokio::spawn(async move {
+            if let Err(e) = tonic::transport::Server::builder()
+                .add_service(ControlServer::with_interceptor(
+                    GrpcControl(handler),
+                    authenticate,
+                ))
+                .serve_with_incoming(incoming)
+                .await
+            {
+                tracing::error!("gRPC control API stopped: {}", e);
+            }
+        });
+
+        Ok(Self { task })
+    }
+}
+
+impl Drop for GrpcServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Reads the token clients authenticate with, creating it on first use readable by the owner
+/// only.
+async fn load_token(path: &Path) -> Result<String> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(token) if !token.trim().is_empty() => return Ok(token.trim().to_string()),
+        Ok(_) => return Err(crate::error!("gRPC token {} is empty", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(crate::error!(
+                source = e,
+                "Failed to read gRPC token {}",
+                path.display()
+            ));
+        }
+    }
+
+    let token: String = rand::random::<[u8; 32]>()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options
+        .open(path)
+        .await
+        .map_err(|e| crate::error!(source = e, "Failed to create gRPC token {}", path.display()))?;
+    file.write_all(token.as_bytes()).await?;
+    crate::info!("Created the gRPC token at {}", path.display());
+    Ok(token)
+}
+
+/// Compares without returning early, so that timing doesn't tell how much of a guess is right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+struct GrpcControl<H>(H);
+
+impl<H: ControlHandler> GrpcControl<H> {
+    async fn handle(
+        &self,
+        request: ControlRequest,
+    ) -> std::result::Result<ControlResponse, Status> {
+        match self.0.handle(request).await {
+            ControlResponse::Error { message } => Err(Status::failed_precondition(message)),
+            response => Ok(response),
+        }
+    }
+}
+
+fn unexpected(response: ControlResponse) -> Status {
+    Status::internal(format!("Unexpected control response: {:?}", response))
+}
+
+#[tonic::async_trait]
+impl<H: ControlHandler> Control for GrpcControl<H> {
+    async fn health(
+        &self,
+        _request: Request<proto::HealthRequest>,
+    ) -> std::result::Result<Response<proto::HealthReport>, Status> {
+        match self.handle(ControlRequest::Health).await? {
+            ControlResponse::Health(report) => Ok(Response::new(report.into())),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn connections(
+        &self,
+        _request: Request<proto::ConnectionsRequest>,
+    ) -> std::result::Result<Response<proto::ConnectionList>, Status> {
+        match self.handle(ControlRequest::Connections).await? {
+            ControlResponse::Connections { connections } => {
+                Ok(Response::new(proto::ConnectionList {
+                    connections: connections.into_iter().map(Into::into).collect(),
+                }))
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn kick(
+        &self,
+        request: Request<proto::KickRequest>,
+    ) -> std::result::Result<Response<proto::KickResponse>, Status> {
+        let request = request.into_inner();
+        let request = ControlRequest::Kick {
+            node_id: request.node_id,
+            id: request.id.map(|id| id as usize),
+        };
+        match self.handle(request).await? {
+            ControlResponse::Kicked { count } => Ok(Response::new(proto::KickResponse {
+                count: count as u64,
+            })),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn revoke(
+        &self,
+        request: Request<proto::RevokeRequest>,
+    ) -> std::result::Result<Response<proto::RevokeResponse>, Status> {
+        let request = ControlRequest::Revoke {
+            node_id: request.into_inner().node_id,
+        };
+        match self.handle(request).await? {
+            ControlResponse::Revoked { removed } => {
+                Ok(Response::new(proto::RevokeResponse { removed }))
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+
+    type EventsStream = std::pin::Pin<
+        Box<
+            dyn tokio_stream::Stream<Item = std::result::Result<proto::ConnectionEvent, Status>>
+                + Send,
+        >,
+    >;
+
+    async fn events(
+        &self,
+        _request: Request<proto::EventsRequest>,
+    ) -> std::result::Result<Response<Self::EventsStream>, Status> {
+        let receiver = self
+            .0
+            .events()
+            .ok_or_else(|| Status::unimplemented("This node doesn't report connection events"))?;
+
+        // Subscribers that fall behind skip the events they missed rather than being cut off
+        let stream = BroadcastStream::new(receiver)
+            .filter_map(|event| event.ok().map(proto::ConnectionEvent::from))
+            .map(Ok);
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+impl From<control::HealthReport> for proto::HealthReport {
+    fn from(report: control::HealthReport) -> Self {
+        let status = match report.status {
+            control::HealthStatus::Healthy => proto::HealthStatus::Healthy,
+            control::HealthStatus::Degraded => proto::HealthStatus::Degraded,
+            control::HealthStatus::Down => proto::HealthStatus::Down,
+        };
+        Self {
+            status: status.into(),
+            node_id: report.node_id,
+            uptime: report.uptime,
+            active_connections: report.active_connections as u64,
+            max_connections: report.max_connections as u64,
+            home_relay: report.home_relay,
+            issues: report.issues,
+        }
+    }
+}
+
+impl From<control::ConnectionInfo> for proto::ConnectionInfo {
+    fn from(info: control::ConnectionInfo) -> Self {
+        Self {
+            id: info.id as u64,
+            node_id: info.node_id,
+            protocol: info.protocol,
+            port: info.port.into(),
+            target: info.target,
+            service: info.service,
+            duration: info.duration,
+            bytes_in: info.bytes_in,
+            bytes_out: info.bytes_out,
+        }
+    }
+}
+
+impl From<control::ConnectionEvent> for proto::ConnectionEvent {
+    fn from(event: control::ConnectionEvent) -> Self {
+        let kind = match event.event {
+            HookEvent::Connect => proto::EventKind::Connect,
+            HookEvent::Reconnect => proto::EventKind::Reconnect,
+            HookEvent::Disconnect => proto::EventKind::Disconnect,
+        };
+        Self {
+            kind: kind.into(),
+            connection: Some(event.connection.into()),
+        }
+    }
+}
@@ -0,0 +1,466 @@
+use crate::Result;
+use crate::core::{
+    Protocol, UdpMode,
+    qos::{Dscp, Qos},
+};
+use crate::utils::constants::MAX_HANDSHAKE_SIZE;
+use bytes::{BufMut, Bytes, BytesMut};
+use iroh::endpoint::Connection;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const TAG_HOST: u8 = 0x01;
+const TAG_UDP_MODE: u8 = 0x02;
+const TAG_SERVICE: u8 = 0x03;
+const TAG_DSCP: u8 = 0x04;
+const TAG_PRIORITY: u8 = 0x05;
+const TAG_MULTIPLEXED: u8 = 0x06;
+const TAG_STREAM_PRIORITY: u8 = 0x07;
+
+/// The tunnel request a client sends as the first datagram of a connection.
+///
+/// Layout: `[protocol: u8][port: u16 BE]` followed by optional `[tag: u8][len: u8][value]`
+/// fields. Unknown tags are skipped so older servers keep working with newer clients.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Handshake {
+    pub protocol: Protocol,
+    pub port: u16,
+    /// Host the server should forward to, `None` meaning the server's loopback interface
+    pub host: Option<String>,
+    /// UDP transport requested by the client, `None` for clients predating the negotiation
+    pub udp_mode: Option<UdpMode>,
+    /// Named service to connect to, in which case the server picks the protocol and port
+    pub service: Option<String>,
+    /// Marking of the server's sockets to the target
+    pub qos: Qos,
+    /// Carries several mappings, each stream starting with a request of its own
+    pub multiplexed: bool,
+    /// Rank of the stream against the other mappings of a multiplexed connection
+    pub stream_priority: i32,
+}
+
+impl Handshake {
+    pub fn new(protocol: Protocol, port: u16) -> Self {
+        Self {
+            protocol,
+            port,
+            host: None,
+            udp_mode: None,
+            service: None,
+            qos: Qos::default(),
+            multiplexed: false,
+            stream_priority: 0,
+        }
+    }
+
+    pub fn with_host(mut self, host: Option<String>) -> Self {
+        self.host = host;
+        self
+    }
+
+    pub fn with_udp_mode(mut self, mode: Option<UdpMode>) -> Self {
+        self.udp_mode = mode;
+        self
+    }
+
+    pub fn with_service(mut self, service: Option<String>) -> Self {
+        self.service = service;
+        self
+    }
+
+    pub fn with_qos(mut self, qos: Qos) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    pub fn with_multiplexed(mut self, multiplexed: bool) -> Self {
+        self.multiplexed = multiplexed;
+        self
+    }
+
+    pub fn with_stream_priority(mut self, priority: i32) -> Self {
+        self.stream_priority = priority;
+        self
+    }
+
+    pub fn encode(&self) -> Result<Bytes> {
+        let mut buf = BytesMut::with_capacity(3);
+        buf.put_u8(self.protocol as u8);
+        buf.put_u16(self.port);
+
+        if let Some(host) = &self.host {
+            put_field(&mut buf, TAG_HOST, host.as_bytes())?;
+        }
+        if let Some(mode) = self.udp_mode {
+            put_field(&mut buf, TAG_UDP_MODE, &[mode as u8])?;
+        }
+        if let Some(service) = &self.service {
+            put_field(&mut buf, TAG_SERVICE, service.as_bytes())?;
+        }
+        if let Some(dscp) = self.qos.dscp {
+            put_field(&mut buf, TAG_DSCP, &[dscp.value()])?;
+        }
+        if let Some(priority) = self.qos.priority {
+            put_field(&mut buf, TAG_PRIORITY, &[priority])?;
+        }
+        if self.multiplexed {
+            put_field(&mut buf, TAG_MULTIPLEXED, &[])?;
+        }
+        if self.stream_priority != 0 {
+            put_field(
+                &mut buf,
+                TAG_STREAM_PRIORITY,
+                &self.stream_priority.to_be_bytes(),
+            )?;
+        }
+
+        Ok(buf.freeze())
+    }
+
+    /// Parses a tunnel request, rejecting anything a well-behaved client wouldn't send:
+    /// oversized requests, repeated fields and empty or unprintable names.
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() > MAX_HANDSHAKE_SIZE {
+            return Err(crate::error!("Handshake too long ({} bytes)", data.len()));
+        }
+        let &[protocol, port_hi, port_lo, ref fields @ ..] = data else {
+            return Err(crate::error!("Handshake too short ({} bytes)", data.len()));
+        };
+
+        let protocol = Protocol::try_from(protocol).map_err(|e| crate::error!("{}", e))?;
+        let mut handshake = Handshake::new(protocol, u16::from_be_bytes([port_hi, port_lo]));
+
+        let mut seen = Vec::new();
+        let mut rest = fields;
+        while let &[tag, len, ref tail @ ..] = rest {
+            let len = len as usize;
+            if tail.len() < len {
+                return Err(crate::error!("Truncated handshake field 0x{:02x}", tag));
+            }
+            if seen.contains(&tag) {
+                return Err(crate::error!("Repeated handshake field 0x{:02x}", tag));
+            }
+            seen.push(tag);
+            let (value, tail) = tail.split_at(len);
+
+            match tag {
+                TAG_HOST => handshake.host = Some(decode_name(value, "Target host")?),
+                TAG_UDP_MODE => {
+                    let &[mode] = value else {
+                        return Err(crate::error!("UDP mode must be a single byte"));
+                    };
+                    // Unknown modes fall back to streams, which every server supports
+                    handshake.udp_mode = Some(UdpMode::try_from(mode).unwrap_or(UdpMode::Stream));
+                }
+                TAG_SERVICE => handshake.service = Some(decode_name(value, "Service name")?),
+                TAG_DSCP => {
+                    let &[dscp] = value else {
+                        return Err(crate::error!("DSCP must be a single byte"));
+                    };
+                    handshake.qos.dscp = Some(
+                        Dscp::new(dscp).ok_or_else(|| crate::error!("Invalid DSCP {}", dscp))?,
+                    );
+                }
+                TAG_PRIORITY => {
+                    let &[priority] = value else {
+                        return Err(crate::error!("Socket priority must be a single byte"));
+                    };
+                    handshake.qos.priority = Some(priority);
+                }
+                TAG_MULTIPLEXED => {
+                    if !value.is_empty() {
+                        return Err(crate::error!("Multiplexing flag takes no value"));
+                    }
+                    handshake.multiplexed = true;
+                }
+                TAG_STREAM_PRIORITY => {
+                    let &[a, b, c, d] = value else {
+                        return Err(crate::error!("Stream priority must be 4 bytes"));
+                    };
+                    handshake.stream_priority = i32::from_be_bytes([a, b, c, d]);
+                }
+                other => tracing::debug!("Ignoring unknown handshake field 0x{:02x}", other),
+            }
+
+            rest = tail;
+        }
+
+        if !rest.is_empty() {
+            return Err(crate::error!("Trailing bytes in handshake"));
+        }
+
+        Ok(handshake)
+    }
+}
+
+/// Host and service names end up in logs and DNS lookups, so only printable ones are taken.
+fn decode_name(value: &[u8], what: &str) -> Result<String> {
+    let name =
+        std::str::from_utf8(value).map_err(|_| crate::error!("{} is not valid UTF-8", what))?;
+    if name.is_empty() {
+        return Err(crate::error!("{} is empty", what));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(crate::error!("{} contains control characters", what));
+    }
+    Ok(name.to_string())
+}
+
+fn put_field(buf: &mut BytesMut, tag: u8, value: &[u8]) -> Result<()> {
+    let len = u8::try_from(value.len())
+        .map_err(|_| crate::error!("Handshake field 0x{:02x} is too long", tag))?;
+    buf.put_u8(tag);
+    buf.put_u8(len);
+    buf.put_slice(value);
+    Ok(())
+}
+
+/// Tells the client what a requested service resolved to, as `[protocol: u8][port: u16 BE]`
+/// on a dedicated uni stream.
+pub async fn send_service(conn: &Connection, protocol: Protocol, port: u16) -> Result<()> {
+    let mut send = conn.open_uni().await?;
+    let [port_hi, port_lo] = port.to_be_bytes();
+    send.write_all(&[protocol as u8, port_hi, port_lo])
+        .await
+        .map_err(|e| crate::error!("Failed to send service: {}", e))?;
+    send.finish()
+        .map_err(|e| crate::error!("Failed to send service: {}", e))
+}
+
+/// Waits for the protocol and port of the requested service.
+pub async fn recv_service(conn: &Connection, timeout: Duration) -> Result<(Protocol, u16)> {
+    let answer = tokio::time::timeout(timeout, async {
+        let mut recv = conn.accept_uni().await?;
+        let mut service = [0u8; 3];
+        recv.read_exact(&mut service)
+            .await
+            .map_err(|e| crate::error!("Failed to read service: {}", e))?;
+        let protocol = Protocol::try_from(service[0]).map_err(|e| crate::error!("{}", e))?;
+        Ok((protocol, u16::from_be_bytes([service[1], service[2]])))
+    })
+    .await;
+
+    answer.map_err(|_| crate::error!("Server didn't answer the service request"))?
+}
+
+/// Tells the client which UDP mode the server picked, on a dedicated uni stream.
+pub async fn send_udp_mode(conn: &Connection, mode: UdpMode) -> Result<()> {
+    let mut send = conn.open_uni().await?;
+    send.write_all(&[mode as u8])
+        .await
+        .map_err(|e| crate::error!("Failed to send UDP mode: {}", e))?;
+    send.finish()
+        .map_err(|e| crate::error!("Failed to send UDP mode: {}", e))
+}
+
+/// Waits for the server's UDP mode answer. Servers that don't know about the negotiation
+/// never answer, and only support unframed packets over a uni stream (`None`).
+pub async fn recv_udp_mode(conn: &Connection, timeout: Duration) -> Result<Option<UdpMode>> {
+    let answer = tokio::time::timeout(timeout, async {
+        let mut recv = conn.accept_uni().await?;
+        let mut mode = [0u8; 1];
+        recv.read_exact(&mut mode)
+            .await
+            .map_err(|e| crate::error!("Failed to read UDP mode: {}", e))?;
+        UdpMode::try_from(mode[0]).map_err(|e| crate::error!("{}", e))
+    })
+    .await;
+
+    match answer {
+        Ok(mode) => mode.map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Tells the client that its mappings share the connection, on a dedicated uni stream.
+pub async fn send_multiplexed(conn: &Connection) -> Result<()> {
+    let mut send = conn.open_uni().await?;
+    send.write_all(&[1])
+        .await
+        .map_err(|e| crate::error!("Failed to accept multiplexing: {}", e))?;
+    send.finish()
+        .map_err(|e| crate::error!("Failed to accept multiplexing: {}", e))
+}
+
+/// Waits for the server to accept multiplexing. Servers that don't know about it never
+/// answer, and only serve the connection's first mapping (`false`).
+pub async fn recv_multiplexed(conn: &Connection, timeout: Duration) -> Result<bool> {
+    let answer = tokio::time::timeout(timeout, async {
+        let mut recv = conn.accept_uni().await?;
+        let mut accepted = [0u8; 1];
+        recv.read_exact(&mut accepted)
+            .await
+            .map_err(|e| crate::error!("Failed to read multiplexing answer: {}", e))?;
+        Ok::<_, crate::PunchError>(accepted[0] == 1)
+    })
+    .await;
+
+    match answer {
+        Ok(accepted) => accepted,
+        Err(_) => Ok(false),
+    }
+}
+
+/// The request naming the target of a stream on a multiplexed connection, sent first on the
+/// stream as `[len: u16 BE][request]`.
+pub fn stream_header(request: &Handshake) -> Result<Bytes> {
+    let request = request.encode()?;
+    let mut header = BytesMut::with_capacity(2 + request.len());
+    header.put_u16(request.len() as u16);
+    header.put_slice(&request);
+    Ok(header.freeze())
+}
+
+pub async fn read_stream_header(reader: &mut (impl AsyncRead + Unpin)) -> Result<Handshake> {
+    let len = reader.read_u16().await? as usize;
+    if len > MAX_HANDSHAKE_SIZE {
+        return Err(crate::error!("Stream header too long ({} bytes)", len));
+    }
+    let mut request = vec![0u8; len];
+    reader.read_exact(&mut request).await?;
+    Handshake::decode(&request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A TCP request for port 22 followed by `fields`
+    fn request(fields: &[u8]) -> Vec<u8> {
+        let mut data = vec![Protocol::Tcp as u8, 0, 22];
+        data.extend_from_slice(fields);
+        data
+    }
+
+    #[test]
+    fn rejects_malformed_handshakes() {
+        let oversized = request(&[0xff; MAX_HANDSHAKE_SIZE]);
+        let cases: [(&str, Vec<u8>); 18] = [
+            ("empty", vec![]),
+            ("truncated header", vec![Protocol::Tcp as u8, 0]),
+            ("unknown protocol", vec![0x7, 0, 22]),
+            ("truncated length", request(&[TAG_HOST, 9, b'l', b'o'])),
+            ("truncated unknown field", request(&[0x7f, 2, 0])),
+            ("repeated tag", request(&[TAG_DSCP, 1, 46, TAG_DSCP, 1, 46])),
+            (
+                "repeated unknown tag",
+                request(&[0x7f, 1, 0, TAG_DSCP, 1, 46, 0x7f, 0]),
+            ),
+            ("trailing byte", request(&[TAG_DSCP, 1, 46, 0])),
+            ("oversize", oversized),
+            ("empty host", request(&[TAG_HOST, 0])),
+            (
+                "host with control characters",
+                request(&[TAG_HOST, 2, b'a', b'\n']),
+            ),
+            ("host not UTF-8", request(&[TAG_HOST, 2, 0xc3, 0x28])),
+            ("empty service", request(&[TAG_SERVICE, 0])),
+            ("long UDP mode", request(&[TAG_UDP_MODE, 2, 0, 0])),
+            ("invalid DSCP", request(&[TAG_DSCP, 1, 64])),
+            ("long priority", request(&[TAG_PRIORITY, 0])),
+            (
+                "multiplexing flag with a value",
+                request(&[TAG_MULTIPLEXED, 1, 1]),
+            ),
+            (
+                "short stream priority",
+                request(&[TAG_STREAM_PRIORITY, 2, 0, 1]),
+            ),
+        ];
+
+        for (name, data) in cases {
+            assert!(Handshake::decode(&data).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn decodes_handshakes() {
+        // Unknown fields filling the handshake up to its limit
+        let mut largest = Vec::new();
+        for (tag, len) in [(0x70, 255), (0x71, 255), (0x72, 255), (0x73, 248)] {
+            largest.extend([tag, len]);
+            largest.resize(largest.len() + len as usize, 0);
+        }
+        let largest = request(&largest);
+        assert_eq!(largest.len(), MAX_HANDSHAKE_SIZE);
+
+        let cases = [
+            ("bare", request(&[]), Handshake::new(Protocol::Tcp, 22)),
+            (
+                "unknown tag",
+                request(&[0x7f, 3, 1, 2, 3, TAG_PRIORITY, 1, 6]),
+                Handshake::new(Protocol::Tcp, 22).with_qos(Qos {
+                    dscp: None,
+                    priority: Some(6),
+                }),
+            ),
+            (
+                "empty unknown tag",
+                request(&[0x7f, 0]),
+                Handshake::new(Protocol::Tcp, 22),
+            ),
+            (
+                "unknown UDP mode",
+                vec![Protocol::Udp as u8, 0x13, 0x88, TAG_UDP_MODE, 1, 0x7f],
+                Handshake::new(Protocol::Udp, 5000).with_udp_mode(Some(UdpMode::Stream)),
+            ),
+            ("largest", largest, Handshake::new(Protocol::Tcp, 22)),
+        ];
+
+        for (name, data, expected) in cases {
+            assert_eq!(Handshake::decode(&data).unwrap(), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn round_trips_through_encode() {
+        let cases = [
+            Handshake::new(Protocol::Tcp, 0),
+            Handshake::new(Protocol::Udp, 65535).with_udp_mode(Some(UdpMode::Datagram)),
+            Handshake::new(Protocol::Tcp, 5432)
+                .with_host(Some("db.internal".to_string()))
+                .with_qos(Qos {
+                    dscp: Dscp::new(46),
+                    priority: Some(6),
+                }),
+            Handshake::new(Protocol::Tcp, 0).with_service(Some("grafana".to_string())),
+            Handshake::new(Protocol::Udp, 53)
+                .with_host(Some("ünïcode.example".to_string()))
+                .with_udp_mode(Some(UdpMode::Stream))
+                .with_service(Some("dns".to_string()))
+                .with_qos(Qos {
+                    dscp: Dscp::new(0),
+                    priority: Some(0),
+                }),
+            Handshake::new(Protocol::Udp, 5000).with_multiplexed(true),
+            Handshake::new(Protocol::Tcp, 22).with_stream_priority(-7),
+        ];
+
+        for handshake in cases {
+            let encoded = handshake.encode().unwrap();
+            assert_eq!(Handshake::decode(&encoded).unwrap(), handshake);
+        }
+    }
+
+    #[test]
+    fn refuses_to_encode_long_fields() {
+        let handshake = Handshake::new(Protocol::Tcp, 22).with_host(Some("a".repeat(256)));
+        assert!(handshake.encode().is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_stream_headers() {
+        let request = Handshake::new(Protocol::Udp, 53).with_host(Some("dns.internal".to_string()));
+        let mut stream = stream_header(&request).unwrap().to_vec();
+        stream.extend_from_slice(b"payload");
+
+        let mut reader = stream.as_slice();
+        assert_eq!(read_stream_header(&mut reader).await.unwrap(), request);
+        assert_eq!(reader, b"payload");
+
+        let mut oversized = ((MAX_HANDSHAKE_SIZE + 1) as u16).to_be_bytes().to_vec();
+        oversized.resize(MAX_HANDSHAKE_SIZE + 3, 0);
+        assert!(read_stream_header(&mut oversized.as_slice()).await.is_err());
+        assert!(read_stream_header(&mut &[0, 3, 0][..]).await.is_err());
+    }
+}
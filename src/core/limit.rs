@@ -0,0 +1,110 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+/// Caps the throughput of a tunnel, both directions drawing from the same budget.
+///
+/// Bytes beyond the budget are borrowed from the next ones rather than refused, so a read
+/// larger than a second's worth of traffic is only delayed, never stuck.
+#[derive(Debug)]
+pub struct RateLimit {
+    bytes_per_sec: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes that can go through right away, negative while in debt
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        Self {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until `bytes` fit in the budget.
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.updated).as_secs_f64() * self.bytes_per_sec;
+            // At most a second's worth of bytes can be saved up for a burst
+            bucket.tokens = (bucket.tokens + refill).min(self.bytes_per_sec) - bytes as f64;
+            bucket.updated = now;
+
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Caps the streams of a tunnel bridged at once, both in number and in the bytes of buffers
+/// they hold. Streams past either cap wait for one to end before being accepted.
+#[derive(Debug)]
+pub struct StreamLimit {
+    streams: Arc<Semaphore>,
+    memory: Option<Arc<Semaphore>>,
+    /// Bytes each stream takes from the memory budget
+    stream_memory: u32,
+}
+
+/// A stream's share of a [`StreamLimit`], given back when dropped.
+pub struct StreamPermit {
+    _stream: OwnedSemaphorePermit,
+    _memory: Option<OwnedSemaphorePermit>,
+}
+
+impl StreamLimit {
+    /// Lets `max_streams` streams through at once, each holding `stream_memory` bytes out of
+    /// `memory`, `None` for no memory cap.
+    pub fn new(max_streams: usize, memory: Option<u64>, stream_memory: usize) -> Self {
+        let memory = memory.map(|memory| usize::try_from(memory).unwrap_or(usize::MAX));
+        // A budget smaller than a single stream would never let any through
+        let stream_memory = memory.map_or(0, |memory| stream_memory.min(memory));
+        Self {
+            streams: Arc::new(Semaphore::new(max_streams.min(Semaphore::MAX_PERMITS))),
+            memory: memory
+                .map(|memory| Arc::new(Semaphore::new(memory.min(Semaphore::MAX_PERMITS)))),
+            stream_memory: u32::try_from(stream_memory).unwrap_or(u32::MAX),
+        }
+    }
+
+    /// Waits until one more stream fits.
+    pub async fn acquire(&self) -> StreamPermit {
+        let stream = Arc::clone(&self.streams)
+            .acquire_owned()
+            .await
+            .expect("Stream semaphores are never closed");
+        let memory = match &self.memory {
+            Some(memory) => Some(
+                Arc::clone(memory)
+                    .acquire_many_owned(self.stream_memory)
+                    .await
+                    .expect("Stream semaphores are never closed"),
+            ),
+            None => None,
+        };
+
+        StreamPermit {
+            _stream: stream,
+            _memory: memory,
+        }
+    }
+}
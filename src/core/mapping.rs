@@ -0,0 +1,442 @@
+use crate::Result as PunchResult;
+use crate::core::{
+    Protocol, ProtocolChoice,
+    qos::{Dscp, Qos},
+};
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+
+/// A port mapping between a local listener and a port on the remote host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    /// Address to bind the local listener to, if given in the mapping itself
+    pub bind: Option<IpAddr>,
+    pub local_port: u16,
+    pub remote_port: u16,
+}
+
+impl Mapping {
+    pub fn local_addr(&self, default_bind: Option<IpAddr>) -> SocketAddr {
+        let ip = self
+            .bind
+            .or(default_bind)
+            .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        SocketAddr::new(ip, self.local_port)
+    }
+
+    pub fn with_local_port(mut self, port: u16) -> Self {
+        self.local_port = port;
+        self
+    }
+}
+
+impl std::str::FromStr for Mapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const FORMAT: &str =
+            "Mapping must be in the format '[bind_address:]local_port:remote_port' or 'port'";
+
+        // A single port is the same on both sides
+        if let Ok(port) = s.parse::<u16>()
+            && port != 0
+        {
+            return Ok(Mapping {
+                bind: None,
+                local_port: port,
+                remote_port: port,
+            });
+        }
+
+        let (bind, ports) = match s.strip_prefix('[') {
+            Some(rest) => {
+                let (address, ports) = rest
+                    .split_once("]:")
+                    .ok_or_else(|| format!("Unterminated IPv6 address in mapping: {}", s))?;
+                let bind = address
+                    .parse::<Ipv6Addr>()
+                    .map_err(|_| format!("Invalid IPv6 bind address: {}", address))?;
+                (Some(IpAddr::V6(bind)), ports)
+            }
+            None => match s.matches(':').count() {
+                1 => (None, s),
+                2 => {
+                    let (address, ports) = s.split_once(':').ok_or(FORMAT)?;
+                    let bind = address
+                        .parse::<IpAddr>()
+                        .map_err(|_| format!("Invalid bind address: {}", address))?;
+                    (Some(bind), ports)
+                }
+                _ if s.contains("::") => {
+                    return Err(format!("IPv6 bind addresses must be bracketed: {}", s));
+                }
+                _ => return Err(FORMAT.to_string()),
+            },
+        };
+
+        let (local, remote) = ports.split_once(':').ok_or(FORMAT)?;
+
+        let local_port = local
+            .parse::<u16>()
+            .map_err(|_| "Invalid local port".to_string())?;
+        let remote_port = remote
+            .parse::<u16>()
+            .map_err(|_| "Invalid remote port".to_string())?;
+
+        Ok(Mapping {
+            bind,
+            local_port,
+            remote_port,
+        })
+    }
+}
+
+impl std::fmt::Display for Mapping {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.bind {
+            Some(IpAddr::V6(bind)) => {
+                write!(f, "[{}]:{}:{}", bind, self.local_port, self.remote_port)
+            }
+            Some(bind) => write!(f, "{}:{}:{}", bind, self.local_port, self.remote_port),
+            None => write!(f, "{}:{}", self.local_port, self.remote_port),
+        }
+    }
+}
+
+impl Serialize for Mapping {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Mapping {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// A local listener and the protocol it forwards, one of the tunnels of a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Forward {
+    pub mapping: Mapping,
+    pub protocol: Protocol,
+    /// Marking of the mapping's sockets, what it leaves unset coming from the command line
+    pub qos: Qos,
+    /// Rank of the mapping's streams against those of the other mappings, higher first
+    pub stream_priority: i32,
+}
+
+impl Forward {
+    /// The forwards for `choice`, both protocols sharing the mapping for `both`.
+    pub fn expand(mapping: Mapping, choice: ProtocolChoice) -> Vec<Self> {
+        match choice {
+            ProtocolChoice::One(protocol) => vec![Forward {
+                mapping,
+                protocol,
+                qos: Qos::default(),
+                stream_priority: 0,
+            }],
+            ProtocolChoice::Both => vec![
+                Forward {
+                    mapping,
+                    protocol: Protocol::Tcp,
+                    qos: Qos::default(),
+                    stream_priority: 0,
+                },
+                Forward {
+                    mapping,
+                    protocol: Protocol::Udp,
+                    qos: Qos::default(),
+                    stream_priority: 0,
+                },
+            ],
+        }
+    }
+}
+
+/// Tunnels opened together by `punch client --mappings-file`, e.g.
+///
+/// ```toml
+/// [[tunnels]]
+/// mapping = "3000"
+///
+/// [[tunnels]]
+/// mapping = "5353:53"
+/// protocol = "both"
+/// bind = "0.0.0.0"
+///
+/// [[tunnels]]
+/// mapping = "5060"
+/// protocol = "udp"
+/// dscp = "ef"
+///
+/// [[tunnels]]
+/// mapping = "22"
+/// stream_priority = 10
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MappingsFile {
+    #[serde(default)]
+    tunnels: Vec<MappingEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MappingEntry {
+    mapping: Mapping,
+    #[serde(default)]
+    protocol: ProtocolChoice,
+    /// Overrides `--bind` for this mapping
+    #[serde(default)]
+    bind: Option<IpAddr>,
+    /// Overrides `--dscp` for this mapping
+    #[serde(default)]
+    dscp: Option<Dscp>,
+    /// Overrides `--priority` for this mapping
+    #[serde(default)]
+    priority: Option<u8>,
+    /// Sends the mapping's data ahead of the mappings with a lower one, e.g. SSH over backups
+    #[serde(default)]
+    stream_priority: i32,
+}
+
+/// Reads and checks a mappings file, before any tunnel is opened.
+pub async fn load_mappings_file(path: &Path) -> PunchResult<Vec<Forward>> {
+    let contents = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| crate::error!(source = e, "Failed to read {}", path.display()))?;
+    parse_mappings_file(&contents, path)
+}
+
+/// Checks the contents of a mappings file, `path` only naming it in errors.
+fn parse_mappings_file(contents: &str, path: &Path) -> PunchResult<Vec<Forward>> {
+    let file: MappingsFile =
+        toml::from_str(contents).map_err(|e| crate::PunchError::ConfigError {
+            path: path.to_path_buf(),
+            source: Box::new(e),
+        })?;
+    if file.tunnels.is_empty() {
+        return Err(crate::error!("No [[tunnels]] in {}", path.display()));
+    }
+
+    let mut forwards = Vec::new();
+    for entry in file.tunnels {
+        let mut mapping = entry.mapping;
+        if let Some(bind) = entry.bind {
+            if mapping.bind.is_some_and(|ip| ip != bind) {
+                return Err(crate::error!(
+                    "Mapping {} has two bind addresses in {}",
+                    mapping,
+                    path.display()
+                ));
+            }
+            mapping.bind = Some(bind);
+        }
+        if mapping.remote_port == 0 {
+            return Err(crate::error!(
+                "Mapping {} has no remote port in {}",
+                mapping,
+                path.display()
+            ));
+        }
+        let qos = Qos {
+            dscp: entry.dscp,
+            priority: entry.priority,
+        };
+        forwards.extend(
+            Forward::expand(mapping, entry.protocol)
+                .into_iter()
+                .map(|forward| Forward {
+                    qos,
+                    stream_priority: entry.stream_priority,
+                    ..forward
+                }),
+        );
+    }
+
+    // Free ports picked for a local port of 0 can't clash
+    let mut listeners = Vec::new();
+    for forward in forwards.iter().filter(|f| f.mapping.local_port != 0) {
+        let listener = (
+            forward.mapping.bind,
+            forward.mapping.local_port,
+            forward.protocol,
+        );
+        if listeners.contains(&listener) {
+            return Err(crate::error!(
+                "Local port {} is used twice for {} in {}",
+                forward.mapping.local_port,
+                forward.protocol,
+                path.display()
+            ));
+        }
+        listeners.push(listener);
+    }
+    Ok(forwards)
+}
+
+/// Restricts which source addresses may use a local listener. An empty filter allows everyone.
+#[derive(Debug, Clone, Default)]
+pub struct SourceFilter {
+    networks: Vec<IpNet>,
+}
+
+impl SourceFilter {
+    pub fn new(networks: Vec<IpNet>) -> Self {
+        Self { networks }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.networks.is_empty()
+    }
+
+    pub fn allows(&self, addr: &IpAddr) -> bool {
+        self.networks.is_empty() || self.networks.iter().any(|net| net.contains(addr))
+    }
+}
+
+/// Parses a CIDR block, accepting bare addresses as single-host networks.
+pub fn parse_network(s: &str) -> Result<IpNet, String> {
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("Invalid network or address: {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(bind: Option<&str>, local_port: u16, remote_port: u16) -> Mapping {
+        Mapping {
+            bind: bind.map(|ip| ip.parse().unwrap()),
+            local_port,
+            remote_port,
+        }
+    }
+
+    #[test]
+    fn parses_mappings() {
+        let cases = [
+            ("22", Some(mapping(None, 22, 22))),
+            ("8080:80", Some(mapping(None, 8080, 80))),
+            ("0:5432", Some(mapping(None, 0, 5432))),
+            ("0.0.0.0:8080:80", Some(mapping(Some("0.0.0.0"), 8080, 80))),
+            ("[::1]:8080:80", Some(mapping(Some("::1"), 8080, 80))),
+            ("0", None),
+            ("", None),
+            ("8080:", None),
+            ("8080:65536", None),
+            ("host:8080:80", None),
+            ("::1:8080:80", None),
+            ("[::1:8080:80", None),
+            ("[127.0.0.1]:8080:80", None),
+            ("1:2:3:4", None),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(input.parse::<Mapping>().ok(), expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn displays_mappings_as_parsed() {
+        for input in ["8080:80", "0.0.0.0:8080:80", "[::1]:8080:80"] {
+            assert_eq!(input.parse::<Mapping>().unwrap().to_string(), input);
+        }
+    }
+
+    #[test]
+    fn parses_mappings_files() {
+        let path = Path::new("tunnels.toml");
+        let forwards = parse_mappings_file(
+            r#"
+            [[tunnels]]
+            mapping = "3000"
+
+            [[tunnels]]
+            mapping = "5353:53"
+            protocol = "both"
+            bind = "0.0.0.0"
+            stream_priority = -5
+
+            [[tunnels]]
+            mapping = "5060"
+            protocol = "udp"
+            dscp = "ef"
+            priority = 6
+            "#,
+            path,
+        )
+        .unwrap();
+
+        let summary: Vec<_> = forwards
+            .iter()
+            .map(|forward| (forward.mapping, forward.protocol))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (mapping(None, 3000, 3000), Protocol::Tcp),
+                (mapping(Some("0.0.0.0"), 5353, 53), Protocol::Tcp),
+                (mapping(Some("0.0.0.0"), 5353, 53), Protocol::Udp),
+                (mapping(None, 5060, 5060), Protocol::Udp),
+            ]
+        );
+        assert_eq!(forwards[0].qos, Qos::default());
+        assert_eq!(forwards[0].stream_priority, 0);
+        assert_eq!(forwards[2].stream_priority, -5);
+        assert_eq!(
+            forwards[3].qos,
+            Qos {
+                dscp: Dscp::new(46),
+                priority: Some(6),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_mappings_files() {
+        let cases = [
+            ("empty", ""),
+            ("unknown field", "[[tunnels]]\nmapping = \"22\"\nport = 22"),
+            ("invalid mapping", "[[tunnels]]\nmapping = \"22:\""),
+            (
+                "two bind addresses",
+                "[[tunnels]]\nmapping = \"127.0.0.1:8080:80\"\nbind = \"0.0.0.0\"",
+            ),
+            ("no remote port", "[[tunnels]]\nmapping = \"8080:0\""),
+            (
+                "same local port",
+                "[[tunnels]]\nmapping = \"8080:80\"\n[[tunnels]]\nmapping = \"8080:81\"",
+            ),
+            (
+                "same local port for both",
+                "[[tunnels]]\nmapping = \"53\"\nprotocol = \"both\"\n[[tunnels]]\nmapping = \"53:5353\"\nprotocol = \"udp\"",
+            ),
+            ("invalid dscp", "[[tunnels]]\nmapping = \"22\"\ndscp = 64"),
+        ];
+
+        for (name, contents) in cases {
+            assert!(
+                parse_mappings_file(contents, Path::new("tunnels.toml")).is_err(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn free_local_ports_can_repeat() {
+        let forwards = parse_mappings_file(
+            "[[tunnels]]\nmapping = \"0:80\"\n[[tunnels]]\nmapping = \"0:81\"",
+            Path::new("tunnels.toml"),
+        )
+        .unwrap();
+        assert_eq!(forwards.len(), 2);
+    }
+}
@@ -1,24 +1,120 @@
-use crate::Result;
-use iroh::{Endpoint, SecretKey, endpoint::Connection};
+use crate::core::buffer::BufferPool;
+use crate::core::datagram::OversizedPolicy;
+use crate::core::framing::PeerStreams;
+use crate::core::handshake::Handshake;
+use crate::core::limit::{RateLimit, StreamLimit, StreamPermit};
+use crate::core::mapping::SourceFilter;
+use crate::core::pool::ConnectionPool;
+use crate::core::qos::Qos;
+use crate::utils::config::{CongestionController, NetworkSettings, TcpSettings, TransportSettings};
+use crate::utils::constants::{DEFAULT_MAX_STREAMS_PER_CONNECTION, HANDSHAKE_TIMEOUT};
+use crate::utils::telemetry;
+use crate::{PunchError, ResetReason, Result};
+use bytes::Bytes;
+use iroh::{
+    Endpoint, RelayMode, SecretKey,
+    endpoint::{Connection, RecvStream, SendStream, TransportConfig, VarInt, WriteError},
+};
+use n0_future::boxed::BoxFuture;
+use quinn::congestion;
+use serde::{Deserialize, Serialize};
+use socket2::SockRef;
 use std::net::SocketAddr;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpStream, UdpSocket};
+use tracing::{Instrument, Span};
 
+pub mod access;
+pub mod balance;
+pub mod bench;
+pub mod buffer;
 pub mod client;
+pub mod confirm;
+pub mod control;
+pub mod datagram;
+pub mod discovery;
+pub mod dns;
+pub mod framing;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod handshake;
+pub mod limit;
+pub mod mapping;
+pub mod net;
+pub mod netcheck;
+pub mod pool;
+pub mod probe;
+pub mod proxy_protocol;
+pub mod qos;
+pub mod serve;
 pub mod server;
+pub mod services;
+pub mod sync;
+pub mod ticket;
+pub mod transfer;
+pub mod udp;
+pub mod vpn;
 
-pub async fn build_endpoint(sk: SecretKey) -> Result<Endpoint> {
-    Ok(Endpoint::builder()
+pub async fn build_endpoint(sk: SecretKey, network: &NetworkSettings) -> Result<Endpoint> {
+    let mut builder = Endpoint::builder()
         .discovery_n0()
         .discovery_local_network()
-        .secret_key(sk)
-        .bind()
-        .await?)
+        .transport_config(transport_config(&network.transport))
+        .secret_key(sk);
+    // Checked when the config is loaded, so this only fails for names that are too long
+    if let Some(user_data) = discovery::user_data(network.discovery_name.as_deref()) {
+        builder = builder.user_data_for_discovery(user_data);
+    }
+    if let Some(relay_url) = &network.relay_url {
+        builder = builder.relay_mode(RelayMode::Custom(relay_url.clone().into()));
+    }
+    Ok(builder.bind().await?)
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+fn transport_config(settings: &TransportSettings) -> TransportConfig {
+    let mut config = TransportConfig::default();
+    // Same keep-alive as iroh's default transport config
+    config.keep_alive_interval(Some(Duration::from_secs(1)));
+
+    match settings.congestion_controller {
+        Some(CongestionController::Cubic) | None => {}
+        Some(CongestionController::Bbr) => {
+            config.congestion_controller_factory(Arc::new(congestion::BbrConfig::default()));
+        }
+        Some(CongestionController::NewReno) => {
+            config.congestion_controller_factory(Arc::new(congestion::NewRenoConfig::default()));
+        }
+    }
+
+    // Values are checked against the VarInt range when the config is loaded
+    let varint = |value: u64| VarInt::from_u64(value).unwrap_or(VarInt::MAX);
+    if let Some(window) = settings.stream_receive_window {
+        config.stream_receive_window(varint(window));
+    }
+    if let Some(window) = settings.receive_window {
+        config.receive_window(varint(window));
+    }
+    if let Some(window) = settings.send_window {
+        config.send_window(window);
+    }
+    if let Some(count) = settings.max_concurrent_bidi_streams {
+        config.max_concurrent_bidi_streams(varint(count));
+    }
+    if let Some(count) = settings.max_concurrent_uni_streams {
+        config.max_concurrent_uni_streams(varint(count));
+    }
+
+    config
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 #[repr(u8)]
 pub enum Protocol {
+    #[default]
     Tcp = 0x0,
     Udp = 0x1,
 }
@@ -56,35 +152,342 @@ impl std::fmt::Display for Protocol {
     }
 }
 
+/// Protocols a client forwards, `both` opening a TCP and a UDP tunnel for the same port, e.g.
+/// for DNS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolChoice {
+    One(Protocol),
+    Both,
+}
+
+impl Default for ProtocolChoice {
+    fn default() -> Self {
+        ProtocolChoice::One(Protocol::default())
+    }
+}
+
+impl From<Protocol> for ProtocolChoice {
+    fn from(protocol: Protocol) -> Self {
+        ProtocolChoice::One(protocol)
+    }
+}
+
+impl std::str::FromStr for ProtocolChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "both" => Ok(ProtocolChoice::Both),
+            _ => s
+                .parse()
+                .map(ProtocolChoice::One)
+                .map_err(|_| "Invalid protocol. Use 'tcp', 'udp' or 'both'.".to_string()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ProtocolChoice {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl std::fmt::Display for ProtocolChoice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolChoice::One(protocol) => write!(f, "{}", protocol),
+            ProtocolChoice::Both => write!(f, "TCP+UDP"),
+        }
+    }
+}
+
+/// How UDP packets are carried through the tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[repr(u8)]
+pub enum UdpMode {
+    /// Over a reliable, ordered stream
+    #[default]
+    Stream = 0x0,
+    /// One unreliable QUIC datagram per packet, avoiding head-of-line blocking
+    Datagram = 0x1,
+}
+
+impl TryFrom<u8> for UdpMode {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x0 => Ok(UdpMode::Stream),
+            0x1 => Ok(UdpMode::Datagram),
+            _ => Err(format!("Invalid UDP mode byte: {}", value)),
+        }
+    }
+}
+
+impl std::fmt::Display for UdpMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UdpMode::Stream => write!(f, "stream"),
+            UdpMode::Datagram => write!(f, "datagram"),
+        }
+    }
+}
+
+/// Source of the IDs of tunnels that weren't given one, like those of the client
+static NEXT_TUNNEL_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Span of a bridged stream, named after its QUIC stream index. Created inside a tunnel's
+/// span, so that its events carry both IDs.
+pub(crate) fn stream_span(id: iroh::endpoint::StreamId) -> Span {
+    tracing::info_span!("stream", id = id.index())
+}
+
+/// How the streams of a mapping start when it shares its connection with others.
+#[derive(Debug, Clone, Default)]
+pub struct StreamSetup {
+    /// Request naming the target, sent first on every stream
+    header: Option<Bytes>,
+    /// Order in which quinn sends the data of the streams of a connection, higher first
+    priority: i32,
+}
+
+impl StreamSetup {
+    pub async fn start(&self, send: &mut SendStream) -> std::result::Result<(), WriteError> {
+        send.set_priority(self.priority).ok();
+        if let Some(header) = &self.header {
+            send.write_all(header).await?;
+        }
+        Ok(())
+    }
+}
+
 pub struct TunnelConnection {
+    /// Short ID tying the log lines of the tunnel and its streams together
+    id: usize,
+    span: Span,
     conn: Connection,
     protocol: Protocol,
+    /// `None` when talking to a server that predates UDP mode negotiation, which only
+    /// understands unframed packets over a uni stream
+    udp_mode: Option<UdpMode>,
+    oversized: OversizedPolicy,
+    buffers: Arc<BufferPool>,
+    stats: Arc<TrafficStats>,
+    /// Port the server forwards to, to explain failures
+    remote_port: Option<u16>,
+    /// Connections TCP streams are spread over, starting with `conn`
+    pool: ConnectionPool,
+    /// Marking of the local sockets
+    qos: Qos,
+    tcp: TcpSettings,
+    streams: StreamSetup,
 }
 
 impl TunnelConnection {
     pub fn new(conn: Connection, protocol: Protocol) -> Self {
-        Self { conn, protocol }
+        let id = NEXT_TUNNEL_ID.fetch_add(1, Ordering::Relaxed);
+        Self {
+            id,
+            span: tracing::info_span!("tunnel", id),
+            pool: ConnectionPool::new(conn.clone()),
+            conn,
+            protocol,
+            udp_mode: None,
+            oversized: OversizedPolicy::default(),
+            buffers: Arc::default(),
+            stats: Arc::default(),
+            remote_port: None,
+            qos: Qos::default(),
+            tcp: TcpSettings::default(),
+            streams: StreamSetup::default(),
+        }
+    }
+
+    /// Uses `id` instead of a generated one, e.g. the ID the server shows to the admin.
+    pub fn with_id(mut self, id: usize) -> Self {
+        self.id = id;
+        self.span = tracing::info_span!("tunnel", id);
+        self
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Span the events of the tunnel are recorded in.
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    pub fn with_remote_port(mut self, port: u16) -> Self {
+        self.remote_port = Some(port);
+        self
+    }
+
+    /// Spreads TCP streams over these extra connections to the same peer as well.
+    pub fn with_striped_connections(mut self, connections: Vec<Connection>) -> Self {
+        self.pool = ConnectionPool::new(self.conn.clone()).with_connections(connections);
+        self
+    }
+
+    /// How packets larger than the tunnel's datagram size are sent in datagram mode.
+    pub fn with_oversized_policy(mut self, policy: OversizedPolicy) -> Self {
+        self.oversized = policy;
+        self
+    }
+
+    pub fn with_udp_mode(mut self, mode: Option<UdpMode>) -> Self {
+        self.udp_mode = mode;
+        self
+    }
+
+    pub fn udp_mode(&self) -> Option<UdpMode> {
+        self.udp_mode
+    }
+
+    pub fn with_qos(mut self, qos: Qos) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    /// Options of the local TCP connections.
+    pub fn with_tcp_settings(mut self, settings: TcpSettings) -> Self {
+        self.tcp = settings;
+        self
+    }
+
+    pub fn with_buffers(mut self, buffers: Arc<BufferPool>) -> Self {
+        self.buffers = buffers;
+        self
+    }
+
+    /// Starts every stream with `header`, for a mapping sharing its connection with others.
+    pub fn with_stream_header(mut self, header: Bytes) -> Self {
+        self.streams.header = Some(header);
+        self
+    }
+
+    /// Ranks the streams against those of the other mappings on the connection.
+    pub fn with_stream_priority(mut self, priority: i32) -> Self {
+        self.streams.priority = priority;
+        self
+    }
+
+    /// Bytes received from (`bytes_in`) and sent through (`bytes_out`) the tunnel so far.
+    pub fn stats(&self) -> &Arc<TrafficStats> {
+        &self.stats
     }
 
     pub fn protocol(&self) -> Protocol {
         self.protocol
     }
 
+    pub fn remote_node_id(&self) -> Result<iroh::NodeId> {
+        Ok(self.conn.remote_node_id()?)
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
     pub async fn wait_closed(&self) {
         self.conn.closed().await;
     }
 
+    pub fn is_closed(&self) -> bool {
+        self.conn.close_reason().is_some()
+    }
+
     pub async fn handle_tcp_stream(&self, mut local_stream: TcpStream) -> Result<()> {
-        let (tunnel_send, tunnel_recv) = self.conn.open_bi().await?;
-        let mut tunnel_stream = tokio::io::join(tunnel_recv, tunnel_send);
+        self.qos.apply(SockRef::from(&local_stream));
+        net::configure_tcp(&local_stream, &self.tcp);
+        let (reader, writer) = local_stream.split();
+        let result = self.handle_local_io(reader, writer).await;
+        if result.is_err() {
+            net::abort_on_drop(&local_stream);
+        }
+        result
+    }
 
-        tokio::io::copy_bidirectional(&mut tunnel_stream, &mut local_stream).await?;
+    /// Bridges a new tunnel stream with any local reader/writer pair, such as stdin/stdout.
+    pub async fn handle_local_io(
+        &self,
+        reader: impl AsyncRead + Unpin,
+        writer: impl AsyncWrite + Unpin,
+    ) -> Result<()> {
+        let (mut tunnel_send, tunnel_recv) = self.pool.pick().open_bi().await?;
+        self.streams
+            .start(&mut tunnel_send)
+            .await
+            .map_err(|e| crate::error!("Failed to send stream header: {}", e))?;
+
+        let span = tracing::info_span!(parent: &self.span, "stream", id = tunnel_send.id().index());
+        async {
+            tracing::debug!("Stream opened");
+            let result = buffer::bridge(
+                (tunnel_recv, tunnel_send),
+                (reader, writer),
+                &self.buffers,
+                &self.stats,
+            )
+            .await;
+            tracing::debug!("Stream closed");
+            result
+        }
+        .instrument(span)
+        .await
+        .map_err(|e| match ResetReason::from_io_error(&e) {
+            Some(reason) => PunchError::StreamReset {
+                reason,
+                port: self.remote_port,
+            },
+            None => e.into(),
+        })?;
         Ok(())
     }
 
-    pub async fn handle_udp_socket(&self, socket: UdpSocket) -> Result<()> {
-        let mut tunnel_stream = self.conn.open_uni().await?;
-        let mut buf = vec![0u8; 64 * 1024]; // 64KB
+    pub async fn handle_udp_socket(&self, socket: UdpSocket, filter: &SourceFilter) -> Result<()> {
+        self.qos.apply(SockRef::from(&socket));
+        self.forward_udp_socket(socket, filter)
+            .instrument(self.span.clone())
+            .await
+    }
+
+    async fn forward_udp_socket(&self, socket: UdpSocket, filter: &SourceFilter) -> Result<()> {
+        match self.udp_mode {
+            Some(UdpMode::Datagram) => {
+                datagram::forward_local_socket(
+                    &self.conn,
+                    Arc::new(socket),
+                    filter,
+                    self.oversized,
+                    &self.buffers,
+                    &self.stats,
+                )
+                .await
+            }
+            Some(UdpMode::Stream) => self.forward_udp_frames(Arc::new(socket), filter).await,
+            None => self.forward_unframed_udp(socket, filter).await,
+        }
+    }
+
+    /// Carries each local peer's packets as frames on its own bidirectional stream, so
+    /// that replies can be delivered back to it.
+    async fn forward_udp_frames(
+        &self,
+        socket: Arc<UdpSocket>,
+        filter: &SourceFilter,
+    ) -> Result<()> {
+        let mut peers = PeerStreams::new(
+            self.conn.clone(),
+            Arc::clone(&socket),
+            Arc::clone(&self.buffers),
+            Arc::clone(&self.stats),
+        )
+        .with_setup(self.streams.clone());
+        let mut batch = Vec::new();
 
         loop {
             tokio::select! {
@@ -93,25 +496,54 @@ impl TunnelConnection {
                     break;
                 }
 
-                result = socket.recv_from(&mut buf) => {
-                    match result {
-                        Ok((size, client_addr)) => {
-                            tracing::debug!("Received {} bytes from {}", size, client_addr);
-
-                            if size > self.conn.datagram_send_buffer_space() {
-                                tracing::warn!("Packet too large for tunnel: {} bytes", size);
-                                continue;
-                            }
-
-                            if let Err(e) = tunnel_stream.write_all(&buf[..size]).await {
-                                tracing::error!("Failed to send UDP packet through tunnel: {}", e);
-                                break;
-                            }
+                result = udp::recv_batch(&socket, &self.buffers, &mut batch) => {
+                    result?;
+                    for packet in &batch {
+                        if !filter.allows(&packet.from.ip()) {
+                            tracing::warn!("Dropped UDP packet from {}", packet.from);
+                            continue;
                         }
-                        Err(e) => {
-                            tracing::error!("Error receiving UDP packet: {}", e);
-                            break;
+
+                        peers.send(packet.from, packet.payload());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn forward_unframed_udp(&self, socket: UdpSocket, filter: &SourceFilter) -> Result<()> {
+        let mut tunnel_stream = self.conn.open_uni().await?;
+        let mut batch = Vec::new();
+
+        'forward: loop {
+            tokio::select! {
+                _ = self.conn.closed() => {
+                    tracing::debug!("UDP tunnel connection closed");
+                    break;
+                }
+
+                result = udp::recv_batch(&socket, &self.buffers, &mut batch) => {
+                    if let Err(e) = result {
+                        tracing::error!("Error receiving UDP packet: {}", e);
+                        break;
+                    }
+
+                    for packet in &batch {
+                        if !filter.allows(&packet.from.ip()) {
+                            tracing::warn!("Dropped UDP packet from {}", packet.from);
+                            continue;
+                        }
+
+                        let size = packet.payload().len();
+                        tracing::debug!("Received {} bytes from {}", size, packet.from);
+
+                        if let Err(e) = tunnel_stream.write_all(packet.payload()).await {
+                            tracing::error!("Failed to send UDP packet through tunnel: {}", e);
+                            break 'forward;
                         }
+                        self.stats.record(0, size as u64);
                     }
                 }
             }
@@ -170,25 +602,230 @@ impl TunnelConnection {
     }
 }
 
+/// Bytes carried by a tunnel, updated as data flows.
+#[derive(Debug, Default)]
+pub struct TrafficStats {
+    /// Bytes received from the tunnel and written to the local socket
+    pub bytes_in: AtomicU64,
+    /// Bytes read from the local socket and sent through the tunnel
+    pub bytes_out: AtomicU64,
+    limit: Option<RateLimit>,
+}
+
+impl TrafficStats {
+    /// Stats of a tunnel that may carry at most `bytes_per_sec`, `None` for no cap.
+    pub fn limited(bytes_per_sec: Option<u64>) -> Self {
+        Self {
+            limit: bytes_per_sec.map(RateLimit::new),
+            ..Default::default()
+        }
+    }
+
+    /// Waits until the tunnel may carry `bytes` more, right away when it has no cap.
+    pub async fn throttle(&self, bytes: u64) {
+        if let Some(limit) = &self.limit {
+            limit.acquire(bytes).await;
+        }
+    }
+
+    pub fn record(&self, bytes_in: u64, bytes_out: u64) {
+        self.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+    }
+
+    pub fn totals(&self) -> (u64, u64) {
+        (
+            self.bytes_in.load(Ordering::Relaxed),
+            self.bytes_out.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Resolves once no byte went through in either direction for `idle`.
+    pub async fn wait_idle(&self, idle: Duration) {
+        Self::wait_all_idle(&[self], idle).await
+    }
+
+    /// Resolves once no byte went through any of `stats` for `idle`.
+    pub async fn wait_all_idle(stats: &[&TrafficStats], idle: Duration) {
+        let totals = || stats.iter().map(|stats| stats.totals()).collect::<Vec<_>>();
+        let interval = idle.min(Duration::from_secs(1));
+        let mut last = totals();
+        let mut quiet_since = tokio::time::Instant::now();
+        loop {
+            tokio::time::sleep(interval).await;
+            let totals = totals();
+            if totals != last {
+                last = totals;
+                quiet_since = tokio::time::Instant::now();
+            } else if quiet_since.elapsed() >= idle {
+                return;
+            }
+        }
+    }
+}
+
+/// Where a stream of a multiplexed connection goes, as the server allowed it.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub protocol: Protocol,
+    pub target: SocketAddr,
+    pub qos: Qos,
+    pub proxy_header: Option<Bytes>,
+    /// Rank of the stream's replies against the other mappings of the connection
+    pub stream_priority: i32,
+}
+
+/// Checks the request at the start of each stream of a multiplexed connection against the
+/// server's policy, the way the connection's own handshake was.
+pub trait StreamRouter: Send + Sync {
+    fn route(&self, request: Handshake) -> BoxFuture<Result<Route>>;
+}
+
+/// How the server reaches the target of a TCP stream.
+struct TcpDial {
+    addr: SocketAddr,
+    qos: Qos,
+    settings: TcpSettings,
+    proxy_header: Option<Bytes>,
+    /// How long a refusing target is dialed again before the stream fails
+    wait: Duration,
+}
+
 pub struct ConnectionHandler {
-    port: u16,
+    target: SocketAddr,
     protocol: Protocol,
+    udp_mode: Option<UdpMode>,
+    proxy_header: Option<Bytes>,
+    /// How long a refusing target is dialed again before the stream fails
+    dial_wait: Duration,
+    buffers: Arc<BufferPool>,
+    stats: Arc<TrafficStats>,
+    /// Streams bridged at once, a new stream being accepted once there is room for it
+    streams: StreamLimit,
+    /// Marking of the sockets to the target, as asked for by the client
+    qos: Qos,
+    tcp: TcpSettings,
+    /// Picks the target of each stream when the client multiplexes its mappings
+    router: Option<Arc<dyn StreamRouter>>,
 }
 
 impl ConnectionHandler {
     pub fn new(port: u16, protocol: Protocol) -> Self {
-        Self { port, protocol }
+        Self {
+            target: ([127, 0, 0, 1], port).into(),
+            protocol,
+            udp_mode: None,
+            proxy_header: None,
+            dial_wait: Duration::ZERO,
+            buffers: Arc::default(),
+            stats: Arc::default(),
+            streams: StreamLimit::new(DEFAULT_MAX_STREAMS_PER_CONNECTION, None, 0),
+            qos: Qos::default(),
+            tcp: TcpSettings::default(),
+            router: None,
+        }
+    }
+
+    pub fn with_buffers(mut self, buffers: Arc<BufferPool>) -> Self {
+        self.buffers = buffers;
+        self
+    }
+
+    pub fn with_stats(mut self, stats: Arc<TrafficStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Sends `header` to the target before any tunneled data on every TCP stream.
+    pub fn with_proxy_header(mut self, header: Option<Bytes>) -> Self {
+        self.proxy_header = header;
+        self
+    }
+
+    pub fn with_dial_wait(mut self, wait: Duration) -> Self {
+        self.dial_wait = wait;
+        self
+    }
+
+    pub fn with_stream_limit(mut self, limit: StreamLimit) -> Self {
+        self.streams = limit;
+        self
+    }
+
+    pub fn with_target(mut self, target: SocketAddr) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// `None` serves clients that predate UDP mode negotiation.
+    pub fn with_udp_mode(mut self, mode: Option<UdpMode>) -> Self {
+        self.udp_mode = mode;
+        self
+    }
+
+    pub fn with_qos(mut self, qos: Qos) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    /// Options of the TCP connections to the target.
+    pub fn with_tcp_settings(mut self, settings: TcpSettings) -> Self {
+        self.tcp = settings;
+        self
+    }
+
+    /// Routes every stream by its request instead of sending them all to the target.
+    pub fn with_router(mut self, router: Option<Arc<dyn StreamRouter>>) -> Self {
+        self.router = router;
+        self
     }
 
     pub async fn handle_connection(&self, tunnel: TunnelConnection) -> Result<()> {
-        match self.protocol {
-            Protocol::Tcp => self.handle_tcp_tunnel(tunnel).await,
-            Protocol::Udp => self.handle_udp_tunnel(tunnel).await,
+        let span = tunnel.span().clone();
+        self.handle_tunnel(tunnel).instrument(span).await
+    }
+
+    async fn handle_tunnel(&self, tunnel: TunnelConnection) -> Result<()> {
+        if let Some(router) = &self.router {
+            return self.handle_multiplexed_tunnel(&tunnel, router).await;
+        }
+        match (self.protocol, self.udp_mode) {
+            (Protocol::Tcp, _) => self.handle_tcp_tunnel(tunnel).await,
+            (Protocol::Udp, None) => self.handle_udp_tunnel(tunnel).await,
+            (Protocol::Udp, Some(UdpMode::Stream)) => self.handle_framed_udp_tunnel(&tunnel).await,
+            (Protocol::Udp, Some(UdpMode::Datagram)) => {
+                // Clients may fall back to streams for packets too large for a datagram
+                tokio::try_join!(
+                    datagram::forward_to_target(
+                        &tunnel.conn,
+                        self.target,
+                        self.qos,
+                        &self.buffers,
+                        &self.stats
+                    ),
+                    self.handle_framed_udp_tunnel(&tunnel),
+                )?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Waits for room for one more stream, `None` once the connection is closed. Streams past
+    /// the limit stay unaccepted until then, held back by QUIC flow control.
+    async fn stream_slot(&self, conn: &Connection) -> Option<StreamPermit> {
+        tokio::select! {
+            biased;
+            _ = conn.closed() => None,
+            permit = self.streams.acquire() => Some(permit),
         }
     }
 
     async fn handle_tcp_tunnel(&self, tunnel: TunnelConnection) -> Result<()> {
         loop {
+            let Some(permit) = self.stream_slot(&tunnel.conn).await else {
+                tracing::info!("TCP tunnel closed");
+                break;
+            };
             tokio::select! {
                 biased;
 
@@ -200,12 +837,17 @@ impl ConnectionHandler {
                 result = tunnel.conn.accept_bi() => {
                     match result {
                         Ok((send, recv)) => {
-                            let port = self.port;
+                            let dial = self.tcp_dial();
+                            let buffers = Arc::clone(&self.buffers);
+                            let stats = Arc::clone(&self.stats);
+                            let span = stream_span(send.id());
+                            telemetry::stream_opened();
                             tokio::spawn(async move {
-                                if let Err(e) = Self::bridge_tcp_streams(send, recv, port).await {
+                                let _permit = permit;
+                                if let Err(e) = Self::bridge_tcp_streams(send, recv, dial, &buffers, &stats).await {
                                     tracing::error!("Error bridging TCP streams: {}", e);
                                 }
-                            });
+                            }.instrument(span));
                         }
                         Err(e) => {
                             tracing::info!("Connection closed: {}", e);
@@ -220,6 +862,10 @@ impl ConnectionHandler {
 
     async fn handle_udp_tunnel(&self, tunnel: TunnelConnection) -> Result<()> {
         loop {
+            let Some(permit) = self.stream_slot(&tunnel.conn).await else {
+                tracing::info!("UDP tunnel closed");
+                break;
+            };
             tokio::select! {
                 biased;
 
@@ -231,12 +877,18 @@ impl ConnectionHandler {
                 result = tunnel.conn.accept_uni() => {
                     match result {
                         Ok(stream) => {
-                            let port = self.port;
+                            let target = self.target;
+                            let qos = self.qos;
+                            let buffers = Arc::clone(&self.buffers);
+                            let stats = Arc::clone(&self.stats);
+                            let span = stream_span(stream.id());
+                            telemetry::stream_opened();
                             tokio::spawn(async move {
-                                if let Err(e) = Self::forward_udp_packets(stream, port).await {
+                                let _permit = permit;
+                                if let Err(e) = Self::forward_udp_packets(stream, target, qos, &buffers, &stats).await {
                                     tracing::error!("Error forwarding UDP packets: {}", e);
                                 }
-                            });
+                            }.instrument(span));
                         }
                         Err(e) => {
                             tracing::info!("Connection closed: {}", e);
@@ -249,40 +901,216 @@ impl ConnectionHandler {
         Ok(())
     }
 
+    async fn handle_framed_udp_tunnel(&self, tunnel: &TunnelConnection) -> Result<()> {
+        loop {
+            let Some(permit) = self.stream_slot(&tunnel.conn).await else {
+                tracing::info!("UDP tunnel closed");
+                break;
+            };
+            tokio::select! {
+                biased;
+
+                _ = tunnel.conn.closed() => {
+                    tracing::info!("UDP tunnel closed");
+                    break;
+                }
+
+                result = tunnel.conn.accept_bi() => {
+                    match result {
+                        Ok((send, recv)) => {
+                            let target = self.target;
+                            let qos = self.qos;
+                            let buffers = Arc::clone(&self.buffers);
+                            let stats = Arc::clone(&self.stats);
+                            let span = stream_span(send.id());
+                            telemetry::stream_opened();
+                            tokio::spawn(async move {
+                                let _permit = permit;
+                                if let Err(e) = Self::forward_udp_frames(send, recv, target, qos, &buffers, &stats).await {
+                                    tracing::error!("Error forwarding UDP packets: {}", e);
+                                }
+                            }.instrument(span));
+                        }
+                        Err(e) => {
+                            tracing::info!("Connection closed: {}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_multiplexed_tunnel(
+        &self,
+        tunnel: &TunnelConnection,
+        router: &Arc<dyn StreamRouter>,
+    ) -> Result<()> {
+        loop {
+            let Some(permit) = self.stream_slot(&tunnel.conn).await else {
+                tracing::info!("Multiplexed tunnel closed");
+                break;
+            };
+            tokio::select! {
+                biased;
+
+                _ = tunnel.conn.closed() => {
+                    tracing::info!("Multiplexed tunnel closed");
+                    break;
+                }
+
+                result = tunnel.conn.accept_bi() => {
+                    match result {
+                        Ok((send, recv)) => {
+                            let router = Arc::clone(router);
+                            let dial = self.tcp_dial();
+                            let buffers = Arc::clone(&self.buffers);
+                            let stats = Arc::clone(&self.stats);
+                            let span = stream_span(send.id());
+                            telemetry::stream_opened();
+                            tokio::spawn(async move {
+                                let _permit = permit;
+                                if let Err(e) = Self::route_stream(send, recv, router, dial, &buffers, &stats).await {
+                                    tracing::error!("Error bridging multiplexed stream: {}", e);
+                                }
+                            }.instrument(span));
+                        }
+                        Err(e) => {
+                            tracing::info!("Connection closed: {}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the request at the start of the stream and bridges it with the target it names,
+    /// resetting the stream if the server doesn't allow it. `dial` holds the settings of TCP
+    /// targets.
+    async fn route_stream(
+        mut send: SendStream,
+        mut recv: RecvStream,
+        router: Arc<dyn StreamRouter>,
+        dial: TcpDial,
+        buffers: &Arc<BufferPool>,
+        stats: &TrafficStats,
+    ) -> Result<()> {
+        let header =
+            tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake::read_stream_header(&mut recv));
+        let (route, reason) = match header.await {
+            Ok(Ok(request)) => (router.route(request).await, ResetReason::Forbidden),
+            Ok(Err(e)) => (Err(e), ResetReason::Aborted),
+            Err(_) => (
+                Err(crate::error!(
+                    "No stream header after {:?}",
+                    HANDSHAKE_TIMEOUT
+                )),
+                ResetReason::Aborted,
+            ),
+        };
+        let route = match route {
+            Ok(route) => route,
+            Err(e) => {
+                send.reset((&reason).into()).ok();
+                recv.stop((&reason).into()).ok();
+                return Err(e);
+            }
+        };
+
+        tracing::debug!("Stream routed to {} ({})", route.target, route.protocol);
+        send.set_priority(route.stream_priority).ok();
+        match route.protocol {
+            Protocol::Tcp => {
+                let dial = TcpDial {
+                    addr: route.target,
+                    qos: route.qos,
+                    proxy_header: route.proxy_header,
+                    ..dial
+                };
+                Self::bridge_tcp_streams(send, recv, dial, buffers, stats).await
+            }
+            Protocol::Udp => {
+                Self::forward_udp_frames(send, recv, route.target, route.qos, buffers, stats).await
+            }
+        }
+    }
+
+    fn tcp_dial(&self) -> TcpDial {
+        TcpDial {
+            addr: self.target,
+            qos: self.qos,
+            settings: self.tcp,
+            proxy_header: self.proxy_header.clone(),
+            wait: self.dial_wait,
+        }
+    }
+
     async fn bridge_tcp_streams(
-        send: impl AsyncWrite + Unpin,
-        recv: impl AsyncRead + Unpin,
-        port: u16,
+        mut send: SendStream,
+        mut recv: RecvStream,
+        dial: TcpDial,
+        buffers: &Arc<BufferPool>,
+        stats: &TrafficStats,
     ) -> Result<()> {
-        let addr: SocketAddr = ([127, 0, 0, 1], port).into();
-        let mut local_stream = TcpStream::connect(addr).await?;
-        let mut tunnel_stream = tokio::io::join(recv, send);
+        let TcpDial {
+            addr,
+            qos,
+            settings,
+            proxy_header,
+            wait,
+        } = dial;
+        let mut local_stream = match net::connect_tcp(addr, wait).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                // Dropping the streams would look like the service closed the connection
+                let reason = ResetReason::from_dial_error(&e);
+                send.reset((&reason).into()).ok();
+                recv.stop((&reason).into()).ok();
+                return Err(e.into());
+            }
+        };
+        qos.apply(SockRef::from(&local_stream));
+        net::configure_tcp(&local_stream, &settings);
+        if let Some(header) = proxy_header {
+            local_stream.write_all(&header).await?;
+        }
 
-        tokio::io::copy_bidirectional(&mut tunnel_stream, &mut local_stream).await?;
+        let result = buffer::bridge((recv, send), local_stream.split(), buffers, stats).await;
+        if result.is_err() {
+            net::abort_on_drop(&local_stream);
+        }
+        result?;
 
-        tracing::info!("TCP stream for port {} closed", port);
+        tracing::info!("TCP stream for {} closed", addr);
         Ok(())
     }
 
     async fn forward_udp_packets(
         mut tunnel_stream: impl AsyncRead + Unpin,
-        port: u16,
+        addr: SocketAddr,
+        qos: Qos,
+        buffers: &Arc<BufferPool>,
+        stats: &TrafficStats,
     ) -> Result<()> {
-        let socket = UdpSocket::bind("127.0.0.1:0").await?;
-        let addr: SocketAddr = ([127, 0, 0, 1], port).into();
-        socket.connect(addr).await?;
+        let socket = net::connect_udp_socket(addr).await?;
+        socket.set_qos(qos);
 
-        let mut buf = vec![0u8; 65536];
+        let mut buf = buffers.get();
 
         loop {
             match tunnel_stream.read(&mut buf).await {
                 Ok(0) => break,
                 Ok(size) => {
-                    tracing::debug!("Forwarding {} bytes to UDP port {}", size, port);
+                    tracing::debug!("Forwarding {} bytes to UDP {}", size, addr);
+                    stats.throttle(size as u64).await;
                     if let Err(e) = socket.send(&buf[..size]).await {
                         tracing::error!("Failed to send UDP packet: {}", e);
                         break;
                     }
+                    stats.record(size as u64, 0);
                 }
                 Err(e) => {
                     tracing::error!("Error reading from tunnel: {}", e);
@@ -291,17 +1119,70 @@ impl ConnectionHandler {
             }
         }
 
-        tracing::info!("UDP stream for port {} closed", port);
+        tracing::info!("UDP stream for {} closed", addr);
+        Ok(())
+    }
+
+    /// Forwards the frames of one client peer to `addr`, framing the replies back.
+    async fn forward_udp_frames(
+        mut send: impl AsyncWrite + Unpin,
+        mut recv: impl AsyncRead + Unpin,
+        addr: SocketAddr,
+        qos: Qos,
+        buffers: &Arc<BufferPool>,
+        stats: &TrafficStats,
+    ) -> Result<()> {
+        let socket = net::connect_udp_socket(addr).await?;
+        socket.set_qos(qos);
+
+        let requests = async {
+            let mut buf = buffers.get();
+            while let Some(size) = framing::read_frame(&mut recv, &mut buf).await? {
+                stats.throttle(size as u64).await;
+                if let Err(e) = socket.send(&buf[..size]).await {
+                    tracing::debug!("Failed to send UDP packet to {}: {}", addr, e);
+                    continue;
+                }
+                stats.record(size as u64, 0);
+            }
+            Ok::<_, std::io::Error>(())
+        };
+
+        let replies = async {
+            let mut batch = Vec::new();
+            loop {
+                socket.recv_batch(buffers, &mut batch).await?;
+                for packet in &batch {
+                    let size = packet.payload().len();
+                    stats.throttle(size as u64).await;
+                    framing::write_frame(&mut send, packet.payload()).await?;
+                    stats.record(0, size as u64);
+                }
+            }
+        };
+
+        tokio::select! {
+            result = requests => result?,
+            result = replies => {
+                let result: std::io::Result<()> = result;
+                result?
+            }
+        }
+
+        tracing::info!("UDP stream for {} closed", addr);
         Ok(())
     }
 
     pub async fn handle_bidirectional_stream(
         &self,
-        send: impl AsyncWrite + Unpin,
-        recv: impl AsyncRead + Unpin,
+        send: SendStream,
+        recv: RecvStream,
     ) -> Result<()> {
         match self.protocol {
-            Protocol::Tcp => Self::bridge_tcp_streams(send, recv, self.port).await,
+            Protocol::Tcp => {
+                Self::bridge_tcp_streams(send, recv, self.tcp_dial(), &self.buffers, &self.stats)
+                    .await
+            }
             Protocol::Udp => Err(crate::error!("Bidirectional UDP streams are not supported")),
         }
     }
@@ -311,7 +1192,10 @@ impl ConnectionHandler {
             Protocol::Tcp => Err(crate::error!(
                 "Unidirectional TCP streams are not supported"
             )),
-            Protocol::Udp => Self::forward_udp_packets(stream, self.port).await,
+            Protocol::Udp => {
+                Self::forward_udp_packets(stream, self.target, self.qos, &self.buffers, &self.stats)
+                    .await
+            }
         }
     }
 }
@@ -0,0 +1,251 @@
+use crate::Result;
+use crate::core::{buffer::BufferPool, qos::Qos, udp};
+use crate::utils::backoff::Backoff;
+use crate::utils::config::TcpSettings;
+use crate::utils::constants::{DIAL_RETRY_INITIAL_DELAY, DIAL_RETRY_MAX_DELAY};
+use socket2::{Domain, Protocol as SocketProtocol, SockAddr, SockRef, Socket, TcpKeepalive, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+/// Binding to the unspecified IPv6 address (`[::]`) also accepts IPv4 clients.
+fn new_socket(addr: SocketAddr, ty: Type, protocol: SocketProtocol) -> Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, Some(protocol))?;
+
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+pub fn bind_tcp_listener(addr: SocketAddr) -> Result<TcpListener> {
+    listen_tcp(addr, false)
+}
+
+/// Binds `count` listeners sharing `addr` through `SO_REUSEPORT`, the kernel spreading
+/// incoming connections over them. Other platforms than Linux get a single listener.
+pub fn bind_tcp_listeners(addr: SocketAddr, count: usize) -> Result<Vec<TcpListener>> {
+    if !cfg!(target_os = "linux") || count <= 1 {
+        return Ok(vec![bind_tcp_listener(addr)?]);
+    }
+
+    let mut addr = addr;
+    let mut listeners = Vec::with_capacity(count);
+    for _ in 0..count {
+        let listener = listen_tcp(addr, true)?;
+        // The others join the port picked for a port of 0
+        addr = listener.local_addr()?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+fn listen_tcp(addr: SocketAddr, reuse_port: bool) -> Result<TcpListener> {
+    let socket = new_socket(addr, Type::STREAM, SocketProtocol::TCP)?;
+    socket.set_reuse_address(true)?;
+    #[cfg(target_os = "linux")]
+    socket.set_reuse_port(reuse_port)?;
+    #[cfg(not(target_os = "linux"))]
+    let _ = reuse_port;
+    socket.bind(&SockAddr::from(addr))?;
+    socket.listen(1024)?;
+
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Binding to a multicast group joins it, taking in what local apps send to the group.
+pub fn bind_udp_socket(addr: SocketAddr) -> Result<UdpSocket> {
+    if addr.ip().is_multicast() {
+        let socket = join_group(addr)?;
+        socket.bind(&SockAddr::from(unspecified(addr)))?;
+        return Ok(UdpSocket::from_std(socket.into())?);
+    }
+
+    let socket = new_socket(addr, Type::DGRAM, SocketProtocol::UDP)?;
+    socket.bind(&SockAddr::from(addr))?;
+
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// The socket a UDP tunnel reaches its target from. A multicast group or the broadcast
+/// address is answered by other hosts than the one sent to, so the socket isn't connected
+/// then and takes packets from anyone.
+#[derive(Debug)]
+pub struct TargetSocket {
+    socket: UdpSocket,
+    target: SocketAddr,
+    connected: bool,
+}
+
+impl TargetSocket {
+    pub async fn send(&self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.connected {
+            self.socket.send(buf).await
+        } else {
+            self.socket.send_to(buf, self.target).await
+        }
+    }
+
+    pub fn set_qos(&self, qos: Qos) {
+        qos.apply(SockRef::from(&self.socket));
+    }
+
+    /// Takes in the replies already queued, see [`udp::recv_batch`].
+    pub async fn recv_batch(
+        &self,
+        buffers: &Arc<BufferPool>,
+        batch: &mut Vec<udp::Packet>,
+    ) -> std::io::Result<()> {
+        udp::recv_batch(&self.socket, buffers, batch).await
+    }
+
+    /// Sends `payloads` to the target, see [`udp::send_batch`].
+    pub async fn send_batch(&self, payloads: &[&[u8]]) -> std::io::Result<u64> {
+        let destination = (!self.connected).then_some(self.target);
+        let packets: Vec<_> = payloads
+            .iter()
+            .map(|payload| (*payload, destination))
+            .collect();
+        udp::send_batch(&self.socket, &packets).await
+    }
+}
+
+/// Binds an ephemeral UDP socket of the same address family as `target` and connects it,
+/// unless `target` is a multicast group, which is joined, or the broadcast address.
+pub async fn connect_udp_socket(target: SocketAddr) -> Result<TargetSocket> {
+    let ip = target.ip();
+    if ip.is_multicast() {
+        let socket = join_group(target)?;
+        // On the group's port, for what its members announce besides their replies
+        if let Err(e) = socket.bind(&SockAddr::from(unspecified(target))) {
+            tracing::debug!(
+                "Port {} is taken, only getting replies: {}",
+                target.port(),
+                e
+            );
+            socket.bind(&SockAddr::from(SocketAddr::new(
+                unspecified(target).ip(),
+                0,
+            )))?;
+        }
+        // Our own packets would come back as replies otherwise
+        match ip {
+            IpAddr::V4(_) => socket.set_multicast_loop_v4(false)?,
+            IpAddr::V6(_) => socket.set_multicast_loop_v6(false)?,
+        }
+        return Ok(TargetSocket {
+            socket: UdpSocket::from_std(socket.into())?,
+            target,
+            connected: false,
+        });
+    }
+
+    let socket = UdpSocket::bind(SocketAddr::new(unspecified(target).ip(), 0)).await?;
+    if matches!(ip, IpAddr::V4(ip) if ip.is_broadcast()) {
+        socket.set_broadcast(true)?;
+        return Ok(TargetSocket {
+            socket,
+            target,
+            connected: false,
+        });
+    }
+    socket.connect(target).await?;
+
+    Ok(TargetSocket {
+        socket,
+        target,
+        connected: true,
+    })
+}
+
+/// An unbound socket member of the group `addr`, which may share its port with others.
+fn join_group(addr: SocketAddr) -> Result<Socket> {
+    let socket = new_socket(unspecified(addr), Type::DGRAM, SocketProtocol::UDP)?;
+    socket.set_reuse_address(true)?;
+    match addr.ip() {
+        IpAddr::V4(group) => socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?,
+        IpAddr::V6(group) => socket.join_multicast_v6(&group, 0)?,
+    }
+    Ok(socket)
+}
+
+/// The unspecified address of the family of `addr`, on its port.
+fn unspecified(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), addr.port()),
+        SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), addr.port()),
+    }
+}
+
+/// Connects to `addr`, retrying with backoff for up to `wait` while it refuses connections,
+/// so that a service that is restarting or not up yet doesn't fail the stream.
+pub async fn connect_tcp(addr: SocketAddr, wait: Duration) -> std::io::Result<TcpStream> {
+    let mut backoff = Backoff::new(DIAL_RETRY_INITIAL_DELAY, DIAL_RETRY_MAX_DELAY, wait);
+    loop {
+        match TcpStream::connect(addr).await {
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                let Some(delay) = backoff.next_delay() else {
+                    return Err(e);
+                };
+                if backoff.retries() == 1 {
+                    tracing::info!("{} refused the connection, waiting up to {:?}", addr, wait);
+                }
+                tokio::time::sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Applies the `[network.tcp]` options to a local connection. The tunnel works with the
+/// system's defaults, so failures are only logged.
+pub fn configure_tcp(stream: &TcpStream, settings: &TcpSettings) {
+    if let Some(nodelay) = settings.nodelay
+        && let Err(e) = stream.set_nodelay(nodelay)
+    {
+        tracing::warn!("Failed to set TCP_NODELAY: {}", e);
+    }
+
+    let socket = SockRef::from(stream);
+    if settings.keepalive() {
+        let mut keepalive = TcpKeepalive::new();
+        if let Some(secs) = settings.keepalive_time {
+            keepalive = keepalive.with_time(Duration::from_secs(secs));
+        }
+        if let Some(secs) = settings.keepalive_interval {
+            keepalive = keepalive.with_interval(Duration::from_secs(secs));
+        }
+        #[cfg(not(windows))]
+        if let Some(retries) = settings.keepalive_retries {
+            keepalive = keepalive.with_retries(retries);
+        }
+        if let Err(e) = socket.set_tcp_keepalive(&keepalive) {
+            tracing::warn!("Failed to enable TCP keepalive: {}", e);
+        }
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    if let Some(ms) = settings.user_timeout_ms
+        && let Err(e) = socket.set_tcp_user_timeout(Some(Duration::from_millis(ms)))
+    {
+        tracing::warn!("Failed to set TCP_USER_TIMEOUT: {}", e);
+    }
+}
+
+/// Makes the socket send a reset instead of a FIN when dropped, so that the other end
+/// doesn't take a failed transfer for a complete one.
+pub fn abort_on_drop(stream: &TcpStream) {
+    if let Err(e) = socket2::SockRef::from(stream).set_linger(Some(Duration::ZERO)) {
+        tracing::debug!("Failed to make the socket reset on close: {}", e);
+    }
+}
+
+/// Strips the brackets around an IPv6 literal, as written in URLs and mappings.
+pub fn unbracket(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host)
+}
@@ -0,0 +1,88 @@
+use crate::Result;
+use iroh::{Endpoint, net_report::Report};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Waits for the endpoint's first report on the network conditions.
+pub async fn report(endpoint: &Endpoint, timeout: Duration) -> Result<Arc<Report>> {
+    let mut watcher = endpoint.net_report();
+    tokio::time::timeout(timeout, watcher.initialized())
+        .await
+        .map_err(|_| crate::error!("No network report after {:?}", timeout))?
+        .map_err(|e| crate::error!("Network report unavailable: {}", e))
+}
+
+/// How the NAT maps our outgoing UDP packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatMapping {
+    /// The same public address for every destination, hole punching works
+    EndpointIndependent,
+    /// A new public address per destination, hole punching mostly fails
+    Symmetric,
+    Unknown,
+}
+
+impl NatMapping {
+    pub fn from_report(report: &Report) -> Self {
+        match report.mapping_varies_by_dest_ip {
+            Some(false) => NatMapping::EndpointIndependent,
+            Some(true) => NatMapping::Symmetric,
+            None => NatMapping::Unknown,
+        }
+    }
+}
+
+impl std::fmt::Display for NatMapping {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NatMapping::EndpointIndependent => write!(f, "endpoint-independent"),
+            NatMapping::Symmetric => write!(f, "varies by destination (symmetric)"),
+            NatMapping::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Something the report tells about the connections to expect.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Hint {
+    Good(&'static str),
+    Warning(&'static str),
+}
+
+pub fn hints(report: &Report) -> Vec<Hint> {
+    let mut hints = Vec::new();
+
+    if !report.udp {
+        hints.push(Hint::Warning(
+            "UDP seems blocked, all traffic will go through a relay",
+        ));
+    } else {
+        match NatMapping::from_report(report) {
+            NatMapping::Symmetric => hints.push(Hint::Warning(
+                "Symmetric NAT detected, expect relay fallback unless the peer has an open port",
+            )),
+            NatMapping::EndpointIndependent => hints.push(Hint::Good(
+                "NAT allows hole punching, direct connections should work",
+            )),
+            NatMapping::Unknown => {}
+        }
+    }
+
+    if report.hair_pinning == Some(false) {
+        hints.push(Hint::Warning(
+            "No hairpinning, peers behind the same NAT will reach each other through local addresses only",
+        ));
+    }
+    if report.relay_latency.iter().next().is_none() {
+        hints.push(Hint::Warning(
+            "No relay reachable, connections will fail unless a direct path exists",
+        ));
+    }
+    if report.captive_portal == Some(true) {
+        hints.push(Hint::Warning(
+            "Captive portal detected, log in to the network before connecting",
+        ));
+    }
+
+    hints
+}
@@ -0,0 +1,45 @@
+use iroh::endpoint::Connection;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Parallel connections to the same peer that new streams are spread over.
+///
+/// Each connection gets its own flow control window and congestion controller, which lets
+/// a fast link carry more than a single connection would allow.
+#[derive(Debug)]
+pub struct ConnectionPool {
+    connections: Vec<Connection>,
+    next: AtomicUsize,
+}
+
+impl ConnectionPool {
+    pub fn new(primary: Connection) -> Self {
+        Self {
+            connections: vec![primary],
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn with_connections(mut self, connections: impl IntoIterator<Item = Connection>) -> Self {
+        self.connections.extend(connections);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    /// Takes the connections in turn, skipping closed ones. Falls back to the first one,
+    /// whose errors then surface to the caller.
+    pub fn pick(&self) -> &Connection {
+        let count = self.connections.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..count)
+            .map(|offset| &self.connections[(start + offset) % count])
+            .find(|conn| conn.close_reason().is_none())
+            .unwrap_or(&self.connections[0])
+    }
+}
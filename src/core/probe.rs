@@ -0,0 +1,203 @@
+use crate::core::net;
+use crate::utils::config::{AuthorizationManager, ConfigCache, ServerConfig};
+use crate::utils::constants::{DEFAULT_TARGET_HOST, HANDSHAKE_TIMEOUT, PROBE_ALPN, PROBE_TIMEOUT};
+use crate::{CloseReason, PunchError, Result};
+use iroh::{
+    Endpoint, NodeId,
+    endpoint::{Connection, ConnectionError, RecvStream, SendStream},
+    protocol::ProtocolHandler,
+};
+use n0_future::boxed::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Largest probe request a server reads
+const MAX_PROBE_REQUEST_SIZE: usize = 1024;
+
+/// Whether something listens on a port, as seen from the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ProbeStatus {
+    /// The connection was accepted
+    Open = 0,
+    /// The connection was refused, nothing listens on the port
+    Closed = 1,
+    /// No answer in time or the host is unreachable, e.g. because of a firewall
+    Filtered = 2,
+    /// The server wouldn't forward this port or host to us anyway
+    Forbidden = 3,
+}
+
+impl TryFrom<u8> for ProbeStatus {
+    type Error = PunchError;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(ProbeStatus::Open),
+            1 => Ok(ProbeStatus::Closed),
+            2 => Ok(ProbeStatus::Filtered),
+            3 => Ok(ProbeStatus::Forbidden),
+            _ => Err(crate::error!("Unknown probe status: {}", value)),
+        }
+    }
+}
+
+impl std::fmt::Display for ProbeStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProbeStatus::Open => write!(f, "open"),
+            ProbeStatus::Closed => write!(f, "closed"),
+            ProbeStatus::Filtered => write!(f, "filtered"),
+            ProbeStatus::Forbidden => write!(f, "not allowed"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ProbeRequest {
+    port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    host: Option<String>,
+}
+
+/// Asks `node_id` whether `port` on `host` (its loopback interface by default) accepts TCP
+/// connections.
+pub async fn probe(
+    endpoint: &Endpoint,
+    node_id: NodeId,
+    port: u16,
+    host: Option<String>,
+) -> Result<ProbeStatus> {
+    let conn = endpoint.connect(node_id, PROBE_ALPN).await?;
+    let (mut send, mut recv) = conn.open_bi().await?;
+
+    let request =
+        serde_json::to_vec(&ProbeRequest { port, host }).map_err(|e| crate::error!("{}", e))?;
+    AsyncWriteExt::write_all(&mut send, &request).await?;
+    send.finish()
+        .map_err(|e| crate::error!("Failed to finish stream: {}", e))?;
+
+    let status = recv
+        .read_u8()
+        .await
+        .map_err(|e| match conn.close_reason() {
+            Some(ConnectionError::ApplicationClosed(close)) => PunchError::from(&close),
+            _ => e.into(),
+        })?;
+    conn.close(0u8.into(), b"done");
+    ProbeStatus::try_from(status)
+}
+
+/// Tries the ports authorized peers ask about, within the limits of their tunnels.
+#[derive(Debug, Clone)]
+pub struct ProbeService {
+    config: Arc<ConfigCache<ServerConfig>>,
+    auth_manager: Arc<AuthorizationManager>,
+}
+
+impl ProbeService {
+    pub fn new(
+        config: Arc<ConfigCache<ServerConfig>>,
+        auth_manager: Arc<AuthorizationManager>,
+    ) -> Self {
+        Self {
+            config,
+            auth_manager,
+        }
+    }
+
+    async fn handle(
+        &self,
+        node_id: NodeId,
+        mut send: SendStream,
+        mut recv: RecvStream,
+    ) -> Result<()> {
+        let request =
+            tokio::time::timeout(HANDSHAKE_TIMEOUT, recv.read_to_end(MAX_PROBE_REQUEST_SIZE))
+                .await
+                .map_err(|_| crate::error!("Timed out reading probe request"))?
+                .map_err(|e| crate::error!("Failed to read probe request: {}", e))?;
+        let request: ProbeRequest = serde_json::from_slice(&request)
+            .map_err(|e| crate::error!("Invalid probe request: {}", e))?;
+
+        let status = self.probe(&node_id, &request).await?;
+        tracing::debug!(
+            "Probe of port {} by {}: {}",
+            request.port,
+            node_id.fmt_short(),
+            status
+        );
+        send.write_u8(status as u8).await?;
+        send.finish()
+            .map_err(|e| crate::error!("Failed to finish stream: {}", e))?;
+        send.stopped().await.ok();
+        Ok(())
+    }
+
+    async fn probe(&self, node_id: &NodeId, request: &ProbeRequest) -> Result<ProbeStatus> {
+        // Held to the same rules as a tunnel, so probing reveals nothing a tunnel wouldn't
+        if !self.config.get().is_port_allowed(node_id, request.port) {
+            return Ok(ProbeStatus::Forbidden);
+        }
+        let host = net::unbracket(request.host.as_deref().unwrap_or(DEFAULT_TARGET_HOST));
+        let addrs: Vec<SocketAddr> = match tokio::net::lookup_host((host, request.port)).await {
+            Ok(addrs) => addrs.collect(),
+            Err(_) => Vec::new(),
+        };
+        let ips: Vec<IpAddr> = addrs.iter().map(SocketAddr::ip).collect();
+        let Some(target) = addrs.first() else {
+            return Ok(ProbeStatus::Forbidden);
+        };
+        if !self
+            .auth_manager
+            .is_target_allowed(node_id, host, &ips)
+            .await?
+        {
+            return Ok(ProbeStatus::Forbidden);
+        }
+
+        let connect = tokio::net::TcpStream::connect(target);
+        Ok(match tokio::time::timeout(PROBE_TIMEOUT, connect).await {
+            Ok(Ok(_)) => ProbeStatus::Open,
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => ProbeStatus::Closed,
+            Ok(Err(_)) | Err(_) => ProbeStatus::Filtered,
+        })
+    }
+}
+
+impl ProtocolHandler for ProbeService {
+    fn on_connecting(
+        &self,
+        connecting: iroh::endpoint::Connecting,
+    ) -> BoxFuture<anyhow::Result<Connection>> {
+        let auth_manager = Arc::clone(&self.auth_manager);
+
+        Box::pin(async move {
+            let conn = connecting.await?;
+            let node_id = conn.remote_node_id()?;
+
+            if !auth_manager.is_authorized(&node_id).await? {
+                CloseReason::Unauthorized.execute(&conn);
+                anyhow::bail!("Unauthorized probe from {}", node_id);
+            }
+
+            Ok(conn)
+        })
+    }
+
+    fn accept(&self, conn: Connection) -> BoxFuture<anyhow::Result<()>> {
+        let service = self.clone();
+
+        Box::pin(async move {
+            let node_id = conn.remote_node_id()?;
+            let (send, recv) = tokio::time::timeout(HANDSHAKE_TIMEOUT, conn.accept_bi()).await??;
+
+            if let Err(e) = service.handle(node_id, send, recv).await {
+                tracing::debug!("Probe from {} failed: {}", node_id, e);
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,58 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use iroh::NodeId;
+use std::net::SocketAddr;
+
+const SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const VERSION_PROXY: u8 = 0x21;
+
+const FAMILY_UNSPEC: u8 = 0x00;
+const FAMILY_TCP4: u8 = 0x11;
+const FAMILY_TCP6: u8 = 0x21;
+
+/// Custom TLV carrying the hex-encoded Node ID of the tunnel client.
+pub const TLV_NODE_ID: u8 = 0xE0;
+
+/// Builds a PROXY protocol v2 header for a TCP stream forwarded on behalf of `node_id`.
+///
+/// `source` is the peer's direct address when known; relayed peers have no meaningful
+/// address, so the header then uses the `UNSPEC` family and only carries the Node ID.
+pub fn v2_header(node_id: &NodeId, source: Option<SocketAddr>, destination: SocketAddr) -> Bytes {
+    let mut addresses = BytesMut::new();
+    let family = match (source, destination) {
+        (Some(SocketAddr::V4(src)), SocketAddr::V4(dst)) => {
+            addresses.put_slice(&src.ip().octets());
+            addresses.put_slice(&dst.ip().octets());
+            addresses.put_u16(src.port());
+            addresses.put_u16(dst.port());
+            FAMILY_TCP4
+        }
+        (Some(src), dst) if src.is_ipv6() || dst.is_ipv6() => {
+            addresses.put_slice(&to_ipv6(src).octets());
+            addresses.put_slice(&to_ipv6(dst).octets());
+            addresses.put_u16(src.port());
+            addresses.put_u16(dst.port());
+            FAMILY_TCP6
+        }
+        _ => FAMILY_UNSPEC,
+    };
+
+    let node_id = node_id.to_string();
+    let mut header = BytesMut::with_capacity(16 + addresses.len() + 3 + node_id.len());
+    header.put_slice(SIGNATURE);
+    header.put_u8(VERSION_PROXY);
+    header.put_u8(family);
+    header.put_u16((addresses.len() + 3 + node_id.len()) as u16);
+    header.put_slice(&addresses);
+    header.put_u8(TLV_NODE_ID);
+    header.put_u16(node_id.len() as u16);
+    header.put_slice(node_id.as_bytes());
+
+    header.freeze()
+}
+
+fn to_ipv6(addr: SocketAddr) -> std::net::Ipv6Addr {
+    match addr {
+        SocketAddr::V4(addr) => addr.ip().to_ipv6_mapped(),
+        SocketAddr::V6(addr) => *addr.ip(),
+    }
+}
@@ -0,0 +1,159 @@
+use serde::{Deserialize, Deserializer};
+use socket2::SockRef;
+use std::fmt;
+use std::str::FromStr;
+
+/// A DiffServ code point, given as a number up to 63 or a name such as `ef` (expedited
+/// forwarding, for VoIP), `af41` or `cs6`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Dscp(u8);
+
+impl Dscp {
+    pub fn new(value: u8) -> Option<Self> {
+        (value < 64).then_some(Self(value))
+    }
+
+    pub fn value(self) -> u8 {
+        self.0
+    }
+}
+
+impl FromStr for Dscp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.to_ascii_lowercase();
+        let value = match name.as_str() {
+            "be" | "default" => Some(0),
+            "ef" => Some(46),
+            "va" => Some(44),
+            _ => {
+                if let Some(class) = name.strip_prefix("cs") {
+                    class.parse::<u8>().ok().filter(|&c| c < 8).map(|c| c << 3)
+                } else if let Some(class) = name.strip_prefix("af") {
+                    match class.as_bytes() {
+                        &[c @ b'1'..=b'4', d @ b'1'..=b'3'] => {
+                            Some(((c - b'0') << 3) | ((d - b'0') << 1))
+                        }
+                        _ => None,
+                    }
+                } else {
+                    name.parse().ok()
+                }
+            }
+        };
+
+        value
+            .and_then(Self::new)
+            .ok_or_else(|| format!("Invalid DSCP value: {} (expected 0-63, ef, afXY or csN)", s))
+    }
+}
+
+impl fmt::Display for Dscp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Dscp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Value(u8),
+            Name(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Value(value) => Self::new(value)
+                .ok_or_else(|| serde::de::Error::custom(format!("Invalid DSCP value: {}", value))),
+            Raw::Name(name) => name.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// How the packets of a mapping are marked: on the client's local sockets, and on the
+/// server's sockets to the target, which the client asks for in its handshake. The QUIC
+/// socket of the tunnel itself is iroh's and isn't marked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Qos {
+    pub dscp: Option<Dscp>,
+    /// `SO_PRIORITY`, the queue the packets go to on Linux. Values above 6 need
+    /// `CAP_NET_ADMIN`.
+    pub priority: Option<u8>,
+}
+
+impl Qos {
+    /// Takes what `self` leaves unset from `other`.
+    pub fn or(self, other: Qos) -> Qos {
+        Qos {
+            dscp: self.dscp.or(other.dscp),
+            priority: self.priority.or(other.priority),
+        }
+    }
+
+    /// Marks `socket`. The tunnel works unmarked, so failures are only logged.
+    pub fn apply(&self, socket: SockRef<'_>) {
+        if let Some(dscp) = self.dscp
+            && let Err(e) = set_dscp(&socket, dscp)
+        {
+            tracing::warn!("Failed to set DSCP {}: {}", dscp, e);
+        }
+
+        if let Some(priority) = self.priority
+            && let Err(e) = set_priority(&socket, priority)
+        {
+            tracing::warn!("Failed to set socket priority {}: {}", priority, e);
+        }
+    }
+}
+
+/// The TOS byte keeps the DSCP in its upper six bits, the lower two being ECN.
+fn set_dscp(socket: &SockRef<'_>, dscp: Dscp) -> std::io::Result<()> {
+    let tos = u32::from(dscp.value()) << 2;
+    let is_ipv6 = socket
+        .local_addr()?
+        .as_socket()
+        .is_some_and(|addr| addr.is_ipv6());
+    if !is_ipv6 {
+        return socket.set_tos(tos);
+    }
+
+    set_traffic_class(socket, tos)?;
+    // Dual-stack sockets reach IPv4 peers with the IPv4 option
+    socket.set_tos(tos).ok();
+    Ok(())
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos"
+))]
+fn set_traffic_class(socket: &SockRef<'_>, tos: u32) -> std::io::Result<()> {
+    socket.set_tclass_v6(tos)
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos"
+)))]
+fn set_traffic_class(_socket: &SockRef<'_>, _tos: u32) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(target_os = "linux")]
+fn set_priority(socket: &SockRef<'_>, priority: u8) -> std::io::Result<()> {
+    use nix::sys::socket::{setsockopt, sockopt};
+
+    setsockopt(&**socket, sockopt::Priority, &i32::from(priority))?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_priority(_socket: &SockRef<'_>, _priority: u8) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
@@ -0,0 +1,273 @@
+use crate::Result;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Largest request line and headers we read before giving up on a request
+const MAX_REQUEST_HEAD_SIZE: usize = 8192;
+
+/// Serves the files under `root` over plain HTTP/1.1 on `listener`, one request per
+/// connection. Only meant to sit behind a tunnel: there is no TLS, caching or range support.
+pub async fn serve(listener: TcpListener, root: PathBuf) -> Result<()> {
+    let root = Arc::new(
+        tokio::fs::canonicalize(&root)
+            .await
+            .map_err(|e| crate::error!(source = e, "Failed to open {}", root.display()))?,
+    );
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let root = Arc::clone(&root);
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &root).await {
+                tracing::debug!("Request from {} failed: {}", addr, e);
+            }
+        });
+    }
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Body,
+}
+
+enum Body {
+    Text(String),
+    File(tokio::fs::File, u64),
+    Redirect(String),
+}
+
+impl Response {
+    fn text(status: &'static str, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: Body::Text(body.into()),
+        }
+    }
+
+    fn html(body: String) -> Self {
+        Self {
+            status: "200 OK",
+            content_type: "text/html; charset=utf-8",
+            body: Body::Text(body),
+        }
+    }
+
+    async fn write<W: AsyncWrite + Unpin>(self, writer: &mut W, head_only: bool) -> Result<()> {
+        let mut head = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nConnection: close\r\nServer: punch/{}\r\n",
+            self.status,
+            self.content_type,
+            env!("CARGO_PKG_VERSION")
+        );
+        let len = match &self.body {
+            Body::Text(text) => text.len() as u64,
+            Body::File(_, len) => *len,
+            Body::Redirect(location) => {
+                let _ = write!(head, "Location: {}\r\n", location);
+                0
+            }
+        };
+        let _ = write!(head, "Content-Length: {}\r\n\r\n", len);
+        writer.write_all(head.as_bytes()).await?;
+
+        if !head_only {
+            match self.body {
+                Body::Text(text) => writer.write_all(text.as_bytes()).await?,
+                Body::File(mut file, _) => {
+                    tokio::io::copy(&mut file, writer).await?;
+                }
+                Body::Redirect(_) => {}
+            }
+        }
+        writer.flush().await?;
+        Ok(())
+    }
+}
+
+async fn handle(stream: TcpStream, root: &Path) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut head = Vec::new();
+    // Read up to the empty line ending the headers, which we have no use for
+    loop {
+        let start = head.len();
+        let n = (&mut reader)
+            .take((MAX_REQUEST_HEAD_SIZE - start) as u64)
+            .read_until(b'\n', &mut head)
+            .await?;
+        if n == 0 || head.len() >= MAX_REQUEST_HEAD_SIZE {
+            return Ok(());
+        }
+        if head[start..].trim_ascii().is_empty() {
+            break;
+        }
+    }
+
+    let line = String::from_utf8_lossy(head.split(|&b| b == b'\n').next().unwrap_or_default());
+    let mut parts = line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or_default(), parts.next());
+    let head_only = method == "HEAD";
+
+    let response = match (method, target) {
+        ("GET" | "HEAD", Some(target)) => respond(root, target).await,
+        (_, Some(_)) => Response::text("405 Method Not Allowed", "Method not allowed\n"),
+        _ => Response::text("400 Bad Request", "Bad request\n"),
+    };
+    tracing::debug!(
+        "{} {} {}",
+        method,
+        target.unwrap_or_default(),
+        response.status
+    );
+    response.write(reader.get_mut(), head_only).await
+}
+
+async fn respond(root: &Path, target: &str) -> Response {
+    let not_found = || Response::text("404 Not Found", "Not found\n");
+
+    let path = target.split(['?', '#']).next().unwrap_or_default();
+    let Some(decoded) = percent_decode(path) else {
+        return Response::text("400 Bad Request", "Bad request\n");
+    };
+    let mut resolved = root.to_path_buf();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return not_found(),
+            segment => resolved.push(segment),
+        }
+    }
+    // Symbolic links may point anywhere, only follow those staying under the root
+    let Ok(resolved) = tokio::fs::canonicalize(&resolved).await else {
+        return not_found();
+    };
+    if !resolved.starts_with(root) {
+        return not_found();
+    }
+
+    if resolved.is_dir() {
+        if !path.ends_with('/') {
+            return Response {
+                status: "301 Moved Permanently",
+                content_type: "text/plain; charset=utf-8",
+                // Never `//host/`, which browsers take for another site
+                body: Body::Redirect(format!("/{}/", path.trim_start_matches('/'))),
+            };
+        }
+        let index = resolved.join("index.html");
+        if index.is_file() {
+            return file_response(&index).await.unwrap_or_else(not_found);
+        }
+        return match listing(&resolved, &decoded).await {
+            Ok(html) => Response::html(html),
+            Err(_) => not_found(),
+        };
+    }
+    file_response(&resolved).await.unwrap_or_else(not_found)
+}
+
+async fn file_response(path: &Path) -> Option<Response> {
+    let file = tokio::fs::File::open(path).await.ok()?;
+    let len = file.metadata().await.ok()?.len();
+    Some(Response {
+        status: "200 OK",
+        content_type: content_type(path),
+        body: Body::File(file, len),
+    })
+}
+
+/// An HTML index of `dir`, with directories listed first.
+async fn listing(dir: &Path, title: &str) -> std::io::Result<String> {
+    let mut entries = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let is_dir = entry.file_type().await?.is_dir();
+        entries.push((!is_dir, entry.file_name().to_string_lossy().into_owned()));
+    }
+    entries.sort();
+
+    let title = html_escape(title);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title></head>\n\
+         <body><h1>{title}</h1>\n<ul>\n"
+    );
+    if title != "/" {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (is_file, name) in entries {
+        let suffix = if is_file { "" } else { "/" };
+        let _ = writeln!(
+            html,
+            "<li><a href=\"{}{suffix}\">{}{suffix}</a></li>",
+            percent_encode(&name),
+            html_escape(&name)
+        );
+    }
+    html.push_str("</ul></body></html>\n");
+    Ok(html)
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" | "md" | "log" | "toml" | "yaml" | "yml" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "zip" => "application/zip",
+        "gz" | "tgz" => "application/gzip",
+        "mp4" => "video/mp4",
+        "mp3" => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+fn percent_decode(path: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut iter = path.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn percent_encode(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for b in name.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            let _ = write!(encoded, "%{:02X}", b);
+        }
+    }
+    encoded
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
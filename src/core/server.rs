@@ -1,60 +1,288 @@
 use crate::utils::{
-    config::{AuthorizationManager, ConfigManager, ServerConfig},
-    constants::ALPN,
-    reduced_node_id,
+    audit::{AuditEvent, AuditLog, AuditRecord},
+    config::{
+        AuthorizationManager, ConfigCache, ConfigManager, Configuration, Role, ServerConfig,
+        ServiceDefinition,
+    },
+    constants::{
+        ACCESS_ALPN, ALPN, BENCH_ALPN, CONNECTION_EVENTS_CAPACITY, CONNECTION_LIMIT_RETRY_AFTER,
+        DEFAULT_DNS_PORT, DEFAULT_TARGET_HOST, DNS_SERVICE, HANDSHAKE_TIMEOUT,
+        MAX_CLIENT_SOCKET_PRIORITY, PROBE_ALPN, SERVICES_ALPN, SYNC_ALPN,
+    },
+    hooks::{self, HookContext, HookEvent},
+    notifications::{self, NotificationEvent},
+    privileges, reduced_node_id, telemetry,
+    usage::{Usage, UsageLedger},
 };
 use crate::{
-    CloseReason, Result,
-    core::{ConnectionHandler, Protocol, TunnelConnection},
+    CloseDetails, CloseReason, Result,
+    core::{
+        ConnectionHandler, Protocol, Route, StreamRouter, TrafficStats, TunnelConnection, UdpMode,
+        access::AccessService,
+        bench::BenchService,
+        buffer::BufferPool,
+        confirm::Confirmer,
+        control::{
+            ConnectionEvent, ConnectionInfo, ControlHandler, ControlRequest, ControlResponse,
+            ControlServer, HealthReport, HealthStatus,
+        },
+        dns,
+        handshake::{self, Handshake},
+        limit::StreamLimit,
+        net,
+        probe::ProbeService,
+        proxy_protocol,
+        qos::Qos,
+        services::CatalogService,
+        sync::SyncService,
+    },
 };
-use dashmap::DashMap;
+use bytes::Bytes;
+use dashmap::{DashMap, DashSet};
 use iroh::{
     Endpoint, NodeId,
-    endpoint::Connection,
+    endpoint::{Connection, ConnectionType},
     protocol::{ProtocolHandler, Router},
 };
 use n0_future::boxed::BoxFuture;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
 
 #[derive(Clone, Debug)]
 pub struct Server {
-    config_manager: Arc<ConfigManager>,
+    endpoint: Endpoint,
+    config: Arc<ConfigCache<ServerConfig>>,
+    audit_log: Option<Arc<AuditLog>>,
+    usage: Option<Arc<UsageLedger>>,
     auth_manager: Arc<AuthorizationManager>,
-    connections: Arc<DashMap<NodeId, ConnectionState>>,
+    buffers: Arc<BufferPool>,
+    connections: Arc<DashMap<NodeId, HashMap<usize, ConnectionState>>>,
     active_connections: Arc<AtomicUsize>,
+    /// Nodes that had a session since the server started, to tell reconnections apart
+    seen_nodes: Arc<DashSet<NodeId>>,
+    /// Set when new nodes need the operator's approval
+    confirmer: Option<Arc<Confirmer>>,
+    /// Source of the short tunnel IDs shown to the admin
+    next_tunnel_id: Arc<AtomicUsize>,
+    /// Services published by this process on top of those of the config
+    services: Arc<BTreeMap<String, ServiceDefinition>>,
+    /// Tunnels opening and closing, for subscribers of the gRPC control API
+    events: broadcast::Sender<ConnectionEvent>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ServerOptions {
+    /// Ask in the terminal before accepting a node for the first time
+    pub confirm: bool,
+    /// Save approved nodes to the config so they aren't asked about again
+    pub remember: bool,
+    /// Services to publish for as long as the server runs, e.g. by `punch serve`, taking
+    /// precedence over services of the same name in the config
+    pub services: BTreeMap<String, ServiceDefinition>,
 }
 
 #[derive(Debug, Clone)]
 struct ConnectionState {
-    port: u16,
+    id: usize,
+    target: SocketAddr,
     protocol: Protocol,
+    /// UDP mode to announce to the client, `None` if it didn't negotiate one
+    udp_mode: Option<UdpMode>,
+    /// Name of the service the client asked for, if it didn't give a port
+    service: Option<String>,
+    /// Marking of the sockets to the target
+    qos: Qos,
+    /// The client names the target of each stream, `target` being that of its first mapping
+    multiplexed: bool,
+    conn: Connection,
+    started_at: Instant,
+    /// When the session is closed, from the key's time limit or schedule
+    expires_at: Option<Instant>,
+    stats: Arc<TrafficStats>,
+}
+
+impl ConnectionState {
+    fn info(&self, node_id: &NodeId) -> ConnectionInfo {
+        let (bytes_in, bytes_out) = self.stats.totals();
+        ConnectionInfo {
+            id: self.id,
+            node_id: node_id.to_string(),
+            protocol: self.protocol.to_string(),
+            port: self.target.port(),
+            target: self.target.to_string(),
+            service: self.service.clone(),
+            duration: self.started_at.elapsed().as_secs(),
+            bytes_in,
+            bytes_out,
+        }
+    }
 }
 
 impl Server {
-    pub async fn new() -> Result<Self> {
-        let config_manager = Arc::new(ConfigManager::new()?);
-        let auth_manager = Arc::new(AuthorizationManager::new((*config_manager).clone()));
+    pub async fn new(endpoint: Endpoint, options: ServerOptions) -> Result<Self> {
+        let config_manager = ConfigManager::new()?;
+        let config = ConfigCache::<ServerConfig>::load(config_manager.clone()).await?;
+        if let Err(e) = config.watch() {
+            crate::warning!("{}, changes to server.toml will need a restart", e);
+        }
+        let auth_manager = Arc::new(
+            AuthorizationManager::new(config_manager.clone()).with_cache(Arc::clone(&config)),
+        );
+
+        let current = config.get();
+        let audit_log = current
+            .settings
+            .audit_log
+            .as_ref()
+            .map(|path| Arc::new(AuditLog::new(config_manager.resolve_path(path))));
+        let usage = current
+            .settings
+            .track_usage
+            .then(|| Arc::new(UsageLedger::new(config_manager.usage_path())));
+        let buffers = Arc::new(BufferPool::from_settings(&current.network.buffers));
+        let confirmer = options
+            .confirm
+            .then(|| Arc::new(Confirmer::new(Arc::clone(&auth_manager), options.remember)));
 
         Ok(Self {
-            config_manager,
+            endpoint,
+            config,
             auth_manager,
+            audit_log,
+            usage,
+            buffers,
             connections: Arc::new(DashMap::new()),
             active_connections: Arc::new(AtomicUsize::new(0)),
+            seen_nodes: Arc::new(DashSet::new()),
+            confirmer,
+            next_tunnel_id: Arc::new(AtomicUsize::new(1)),
+            services: Arc::new(options.services),
+            events: broadcast::channel(CONNECTION_EVENTS_CAPACITY).0,
         })
     }
 
-    pub async fn start(self, endpoint: Endpoint) -> Result<()> {
+    pub async fn start(self) -> Result<()> {
+        let endpoint = self.endpoint.clone();
         let node_id = endpoint.node_id();
 
-        let config: ServerConfig = self.config_manager.load().await?;
+        let config = self.config.get();
 
         if config.authorized_keys.is_empty() {
             crate::warning!("No authorized keys configured. No clients will be able to connect.");
-            crate::info!("Add authorized keys to {}", "~/.punch/server.toml".bold());
+            crate::info!(
+                "Add authorized keys to {}",
+                self.config
+                    .manager()
+                    .config_path(ServerConfig::filename())
+                    .display()
+                    .bold()
+            );
         }
 
-        let router = Router::builder(endpoint).accept(ALPN, self).spawn();
+        self.watch_revocations();
+
+        let control = ServerControl {
+            server: self.clone(),
+            endpoint: endpoint.clone(),
+            started_at: Instant::now(),
+        };
+        let control_server = match ControlServer::spawn(
+            self.config.manager().control_socket_path(),
+            control.clone(),
+        )
+        .await
+        {
+            Ok(control_server) => Some(control_server),
+            Err(e) => {
+                crate::warning!("Control socket unavailable: {}", e);
+                None
+            }
+        };
+
+        #[cfg(feature = "grpc")]
+        let _grpc_server = match config.settings.grpc_listen {
+            Some(address) => {
+                let server = crate::core::grpc::GrpcServer::spawn(
+                    address,
+                    &self.config.manager().grpc_token_path(),
+                    control.clone(),
+                )
+                .await?;
+                crate::info!("gRPC control API listening on {}", address);
+                Some(server)
+            }
+            None => None,
+        };
+        #[cfg(not(feature = "grpc"))]
+        if config.settings.grpc_listen.is_some() {
+            crate::warning!(
+                "Ignoring settings.grpc_listen, punch was built without the grpc feature"
+            );
+        }
+
+        #[cfg(feature = "vpn")]
+        let vpn = crate::core::vpn::VpnService::start(&config.vpn, Arc::clone(&self.auth_manager))?;
+        #[cfg(not(feature = "vpn"))]
+        if config.vpn.subnet.is_some() {
+            crate::warning!("Ignoring [vpn], punch was built without the vpn feature");
+        }
+
+        // Everything root was needed for is done: the key is read, the endpoint, the control
+        // socket and the VPN interface are bound and the config is being watched
+        if let Some(run_as) = &config.settings.run_as {
+            let manager = self.config.manager();
+            let socket = control_server
+                .as_ref()
+                .map(|_| manager.control_socket_path());
+            privileges::drop_privileges(run_as, socket.as_slice())?;
+            crate::info!("Running as {}", run_as.bold());
+
+            let paths = [
+                manager.config_path(ServerConfig::filename()),
+                manager.usage_path(),
+            ];
+            let mut dirs: Vec<_> = paths.iter().filter_map(|path| path.parent()).collect();
+            dirs.dedup();
+            for dir in dirs {
+                if !privileges::is_writable(dir) {
+                    crate::warning!(
+                        "{} isn't writable by {}, saving keys and usage will fail",
+                        dir.display(),
+                        run_as
+                    );
+                }
+            }
+        }
+
+        let bench = BenchService::new(Arc::clone(&self.auth_manager));
+        let access = AccessService::new(Arc::clone(&self.config), Arc::clone(&self.auth_manager));
+        let catalog = CatalogService::new(Arc::clone(&self.config), Arc::clone(&self.auth_manager))
+            .with_services(Arc::clone(&self.services));
+        let probe = ProbeService::new(Arc::clone(&self.config), Arc::clone(&self.auth_manager));
+        let sync = SyncService::new(
+            Arc::clone(&self.config),
+            Arc::clone(&self.auth_manager),
+            node_id,
+        );
+        let router = Router::builder(endpoint)
+            .accept(ALPN, self)
+            .accept(BENCH_ALPN, bench)
+            .accept(ACCESS_ALPN, access)
+            .accept(SERVICES_ALPN, catalog)
+            .accept(SYNC_ALPN, sync)
+            .accept(PROBE_ALPN, probe);
+        #[cfg(feature = "vpn")]
+        let router = match vpn {
+            Some(vpn) => router.accept(crate::utils::constants::VPN_ALPN, vpn),
+            None => router,
+        };
+        let router = router.spawn();
 
         crate::info!(
             "Server started, connect to it at: {}",
@@ -69,11 +297,57 @@ impl Server {
         Ok(())
     }
 
-    async fn check_connection_limit(&self) -> Result<()> {
-        let config: ServerConfig = self.config_manager.load().await?;
+    /// Closes the tunnels of `node_id`, or only tunnel `id`, returning how many were closed.
+    fn close_tunnels(&self, node_id: &NodeId, id: Option<usize>, reason: CloseReason) -> usize {
+        let Some(connections) = self.connections.get(node_id) else {
+            return 0;
+        };
+
+        let mut closed = 0;
+        for state in connections
+            .values()
+            .filter(|state| id.is_none_or(|id| state.id == id))
+        {
+            reason.execute(&state.conn);
+            closed += 1;
+        }
+        closed
+    }
+
+    /// Closes the tunnels of keys as soon as they are revoked.
+    fn watch_revocations(&self) {
+        let server = self.clone();
+        let mut revocations = self.auth_manager.subscribe_revocations();
+
+        tokio::spawn(async move {
+            loop {
+                match revocations.recv().await {
+                    Ok(key) => {
+                        let closed = server.close_tunnels(&key, None, CloseReason::Unauthorized);
+                        if closed > 0 {
+                            crate::info!(
+                                "Closed {} tunnel(s) of revoked node {}",
+                                closed,
+                                reduced_node_id(&key)
+                            );
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    async fn check_connection_limit(&self, conn: &Connection) -> Result<()> {
+        let config = self.config.get();
         let current = self.active_connections.load(Ordering::Relaxed);
 
         if current >= config.settings.max_connections {
+            CloseReason::TooManyConnections.execute_with(
+                conn,
+                CloseDetails::default().with_retry_after(CONNECTION_LIMIT_RETRY_AFTER),
+            );
             return Err(anyhow::anyhow!(
                 "Maximum connections ({}) reached",
                 config.settings.max_connections
@@ -84,65 +358,419 @@ impl Server {
         Ok(())
     }
 
+    /// Records the connection's state, unless its key already uses all of its connection slots.
+    async fn register_connection(&self, conn: &Connection, state: ConnectionState) -> Result<()> {
+        let remote_node_id = conn.remote_node_id()?;
+        let config = self.config.get();
+        let limit = config.max_connections_for(&remote_node_id);
+
+        let mut connections = self.connections.entry(remote_node_id).or_default();
+        if connections.len() >= limit {
+            drop(connections);
+            crate::warning!(
+                "Node {} reached its limit of {} concurrent connections",
+                reduced_node_id(&remote_node_id),
+                limit
+            );
+            CloseReason::TooManyConnections.execute_with(
+                conn,
+                CloseDetails::default().with_retry_after(CONNECTION_LIMIT_RETRY_AFTER),
+            );
+            return Err(anyhow::anyhow!("Maximum connections per key ({}) reached", limit).into());
+        }
+
+        connections.insert(conn.stable_id(), state);
+        Ok(())
+    }
+
+    async fn audit(&self, record: &AuditRecord) {
+        if let Some(audit_log) = &self.audit_log
+            && let Err(e) = audit_log.append(record).await
+        {
+            tracing::warn!(
+                "Failed to write audit log {}: {}",
+                audit_log.path().display(),
+                e
+            );
+        }
+    }
+
+    async fn record_usage(&self, node_id: NodeId, usage: Usage) {
+        if let Some(ledger) = &self.usage
+            && let Err(e) = ledger.record(node_id, usage).await
+        {
+            tracing::warn!(
+                "Failed to write usage counters {}: {}",
+                ledger.path().display(),
+                e
+            );
+        }
+    }
+
     async fn validate_connection(&self, conn: &Connection) -> Result<ConnectionState> {
         let remote_node_id = conn.remote_node_id()?;
+        let mut record = AuditRecord::new(AuditEvent::Accepted, &remote_node_id);
+
+        let started = Instant::now();
+        let span = tracing::info_span!("handshake", peer = %remote_node_id.fmt_short());
+        let result = self.negotiate(conn, &mut record).instrument(span).await;
+        telemetry::handshake(result.is_ok(), started.elapsed());
+        match &result {
+            Ok(state) => {
+                record.tunnel = Some(state.id);
+                if let Err(e) = self.config.manager().record_seen(&remote_node_id) {
+                    tracing::warn!("Failed to record when {} was seen: {}", remote_node_id, e);
+                }
+            }
+            Err(e) => {
+                record.event = AuditEvent::Rejected;
+                record.reason = Some(e.to_string());
+            }
+        }
+        self.audit(&record).await;
+
+        result
+    }
+
+    async fn negotiate(
+        &self,
+        conn: &Connection,
+        record: &mut AuditRecord,
+    ) -> Result<ConnectionState> {
+        let remote_node_id = conn.remote_node_id()?;
 
         if !self.auth_manager.is_authorized(&remote_node_id).await? {
             crate::warning!(
                 "Unauthorized connection attempt from node: {}",
                 reduced_node_id(&remote_node_id)
             );
+            notifications::notify(
+                &self.config.get().notifications,
+                NotificationEvent::NewKey,
+                format!("{} tried to connect", remote_node_id),
+            );
             CloseReason::Unauthorized.execute(conn);
             return Err(anyhow::anyhow!("Unauthorized connection").into());
         }
 
-        self.check_connection_limit().await?;
+        let expires_in = self.session_limit(conn)?;
+        self.check_connection_limit(conn).await?;
 
-        let protocol = self.read_protocol(conn).await?;
+        let Handshake {
+            protocol,
+            port,
+            host,
+            udp_mode,
+            service,
+            qos,
+            multiplexed,
+            // Each stream of a multiplexed connection names its own
+            stream_priority: _,
+        } = self.read_handshake(conn).await?;
 
-        let port = self.read_port(conn).await?;
+        // Streams name a port, a service has none to name
+        if multiplexed && service.is_some() {
+            let reason = CloseReason::InvalidHandshake;
+            let message = format!("{}: services can't be multiplexed", reason);
+            reason.execute_with(conn, CloseDetails::default().with_message(message));
+            return Err(anyhow::anyhow!("Multiplexed service request").into());
+        }
 
-        if !self.auth_manager.is_port_allowed(port).await? {
+        // Services are published by the admin, so they aren't held to the allowed ports
+        let (protocol, port, host) = match &service {
+            Some(name) => {
+                let service = self.resolve_service(conn, name, protocol).await?;
+                (service.protocol, service.port, service.host)
+            }
+            None => (protocol, port, host),
+        };
+        record.protocol = Some(protocol.to_string());
+        record.port = Some(port);
+
+        let config = self.config.get();
+        if service.is_none() && !config.is_port_allowed(&remote_node_id, port) {
             crate::warning!(
                 "Invalid port requested by node {}: {}",
                 reduced_node_id(&remote_node_id),
                 port
             );
-            CloseReason::InvalidPort.execute(conn);
+            // Guests are only told about services, not the range of regular keys
+            let details = match config.role_of(&remote_node_id) {
+                Role::Guest => CloseDetails::default(),
+                _ => CloseDetails::default().with_allowed_port_ranges(
+                    config
+                        .settings
+                        .effective_ports()
+                        .into_iter()
+                        .map(Into::into)
+                        .collect(),
+                ),
+            };
+            CloseReason::InvalidPort.execute_with(conn, details);
             return Err(anyhow::anyhow!("Port {} not allowed", port).into());
         }
 
+        let target = self.resolve_target(conn, host.as_deref(), port).await?;
+        record.target = Some(target.to_string());
+
         tracing::info!(
-            "Connection request from node: {}, protocol: {:?}, port: {}",
+            "Connection request from node: {}, protocol: {:?}, target: {}",
             reduced_node_id(&remote_node_id),
             protocol,
-            port
+            target
         );
 
-        Ok(ConnectionState { port, protocol })
+        if let Some(confirmer) = &self.confirmer {
+            let request = format!("{} ({})", target, protocol);
+            if !confirmer.confirm(remote_node_id, &request).await? {
+                crate::warning!(
+                    "Connection from node {} rejected by the operator",
+                    reduced_node_id(&remote_node_id)
+                );
+                CloseReason::Unauthorized.execute(conn);
+                return Err(anyhow::anyhow!("Rejected by the operator").into());
+            }
+        }
+
+        let udp_mode = match (protocol, udp_mode) {
+            // UDP streams of a multiplexed connection are always framed
+            _ if multiplexed => None,
+            (Protocol::Udp, Some(UdpMode::Datagram)) if conn.max_datagram_size().is_some() => {
+                Some(UdpMode::Datagram)
+            }
+            (Protocol::Udp, Some(_)) => Some(UdpMode::Stream),
+            _ => None,
+        };
+
+        let state = ConnectionState {
+            id: self.next_tunnel_id.fetch_add(1, Ordering::Relaxed),
+            target,
+            protocol,
+            udp_mode,
+            service,
+            qos: client_qos(qos),
+            multiplexed,
+            conn: conn.clone(),
+            started_at: Instant::now(),
+            expires_at: expires_in.map(|expires_in| Instant::now() + expires_in),
+            stats: Arc::new(TrafficStats::limited(config.bandwidth_for(&remote_node_id))),
+        };
+        self.register_connection(conn, state.clone()).await?;
+
+        Ok(state)
+    }
+
+    /// How long a session of the peer may last, closing the connection if its schedule
+    /// doesn't allow it to connect now.
+    fn session_limit(&self, conn: &Connection) -> Result<Option<Duration>> {
+        let remote_node_id = conn.remote_node_id()?;
+        let config = self.config.get();
+        let max_session = config.max_session_for(&remote_node_id);
+
+        let Some(schedule) = config.schedule_of(&remote_node_id) else {
+            return Ok(max_session);
+        };
+        match schedule.remaining(SystemTime::now()) {
+            Some(remaining) => Ok(Some(
+                max_session.map_or(remaining, |max| max.min(remaining)),
+            )),
+            None => {
+                crate::warning!(
+                    "Node {} connected outside of its schedule ({})",
+                    reduced_node_id(&remote_node_id),
+                    schedule
+                );
+                let reason = CloseReason::OutsideSchedule;
+                let message = format!("{}, allowed {}", reason, schedule);
+                reason.execute_with(conn, CloseDetails::default().with_message(message));
+                Err(anyhow::anyhow!("Outside of the key's schedule").into())
+            }
+        }
+    }
+
+    /// Closes the connection once its session expires, until the returned handle is aborted.
+    fn expire_session(&self, conn: &Connection, expires_at: Instant) -> JoinHandle<()> {
+        let conn = conn.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep_until(expires_at.into()).await;
+            if let Ok(node_id) = conn.remote_node_id() {
+                crate::info!(
+                    "Session of node {} expired, closing the tunnel",
+                    reduced_node_id(&node_id)
+                );
+            }
+            CloseReason::SessionExpired.execute(&conn);
+        })
+    }
+
+    async fn resolve_service(
+        &self,
+        conn: &Connection,
+        name: &str,
+        protocol: Protocol,
+    ) -> Result<ServiceDefinition> {
+        let config = self.config.get();
+
+        match self
+            .services
+            .get(name)
+            .or_else(|| config.services.get(name))
+        {
+            Some(service) => Ok(service.clone()),
+            // Unless published under the same name, forwards to our resolver over the
+            // protocol asked for, as DNS goes over both
+            None if name == DNS_SERVICE => {
+                match dns::server_resolver(config.settings.dns_resolver.as_deref()).await {
+                    Some(resolver) => Ok(ServiceDefinition {
+                        port: DEFAULT_DNS_PORT,
+                        protocol,
+                        host: Some(resolver),
+                        description: None,
+                    }),
+                    None => {
+                        crate::warning!("No resolver to forward DNS to, set settings.dns_resolver");
+                        CloseReason::UnknownService.execute(conn);
+                        Err(anyhow::anyhow!("No DNS resolver").into())
+                    }
+                }
+            }
+            None => {
+                crate::warning!(
+                    "Unknown service requested by node {}: {}",
+                    reduced_node_id(&conn.remote_node_id()?),
+                    name
+                );
+                CloseReason::UnknownService.execute(conn);
+                Err(anyhow::anyhow!("Unknown service {}", name).into())
+            }
+        }
+    }
+
+    async fn resolve_target(
+        &self,
+        conn: &Connection,
+        host: Option<&str>,
+        port: u16,
+    ) -> Result<SocketAddr> {
+        let remote_node_id = conn.remote_node_id()?;
+        self.allowed_target(&remote_node_id, host, port)
+            .await
+            .inspect_err(|_| CloseReason::ForbiddenTarget.execute(conn))
+    }
+
+    /// Resolves the target `node_id` asks for, if the node may reach it.
+    async fn allowed_target(
+        &self,
+        node_id: &NodeId,
+        host: Option<&str>,
+        port: u16,
+    ) -> Result<SocketAddr> {
+        let host = net::unbracket(host.unwrap_or(DEFAULT_TARGET_HOST));
+
+        let addrs: Vec<SocketAddr> = match tokio::net::lookup_host((host, port)).await {
+            Ok(addrs) => addrs.collect(),
+            Err(e) => {
+                tracing::warn!("Could not resolve target {}: {}", host, e);
+                Vec::new()
+            }
+        };
+        let ips: Vec<IpAddr> = addrs.iter().map(SocketAddr::ip).collect();
+
+        match addrs.first() {
+            Some(target)
+                if self
+                    .auth_manager
+                    .is_target_allowed(node_id, host, &ips)
+                    .await? =>
+            {
+                Ok(*target)
+            }
+            _ => {
+                crate::warning!(
+                    "Forbidden target requested by node {}: {}",
+                    reduced_node_id(node_id),
+                    host
+                );
+                Err(anyhow::anyhow!("Target {} not allowed", host).into())
+            }
+        }
     }
 
-    async fn read_protocol(&self, conn: &Connection) -> Result<Protocol> {
-        let datagram = conn.read_datagram().await?;
-        let first_byte = datagram
-            .first()
-            .ok_or_else(|| anyhow::anyhow!("Failed to read protocol from datagram"))?;
+    /// Reads the tunnel request, closing the connection with the reason it was refused for
+    /// when it is malformed or doesn't come within `HANDSHAKE_TIMEOUT`.
+    async fn read_handshake(&self, conn: &Connection) -> Result<Handshake> {
+        let read = async {
+            let mut datagram = conn.read_datagram().await?.to_vec();
 
-        Protocol::try_from(*first_byte).map_err(|_| {
-            CloseReason::InvalidProtocol.execute(conn);
-            anyhow::anyhow!("Invalid protocol requested").into()
+            // Older clients send the protocol and the port as two separate datagrams
+            if datagram.len() == 1 {
+                datagram.extend_from_slice(&conn.read_datagram().await?);
+            }
+            Ok::<_, crate::PunchError>(datagram)
+        };
+        let datagram = match tokio::time::timeout(HANDSHAKE_TIMEOUT, read).await {
+            Ok(datagram) => datagram?,
+            Err(_) => {
+                CloseReason::HandshakeTimeout.execute(conn);
+                return Err(
+                    anyhow::anyhow!("No tunnel request after {:?}", HANDSHAKE_TIMEOUT).into(),
+                );
+            }
+        };
+
+        Handshake::decode(&datagram).inspect_err(|e| {
+            let reason = match datagram.first().map(|b| Protocol::try_from(*b)) {
+                Some(Err(_)) => CloseReason::InvalidProtocol,
+                _ => CloseReason::InvalidHandshake,
+            };
+            let message = match e {
+                crate::PunchError::Error { message, .. } => format!("{}: {}", reason, message),
+                _ => reason.to_string(),
+            };
+            reason.execute_with(conn, CloseDetails::default().with_message(message));
         })
     }
 
-    async fn read_port(&self, conn: &Connection) -> Result<u16> {
-        let datagram = conn.read_datagram().await?;
+    async fn proxy_header(
+        &self,
+        node_id: &NodeId,
+        protocol: Protocol,
+        target: SocketAddr,
+    ) -> Result<Option<Bytes>> {
+        let config = self.config.get();
+        if !config.settings.proxy_protocol || protocol != Protocol::Tcp {
+            return Ok(None);
+        }
 
-        let port_bytes: [u8; 2] = datagram.iter().as_slice().try_into().map_err(|_| {
-            CloseReason::InvalidPort.execute(conn);
-            anyhow::anyhow!("Invalid port bytes")
-        })?;
+        let source = match self.endpoint.conn_type(*node_id)?.get() {
+            Ok(ConnectionType::Direct(addr) | ConnectionType::Mixed(addr, _)) => Some(addr),
+            _ => None,
+        };
 
-        Ok(u16::from_be_bytes(port_bytes))
+        Ok(Some(proxy_protocol::v2_header(node_id, source, target)))
+    }
+
+    /// Caps the streams of a tunnel as configured, each stream borrowing up to one buffer per
+    /// direction.
+    fn stream_limit(&self) -> StreamLimit {
+        let settings = &self.config.get().settings;
+        let memory = (settings.stream_memory_per_connection > 0)
+            .then_some(settings.stream_memory_per_connection);
+        StreamLimit::new(
+            settings.max_streams_per_connection,
+            memory,
+            2 * self.buffers.buffer_size(),
+        )
+    }
+
+    /// Reports a tunnel opening or closing to the gRPC subscribers, if there are any.
+    fn publish(&self, event: HookEvent, node_id: &NodeId, state: &ConnectionState) {
+        if self.events.receiver_count() > 0 {
+            let _ = self.events.send(ConnectionEvent {
+                event,
+                connection: state.info(node_id),
+            });
+        }
     }
 
     async fn handle_connection(&self, conn: Connection) -> Result<()> {
@@ -153,39 +781,358 @@ impl Server {
         let _guard = ConnectionGuard {
             counter: Arc::clone(&self.active_connections),
             node_id: remote_node_id,
+            stable_id: conn.stable_id(),
             connections: Arc::clone(&self.connections),
         };
 
         let state = self
             .connections
             .get(&remote_node_id)
+            .and_then(|connections| connections.get(&conn.stable_id()).cloned())
             .ok_or_else(|| anyhow::anyhow!("Connection state not found"))?;
 
-        let tunnel = TunnelConnection::new(conn, state.protocol);
-        let handler = ConnectionHandler::new(state.port, state.protocol);
+        let proxy_header = self
+            .proxy_header(&remote_node_id, state.protocol, state.target)
+            .await?;
+
+        let router: Option<Arc<dyn StreamRouter>> = match state.multiplexed {
+            true => {
+                handshake::send_multiplexed(&conn).await?;
+                Some(Arc::new(StreamRoutes::new(self.clone(), remote_node_id)))
+            }
+            false => None,
+        };
+        if state.service.is_some() {
+            handshake::send_service(&conn, state.protocol, state.target.port()).await?;
+        }
+        if let Some(mode) = state.udp_mode {
+            handshake::send_udp_mode(&conn, mode).await?;
+        }
+
+        let started_at = state.started_at;
+        let stats = Arc::clone(&state.stats);
+
+        let tunnel = TunnelConnection::new(conn.clone(), state.protocol).with_id(state.id);
+        telemetry::tunnel_opened();
+        let handler = ConnectionHandler::new(state.target.port(), state.protocol)
+            .with_target(state.target)
+            .with_proxy_header(proxy_header)
+            .with_dial_wait(Duration::from_secs(
+                self.config.get().settings.wait_for_service,
+            ))
+            .with_udp_mode(state.udp_mode)
+            .with_qos(state.qos)
+            .with_tcp_settings(self.config.get().network.tcp)
+            .with_stream_limit(self.stream_limit())
+            .with_buffers(Arc::clone(&self.buffers))
+            .with_stats(Arc::clone(&stats))
+            .with_router(router);
+
+        tunnel.span().in_scope(|| {
+            tracing::info!(
+                "Handling connection from node: {} to {}",
+                reduced_node_id(&remote_node_id),
+                state.target
+            )
+        });
+
+        let config = self.config.get();
+        let hooks = config.hooks.clone();
+        let hook_context = HookContext {
+            peer: remote_node_id,
+            protocol: state.protocol.to_string().to_lowercase(),
+            port: state.target.port(),
+            target: Some(state.target.to_string()),
+        };
+        let event = if self.seen_nodes.insert(remote_node_id) {
+            HookEvent::Connect
+        } else {
+            HookEvent::Reconnect
+        };
+        hooks::trigger(&hooks, event, &hook_context);
+        self.publish(event, &remote_node_id, &state);
+        let description = format!(
+            "{} to {} from {}",
+            state.protocol,
+            state.target,
+            remote_node_id.fmt_short()
+        );
+        notifications::notify(&config.notifications, event.into(), description.clone());
+
+        let expiry = state
+            .expires_at
+            .map(|expires_at| self.expire_session(&conn, expires_at));
+        let result = handler.handle_connection(tunnel).await;
+        if let Some(expiry) = expiry {
+            expiry.abort();
+        }
+        hooks::trigger(&hooks, HookEvent::Disconnect, &hook_context);
+        self.publish(HookEvent::Disconnect, &remote_node_id, &state);
+        notifications::notify(
+            &config.notifications,
+            NotificationEvent::Disconnect,
+            description,
+        );
+
+        let (bytes_in, bytes_out) = stats.totals();
+        telemetry::tunnel_closed(bytes_in, bytes_out);
+        let duration = started_at.elapsed().as_secs();
+        self.record_usage(
+            remote_node_id,
+            Usage::session(bytes_in, bytes_out, duration),
+        )
+        .await;
+
+        let mut record = AuditRecord::new(AuditEvent::Closed, &remote_node_id);
+        record.tunnel = Some(state.id);
+        record.protocol = Some(state.protocol.to_string());
+        record.port = Some(state.target.port());
+        record.target = Some(state.target.to_string());
+        record.duration = Some(duration);
+        record.bytes_in = Some(bytes_in);
+        record.bytes_out = Some(bytes_out);
+        record.reason = match &result {
+            Err(e) => Some(e.to_string()),
+            Ok(()) => conn.close_reason().map(|reason| reason.to_string()),
+        };
+        self.audit(&record).await;
+
+        result
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ServerControl {
+    server: Server,
+    endpoint: Endpoint,
+    started_at: Instant,
+}
+
+impl ServerControl {
+    async fn health(&self) -> Result<HealthReport> {
+        let config = self.server.config.get();
+        let active_connections = self.server.active_connections.load(Ordering::Relaxed);
+        let home_relay = self.endpoint.home_relay().get().ok().flatten();
+
+        let mut issues = Vec::new();
+        if home_relay.is_none() {
+            issues.push("Not connected to a home relay".to_string());
+        }
+        if active_connections >= config.settings.max_connections {
+            issues.push(format!(
+                "Maximum connections ({}) reached",
+                config.settings.max_connections
+            ));
+        }
+        if config.authorized_keys.is_empty() {
+            issues.push("No authorized keys configured".to_string());
+        }
+
+        let status = if self.endpoint.is_closed() {
+            HealthStatus::Down
+        } else if issues.is_empty() {
+            HealthStatus::Healthy
+        } else {
+            HealthStatus::Degraded
+        };
+
+        Ok(HealthReport {
+            status,
+            node_id: self.endpoint.node_id().to_string(),
+            uptime: self.started_at.elapsed().as_secs(),
+            active_connections,
+            max_connections: config.settings.max_connections,
+            home_relay: home_relay.map(|url| url.to_string()),
+            issues,
+        })
+    }
+
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<ConnectionInfo> = self
+            .server
+            .connections
+            .iter()
+            .flat_map(|entry| {
+                let node_id = *entry.key();
+                entry
+                    .value()
+                    .values()
+                    .map(|state| state.info(&node_id))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        connections.sort_by_key(|c| std::cmp::Reverse(c.duration));
+        connections
+    }
+
+    /// Closes the tunnels of the node whose ID starts with `node_id`, or only tunnel `id`.
+    fn kick(&self, node_id: &str, id: Option<usize>) -> Result<usize> {
+        let matches: Vec<NodeId> = self
+            .server
+            .connections
+            .iter()
+            .map(|entry| *entry.key())
+            .filter(|key| key.to_string().starts_with(node_id))
+            .collect();
+
+        let node_id = match matches.as_slice() {
+            [node_id] => *node_id,
+            [] => return Err(crate::error!("No active tunnel from node {}", node_id)),
+            _ => return Err(crate::error!("Node ID {} is ambiguous", node_id)),
+        };
+
+        let kicked = self.server.close_tunnels(&node_id, id, CloseReason::Kicked);
+        if kicked == 0 {
+            return Err(crate::error!("No matching tunnel from node {}", node_id));
+        }
+
+        crate::info!(
+            "Kicked {} tunnel(s) from node {}",
+            kicked,
+            reduced_node_id(&node_id)
+        );
+        Ok(kicked)
+    }
+
+    async fn revoke(&self, node_id: &str) -> Result<ControlResponse> {
+        let key: NodeId = node_id
+            .parse()
+            .map_err(|_| crate::error!("Invalid public key format: {}", node_id))?;
+        let removed = self.server.auth_manager.revoke(&key).await?;
+        Ok(ControlResponse::Revoked { removed })
+    }
+}
+
+impl ControlHandler for ServerControl {
+    fn handle(&self, request: ControlRequest) -> BoxFuture<ControlResponse> {
+        let control = self.clone();
+
+        Box::pin(async move {
+            let result = match request {
+                ControlRequest::Health => control.health().await.map(ControlResponse::Health),
+                ControlRequest::Connections => Ok(ControlResponse::Connections {
+                    connections: control.connections(),
+                }),
+                ControlRequest::Kick { node_id, id } => control
+                    .kick(&node_id, id)
+                    .map(|count| ControlResponse::Kicked { count }),
+                ControlRequest::Revoke { node_id } => control.revoke(&node_id).await,
+            };
+
+            result.unwrap_or_else(|e| ControlResponse::Error {
+                message: e.to_string(),
+            })
+        })
+    }
+
+    fn events(&self) -> Option<broadcast::Receiver<ConnectionEvent>> {
+        Some(self.server.events.subscribe())
+    }
+}
+
+/// Higher socket priorities take queues reserved to the admin, which a server running as root
+/// would hand out to anyone.
+fn client_qos(mut qos: Qos) -> Qos {
+    if qos
+        .priority
+        .is_some_and(|priority| priority > MAX_CLIENT_SOCKET_PRIORITY)
+    {
+        tracing::debug!(
+            "Ignoring socket priority {:?} asked for by the client",
+            qos.priority
+        );
+        qos.priority = None;
+    }
+    qos
+}
 
+/// Targets the streams of a multiplexed connection may go to, each request being checked
+/// once per connection, like a handshake naming it would be.
+#[derive(Clone)]
+struct StreamRoutes {
+    server: Server,
+    node_id: NodeId,
+    routes: Arc<tokio::sync::Mutex<HashMap<Handshake, Route>>>,
+}
+
+impl StreamRoutes {
+    fn new(server: Server, node_id: NodeId) -> Self {
+        Self {
+            server,
+            node_id,
+            routes: Default::default(),
+        }
+    }
+
+    async fn check(&self, request: &Handshake) -> Result<Route> {
+        let server = &self.server;
+        let config = server.config.get();
+        if request.service.is_some() {
+            return Err(crate::error!("Services can't be multiplexed"));
+        }
+        if !config.is_port_allowed(&self.node_id, request.port) {
+            crate::warning!(
+                "Invalid port requested by node {}: {}",
+                reduced_node_id(&self.node_id),
+                request.port
+            );
+            return Err(anyhow::anyhow!("Port {} not allowed", request.port).into());
+        }
+
+        let target = server
+            .allowed_target(&self.node_id, request.host.as_deref(), request.port)
+            .await?;
         tracing::info!(
-            "Handling connection from node: {} on port {}",
-            reduced_node_id(&remote_node_id),
-            state.port
+            "Stream request from node: {}, protocol: {:?}, target: {}",
+            reduced_node_id(&self.node_id),
+            request.protocol,
+            target
         );
+        Ok(Route {
+            protocol: request.protocol,
+            target,
+            qos: client_qos(request.qos),
+            proxy_header: server
+                .proxy_header(&self.node_id, request.protocol, target)
+                .await?,
+            stream_priority: request.stream_priority,
+        })
+    }
+}
 
-        handler.handle_connection(tunnel).await?;
+impl StreamRouter for StreamRoutes {
+    fn route(&self, request: Handshake) -> BoxFuture<Result<Route>> {
+        let router = self.clone();
 
-        Ok(())
+        Box::pin(async move {
+            // Held while checking, so that the streams of a new mapping wait for one check
+            let mut routes = router.routes.lock().await;
+            if let Some(route) = routes.get(&request) {
+                return Ok(route.clone());
+            }
+            let route = router.check(&request).await?;
+            routes.insert(request, route.clone());
+            Ok(route)
+        })
     }
 }
 
 struct ConnectionGuard {
     counter: Arc<AtomicUsize>,
     node_id: NodeId,
-    connections: Arc<DashMap<NodeId, ConnectionState>>,
+    stable_id: usize,
+    connections: Arc<DashMap<NodeId, HashMap<usize, ConnectionState>>>,
 }
 
 impl Drop for ConnectionGuard {
     fn drop(&mut self) {
         self.counter.fetch_sub(1, Ordering::Relaxed);
-        self.connections.remove(&self.node_id);
+        if let Some(mut connections) = self.connections.get_mut(&self.node_id) {
+            connections.remove(&self.stable_id);
+        }
+        self.connections
+            .remove_if(&self.node_id, |_, connections| connections.is_empty());
         tracing::debug!(
             "Connection closed for node: {}, active connections: {}",
             reduced_node_id(&self.node_id),
@@ -203,11 +1150,7 @@ impl ProtocolHandler for Server {
 
         Box::pin(async move {
             let conn = connecting.await?;
-            let remote_node_id = conn.remote_node_id()?;
-
-            let state = server.validate_connection(&conn).await?;
-
-            server.connections.insert(remote_node_id, state);
+            server.validate_connection(&conn).await?;
 
             Ok(conn)
         })
@@ -237,7 +1180,7 @@ impl ProtocolHandler for Server {
     }
 }
 
-pub async fn server(endpoint: Endpoint) -> Result<()> {
-    let server = Server::new().await?;
-    server.start(endpoint).await
+pub async fn server(endpoint: Endpoint, options: ServerOptions) -> Result<()> {
+    let server = Server::new(endpoint, options).await?;
+    server.start().await
 }
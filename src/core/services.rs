@@ -0,0 +1,153 @@
+use crate::core::{Protocol, net};
+use crate::utils::config::{AuthorizationManager, ConfigCache, ServerConfig, ServiceDefinition};
+use crate::utils::constants::{DEFAULT_TARGET_HOST, SERVICES_ALPN};
+use crate::{CloseReason, PunchError, Result};
+use iroh::{
+    Endpoint, NodeId,
+    endpoint::{Connection, ConnectionError, SendStream},
+    protocol::ProtocolHandler,
+};
+use n0_future::boxed::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+/// Largest catalog a client accepts
+const MAX_CATALOG_SIZE: usize = 1024 * 1024;
+
+/// A service as listed to clients.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceEntry {
+    pub name: String,
+    pub protocol: Protocol,
+    pub port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+/// Fetches the services `node_id` lets us connect to.
+pub async fn list(endpoint: &Endpoint, node_id: NodeId) -> Result<Vec<ServiceEntry>> {
+    let conn = endpoint.connect(node_id, SERVICES_ALPN).await?;
+    let mut recv = conn.accept_uni().await.map_err(|e| match e {
+        ConnectionError::ApplicationClosed(close) => PunchError::from(&close),
+        e => e.into(),
+    })?;
+
+    let catalog = recv
+        .read_to_end(MAX_CATALOG_SIZE)
+        .await
+        .map_err(|e| crate::error!("Failed to read service catalog: {}", e))?;
+    conn.close(0u8.into(), b"done");
+
+    serde_json::from_slice(&catalog).map_err(|e| crate::error!("Invalid service catalog: {}", e))
+}
+
+/// Answers authorized peers with the services they are allowed to reach.
+#[derive(Debug, Clone)]
+pub struct CatalogService {
+    config: Arc<ConfigCache<ServerConfig>>,
+    auth_manager: Arc<AuthorizationManager>,
+    /// Published by the running server on top of those of the config
+    services: Arc<BTreeMap<String, ServiceDefinition>>,
+}
+
+impl CatalogService {
+    pub fn new(
+        config: Arc<ConfigCache<ServerConfig>>,
+        auth_manager: Arc<AuthorizationManager>,
+    ) -> Self {
+        Self {
+            config,
+            auth_manager,
+            services: Arc::default(),
+        }
+    }
+
+    pub fn with_services(mut self, services: Arc<BTreeMap<String, ServiceDefinition>>) -> Self {
+        self.services = services;
+        self
+    }
+
+    async fn catalog(&self, node_id: &NodeId) -> Result<Vec<ServiceEntry>> {
+        let config = self.config.get();
+
+        let mut entries = Vec::new();
+        let services = config
+            .services
+            .iter()
+            .filter(|(name, _)| !self.services.contains_key(*name))
+            .chain(self.services.iter());
+        for (name, service) in services {
+            let host = net::unbracket(service.host.as_deref().unwrap_or(DEFAULT_TARGET_HOST));
+            let ips: Vec<IpAddr> = match tokio::net::lookup_host((host, service.port)).await {
+                Ok(addrs) => addrs.map(|addr| addr.ip()).collect(),
+                Err(_) => continue,
+            };
+
+            // Hide services the target policy would refuse this node anyway
+            if !self
+                .auth_manager
+                .is_target_allowed(node_id, host, &ips)
+                .await?
+            {
+                continue;
+            }
+
+            entries.push(ServiceEntry {
+                name: name.clone(),
+                protocol: service.protocol,
+                port: service.port,
+                description: service.description.clone(),
+            });
+        }
+
+        Ok(entries)
+    }
+
+    async fn send(&self, conn: &Connection) -> Result<()> {
+        let node_id = conn.remote_node_id()?;
+        let catalog = serde_json::to_vec(&self.catalog(&node_id).await?)
+            .map_err(|e| crate::error!("{}", e))?;
+
+        let mut send: SendStream = conn.open_uni().await?;
+        AsyncWriteExt::write_all(&mut send, &catalog).await?;
+        send.finish()
+            .map_err(|e| crate::error!("Failed to finish stream: {}", e))?;
+        send.stopped().await.ok();
+        Ok(())
+    }
+}
+
+impl ProtocolHandler for CatalogService {
+    fn on_connecting(
+        &self,
+        connecting: iroh::endpoint::Connecting,
+    ) -> BoxFuture<anyhow::Result<Connection>> {
+        let auth_manager = Arc::clone(&self.auth_manager);
+
+        Box::pin(async move {
+            let conn = connecting.await?;
+            let node_id = conn.remote_node_id()?;
+
+            if !auth_manager.is_authorized(&node_id).await? {
+                CloseReason::Unauthorized.execute(&conn);
+                anyhow::bail!("Unauthorized service catalog request from {}", node_id);
+            }
+
+            Ok(conn)
+        })
+    }
+
+    fn accept(&self, conn: Connection) -> BoxFuture<anyhow::Result<()>> {
+        let service = self.clone();
+
+        Box::pin(async move {
+            if let Err(e) = service.send(&conn).await {
+                tracing::debug!("Failed to send service catalog: {}", e);
+            }
+            Ok(())
+        })
+    }
+}
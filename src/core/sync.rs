@@ -0,0 +1,216 @@
+use crate::utils::config::{
+    AuthorizationManager, ConfigCache, Host, HostManager, Role, ServerConfig,
+};
+use crate::utils::constants::{HANDSHAKE_TIMEOUT, MAX_SYNC_SIZE, SYNC_ALPN};
+use crate::utils::reduced_node_id;
+use crate::{CloseReason, PunchError, Result};
+use iroh::{
+    Endpoint, NodeId, PublicKey,
+    endpoint::{Connection, ConnectionError, ReadError, ReadToEndError, RecvStream, SendStream},
+    protocol::ProtocolHandler,
+};
+use n0_future::boxed::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+/// What a node sends to one of its sync peers.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SyncRequest {
+    /// Hosts to merge into the peer's, when pushing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hosts: Option<Vec<Host>>,
+    /// Keys for the peer to authorize, when pushing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    authorized_keys: Option<Vec<PublicKey>>,
+    /// Whether to send back the peer's authorized keys
+    #[serde(default)]
+    want_keys: bool,
+}
+
+/// The peer's side of the exchange, after it merged what it was pushed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SyncResponse {
+    pub hosts: Vec<Host>,
+    #[serde(default)]
+    pub authorized_keys: Vec<PublicKey>,
+    #[serde(default)]
+    pub merged: Merged,
+}
+
+/// What changed on the node that merged a push.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct Merged {
+    pub hosts_added: usize,
+    pub hosts_replaced: usize,
+    pub keys_added: usize,
+}
+
+/// Sends our hosts, and `authorized_keys` if given, to be merged into those of `node_id`.
+pub async fn push(
+    endpoint: &Endpoint,
+    node_id: NodeId,
+    hosts: Vec<Host>,
+    authorized_keys: Option<Vec<PublicKey>>,
+) -> Result<Merged> {
+    let request = SyncRequest {
+        hosts: Some(hosts),
+        authorized_keys,
+        want_keys: false,
+    };
+    Ok(exchange(endpoint, node_id, &request).await?.merged)
+}
+
+/// Fetches the hosts of `node_id`, and its authorized keys if `want_keys` is set.
+pub async fn pull(endpoint: &Endpoint, node_id: NodeId, want_keys: bool) -> Result<SyncResponse> {
+    let request = SyncRequest {
+        want_keys,
+        ..Default::default()
+    };
+    exchange(endpoint, node_id, &request).await
+}
+
+async fn exchange(
+    endpoint: &Endpoint,
+    node_id: NodeId,
+    request: &SyncRequest,
+) -> Result<SyncResponse> {
+    let conn = endpoint.connect(node_id, SYNC_ALPN).await?;
+    let (mut send, mut recv) = conn.open_bi().await.map_err(closed)?;
+
+    let body = serde_json::to_vec(request).map_err(|e| crate::error!("{}", e))?;
+    AsyncWriteExt::write_all(&mut send, &body).await?;
+    send.finish()
+        .map_err(|e| crate::error!("Failed to finish stream: {}", e))?;
+
+    let response = recv.read_to_end(MAX_SYNC_SIZE).await.map_err(|e| match e {
+        ReadToEndError::Read(ReadError::ConnectionLost(e)) => closed(e),
+        e => crate::error!("Failed to read sync response: {}", e),
+    })?;
+    conn.close(0u8.into(), b"done");
+
+    serde_json::from_slice(&response).map_err(|e| crate::error!("Invalid sync response: {}", e))
+}
+
+/// Explains that the peer refused us, e.g. as it doesn't list us in its sync peers.
+fn closed(e: ConnectionError) -> PunchError {
+    match e {
+        ConnectionError::ApplicationClosed(close) => PunchError::from(&close),
+        e => e.into(),
+    }
+}
+
+/// Exchanges hosts and authorized keys with the nodes listed in `settings.sync_peers`, and
+/// with admin keys.
+#[derive(Debug, Clone)]
+pub struct SyncService {
+    config: Arc<ConfigCache<ServerConfig>>,
+    auth_manager: Arc<AuthorizationManager>,
+    node_id: NodeId,
+}
+
+impl SyncService {
+    pub fn new(
+        config: Arc<ConfigCache<ServerConfig>>,
+        auth_manager: Arc<AuthorizationManager>,
+        node_id: NodeId,
+    ) -> Self {
+        Self {
+            config,
+            auth_manager,
+            node_id,
+        }
+    }
+
+    async fn handle(&self, peer: NodeId, mut send: SendStream, mut recv: RecvStream) -> Result<()> {
+        let body = tokio::time::timeout(HANDSHAKE_TIMEOUT, recv.read_to_end(MAX_SYNC_SIZE))
+            .await
+            .map_err(|_| crate::error!("Timed out reading sync request"))?
+            .map_err(|e| crate::error!("Failed to read sync request: {}", e))?;
+        let request: SyncRequest = serde_json::from_slice(&body)
+            .map_err(|e| crate::error!("Invalid sync request: {}", e))?;
+
+        let response = self.apply(request).await?;
+        if response.merged.hosts_added + response.merged.hosts_replaced + response.merged.keys_added
+            > 0
+        {
+            crate::info!(
+                "Synced from node {}: {} host(s) added, {} replaced, {} key(s) authorized",
+                reduced_node_id(&peer),
+                response.merged.hosts_added,
+                response.merged.hosts_replaced,
+                response.merged.keys_added
+            );
+        }
+
+        let body = serde_json::to_vec(&response).map_err(|e| crate::error!("{}", e))?;
+        AsyncWriteExt::write_all(&mut send, &body).await?;
+        send.finish()
+            .map_err(|e| crate::error!("Failed to finish stream: {}", e))?;
+        send.stopped().await.ok();
+        Ok(())
+    }
+
+    async fn apply(&self, request: SyncRequest) -> Result<SyncResponse> {
+        let hosts = HostManager::new(self.config.manager().clone());
+        let mut merged = Merged::default();
+
+        if let Some(pushed) = request.hosts {
+            (merged.hosts_added, merged.hosts_replaced) =
+                hosts.merge_hosts(pushed, &self.node_id).await?;
+        }
+        if let Some(keys) = request.authorized_keys {
+            merged.keys_added = self.auth_manager.authorize_all(&keys).await?;
+        }
+
+        Ok(SyncResponse {
+            hosts: hosts.list_hosts().await?,
+            authorized_keys: if request.want_keys {
+                self.auth_manager.list_authorized().await?
+            } else {
+                Vec::new()
+            },
+            merged,
+        })
+    }
+}
+
+impl ProtocolHandler for SyncService {
+    fn on_connecting(
+        &self,
+        connecting: iroh::endpoint::Connecting,
+    ) -> BoxFuture<anyhow::Result<Connection>> {
+        let config = Arc::clone(&self.config);
+        let auth_manager = Arc::clone(&self.auth_manager);
+
+        Box::pin(async move {
+            let conn = connecting.await?;
+            let node_id = conn.remote_node_id()?;
+
+            let config = config.get();
+            // A revoked admin could otherwise push a list of authorized keys holding itself
+            let is_admin = auth_manager.is_authorized(&node_id).await?
+                && config.role_of(&node_id) == Role::Admin;
+            if !config.settings.sync_peers.contains(&node_id) && !is_admin {
+                CloseReason::Unauthorized.execute(&conn);
+                anyhow::bail!("Sync request from {}, which is not a sync peer", node_id);
+            }
+
+            Ok(conn)
+        })
+    }
+
+    fn accept(&self, conn: Connection) -> BoxFuture<anyhow::Result<()>> {
+        let service = self.clone();
+
+        Box::pin(async move {
+            let node_id = conn.remote_node_id()?;
+            let (send, recv) = tokio::time::timeout(HANDSHAKE_TIMEOUT, conn.accept_bi()).await??;
+
+            if let Err(e) = service.handle(node_id, send, recv).await {
+                tracing::debug!("Sync with {} failed: {}", node_id, e);
+            }
+            Ok(())
+        })
+    }
+}
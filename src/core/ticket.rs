@@ -0,0 +1,79 @@
+use crate::core::Protocol;
+use iroh::NodeId;
+
+const PREFIX: &str = "punch";
+const VERSION: u8 = 0;
+
+/// Everything a client needs to open a tunnel, as a single string to hand out.
+///
+/// Layout: `[version: u8][node id: 32 bytes][protocol: u8][port: u16 BE]`, base32 encoded
+/// after a `punch` prefix. Tickets don't grant access, the client still has to be authorized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ticket {
+    pub node_id: NodeId,
+    pub protocol: Protocol,
+    pub port: u16,
+}
+
+impl Ticket {
+    pub fn new(node_id: NodeId, protocol: Protocol, port: u16) -> Self {
+        Self {
+            node_id,
+            protocol,
+            port,
+        }
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(36);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(self.node_id.as_bytes());
+        bytes.push(self.protocol as u8);
+        bytes.extend_from_slice(&self.port.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let Some((&version, rest)) = bytes.split_first() else {
+            return Err("Empty ticket".to_string());
+        };
+        if version != VERSION {
+            return Err(format!(
+                "Unsupported ticket version {}, try upgrading punch",
+                version
+            ));
+        }
+        let &[ref key @ .., protocol, port_hi, port_lo] = rest else {
+            return Err("Ticket too short".to_string());
+        };
+        let key: &[u8; 32] = key.try_into().map_err(|_| "Invalid ticket length")?;
+
+        Ok(Self {
+            node_id: NodeId::from_bytes(key).map_err(|e| format!("Invalid node ID: {}", e))?,
+            protocol: Protocol::try_from(protocol)?,
+            port: u16::from_be_bytes([port_hi, port_lo]),
+        })
+    }
+}
+
+impl std::fmt::Display for Ticket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let encoded = data_encoding::BASE32_NOPAD.encode(&self.to_bytes());
+        write!(f, "{}{}", PREFIX, encoded.to_ascii_lowercase())
+    }
+}
+
+impl std::str::FromStr for Ticket {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let encoded = s
+            .trim()
+            .strip_prefix(PREFIX)
+            .ok_or_else(|| format!("Tickets start with '{}'", PREFIX))?;
+        let bytes = data_encoding::BASE32_NOPAD
+            .decode(encoded.to_ascii_uppercase().as_bytes())
+            .map_err(|e| format!("Invalid ticket: {}", e))?;
+        Self::from_bytes(&bytes)
+    }
+}
@@ -0,0 +1,373 @@
+use crate::utils::config::AuthorizationManager;
+use crate::utils::constants::{MAX_TRANSFER_OFFER_SIZE, TRANSFER_ALPN};
+use crate::utils::format::format_bytes;
+use crate::utils::reduced_node_id;
+use crate::{CloseReason, PunchError, Result};
+use iroh::{
+    Endpoint, NodeId,
+    endpoint::{Connection, ConnectionError, RecvStream, SendStream},
+    protocol::{ProtocolHandler, Router},
+};
+use n0_future::boxed::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+const STATUS_OK: u8 = 0x0;
+const STATUS_EXISTS: u8 = 0x1;
+const STATUS_INVALID: u8 = 0x2;
+const STATUS_BUSY: u8 = 0x3;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// What the sender announces before each file.
+#[derive(Debug, Serialize, Deserialize)]
+struct Offer {
+    name: String,
+    size: u64,
+}
+
+/// A file that made it to the receiver.
+#[derive(Debug, Clone)]
+pub struct Sent {
+    pub name: String,
+    pub size: u64,
+    /// Bytes the receiver already had from an interrupted transfer
+    pub resumed_from: u64,
+}
+
+/// Connects to the transfer service of `node_id`, run by `punch receive`.
+pub async fn connect(endpoint: &Endpoint, node_id: NodeId) -> Result<Connection> {
+    Ok(endpoint.connect(node_id, TRANSFER_ALPN).await?)
+}
+
+/// Sends the file at `path`, picking up where an interrupted transfer of it stopped.
+/// `progress` is told how many bytes of the file the receiver has so far.
+pub async fn send_file(
+    conn: &Connection,
+    path: &Path,
+    mut progress: impl FnMut(u64, u64),
+) -> Result<Sent> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| crate::error!("Invalid file name: {}", path.display()))?
+        .to_string();
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| crate::error!(source = e, "Failed to open {}", path.display()))?;
+    let size = file.metadata().await?.len();
+
+    let (mut send, mut recv) = conn.open_bi().await.map_err(closed)?;
+    let offer = serde_json::to_vec(&Offer {
+        name: name.clone(),
+        size,
+    })
+    .map_err(|e| crate::error!("{}", e))?;
+    send.write_u32(offer.len() as u32).await?;
+    AsyncWriteExt::write_all(&mut send, &offer).await?;
+
+    let offset = match read_status(conn, &mut recv).await? {
+        STATUS_OK => recv.read_u64().await?.min(size),
+        STATUS_EXISTS => return Err(crate::error!("{} already exists on the receiver", name)),
+        STATUS_BUSY => {
+            return Err(crate::error!(
+                "{} is already being sent to the receiver, try again once it is done",
+                name
+            ));
+        }
+        _ => return Err(crate::error!("The receiver refused {}", name)),
+    };
+
+    file.seek(std::io::SeekFrom::Start(offset)).await?;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut done = offset;
+    progress(done, size);
+    while done < size {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Err(crate::error!("{} shrank while being sent", path.display()));
+        }
+        AsyncWriteExt::write_all(&mut send, &buf[..n]).await?;
+        done += n as u64;
+        progress(done, size);
+    }
+    send.finish()
+        .map_err(|e| crate::error!("Failed to finish stream: {}", e))?;
+
+    // Only done once the receiver has the whole file on disk
+    match read_status(conn, &mut recv).await? {
+        STATUS_OK => Ok(Sent {
+            name,
+            size,
+            resumed_from: offset,
+        }),
+        _ => Err(crate::error!("The receiver failed to save {}", name)),
+    }
+}
+
+async fn read_status(conn: &Connection, recv: &mut RecvStream) -> Result<u8> {
+    recv.read_u8().await.map_err(|e| match conn.close_reason() {
+        Some(reason) => closed(reason),
+        None => crate::error!("The receiver closed the transfer: {}", e),
+    })
+}
+
+/// Explains that the receiver refused us, e.g. as it doesn't authorize our key.
+fn closed(e: ConnectionError) -> PunchError {
+    match e {
+        ConnectionError::ApplicationClosed(close) => PunchError::from(&close),
+        e => e.into(),
+    }
+}
+
+/// Receives files into `dir` from authorized keys until interrupted, or until the first
+/// sender is done unless `keep_open` is set.
+pub async fn receive(
+    endpoint: Endpoint,
+    auth_manager: Arc<AuthorizationManager>,
+    dir: PathBuf,
+    keep_open: bool,
+) -> Result<()> {
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .map_err(|e| crate::error!(source = e, "Failed to create {}", dir.display()))?;
+
+    let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+    let service = TransferService {
+        auth_manager,
+        dir,
+        in_flight: Arc::default(),
+        done: done_tx,
+    };
+    let router = Router::builder(endpoint)
+        .accept(TRANSFER_ALPN, service)
+        .spawn();
+
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            Some(()) = done_rx.recv() => {
+                if !keep_open {
+                    break;
+                }
+            }
+        }
+    }
+
+    router
+        .shutdown()
+        .await
+        .map_err(|e| crate::error!("Failed to stop receiving: {}", e))?;
+    Ok(())
+}
+
+/// Saves the files sent by authorized nodes.
+#[derive(Debug, Clone)]
+struct TransferService {
+    auth_manager: Arc<AuthorizationManager>,
+    dir: PathBuf,
+    in_flight: Arc<InFlight>,
+    /// Told whenever a sender that got at least one file across is done
+    done: mpsc::UnboundedSender<()>,
+}
+
+/// Names of the files being received, whose `.part` file a second sender would write to as
+/// well.
+#[derive(Debug, Default)]
+struct InFlight(Mutex<HashSet<String>>);
+
+impl InFlight {
+    /// Claims `name` until the returned guard is dropped, `None` if it is already claimed.
+    fn claim(self: &Arc<Self>, name: &str) -> Option<Claim> {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(name.to_string())
+            .then(|| Claim {
+                in_flight: Arc::clone(self),
+                name: name.to_string(),
+            })
+    }
+}
+
+struct Claim {
+    in_flight: Arc<InFlight>,
+    name: String,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        self.in_flight.0.lock().unwrap().remove(&self.name);
+    }
+}
+
+impl TransferService {
+    async fn receive_file(
+        &self,
+        peer: NodeId,
+        mut send: SendStream,
+        mut recv: RecvStream,
+    ) -> Result<()> {
+        let len = recv.read_u32().await? as usize;
+        if len > MAX_TRANSFER_OFFER_SIZE {
+            return Err(crate::error!("Transfer offer too large: {} bytes", len));
+        }
+        let mut offer = vec![0u8; len];
+        AsyncReadExt::read_exact(&mut recv, &mut offer).await?;
+        let offer: Offer = serde_json::from_slice(&offer)
+            .map_err(|e| crate::error!("Invalid transfer offer: {}", e))?;
+
+        let Some(path) = file_path(&self.dir, &offer.name) else {
+            send.write_u8(STATUS_INVALID).await?;
+            return Err(crate::error!("Invalid file name: {:?}", offer.name));
+        };
+        let Some(_claim) = self.in_flight.claim(&offer.name) else {
+            send.write_u8(STATUS_BUSY).await?;
+            return Err(crate::error!(
+                "Not receiving {} twice at once, sent by node {}",
+                offer.name,
+                reduced_node_id(&peer)
+            ));
+        };
+        if tokio::fs::try_exists(&path).await? {
+            send.write_u8(STATUS_EXISTS).await?;
+            return Err(crate::error!(
+                "Not overwriting {}, sent by node {}",
+                path.display(),
+                reduced_node_id(&peer)
+            ));
+        }
+
+        // Kept next to the file until it is complete, so that a new attempt picks it up
+        let part_path = path.with_file_name(format!("{}.part", offer.name));
+        let mut part = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&part_path)
+            .await
+            .map_err(|e| crate::error!(source = e, "Failed to create {}", part_path.display()))?;
+        let mut offset = part.metadata().await?.len();
+        if offset > offer.size {
+            part.set_len(0).await?;
+            offset = 0;
+        }
+        if offset > 0 {
+            crate::info!("Resuming {} from {}", offer.name, format_bytes(offset));
+        }
+        send.write_u8(STATUS_OK).await?;
+        send.write_u64(offset).await?;
+
+        let remaining = offer.size - offset;
+        let copied = tokio::io::copy(&mut (&mut recv).take(remaining), &mut part).await?;
+        part.sync_all().await?;
+        if copied < remaining {
+            return Err(crate::error!(
+                "Transfer of {} interrupted at {} of {}",
+                offer.name,
+                format_bytes(offset + copied),
+                format_bytes(offer.size)
+            ));
+        }
+
+        tokio::fs::rename(&part_path, &path).await?;
+        send.write_u8(STATUS_OK).await?;
+        send.finish()
+            .map_err(|e| crate::error!("Failed to finish stream: {}", e))?;
+        send.stopped().await.ok();
+
+        crate::success!(
+            "Received {} ({}) from node {}",
+            path.display(),
+            format_bytes(offer.size),
+            reduced_node_id(&peer)
+        );
+        Ok(())
+    }
+}
+
+/// Where to save a file sent as `name`, `None` for names that would leave `dir`.
+fn file_path(dir: &Path, name: &str) -> Option<PathBuf> {
+    let valid = !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\', '\0'])
+        && !name.ends_with(".part");
+    valid.then(|| dir.join(name))
+}
+
+impl ProtocolHandler for TransferService {
+    fn on_connecting(
+        &self,
+        connecting: iroh::endpoint::Connecting,
+    ) -> BoxFuture<anyhow::Result<Connection>> {
+        let auth_manager = Arc::clone(&self.auth_manager);
+
+        Box::pin(async move {
+            let conn = connecting.await?;
+            let node_id = conn.remote_node_id()?;
+
+            if !auth_manager.is_authorized(&node_id).await? {
+                crate::warning!(
+                    "Refusing files from unauthorized node {}",
+                    reduced_node_id(&node_id)
+                );
+                CloseReason::Unauthorized.execute(&conn);
+                anyhow::bail!("Unauthorized transfer from {}", node_id);
+            }
+
+            Ok(conn)
+        })
+    }
+
+    fn accept(&self, conn: Connection) -> BoxFuture<anyhow::Result<()>> {
+        let service = self.clone();
+
+        Box::pin(async move {
+            let node_id = conn.remote_node_id()?;
+            let received = Arc::new(AtomicUsize::new(0));
+
+            let mut tasks = tokio::task::JoinSet::new();
+            while let Ok((send, recv)) = conn.accept_bi().await {
+                let service = service.clone();
+                let received = Arc::clone(&received);
+                tasks.spawn(async move {
+                    match service.receive_file(node_id, send, recv).await {
+                        Ok(()) => {
+                            received.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Err(e) => crate::warning!("{}", e),
+                    }
+                });
+            }
+            tasks.join_all().await;
+
+            if received.load(Ordering::Relaxed) > 0 {
+                let _ = service.done.send(());
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_received_once_at_a_time() {
+        let in_flight = Arc::new(InFlight::default());
+
+        let claim = in_flight.claim("backup.tar");
+        assert!(claim.is_some());
+        assert!(in_flight.claim("backup.tar").is_none());
+        assert!(in_flight.claim("notes.txt").is_some());
+
+        drop(claim);
+        assert!(in_flight.claim("backup.tar").is_some());
+    }
+}
@@ -0,0 +1,422 @@
+use crate::core::buffer::{BufferPool, PooledBuffer};
+use crate::utils::constants::UDP_BATCH_SIZE;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+/// A packet taken in by [`recv_batch`], in a buffer of the pool.
+pub struct Packet {
+    buf: PooledBuffer,
+    len: usize,
+    pub from: SocketAddr,
+}
+
+impl Packet {
+    pub fn payload(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// When a UDP session last carried a packet, either way, shared by the tasks forwarding
+/// each direction.
+#[derive(Debug)]
+pub struct LastSeen {
+    started: Instant,
+    /// Milliseconds from `started`
+    seen: AtomicU64,
+}
+
+impl LastSeen {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    pub fn touch(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.seen.store(elapsed, Ordering::Relaxed);
+    }
+
+    pub fn idle(&self) -> Duration {
+        let seen = Duration::from_millis(self.seen.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(seen)
+    }
+
+    /// Resolves once no packet went by for `timeout`.
+    pub async fn idle_for(&self, timeout: Duration) {
+        loop {
+            let idle = self.idle();
+            if idle >= timeout {
+                return;
+            }
+            tokio::time::sleep(timeout - idle).await;
+        }
+    }
+}
+
+impl Default for LastSeen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Waits for packets on `socket` and takes in those already queued, up to
+/// `UDP_BATCH_SIZE`, in place of the previous content of `batch`. Linux reads them with a
+/// single `recvmmsg` call, other platforms one at a time.
+///
+/// Buffers are only borrowed once packets are ready, so idle sockets hold none.
+#[cfg(target_os = "linux")]
+pub async fn recv_batch(
+    socket: &UdpSocket,
+    buffers: &Arc<BufferPool>,
+    batch: &mut Vec<Packet>,
+) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    batch.clear();
+    loop {
+        socket.readable().await?;
+
+        let mut bufs: Vec<PooledBuffer> = (0..UDP_BATCH_SIZE).map(|_| buffers.get()).collect();
+        let received = socket.try_io(tokio::io::Interest::READABLE, || {
+            linux::recvmmsg(socket.as_raw_fd(), &mut bufs)
+        });
+        match received {
+            Ok(received) => {
+                for ((len, from), buf) in received.into_iter().zip(bufs) {
+                    if let Some(from) = from {
+                        batch.push(Packet { buf, len, from });
+                    }
+                }
+                return Ok(());
+            }
+            // Someone else took the packets in first
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn recv_batch(
+    socket: &UdpSocket,
+    buffers: &Arc<BufferPool>,
+    batch: &mut Vec<Packet>,
+) -> std::io::Result<()> {
+    batch.clear();
+    socket.readable().await?;
+
+    let mut buf = buffers.get();
+    let (len, from) = socket.recv_from(&mut buf).await?;
+    batch.push(Packet { buf, len, from });
+    Ok(())
+}
+
+/// Sends `packets` to their destination, `None` being the peer of a connected socket, with
+/// a single `sendmmsg` call on Linux. Runs of packets to the same destination and of the
+/// same size, such as the chunks of a stream, are handed to the kernel at once to be split
+/// by UDP segmentation offload (GSO) where it is available. A packet that can't be sent is
+/// skipped, the error only being returned when none could. Returns the bytes sent.
+#[cfg(target_os = "linux")]
+pub async fn send_batch(
+    socket: &UdpSocket,
+    packets: &[(&[u8], Option<SocketAddr>)],
+) -> std::io::Result<u64> {
+    use std::os::fd::AsRawFd;
+
+    let mut gso = linux::gso_enabled();
+    let mut sent = 0;
+    let mut bytes = 0;
+    let mut error = None;
+    while sent < packets.len() {
+        socket.writable().await?;
+
+        let pending = &packets[sent..];
+        let segments = linux::segments(pending, gso);
+        let result = socket.try_io(tokio::io::Interest::WRITABLE, || {
+            if segments > 1 {
+                linux::send_segments(socket.as_raw_fd(), &pending[..segments]).map(|()| segments)
+            } else {
+                let unsegmented = (1..pending.len())
+                    .find(|&i| linux::segments(&pending[i..], gso) > 1)
+                    .unwrap_or(pending.len());
+                linux::sendmmsg(socket.as_raw_fd(), &pending[..unsegmented])
+            }
+        });
+        match result {
+            Ok(count) => {
+                bytes += pending[..count]
+                    .iter()
+                    .map(|(payload, _)| payload.len() as u64)
+                    .sum::<u64>();
+                sent += count;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(e) if segments > 1 && linux::disables_gso(&e) => {
+                tracing::debug!("UDP segmentation offload unavailable: {}", e);
+                gso = false;
+            }
+            // The call only fails when the first packet does, the others are still sent.
+            // Segments share their destination, so they would fail alike
+            Err(e) => {
+                error = Some(e);
+                sent += segments.max(1);
+            }
+        }
+    }
+
+    match error {
+        Some(e) if bytes == 0 => Err(e),
+        _ => Ok(bytes),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn send_batch(
+    socket: &UdpSocket,
+    packets: &[(&[u8], Option<SocketAddr>)],
+) -> std::io::Result<u64> {
+    let mut bytes = 0;
+    let mut error = None;
+    for (payload, destination) in packets {
+        let result = match destination {
+            Some(destination) => socket.send_to(payload, destination).await,
+            None => socket.send(payload).await,
+        };
+        match result {
+            Ok(_) => bytes += payload.len() as u64,
+            Err(e) => error = Some(e),
+        }
+    }
+
+    match error {
+        Some(e) if bytes == 0 => Err(e),
+        _ => Ok(bytes),
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use nix::errno::Errno;
+    use nix::sys::socket::{
+        self, ControlMessage, MsgFlags, MultiHeaders, SockaddrLike, SockaddrStorage,
+    };
+    use std::io::{IoSlice, IoSliceMut};
+    use std::net::SocketAddr;
+    use std::os::fd::RawFd;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// `UDP_MAX_SEGMENTS` of the kernel
+    const MAX_GSO_SEGMENTS: usize = 64;
+    /// The payload of a single IPv4 UDP datagram, which the segments are sent as to the kernel
+    const MAX_GSO_BYTES: usize = 65507;
+
+    /// Cleared on the first error telling that the kernel or the interface can't segment
+    static GSO: AtomicBool = AtomicBool::new(true);
+
+    pub fn gso_enabled() -> bool {
+        GSO.load(Ordering::Relaxed)
+    }
+
+    /// Whether `e`, returned for segments, means that GSO can't be used. Kernels before 4.18
+    /// don't know about it, and interfaces without checksum offload fail with `EIO`. Segments
+    /// above the MTU fail with `EINVAL` too, so from then on packets go out one by one as
+    /// they would without GSO.
+    pub fn disables_gso(e: &std::io::Error) -> bool {
+        let unusable = [
+            Errno::EIO,
+            Errno::EINVAL,
+            Errno::EOPNOTSUPP,
+            Errno::ENOPROTOOPT,
+        ]
+        .iter()
+        .any(|&errno| e.raw_os_error() == Some(errno as i32));
+        if unusable {
+            GSO.store(false, Ordering::Relaxed);
+        }
+        unusable
+    }
+
+    /// How many of the first `packets` can be sent as segments of a single datagram: they
+    /// go to the same destination and share the size of the first, the last one possibly
+    /// being smaller.
+    pub fn segments(packets: &[(&[u8], Option<SocketAddr>)], gso: bool) -> usize {
+        let [(first, destination), rest @ ..] = packets else {
+            return 0;
+        };
+        let size = first.len();
+        if !gso || size == 0 {
+            return 1;
+        }
+
+        let mut count = 1;
+        let mut total = size;
+        for (payload, to) in rest {
+            if to != destination
+                || payload.is_empty()
+                || payload.len() > size
+                || count == MAX_GSO_SEGMENTS
+                || total + payload.len() > MAX_GSO_BYTES
+            {
+                break;
+            }
+            count += 1;
+            total += payload.len();
+            if payload.len() < size {
+                break;
+            }
+        }
+        count
+    }
+
+    /// Sends `packets`, as told by [`segments`], in a single call for the kernel to split.
+    pub fn send_segments(
+        fd: RawFd,
+        packets: &[(&[u8], Option<SocketAddr>)],
+    ) -> std::io::Result<()> {
+        let slices: Vec<IoSlice> = packets
+            .iter()
+            .map(|(payload, _)| IoSlice::new(payload))
+            .collect();
+        let segment_size = packets[0].0.len() as u16;
+        let destination = packets[0].1.map(SockaddrStorage::from);
+
+        socket::sendmsg(
+            fd,
+            &slices,
+            &[ControlMessage::UdpGsoSegments(&segment_size)],
+            MsgFlags::MSG_DONTWAIT,
+            destination.as_ref(),
+        )?;
+        Ok(())
+    }
+
+    /// Reads a packet into each buffer it can without blocking, returning the length and
+    /// sender of each.
+    pub fn recvmmsg(
+        fd: RawFd,
+        bufs: &mut [super::PooledBuffer],
+    ) -> std::io::Result<Vec<(usize, Option<SocketAddr>)>> {
+        let mut slices: Vec<[IoSliceMut; 1]> =
+            bufs.iter_mut().map(|buf| [IoSliceMut::new(buf)]).collect();
+        let mut headers = MultiHeaders::<SockaddrStorage>::preallocate(slices.len(), None);
+
+        let received = socket::recvmmsg(
+            fd,
+            &mut headers,
+            slices.iter_mut(),
+            MsgFlags::MSG_DONTWAIT,
+            None,
+        )?;
+        Ok(received
+            .map(|msg| (msg.bytes, msg.address.and_then(socket_addr)))
+            .collect())
+    }
+
+    /// Sends as many of `packets` as possible without blocking, returning how many were.
+    pub fn sendmmsg(fd: RawFd, packets: &[(&[u8], Option<SocketAddr>)]) -> std::io::Result<usize> {
+        let slices: Vec<[IoSlice; 1]> = packets
+            .iter()
+            .map(|(payload, _)| [IoSlice::new(payload)])
+            .collect();
+        let addrs: Vec<Option<SockaddrStorage>> = packets
+            .iter()
+            .map(|(_, destination)| destination.map(SockaddrStorage::from))
+            .collect();
+        let mut headers = MultiHeaders::<SockaddrStorage>::preallocate(slices.len(), None);
+
+        let sent = socket::sendmmsg(
+            fd,
+            &mut headers,
+            &slices,
+            &addrs,
+            [],
+            MsgFlags::MSG_DONTWAIT,
+        )?;
+        Ok(sent.count())
+    }
+
+    fn socket_addr(addr: SockaddrStorage) -> Option<SocketAddr> {
+        match addr.family()? {
+            socket::AddressFamily::Inet => Some(SocketAddr::V4((*addr.as_sockaddr_in()?).into())),
+            socket::AddressFamily::Inet6 => Some(SocketAddr::V6((*addr.as_sockaddr_in6()?).into())),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    fn to<'a>(packets: &[(&'a [u8], SocketAddr)]) -> Vec<(&'a [u8], Option<SocketAddr>)> {
+        packets
+            .iter()
+            .map(|&(payload, to)| (payload, Some(to)))
+            .collect()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn groups_segments() {
+        let a: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        let full: &[u8] = &[0; 1200];
+        let short: &[u8] = &[0; 300];
+        let big: &[u8] = &[0; 1300];
+
+        let cases = [
+            ("none", vec![], 0),
+            ("single", to(&[(full, a)]), 1),
+            ("same size", to(&[(full, a), (full, a), (full, a)]), 3),
+            (
+                "short last",
+                to(&[(full, a), (full, a), (short, a), (full, a)]),
+                3,
+            ),
+            ("larger next", to(&[(full, a), (big, a)]), 1),
+            (
+                "other destination",
+                to(&[(full, a), (full, a), (full, b)]),
+                2,
+            ),
+            ("short first", to(&[(short, a), (full, a)]), 1),
+            ("too many", to(&[(short, a); 100]), 64),
+            ("too large", to(&[(big, a); 60]), 50),
+        ];
+
+        for (name, packets, expected) in cases {
+            assert_eq!(linux::segments(&packets, true), expected, "{}", name);
+        }
+        let same = to(&[(full, a), (full, a)]);
+        assert_eq!(linux::segments(&same, false), 1);
+    }
+
+    #[tokio::test]
+    async fn sent_batches_keep_packet_boundaries() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let to = Some(receiver.local_addr().unwrap());
+
+        let payloads: Vec<Vec<u8>> = [1200, 1200, 1200, 500, 1200, 1200, 80]
+            .iter()
+            .enumerate()
+            .map(|(i, &len)| vec![i as u8; len])
+            .collect();
+        let packets: Vec<(&[u8], Option<SocketAddr>)> =
+            payloads.iter().map(|payload| (&payload[..], to)).collect();
+        let sent = send_batch(&sender, &packets).await.unwrap();
+        assert_eq!(sent, payloads.iter().map(|p| p.len() as u64).sum::<u64>());
+
+        let mut buf = [0u8; 2048];
+        for payload in &payloads {
+            let (len, _) = receiver.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], &payload[..]);
+        }
+    }
+}
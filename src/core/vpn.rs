@@ -0,0 +1,413 @@
+use crate::utils::constants::{MAX_VPN_LEASE_SIZE, VPN_ALPN};
+use crate::{PunchError, Result};
+use ipnet::Ipv4Net;
+use iroh::{
+    Endpoint, NodeId,
+    endpoint::{Connection, ConnectionError},
+};
+use serde::{Deserialize, Serialize};
+use std::net::Ipv4Addr;
+
+#[cfg(feature = "vpn")]
+pub use service::VpnService;
+
+/// What the server hands a client joining its VPN.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lease {
+    /// Address of the client's interface
+    pub address: Ipv4Addr,
+    /// The network of the VPN, reached through the client's interface
+    pub subnet: Ipv4Net,
+    /// Address of the server's interface
+    pub gateway: Ipv4Addr,
+    /// Other networks to route through the server
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<Ipv4Net>,
+    pub mtu: u16,
+}
+
+/// Joins the VPN of `node_id`, returning the connection packets go over and the address
+/// leased to us.
+pub async fn connect(endpoint: &Endpoint, node_id: NodeId) -> Result<(Connection, Lease)> {
+    let conn = endpoint.connect(node_id, VPN_ALPN).await?;
+    let mut recv = conn.accept_uni().await.map_err(closed)?;
+    let lease = recv
+        .read_to_end(MAX_VPN_LEASE_SIZE)
+        .await
+        .map_err(|e| crate::error!("Failed to read the VPN lease: {}", e))?;
+    let lease: Lease =
+        serde_json::from_slice(&lease).map_err(|e| crate::error!("Invalid VPN lease: {}", e))?;
+
+    match conn.max_datagram_size() {
+        None => return Err(crate::error!("The server doesn't accept datagrams")),
+        Some(max) if max < usize::from(lease.mtu) => crate::warning!(
+            "Packets over {} bytes won't fit in a datagram and will be dropped, lower vpn.mtu on the server",
+            max
+        ),
+        Some(_) => {}
+    }
+    Ok((conn, lease))
+}
+
+/// Explains why the server closed the connection, e.g. as our key isn't an admin.
+fn closed(e: ConnectionError) -> PunchError {
+    match e {
+        ConnectionError::ApplicationClosed(close) => PunchError::from(&close),
+        e => e.into(),
+    }
+}
+
+/// Source address of an IPv4 packet.
+#[cfg_attr(not(feature = "vpn"), allow(dead_code))]
+fn source(packet: &[u8]) -> Option<Ipv4Addr> {
+    ipv4_address(packet, 12)
+}
+
+/// Destination address of an IPv4 packet.
+#[cfg_attr(not(feature = "vpn"), allow(dead_code))]
+fn destination(packet: &[u8]) -> Option<Ipv4Addr> {
+    ipv4_address(packet, 16)
+}
+
+#[cfg_attr(not(feature = "vpn"), allow(dead_code))]
+fn ipv4_address(packet: &[u8], offset: usize) -> Option<Ipv4Addr> {
+    if packet.len() < 20 || packet[0] >> 4 != 4 {
+        return None;
+    }
+    let octets: [u8; 4] = packet[offset..offset + 4].try_into().ok()?;
+    Some(Ipv4Addr::from(octets))
+}
+
+/// Brings up `interface` with the leased address and routes, then carries packets between
+/// it and the server until interrupted or disconnected. Routes go away with the interface.
+#[cfg(feature = "vpn")]
+pub async fn run(conn: Connection, lease: &Lease, interface: &str) -> Result<()> {
+    let device = interface::Interface::create(
+        interface,
+        lease.address,
+        lease.subnet.prefix_len(),
+        lease.mtu,
+    )?;
+    for route in &lease.routes {
+        interface::add_route(route, device.name()).await?;
+    }
+    crate::success!(
+        "Joined the VPN as {}/{} on {}",
+        lease.address,
+        lease.subnet.prefix_len(),
+        device.name()
+    );
+
+    let outgoing = async {
+        let mut buf = vec![0u8; usize::from(lease.mtu)];
+        loop {
+            let n = device.recv(&mut buf).await?;
+            if let Err(e) = conn.send_datagram(bytes::Bytes::copy_from_slice(&buf[..n])) {
+                tracing::trace!("Dropped a packet of {} bytes: {}", n, e);
+            }
+        }
+    };
+    let incoming = async {
+        loop {
+            let packet = conn.read_datagram().await.map_err(closed)?;
+            device.send(&packet).await?;
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {
+            conn.close(0u8.into(), b"done");
+            Ok(())
+        }
+        result = outgoing => result,
+        result = incoming => result,
+    }
+}
+
+#[cfg(not(feature = "vpn"))]
+pub async fn run(conn: Connection, lease: &Lease, interface: &str) -> Result<()> {
+    let _ = (lease, interface);
+    conn.close(0u8.into(), b"done");
+    Err(crate::error!("punch was built without the vpn feature"))
+}
+
+#[cfg(feature = "vpn")]
+mod interface {
+    use crate::Result;
+    use ipnet::Ipv4Net;
+    use std::net::Ipv4Addr;
+    use tun::AbstractDevice;
+
+    /// A TUN interface carrying IPv4 packets, removed when dropped.
+    pub struct Interface {
+        name: String,
+        device: tun::AsyncDevice,
+    }
+
+    impl std::fmt::Debug for Interface {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("Interface")
+                .field("name", &self.name)
+                .finish()
+        }
+    }
+
+    impl Interface {
+        /// Needs root, or `CAP_NET_ADMIN` on Linux.
+        pub fn create(name: &str, address: Ipv4Addr, prefix: u8, mtu: u16) -> Result<Self> {
+            let netmask = Ipv4Net::new(address, prefix)
+                .map_err(|e| crate::error!("{}", e))?
+                .netmask();
+            let mut config = tun::Configuration::default();
+            config
+                .tun_name(name)
+                .address(address)
+                .netmask(netmask)
+                .mtu(mtu)
+                .up();
+            let device = tun::create_as_async(&config)
+                .map_err(|e| crate::error!("Failed to create the {} interface: {}", name, e))?;
+            let name = device.tun_name().unwrap_or_else(|_| name.to_string());
+            Ok(Self { name, device })
+        }
+
+        pub fn name(&self) -> &str {
+            &self.name
+        }
+
+        pub async fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.device.recv(buf).await
+        }
+
+        pub async fn send(&self, packet: &[u8]) -> std::io::Result<usize> {
+            self.device.send(packet).await
+        }
+    }
+
+    /// Sends the traffic to `route` through `interface`.
+    pub async fn add_route(route: &Ipv4Net, interface: &str) -> Result<()> {
+        let route = route.to_string();
+        let mut command = if cfg!(target_os = "macos") {
+            let mut command = tokio::process::Command::new("route");
+            command.args(["-n", "add", "-net", &route, "-interface", interface]);
+            command
+        } else {
+            let mut command = tokio::process::Command::new("ip");
+            command.args(["route", "replace", &route, "dev", interface]);
+            command
+        };
+        let output = command
+            .output()
+            .await
+            .map_err(|e| crate::error!(source = e, "Failed to add a route to {}", route))?;
+        if !output.status.success() {
+            return Err(crate::error!(
+                "Failed to route {} through {}: {}",
+                route,
+                interface,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "vpn")]
+mod service {
+    use super::{Lease, destination, interface::Interface, source};
+    use crate::utils::config::{AuthorizationManager, Role, VpnSettings};
+    use crate::utils::reduced_node_id;
+    use crate::{CloseDetails, CloseReason, Result};
+    use dashmap::DashMap;
+    use ipnet::Ipv4Net;
+    use iroh::{NodeId, endpoint::Connection, protocol::ProtocolHandler};
+    use n0_future::boxed::BoxFuture;
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use std::sync::{Arc, Mutex};
+
+    /// Leases addresses of `[vpn].subnet` to admin keys and routes packets between them and
+    /// the server's interface. The settings are read once, changing them needs a restart.
+    #[derive(Debug, Clone)]
+    pub struct VpnService {
+        auth_manager: Arc<AuthorizationManager>,
+        interface: Arc<Interface>,
+        subnet: Ipv4Net,
+        gateway: Ipv4Addr,
+        routes: Vec<Ipv4Net>,
+        mtu: u16,
+        /// Kept for as long as the server runs, so that a client gets its address back
+        leases: Arc<Mutex<HashMap<NodeId, Ipv4Addr>>>,
+        peers: Arc<DashMap<Ipv4Addr, Connection>>,
+    }
+
+    impl VpnService {
+        /// Brings up the server's interface, `None` when `settings` offers no VPN. Needs to
+        /// run before privileges are dropped.
+        pub fn start(
+            settings: &VpnSettings,
+            auth_manager: Arc<AuthorizationManager>,
+        ) -> Result<Option<Self>> {
+            let Some(subnet) = settings.subnet else {
+                return Ok(None);
+            };
+            let gateway = subnet
+                .hosts()
+                .next()
+                .ok_or_else(|| crate::error!("vpn.subnet {} has no address to use", subnet))?;
+            let interface = Arc::new(Interface::create(
+                settings.interface(),
+                gateway,
+                subnet.prefix_len(),
+                settings.mtu(),
+            )?);
+            crate::info!(
+                "VPN on {} at {}/{}",
+                interface.name(),
+                gateway,
+                subnet.prefix_len()
+            );
+
+            let service = Self {
+                auth_manager,
+                interface,
+                subnet,
+                gateway,
+                routes: settings.routes.clone(),
+                mtu: settings.mtu(),
+                leases: Arc::new(Mutex::new(HashMap::new())),
+                peers: Arc::new(DashMap::new()),
+            };
+            tokio::spawn(service.clone().route_packets());
+            Ok(Some(service))
+        }
+
+        /// Sends the packets leaving the server's interface to the client they are for.
+        async fn route_packets(self) {
+            let mut buf = vec![0u8; usize::from(self.mtu)];
+            loop {
+                let n = match self.interface.recv(&mut buf).await {
+                    Ok(n) => n,
+                    Err(e) => {
+                        crate::warning!("Stopped reading from {}: {}", self.interface.name(), e);
+                        return;
+                    }
+                };
+                let Some(conn) = destination(&buf[..n]).and_then(|dst| self.peers.get(&dst)) else {
+                    continue;
+                };
+                if let Err(e) = conn.send_datagram(bytes::Bytes::copy_from_slice(&buf[..n])) {
+                    tracing::trace!("Dropped a packet of {} bytes: {}", n, e);
+                }
+            }
+        }
+
+        /// The address of `node_id`, picking the first free one on its first visit.
+        fn lease_for(&self, node_id: NodeId) -> Option<Ipv4Addr> {
+            let mut leases = self.leases.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(address) = leases.get(&node_id) {
+                return Some(*address);
+            }
+            let address = self.subnet.hosts().find(|address| {
+                *address != self.gateway && !leases.values().any(|a| a == address)
+            })?;
+            leases.insert(node_id, address);
+            Some(address)
+        }
+
+        async fn serve(&self, node_id: NodeId, conn: Connection) -> Result<()> {
+            let Some(address) = self.lease_for(node_id) else {
+                CloseReason::TooManyConnections.execute_with(
+                    &conn,
+                    CloseDetails::default().with_message("No address left in the VPN subnet"),
+                );
+                return Err(crate::error!("No address left for {}", node_id));
+            };
+
+            let lease = serde_json::to_vec(&Lease {
+                address,
+                subnet: self.subnet,
+                gateway: self.gateway,
+                routes: self.routes.clone(),
+                mtu: self.mtu,
+            })
+            .map_err(|e| crate::error!("{}", e))?;
+            let mut send = conn.open_uni().await?;
+            send.write_all(&lease)
+                .await
+                .map_err(|e| crate::error!("Failed to send the VPN lease: {}", e))?;
+            send.finish()
+                .map_err(|e| crate::error!("Failed to finish stream: {}", e))?;
+
+            // A client coming back before its previous connection timed out takes over
+            if let Some(previous) = self.peers.insert(address, conn.clone()) {
+                previous.close(0u8.into(), b"replaced");
+            }
+            crate::info!(
+                "Node {} joined the VPN as {}",
+                reduced_node_id(&node_id),
+                address
+            );
+
+            while let Ok(packet) = conn.read_datagram().await {
+                // Nobody gets to speak for another address
+                if source(&packet) != Some(address) {
+                    continue;
+                }
+                if let Err(e) = self.interface.send(&packet).await {
+                    tracing::debug!(
+                        "Failed to write a packet to {}: {}",
+                        self.interface.name(),
+                        e
+                    );
+                }
+            }
+
+            self.peers
+                .remove_if(&address, |_, peer| peer.stable_id() == conn.stable_id());
+            crate::info!("Node {} left the VPN", reduced_node_id(&node_id));
+            Ok(())
+        }
+    }
+
+    impl ProtocolHandler for VpnService {
+        fn on_connecting(
+            &self,
+            connecting: iroh::endpoint::Connecting,
+        ) -> BoxFuture<anyhow::Result<Connection>> {
+            let auth_manager = Arc::clone(&self.auth_manager);
+
+            Box::pin(async move {
+                let conn = connecting.await?;
+                let node_id = conn.remote_node_id()?;
+
+                if !auth_manager.is_authorized(&node_id).await? {
+                    CloseReason::Unauthorized.execute(&conn);
+                    anyhow::bail!("Unauthorized VPN connection from {}", node_id);
+                }
+                // The VPN isn't held to the allowed ports or targets
+                if auth_manager.role_of(&node_id).await? != Role::Admin {
+                    CloseReason::Unauthorized.execute_with(
+                        &conn,
+                        CloseDetails::default().with_message("Only admin keys may join the VPN"),
+                    );
+                    anyhow::bail!("VPN connection from non-admin {}", node_id);
+                }
+
+                Ok(conn)
+            })
+        }
+
+        fn accept(&self, conn: Connection) -> BoxFuture<anyhow::Result<()>> {
+            let service = self.clone();
+
+            Box::pin(async move {
+                let node_id = conn.remote_node_id()?;
+                if let Err(e) = service.serve(node_id, conn).await {
+                    tracing::debug!("VPN connection of {} failed: {}", node_id, e);
+                }
+                Ok(())
+            })
+        }
+    }
+}
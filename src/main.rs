@@ -1,37 +1,496 @@
-use clap::Parser;
-use owo_colors::OwoColorize;
+use anstream::{eprint, eprintln, print, println};
+use clap::{CommandFactory, Parser};
+use inquire::validator::Validation;
+use owo_colors::{OwoColorize, Style};
 use punch::{
-    cli::{Command, HostCommand, Opts},
-    core::{build_endpoint, client::client, server::server},
+    cli::{
+        AccessRequestCommand, AuthCommand, Command, ConfigCommand, HostCommand, KeyCommand, Opts,
+        ServerCommand, SyncCommand, TicketCommand,
+    },
+    core::{
+        Protocol, ProtocolChoice, UdpMode,
+        access::{self, AccessStatus},
+        bench::{self, BenchOptions, BenchReport},
+        build_endpoint,
+        client::{Client, ClientOptions, client},
+        control::{self, ConnectionInfo, ControlRequest, ControlResponse, HealthStatus},
+        discovery::{self, DiscoveredPeer},
+        mapping::{self, Forward, Mapping, SourceFilter},
+        netcheck::{self, Hint, NatMapping},
+        probe::{self, ProbeStatus},
+        qos::Qos,
+        serve,
+        server::{ServerOptions, server},
+        services::{self, ServiceEntry},
+        sync,
+        ticket::Ticket,
+        transfer, vpn,
+    },
     utils::{
-        config::{AuthorizationManager, ConfigManager, HostManager},
-        crypto::load_secret_key,
-        format::format_duration,
-        logging, reduced_node_id,
+        access::AccessRequests,
+        audit::{AuditEvent, AuditLog, AuditRecord},
+        backup::Backup,
+        completions,
+        config::{
+            self, AuthorizationManager, ClientConfig, ConfigManager, Configuration, Host,
+            HostManager, HostStats, Role, RuntimeSettings, ServerConfig, ServiceDefinition,
+            StoreKind,
+        },
+        constants::{
+            DEFAULT_DNS_PORT, DEFAULT_EDITOR, DNS_SERVICE, ENV_BACKUP_PASSPHRASE, STATE_DB_PATH,
+            TRANSFER_PROGRESS_INTERVAL,
+        },
+        crypto::{import_ssh_key, load_secret_key, take_env_secret_key},
+        format::{format_bitrate, format_bytes, format_duration, format_elapsed, format_path},
+        history::{History, HistoryRecord},
+        import::{ImportSource, ImportedKey, parse_keys},
+        logging, output,
+        ports::format_port_ranges,
+        prompt::PromptMode,
+        reduced_node_id, styled, telemetry, upgrade,
+        usage::{Usage, UsageLedger},
+        validate::validate,
     },
 };
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-#[tokio::main]
-async fn main() -> miette::Result<()> {
-    let opts = Opts::parse();
-    run(opts).await?;
-    Ok(())
+fn main() {
+    let mut opts = Opts::parse();
+    // SAFETY: no runtime and so no other thread exists yet
+    opts.env_secret_key = unsafe { take_env_secret_key() };
+    let json = opts.json;
+    let result = build_runtime(&opts).and_then(|runtime| runtime.block_on(run(opts)));
+    if let Err(e) = result {
+        let code = e.exit_code();
+        if json {
+            eprintln!("{}", e.to_json());
+        } else {
+            eprintln!("Error: {:?}", miette::Report::new(e));
+        }
+        std::process::exit(code);
+    }
+}
+
+/// Builds the runtime from the flags and the `[runtime]` settings of the config the command
+/// uses. A config that can't be read leaves Tokio's defaults, its errors are reported once
+/// the command loads it.
+fn build_runtime(opts: &Opts) -> punch::Result<tokio::runtime::Runtime> {
+    if let Some(path) = &opts.config_dir {
+        config::set_config_dir(path.clone());
+    }
+    let settings = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(read_runtime_settings(&opts.command))
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    let settings = opts.runtime_settings(settings);
+
+    let mut builder = if settings.current_thread {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        tokio::runtime::Builder::new_multi_thread()
+    };
+    if let (Some(threads), false) = (settings.worker_threads, settings.current_thread) {
+        builder.worker_threads(threads);
+    }
+    if let Some(threads) = settings.max_blocking_threads {
+        builder.max_blocking_threads(threads);
+    }
+    Ok(builder.enable_all().build()?)
+}
+
+/// Reads the config without saving a default one, which is left to the command.
+async fn read_runtime_settings(command: &Command) -> punch::Result<Option<RuntimeSettings>> {
+    let config_manager = ConfigManager::new()?;
+    Ok(match command {
+        Command::Server { .. } | Command::Serve { .. } => config_manager
+            .read::<ServerConfig>()
+            .await?
+            .map(|config| config.runtime),
+        _ => config_manager
+            .read::<ClientConfig>()
+            .await?
+            .map(|config| config.runtime),
+    })
 }
 
 async fn run(opts: Opts) -> punch::Result<()> {
+    punch::utils::init_colors(opts.no_color);
+    output::set_verbosity(opts.verbosity());
+    output::set_json(opts.json);
+    output::set_stdout_is_data(matches!(opts.command, Command::Stdio { .. }));
     logging::init()?;
 
-    let sk = load_secret_key(&opts).await?;
-    let endpoint = build_endpoint(sk).await?;
+    // Handled before anything is loaded, packagers run them where there is no config
+    if let Command::Completions { shell } = opts.command {
+        completions::generate(shell, &mut Opts::command(), &mut std::io::stdout())?;
+        return Ok(());
+    }
+    if let Command::Manpages { out_dir } = opts.command {
+        let mut command = Opts::command();
+        command.build();
+        std::fs::create_dir_all(&out_dir)?;
+        let count = write_manpages(&command, &out_dir)?;
+        punch::success!("Wrote {} man pages to {}", count, out_dir.display());
+        return Ok(());
+    }
+
+    if let Some(path) = &opts.config_dir {
+        config::set_config_dir(path.clone());
+    }
     let config_manager = ConfigManager::new()?;
 
+    if let Command::Healthcheck { timeout } = opts.command {
+        let status = handle_healthcheck(&config_manager, timeout, opts.quiet).await;
+        std::process::exit(status.exit_code());
+    }
+    let prompt = opts.prompt_mode();
+    // Handled before loading anything, so that a broken config can still be checked and fixed
+    if let Command::Config {
+        command,
+        show_path,
+        store,
+    } = opts.command
+    {
+        return handle_config_command(command, show_path, store, config_manager, prompt).await;
+    }
+    if let Command::Upgrade { check } = opts.command {
+        return handle_upgrade(check, prompt).await;
+    }
+    // Handled before the key is loaded, which would generate one if there is none yet
+    if let Command::Key {
+        command: KeyCommand::Import { path },
+    } = &opts.command
+    {
+        import_ssh_key(&opts, path).await?;
+        return Ok(());
+    }
+    let (mut network, logging, telemetry, target) = match &opts.command {
+        Command::Server { .. } | Command::Serve { .. } => {
+            let config: ServerConfig = config_manager.load().await?;
+            (config.network, config.logging, config.telemetry, None)
+        }
+        command => {
+            let config: ClientConfig = config_manager.load().await?;
+            let target = target_host(command, &config, prompt)?;
+            (config.network, config.logging, config.telemetry, target)
+        }
+    };
+    logging::configure(&logging)?;
+    let _telemetry = telemetry::init(&telemetry)?;
+    // The endpoint can only relay through the pinned relay, so it must be known before binding
+    if let Some(relay_url) = opts
+        .relay_url
+        .clone()
+        .or_else(|| target.as_ref().and_then(|host| host.relay_url.clone()))
+    {
+        network.relay_url = Some(relay_url);
+    }
+    let relay_url = network.relay_url.clone();
+
+    let sk = load_secret_key(&opts).await?;
+    let endpoint = build_endpoint(sk, &network).await?;
+
     match opts.command {
-        Command::Server {} => server(endpoint).await?,
+        Command::Server {
+            command: None,
+            confirm,
+            remember,
+        } => {
+            let options = ServerOptions {
+                confirm,
+                remember,
+                ..Default::default()
+            };
+            server(endpoint, options).await?
+        }
+        Command::Serve { dir, name, port } => serve_dir(endpoint, dir, name, port).await?,
+        Command::Server {
+            command: Some(command),
+            ..
+        } => handle_server_command(command, config_manager).await?,
         Command::Client {
+            to,
+            mapping,
+            mappings_file,
+            protocol,
+            service,
+            local_port,
+            list_services,
+            dns,
+            set_resolver,
+            ticket,
+            bind,
+            allow_from,
+            remote_host,
+            datagrams,
+            oversized,
+            connections,
+            idle_exit,
+            dscp,
+            priority,
+        } => {
+            // Checked before connecting, so that a typo doesn't leave half of the tunnels up
+            let mappings = match &mappings_file {
+                Some(path) => Some(mapping::load_mappings_file(path).await?),
+                None => None,
+            };
+            let (to, mapping, protocol) = match ticket {
+                Some(ticket) => (
+                    ticket.node_id.to_string(),
+                    Some(Mapping {
+                        bind: None,
+                        local_port: local_port.unwrap_or(ticket.port),
+                        remote_port: ticket.port,
+                    }),
+                    ticket.protocol.into(),
+                ),
+                // clap makes sure there is a host when there is no ticket
+                None => (to.unwrap_or_default(), mapping, protocol),
+            };
+
+            if list_services {
+                let mut config: ClientConfig = config_manager.load().await?;
+                let node_id = config.resolve_host(endpoint.dns_resolver(), &to).await?;
+                print_services(&services::list(&endpoint, node_id).await?);
+                return Ok(());
+            }
+
+            if set_resolver && dns != Some(DEFAULT_DNS_PORT) {
+                return Err(punch::error!(
+                    "--set-resolver needs --dns on port {}, resolv.conf has no say in the port",
+                    DEFAULT_DNS_PORT
+                ));
+            }
+            // DNS is a built-in service of the server, reached over both protocols
+            let (service, protocol) = match dns {
+                Some(_) => (Some(DNS_SERVICE.to_string()), ProtocolChoice::Both),
+                None => (service, protocol),
+            };
+
+            // With a service, the remote port (and the local one by default) come from the server
+            let mapping = mapping.unwrap_or(Mapping {
+                bind: None,
+                local_port: dns.or(local_port).unwrap_or(0),
+                remote_port: 0,
+            });
+            let options = ClientOptions {
+                bind,
+                allowed_sources: SourceFilter::new(allow_from),
+                remote_host,
+                udp_mode: if datagrams {
+                    UdpMode::Datagram
+                } else {
+                    UdpMode::Stream
+                },
+                oversized,
+                service,
+                prompt,
+                relay_url,
+                connections: connections.map(usize::from),
+                idle_exit: idle_exit.map(Duration::from_secs),
+                set_resolver,
+                qos: Qos { dscp, priority },
+            };
+            match (mappings, protocol) {
+                (None, ProtocolChoice::One(protocol)) => {
+                    client(endpoint, to, mapping, protocol, options).await?
+                }
+                (mappings, protocol) => {
+                    let forwards = mappings.unwrap_or_else(|| Forward::expand(mapping, protocol));
+                    Client::new(endpoint, options)
+                        .await?
+                        .connect_many(to, forwards)
+                        .await?
+                }
+            }
+        }
+        Command::Run {
             to,
             mapping,
             protocol,
-        } => client(endpoint, to, mapping, protocol).await?,
+            bind,
+            remote_host,
+            command,
+        } => {
+            let options = ClientOptions {
+                bind,
+                remote_host,
+                prompt,
+                relay_url,
+                ..Default::default()
+            };
+            let client = Client::new(endpoint, options).await?;
+            let code = client.run(to, mapping, protocol, command).await?;
+            std::process::exit(code);
+        }
+        Command::Balance {
+            mapping,
+            hosts,
+            strategy,
+            bind,
+            allow_from,
+        } => {
+            let options = ClientOptions {
+                bind,
+                allowed_sources: SourceFilter::new(allow_from),
+                prompt,
+                relay_url,
+                ..Default::default()
+            };
+            Client::new(endpoint, options)
+                .await?
+                .balance(hosts, mapping, strategy)
+                .await?
+        }
+        Command::Stdio {
+            to,
+            port,
+            remote_host,
+        } => {
+            let options = ClientOptions {
+                remote_host,
+                prompt,
+                relay_url,
+                ..Default::default()
+            };
+            Client::new(endpoint, options)
+                .await?
+                .stdio(to, port)
+                .await?
+        }
+        Command::Connect { mapping, .. } => {
+            let host = target.ok_or_else(|| punch::error!("No host to connect to"))?;
+            let (mapping, protocol) = host_mapping(&host, mapping, prompt)?;
+            let options = ClientOptions {
+                prompt,
+                relay_url,
+                ..Default::default()
+            };
+            client(endpoint, host.id.to_string(), mapping, protocol, options).await?
+        }
+        Command::Bench {
+            to,
+            duration,
+            pings,
+        } => {
+            let mut config: ClientConfig = config_manager.load().await?;
+            let node_id = config.resolve_host(endpoint.dns_resolver(), &to).await?;
+
+            punch::info!("Benchmarking node {}", reduced_node_id(&node_id));
+            let options = BenchOptions {
+                duration: std::time::Duration::from_secs(duration),
+                pings,
+            };
+            let report = bench::run(&endpoint, node_id, &options).await?;
+            print_bench_report(&report);
+        }
+        Command::Probe {
+            to,
+            port,
+            remote_host,
+        } => {
+            let mut config: ClientConfig = config_manager.load().await?;
+            let node_id = config.resolve_host(endpoint.dns_resolver(), &to).await?;
+            let target = format!("{}:{}", remote_host.as_deref().unwrap_or("localhost"), port);
+
+            let status = probe::probe(&endpoint, node_id, port, remote_host).await?;
+            let styled_status = match status {
+                ProbeStatus::Open => status.green().bold().to_string(),
+                ProbeStatus::Closed => status.red().bold().to_string(),
+                ProbeStatus::Filtered | ProbeStatus::Forbidden => {
+                    status.yellow().bold().to_string()
+                }
+            };
+            println!("{} on {}: {}", target, to, styled_status);
+            if status != ProbeStatus::Open {
+                std::process::exit(1);
+            }
+        }
+        Command::Vpn { to, interface } => {
+            let mut config: ClientConfig = config_manager.load().await?;
+            let node_id = config.resolve_host(endpoint.dns_resolver(), &to).await?;
+            let (conn, lease) = vpn::connect(&endpoint, node_id).await?;
+            vpn::run(conn, &lease, &interface).await?;
+        }
+        Command::Send { files, to } => {
+            let mut config: ClientConfig = config_manager.load().await?;
+            let node_id = config.resolve_host(endpoint.dns_resolver(), &to).await?;
+            send_files(&endpoint, node_id, &files).await?;
+        }
+        Command::Receive { dir, keep_open } => {
+            let auth_manager = AuthorizationManager::new(config_manager);
+            punch::info!(
+                "Receiving files in {}, send them with {}",
+                dir.display(),
+                styled(
+                    format!("punch send <files> --to {}", endpoint.node_id()),
+                    Style::new().bold()
+                )
+            );
+            transfer::receive(endpoint, auth_manager.into(), dir, keep_open).await?;
+        }
+        Command::Sync { command } => {
+            handle_sync_command(command, &endpoint, &config_manager, prompt).await?
+        }
+        Command::Ticket {
+            command: TicketCommand::Create { port, protocol },
+        } => {
+            let config: ServerConfig = config_manager.load().await?;
+            let allowed = config.settings.effective_ports();
+            if !allowed.iter().any(|range| range.contains(port)) {
+                punch::warning!(
+                    "Port {} is not allowed (allowed ports: {}), clients will be refused",
+                    port,
+                    format_port_ranges(&allowed)
+                );
+            }
+            println!("{}", Ticket::new(endpoint.node_id(), protocol, port));
+        }
+        Command::Discover { timeout, all } => {
+            punch::info!(
+                "Listening for nodes on the local network for {}s...",
+                timeout
+            );
+            let peers = discovery::discover(&endpoint, Duration::from_secs(timeout), all).await;
+            handle_discovered(peers, HostManager::new(config_manager), prompt).await?;
+        }
+        Command::Netcheck { timeout } => {
+            punch::info!("Checking the network...");
+            let report = netcheck::report(&endpoint, Duration::from_secs(timeout)).await?;
+            print_netcheck(&report);
+        }
+        Command::History {
+            host,
+            since,
+            until,
+            lines,
+        } => {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let history = History::new(config_manager.history_path());
+            let records: Vec<_> = history
+                .read()
+                .await?
+                .into_iter()
+                .filter(|r| host.as_deref().is_none_or(|host| r.matches_host(host)))
+                .filter(|r| since.is_none_or(|age| r.started >= now.saturating_sub(age)))
+                .filter(|r| until.is_none_or(|age| r.ended <= now.saturating_sub(age)))
+                .collect();
+
+            if records.is_empty() {
+                punch::info!("No sessions found.");
+            }
+            for record in records.iter().skip(records.len().saturating_sub(lines)) {
+                print_history_record(record, now);
+            }
+        }
         Command::Id { short } => {
             let node_id = endpoint.node_id();
             if short {
@@ -42,37 +501,960 @@ async fn run(opts: Opts) -> punch::Result<()> {
         }
         Command::Hosts { command } => {
             let host_manager = HostManager::new(config_manager);
-            handle_hosts_command(command, host_manager).await?;
+            handle_hosts_command(command, host_manager, &endpoint, prompt).await?;
+        }
+        Command::Auth {
+            command: AuthCommand::Request { to, ports, reason },
+        } => {
+            let mut config: ClientConfig = config_manager.load().await?;
+            let node_id = config.resolve_host(endpoint.dns_resolver(), &to).await?;
+
+            punch::info!("Requesting access to node {}", reduced_node_id(&node_id));
+            match access::request(&endpoint, node_id, ports, reason).await? {
+                AccessStatus::Submitted => {
+                    punch::success!("Access request sent, waiting for the administrator")
+                }
+                AccessStatus::Updated => punch::success!("Pending access request updated"),
+                AccessStatus::AlreadyAuthorized => {
+                    punch::success!("This node is already authorized")
+                }
+                AccessStatus::Throttled => {
+                    punch::warning!("The server gets too many access requests, try again later")
+                }
+                AccessStatus::Disabled => {
+                    punch::warning!("The server doesn't accept access requests")
+                }
+            }
+        }
+        Command::Auth {
+            command: AuthCommand::Requests { command },
+        } => {
+            handle_access_request_command(command, config_manager).await?;
+        }
+        Command::Auth {
+            command: AuthCommand::Import { source },
+        } => {
+            let auth_manager = AuthorizationManager::new(config_manager);
+            import_keys(&source, &auth_manager, endpoint.node_id(), prompt).await?;
         }
         Command::Auth { command } => {
+            let control_socket = config_manager.control_socket_path();
             let auth_manager = AuthorizationManager::new(config_manager);
-            handle_auth_command(command, auth_manager, endpoint.node_id()).await?;
-        }
-        Command::Config { show_path } => {
-            if show_path {
-                let path = dirs::home_dir()
-                    .expect("Could not find home directory")
-                    .join(".punch");
-                println!("Configuration directory: {}", path.display().purple());
+            handle_auth_command(command, auth_manager, control_socket, endpoint.node_id()).await?;
+        }
+        Command::Healthcheck { .. } => unreachable!(),
+        Command::Config { .. } => unreachable!(),
+        Command::Upgrade { .. } => unreachable!(),
+        Command::Key { .. } => unreachable!(),
+        Command::Completions { .. } | Command::Manpages { .. } => unreachable!(),
+    }
+
+    Ok(())
+}
+
+/// Writes the man page of `command`, then those of its subcommands (`punch-client.1` and so
+/// on), returning how many were written. The command has to be built for them to be named.
+fn write_manpages(command: &clap::Command, out_dir: &Path) -> punch::Result<usize> {
+    let man =
+        clap_mangen::Man::new(command.clone()).source(concat!("punch ", env!("CARGO_PKG_VERSION")));
+    let mut page = Vec::new();
+    man.render(&mut page)?;
+    std::fs::write(out_dir.join(man.get_filename()), page)?;
+
+    let mut count = 1;
+    for subcommand in command.get_subcommands() {
+        if !subcommand.is_hide_set() && subcommand.get_name() != "help" {
+            count += write_manpages(subcommand, out_dir)?;
+        }
+    }
+    Ok(count)
+}
+
+async fn handle_upgrade(check: bool, prompt: PromptMode) -> punch::Result<()> {
+    let current = upgrade::current_version();
+    let release = upgrade::latest().await?;
+    if !release.is_newer() {
+        punch::success!("punch {} is up to date", current);
+        return Ok(());
+    }
+    if check {
+        punch::info!(
+            "punch {} is available, {} is installed",
+            release.version.bold(),
+            current
+        );
+        return Ok(());
+    }
+
+    let features = upgrade::enabled_features();
+    if !features.is_empty() {
+        punch::warning!(
+            "This build has the {} feature(s), which released binaries are built without",
+            features.join(", ")
+        );
+    }
+    let message = format!("Upgrade punch from {} to {}?", current, release.version);
+    if !prompt.confirm(&message, features.is_empty())? {
+        return Ok(());
+    }
+
+    punch::info!("Downloading punch {}...", release.version);
+    let binary = upgrade::download(&release).await?;
+    let path = upgrade::install(&binary)?;
+    punch::success!("Upgraded {} to {}", path.display(), release.version.bold());
+    Ok(())
+}
+
+async fn handle_sync_command(
+    command: SyncCommand,
+    endpoint: &iroh::Endpoint,
+    config_manager: &ConfigManager,
+    prompt: PromptMode,
+) -> punch::Result<()> {
+    let (SyncCommand::Push {
+        to,
+        authorized_keys,
+    }
+    | SyncCommand::Pull {
+        to,
+        authorized_keys,
+    }) = &command;
+    let mut config: ClientConfig = config_manager.load().await?;
+    let node_id = config.resolve_host(endpoint.dns_resolver(), to).await?;
+    let host_manager = HostManager::new(config_manager.clone());
+    let auth_manager = AuthorizationManager::new(config_manager.clone());
+
+    match command {
+        SyncCommand::Push { .. } => {
+            let keys = if *authorized_keys {
+                let keys = auth_manager.list_authorized().await?;
+                for key in &keys {
+                    println!("  {}", key);
+                }
+                if !prompt.confirm(
+                    &format!(
+                        "Authorize these {} key(s) on node {}?",
+                        keys.len(),
+                        reduced_node_id(&node_id)
+                    ),
+                    false,
+                )? {
+                    return Err(punch::error!(
+                        "Not pushing authorized keys, pass {} to skip the confirmation",
+                        styled("--yes", Style::new().bold())
+                    ));
+                }
+                Some(keys)
             } else {
-                println!("Configuration files:");
-                println!("  Client: ~/.punch/client.toml");
-                println!("  Server: ~/.punch/server.toml");
-                println!("\nUse --show-path to see the full configuration directory path");
+                None
+            };
+
+            let merged = sync::push(endpoint, node_id, config.hosts, keys).await?;
+            punch::success!(
+                "Pushed to node {}: {} host(s) added, {} replaced, {} key(s) authorized",
+                reduced_node_id(&node_id),
+                merged.hosts_added,
+                merged.hosts_replaced,
+                merged.keys_added
+            );
+        }
+        SyncCommand::Pull { .. } => {
+            let response = sync::pull(endpoint, node_id, *authorized_keys).await?;
+            let (added, replaced) = host_manager
+                .merge_hosts(response.hosts, &endpoint.node_id())
+                .await?;
+
+            let mut new_keys = Vec::new();
+            for key in response.authorized_keys {
+                if key != endpoint.node_id() && !auth_manager.is_authorized(&key).await? {
+                    new_keys.push(key);
+                }
+            }
+            if !new_keys.is_empty() {
+                for key in &new_keys {
+                    println!("  {}", key);
+                }
+                if prompt.confirm(
+                    &format!(
+                        "Authorize these {} key(s) from node {} here?",
+                        new_keys.len(),
+                        reduced_node_id(&node_id)
+                    ),
+                    false,
+                )? {
+                    auth_manager.authorize_all(&new_keys).await?;
+                } else {
+                    punch::warning!(
+                        "Not authorizing the keys of node {}",
+                        reduced_node_id(&node_id)
+                    );
+                    new_keys.clear();
+                }
+            }
+
+            punch::success!(
+                "Pulled from node {}: {} host(s) added, {} replaced, {} key(s) authorized",
+                reduced_node_id(&node_id),
+                added,
+                replaced,
+                new_keys.len()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_config_command(
+    command: Option<ConfigCommand>,
+    show_path: bool,
+    store: Option<StoreKind>,
+    mut config_manager: ConfigManager,
+    prompt: PromptMode,
+) -> punch::Result<()> {
+    match command {
+        Some(ConfigCommand::Validate) => {
+            let mut problems = 0;
+            problems += validate_config::<ClientConfig>(&config_manager).await?;
+            problems += validate_config::<ServerConfig>(&config_manager).await?;
+            if problems > 0 {
+                return Err(punch::error!(
+                    "Found {} problem(s) in the configuration",
+                    problems
+                ));
+            }
+            return Ok(());
+        }
+        Some(ConfigCommand::Edit { server: true }) => {
+            return edit_config::<ServerConfig>(&config_manager, prompt).await;
+        }
+        Some(ConfigCommand::Edit { server: false }) => {
+            return edit_config::<ClientConfig>(&config_manager, prompt).await;
+        }
+        Some(ConfigCommand::Backup { output }) => {
+            let backup = Backup::collect(&config_manager).await?;
+            if backup.is_empty() {
+                return Err(punch::error!(
+                    "Nothing to back up in {}",
+                    config_manager.base_path().display()
+                ));
+            }
+            if output.exists()
+                && !prompt.confirm(&format!("Overwrite {}?", output.display().purple()), false)?
+            {
+                return Err(punch::error!(
+                    "Not overwriting {}, pass {} to skip the confirmation",
+                    output.display(),
+                    styled("--yes", Style::new().bold())
+                ));
+            }
+
+            let passphrase = backup_passphrase(prompt, true)?;
+            tokio::fs::write(&output, backup.encrypt(&passphrase)?).await?;
+            punch::success!(
+                "Backed up {} to {}",
+                backup.files().collect::<Vec<_>>().join(", "),
+                output.display().purple()
+            );
+            return Ok(());
+        }
+        Some(ConfigCommand::Restore { input }) => {
+            let passphrase = backup_passphrase(prompt, false)?;
+            let backup = Backup::decrypt(&tokio::fs::read(&input).await?, &passphrase)?;
+
+            let conflicts = backup.conflicts(&config_manager).await?;
+            if !conflicts.is_empty()
+                && !prompt.confirm(
+                    &format!("Replace the existing {}?", conflicts.join(", ")),
+                    false,
+                )?
+            {
+                return Err(punch::error!(
+                    "Not restoring over the existing {}, pass {} to skip the confirmation",
+                    conflicts.join(", "),
+                    styled("--yes", Style::new().bold())
+                ));
+            }
+
+            backup.restore(&config_manager).await?;
+            punch::success!(
+                "Restored {} from a backup made {}",
+                backup.files().collect::<Vec<_>>().join(", "),
+                format_duration(backup.age())
+            );
+            return Ok(());
+        }
+        None => {}
+    }
+
+    if let Some(store) = store {
+        config_manager.migrate(store).await?;
+        punch::success!("Configuration is now kept in the {} store", store.bold());
+    } else if show_path {
+        println!(
+            "Configuration directory: {}",
+            config_manager.base_path().display().purple()
+        );
+    } else if config_manager.store_kind() == StoreKind::Sqlite {
+        println!(
+            "Configuration database: {}",
+            config_manager.config_path(STATE_DB_PATH).display()
+        );
+    } else {
+        println!("Configuration files:");
+        println!(
+            "  Client: {}",
+            config_manager
+                .config_path(ClientConfig::filename())
+                .display()
+        );
+        println!(
+            "  Server: {}",
+            config_manager
+                .config_path(ServerConfig::filename())
+                .display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Lets the user edit a config until it is valid, like `visudo`. The copy being edited is
+/// kept when giving up, so that the changes are not lost.
+async fn edit_config<C: Configuration>(
+    config_manager: &ConfigManager,
+    prompt: PromptMode,
+) -> punch::Result<()> {
+    if !prompt.is_interactive() {
+        return Err(punch::error!("Editing the configuration needs a terminal"));
+    }
+
+    let (path, original) = config_manager.edit_copy::<C>().await?;
+    let name = path.display().to_string();
+    let content = loop {
+        run_editor(&path).await?;
+        let content = tokio::fs::read_to_string(&path).await?;
+        if content == original {
+            tokio::fs::remove_file(&path).await?;
+            punch::info!("No changes made to {}", C::filename());
+            return Ok(());
+        }
+
+        match validate::<C>(&name, &content) {
+            None => break content,
+            Some(report) => {
+                eprintln!("{:?}", miette::Report::new(report));
+                if !prompt.confirm("Edit again?", true)? {
+                    return Err(punch::error!(
+                        "{} was not changed, the edited copy is kept at {}",
+                        C::filename(),
+                        name
+                    ));
+                }
+            }
+        }
+    };
+
+    config_manager.replace_raw::<C>(&content, &original).await?;
+    tokio::fs::remove_file(&path).await?;
+    punch::success!("Saved {}", C::filename());
+    Ok(())
+}
+
+/// Opens `path` in `$VISUAL` or `$EDITOR` and waits for it to be closed.
+async fn run_editor(path: &std::path::Path) -> punch::Result<()> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| DEFAULT_EDITOR.to_string());
+    // Editors are often set with arguments, e.g. `code --wait`
+    let mut words = editor.split_whitespace();
+    let program = words
+        .next()
+        .ok_or_else(|| punch::error!("$EDITOR is empty"))?;
+
+    let status = tokio::process::Command::new(program)
+        .args(words)
+        .arg(path)
+        .status()
+        .await
+        .map_err(|e| punch::error!(source = e, "Failed to run {}", program))?;
+    if !status.success() {
+        return Err(punch::error!("{} exited with {}", program, status));
+    }
+    Ok(())
+}
+
+/// The passphrase of a backup, from the environment or asked for.
+fn backup_passphrase(prompt: PromptMode, confirm: bool) -> punch::Result<String> {
+    if let Ok(passphrase) = std::env::var(ENV_BACKUP_PASSPHRASE) {
+        return Ok(passphrase);
+    }
+    if !prompt.is_interactive() {
+        return Err(punch::error!(
+            "No passphrase, set {} when there is no terminal",
+            ENV_BACKUP_PASSPHRASE
+        ));
+    }
+
+    let mut question = inquire::Password::new("Backup passphrase:")
+        .with_display_mode(inquire::PasswordDisplayMode::Masked)
+        .with_validator(inquire::min_length!(8, "Use at least 8 characters"));
+    if !confirm {
+        question = question.without_confirmation();
+    }
+    Ok(question.prompt()?)
+}
+
+/// Prints the problems of one config, returning how many were found.
+async fn validate_config<C: Configuration>(config_manager: &ConfigManager) -> punch::Result<usize> {
+    let name = match config_manager.store_kind() {
+        StoreKind::Toml => config_manager
+            .config_path(C::filename())
+            .display()
+            .to_string(),
+        StoreKind::Sqlite => C::filename().to_string(),
+    };
+    let Some(content) = config_manager.read_raw(C::filename()).await? else {
+        punch::info!("{} does not exist yet", name);
+        return Ok(0);
+    };
+
+    match validate::<C>(&name, &content) {
+        Some(report) => {
+            let problems = report.problems();
+            eprintln!("{:?}", miette::Report::new(report));
+            Ok(problems)
+        }
+        None => {
+            punch::success!("{} is valid", name);
+            Ok(0)
+        }
+    }
+}
+
+async fn handle_healthcheck(
+    config_manager: &ConfigManager,
+    timeout: u64,
+    quiet: bool,
+) -> HealthStatus {
+    let path = config_manager.control_socket_path();
+    let response = tokio::time::timeout(
+        std::time::Duration::from_secs(timeout),
+        control::request(&path, &ControlRequest::Health),
+    )
+    .await;
+
+    let report = match response {
+        Ok(Ok(ControlResponse::Health(report))) => report,
+        Ok(Ok(ControlResponse::Error { message })) => {
+            if !quiet {
+                punch::warning!("Server reported an error: {}", message);
+            }
+            return HealthStatus::Degraded;
+        }
+        Ok(Ok(_)) => {
+            if !quiet {
+                punch::warning!("Unexpected response from the server");
+            }
+            return HealthStatus::Degraded;
+        }
+        Ok(Err(e)) => {
+            if !quiet {
+                println!("{} server is {}: {}", "✗".red(), "down".red().bold(), e);
+            }
+            return HealthStatus::Down;
+        }
+        Err(_) => {
+            if !quiet {
+                println!(
+                    "{} server is {}: no answer within {}s",
+                    "✗".red(),
+                    "down".red().bold(),
+                    timeout
+                );
+            }
+            return HealthStatus::Down;
+        }
+    };
+
+    if !quiet {
+        match report.status {
+            HealthStatus::Healthy => punch::success!("Server is {}", "healthy".green().bold()),
+            HealthStatus::Degraded => punch::warning!("Server is {}", "degraded".yellow().bold()),
+            HealthStatus::Down => println!("{} server is {}", "✗".red(), "down".red().bold()),
+        }
+        println!("  Node ID: {}", report.node_id.blue());
+        println!("  Uptime: {}s", report.uptime);
+        println!(
+            "  Connections: {}/{}",
+            report.active_connections, report.max_connections
+        );
+        println!(
+            "  Home relay: {}",
+            report.home_relay.as_deref().unwrap_or("none")
+        );
+        for issue in &report.issues {
+            println!("  {} {}", "-".yellow(), issue);
+        }
+    }
+
+    report.status
+}
+
+async fn handle_server_command(
+    command: ServerCommand,
+    config_manager: ConfigManager,
+) -> punch::Result<()> {
+    match command {
+        ServerCommand::Log { lines, tail } => {
+            let config: ServerConfig = config_manager.load().await?;
+            let Some(path) = config.settings.audit_log else {
+                punch::warning!("Audit log is disabled.");
+                punch::info!(
+                    "Set {} in {} to enable it",
+                    "settings.audit_log".bold(),
+                    config_manager
+                        .config_path(ServerConfig::filename())
+                        .display()
+                        .bold()
+                );
+                return Ok(());
+            };
+
+            let audit_log = AuditLog::new(config_manager.resolve_path(&path));
+            let (records, mut offset) = audit_log.read_since(0).await?;
+            for record in records.iter().skip(records.len().saturating_sub(lines)) {
+                print_audit_record(record);
+            }
+
+            if tail {
+                loop {
+                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                    let (records, next) = audit_log.read_since(offset).await?;
+                    records.iter().for_each(print_audit_record);
+                    offset = next;
+                }
+            }
+        }
+        ServerCommand::Usage { since, top } => {
+            let config: ServerConfig = config_manager.load().await?;
+            if !config.settings.track_usage {
+                punch::warning!("Usage tracking is disabled.");
+                punch::info!(
+                    "Set {} in {} to enable it",
+                    "settings.track_usage".bold(),
+                    config_manager
+                        .config_path(ServerConfig::filename())
+                        .display()
+                        .bold()
+                );
+                return Ok(());
+            }
+
+            let usage = UsageLedger::new(config_manager.usage_path())
+                .report(since)
+                .await?;
+            print_usage(&usage, &config.authorized_keys, since, top);
+        }
+        ServerCommand::Connections => {
+            let path = config_manager.control_socket_path();
+            match control::request(&path, &ControlRequest::Connections).await? {
+                ControlResponse::Connections { connections } => print_connections(&connections),
+                ControlResponse::Error { message } => return Err(anyhow::anyhow!(message).into()),
+                _ => return Err(punch::error!("Unexpected response from the server")),
+            }
+        }
+        ServerCommand::Kick { node_id, id } => {
+            let path = config_manager.control_socket_path();
+            match control::request(&path, &ControlRequest::Kick { node_id, id }).await? {
+                ControlResponse::Kicked { count } => {
+                    punch::success!("Closed {} tunnel(s)", count)
+                }
+                ControlResponse::Error { message } => return Err(anyhow::anyhow!(message).into()),
+                _ => return Err(punch::error!("Unexpected response from the server")),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_usage(
+    usage: &[(iroh::NodeId, Usage)],
+    authorized: &[iroh::NodeId],
+    since: u64,
+    top: usize,
+) {
+    if usage.is_empty() {
+        println!("No sessions over the last {}.", format_elapsed(since));
+        return;
+    }
+
+    println!("Usage over the last {}:", format_elapsed(since));
+    for (node_id, usage) in usage.iter().take(top) {
+        let node_id = if authorized.contains(node_id) {
+            node_id.to_string().blue().to_string()
+        } else {
+            format!("{} {}", node_id.dimmed(), "(revoked)".dimmed())
+        };
+        println!("  {} {}", node_id, format_bytes(usage.total_bytes()).bold());
+        println!(
+            "      in {} / out {}, {} session(s), connected {}",
+            format_bytes(usage.bytes_in),
+            format_bytes(usage.bytes_out),
+            usage.sessions,
+            format_elapsed(usage.duration)
+        );
+    }
+    if usage.len() > top {
+        println!(
+            "  {}",
+            format!("... and {} more", usage.len() - top).dimmed()
+        );
+    }
+}
+
+fn print_connections(connections: &[ConnectionInfo]) {
+    if connections.is_empty() {
+        println!("No active tunnels.");
+        return;
+    }
+
+    println!("Active tunnels:");
+    for connection in connections {
+        let destination = match &connection.service {
+            Some(service) => format!("{} ({})", service, connection.target),
+            None => connection.target.clone(),
+        };
+        println!(
+            "  {} {} {} {} {}",
+            format!("#{}", connection.id).dimmed(),
+            connection.node_id.blue(),
+            connection.protocol,
+            destination.bold(),
+            format_elapsed(connection.duration)
+        );
+        println!(
+            "      in {} / out {}",
+            format_bytes(connection.bytes_in),
+            format_bytes(connection.bytes_out)
+        );
+    }
+}
+
+fn print_services(services: &[ServiceEntry]) {
+    if services.is_empty() {
+        println!("No services available.");
+        return;
+    }
+
+    let width = services.iter().map(|s| s.name.len()).max().unwrap_or(0);
+    println!("Available services:");
+    for service in services {
+        let protocol = service.protocol.to_string().to_lowercase();
+        print!(
+            "  {:width$}  {}/{}",
+            service.name.blue().bold(),
+            service.port,
+            protocol,
+            width = width
+        );
+        match &service.description {
+            Some(description) => println!("  {}", description.dimmed()),
+            None => println!(),
+        }
+    }
+}
+
+fn print_netcheck(report: &iroh::net_report::Report) {
+    let flag = |value: Option<bool>| match value {
+        Some(true) => "yes".green().to_string(),
+        Some(false) => "no".red().to_string(),
+        None => "unknown".dimmed().to_string(),
+    };
+
+    println!("  UDP: {}", flag(Some(report.udp)));
+    print!("  IPv4: {}", flag(Some(report.ipv4)));
+    match report.global_v4 {
+        Some(addr) => println!(" (public address {})", addr),
+        None => println!(),
+    }
+    print!("  IPv6: {}", flag(Some(report.ipv6)));
+    match report.global_v6 {
+        Some(addr) => println!(" (public address {})", addr),
+        None => println!(),
+    }
+    println!("  NAT mapping: {}", NatMapping::from_report(report));
+    println!("  Hairpinning: {}", flag(report.hair_pinning));
+
+    let portmap = report.portmap_probe.as_ref().map(|probe| {
+        [
+            (probe.upnp, "UPnP"),
+            (probe.pcp, "PCP"),
+            (probe.nat_pmp, "NAT-PMP"),
+        ]
+        .into_iter()
+        .filter_map(|(available, name)| available.then_some(name))
+        .collect::<Vec<_>>()
+    });
+    match portmap {
+        Some(protocols) if !protocols.is_empty() => {
+            println!("  Port mapping: {}", protocols.join(", ").green())
+        }
+        Some(_) => println!("  Port mapping: {}", "none".dimmed()),
+        None => println!("  Port mapping: {}", "unknown".dimmed()),
+    }
+    if report.captive_portal == Some(true) {
+        println!("  Captive portal: {}", "yes".red());
+    }
+
+    let mut relays: Vec<_> = report.relay_latency.iter().collect();
+    relays.sort_by_key(|(_, latency)| *latency);
+    if relays.is_empty() {
+        println!("  Relays: {}", "none reachable".red());
+    } else {
+        println!("  Relays:");
+        for (url, latency) in relays {
+            print!("    {} {:.1?}", url, latency);
+            if report.preferred_relay.as_ref() == Some(url) {
+                print!(" {}", "(preferred)".green());
+            }
+            println!();
+        }
+    }
+
+    let hints = netcheck::hints(report);
+    if !hints.is_empty() {
+        println!();
+    }
+    for hint in hints {
+        match hint {
+            Hint::Good(message) => punch::success!("{}", message),
+            Hint::Warning(message) => punch::warning!("{}", message),
+        }
+    }
+}
+
+/// Shares `dir` over HTTP as service `name`, until interrupted.
+async fn serve_dir(
+    endpoint: iroh::Endpoint,
+    dir: PathBuf,
+    name: String,
+    port: u16,
+) -> punch::Result<()> {
+    if !dir.is_dir() {
+        return Err(punch::error!("{} is not a directory", dir.display()));
+    }
+    let listener = tokio::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, port))
+        .await
+        .map_err(|e| punch::error!(source = e, "Failed to listen on port {}", port))?;
+    let port = listener.local_addr()?.port();
+
+    let service = ServiceDefinition {
+        port,
+        protocol: Protocol::Tcp,
+        host: None,
+        description: Some(format!("Files in {}", dir.display())),
+    };
+    let options = ServerOptions {
+        services: [(name.clone(), service)].into(),
+        ..Default::default()
+    };
+
+    punch::info!("Serving {} on http://localhost:{}", dir.display(), port);
+    punch::info!(
+        "Authorized keys can open it with {}, then http://localhost:{}",
+        styled(
+            format!("punch client {} --service {}", endpoint.node_id(), name),
+            Style::new().bold()
+        ),
+        port
+    );
+    tokio::select! {
+        result = serve::serve(listener, dir) => result,
+        result = server(endpoint, options) => result,
+    }
+}
+
+async fn send_files(
+    endpoint: &iroh::Endpoint,
+    node_id: iroh::NodeId,
+    files: &[PathBuf],
+) -> punch::Result<()> {
+    punch::info!("Sending to node {}", reduced_node_id(&node_id));
+    let conn = transfer::connect(endpoint, node_id).await?;
+    let show_progress = output::shows_progress() && std::io::stderr().is_terminal();
+
+    let mut failed = 0;
+    for path in files {
+        let started = std::time::Instant::now();
+        let mut last_update = started;
+        let result = transfer::send_file(&conn, path, |done, total| {
+            if show_progress
+                && (last_update.elapsed() >= TRANSFER_PROGRESS_INTERVAL || done == total)
+            {
+                last_update = std::time::Instant::now();
+                print_transfer_progress(path, done, total, started.elapsed());
+            }
+        })
+        .await;
+        if show_progress {
+            eprint!("\r\x1b[2K");
+        }
+
+        match result {
+            Ok(sent) if sent.resumed_from > 0 => punch::success!(
+                "Sent {} ({}, resumed from {})",
+                sent.name,
+                format_bytes(sent.size),
+                format_bytes(sent.resumed_from)
+            ),
+            Ok(sent) => punch::success!("Sent {} ({})", sent.name, format_bytes(sent.size)),
+            // Nothing else will make it either, e.g. as the receiver doesn't authorize us
+            Err(e) if conn.close_reason().is_some() => return Err(e),
+            Err(e) => {
+                punch::warning!("{}", e);
+                failed += 1;
             }
         }
     }
+    conn.close(0u8.into(), b"done");
+    endpoint.close().await;
 
+    if failed > 0 {
+        return Err(punch::error!(
+            "Failed to send {} of {} files",
+            failed,
+            files.len()
+        ));
+    }
     Ok(())
 }
 
+fn print_transfer_progress(path: &std::path::Path, done: u64, total: u64, elapsed: Duration) {
+    let percent = (done * 100).checked_div(total).unwrap_or(100);
+    let rate = done as f64 * 8.0 / elapsed.as_secs_f64().max(0.001);
+    eprint!(
+        "\r\x1b[2K{} {}/{} ({}%) {}",
+        path.display(),
+        format_bytes(done),
+        format_bytes(total),
+        percent,
+        format_bitrate(rate).dimmed()
+    );
+}
+
+fn print_bench_report(report: &BenchReport) {
+    println!("  Path: {}", format_path(&report.path));
+
+    let rtt = |p: f64| {
+        report
+            .rtt_percentile(p)
+            .map_or_else(|| "-".to_string(), |rtt| format!("{:.1?}", rtt))
+    };
+    println!(
+        "  RTT: p50 {} / p90 {} / p99 {}",
+        rtt(50.0),
+        rtt(90.0),
+        rtt(99.0)
+    );
+    println!(
+        "  Jitter: {}",
+        report
+            .jitter
+            .map_or_else(|| "-".to_string(), |jitter| format!("{:.1?}", jitter))
+    );
+    println!(
+        "  Upload: {}",
+        format_bitrate(report.upload.bits_per_second()).bold()
+    );
+    println!(
+        "  Download: {}",
+        format_bitrate(report.download.bits_per_second()).bold()
+    );
+}
+
+fn print_audit_record(record: &AuditRecord) {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let event = match record.event {
+        AuditEvent::Accepted => record.event.green().to_string(),
+        AuditEvent::Rejected => record.event.red().to_string(),
+        AuditEvent::Closed => record.event.dimmed().to_string(),
+    };
+    let node_id = record
+        .node_id
+        .parse()
+        .map(|id| reduced_node_id(&id))
+        .unwrap_or_else(|_| record.node_id.clone());
+
+    print!(
+        "{} {} {}",
+        format_duration(now.saturating_sub(record.timestamp)).dimmed(),
+        event,
+        node_id
+    );
+    if let Some(tunnel) = record.tunnel {
+        print!(" {}", format!("#{}", tunnel).dimmed());
+    }
+    if let (Some(protocol), Some(port)) = (&record.protocol, record.port) {
+        print!(" {}/{}", protocol, port);
+    }
+    if let Some(target) = &record.target {
+        print!(" -> {}", target);
+    }
+    if let Some(duration) = record.duration {
+        print!(" for {}s", duration);
+    }
+    if let (Some(bytes_in), Some(bytes_out)) = (record.bytes_in, record.bytes_out) {
+        print!(" ({} in, {} out)", bytes_in, bytes_out);
+    }
+    if let Some(reason) = &record.reason {
+        print!(" - {}", reason.dimmed());
+    }
+    println!();
+}
+
+fn print_history_record(record: &HistoryRecord, now: u64) {
+    let peer = match &record.host {
+        Some(name) => name.bold().to_string(),
+        None => record
+            .node_id
+            .parse()
+            .map(|id| reduced_node_id(&id))
+            .unwrap_or_else(|_| record.node_id.clone()),
+    };
+    println!(
+        "{} {} {}/{} for {} ({} up, {} down) - {}",
+        format_duration(now.saturating_sub(record.started)).dimmed(),
+        peer,
+        record.protocol,
+        record.mapping,
+        format_elapsed(record.duration()),
+        format_bytes(record.bytes_up),
+        format_bytes(record.bytes_down),
+        record.reason.dimmed()
+    );
+}
+
 async fn handle_hosts_command(
     command: HostCommand,
     host_manager: HostManager,
+    endpoint: &iroh::Endpoint,
+    prompt: PromptMode,
 ) -> punch::Result<()> {
     match command {
-        HostCommand::List { full } => {
+        HostCommand::List { full, stats, plain } => {
             let hosts = host_manager.list_hosts().await?;
+            if plain {
+                let mut names: Vec<_> = hosts.into_iter().map(|host| host.name).collect();
+                names.sort();
+                for name in names {
+                    println!("{}", name);
+                }
+                return Ok(());
+            }
             if hosts.is_empty() {
                 println!("No hosts configured.");
                 return Ok(());
@@ -94,34 +1476,64 @@ async fn handle_hosts_command(
                     print!(" - {}", desc.dimmed());
                 }
 
-                if let Some(last_connected) = host.last_connected {
-                    let duration = std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs()
-                        .saturating_sub(last_connected);
+                if let Some(relay_url) = &host.relay_url {
+                    print!(" (relay: {})", relay_url);
+                }
 
-                    let time_ago = format_duration(duration);
-                    print!(" (last connected: {})", time_ago.green());
+                if let Some(dns) = &host.dns {
+                    print!(" (dns: {})", dns.purple());
+                }
+
+                if !host.backups.is_empty() {
+                    print!(" (backups: {})", host.backups.len());
+                }
+
+                if let Some(ago) = host.last_connected_ago() {
+                    print!(" (last connected: {})", format_duration(ago).green());
                 }
 
                 println!();
+
+                if stats {
+                    print_host_stats(&host.stats);
+                }
             }
         }
-        HostCommand::Add { name, id } => {
-            let node_id = id
-                .parse()
-                .map_err(|_| anyhow::anyhow!("Invalid node ID format."))?;
+        HostCommand::Add {
+            name,
+            id,
+            dns,
+            relay_url,
+            backups,
+            mapping,
+            protocol,
+        } => {
+            let node_id = match (&id, &dns) {
+                (_, Some(dns)) => discovery::resolve_dns(endpoint.dns_resolver(), dns).await?,
+                (Some(id), None) => id
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid node ID format."))?,
+                (None, None) => unreachable!("clap requires an ID or a domain"),
+            };
 
-            let description = inquire::Text::new("Description (optional):")
-                .with_default("")
-                .prompt()
-                .ok()
+            let description = prompt
+                .is_interactive()
+                .then(|| {
+                    inquire::Text::new("Description (optional):")
+                        .with_default("")
+                        .prompt()
+                        .ok()
+                })
+                .flatten()
                 .filter(|s| !s.is_empty());
 
-            host_manager
-                .add_host(name.clone(), node_id, description)
-                .await?;
+            let host = Host::new(name.clone(), node_id)
+                .with_description(description)
+                .with_relay_url(relay_url)
+                .with_dns(dns)
+                .with_backups(backups)
+                .with_mapping(mapping, protocol);
+            host_manager.add_host(host).await?;
             punch::success!("Added host: {} ({})", name, reduced_node_id(&node_id));
         }
         HostCommand::Remove { identifier } => {
@@ -136,15 +1548,310 @@ async fn handle_hosts_command(
     Ok(())
 }
 
+/// Lists discovered peers, then lets the user save the new ones as hosts.
+async fn handle_discovered(
+    peers: Vec<DiscoveredPeer>,
+    host_manager: HostManager,
+    prompt: PromptMode,
+) -> punch::Result<()> {
+    if peers.is_empty() {
+        println!("No nodes found.");
+        return Ok(());
+    }
+
+    let hosts = host_manager.list_hosts().await?;
+    let mut new_peers = Vec::new();
+    for peer in peers {
+        print!("{}", reduced_node_id(&peer.node_id));
+        if let Some(name) = &peer.name {
+            print!(" {}", name.bold());
+        }
+        if !peer.is_punch {
+            print!(" {}", "(not punch)".dimmed());
+        }
+        let addrs: Vec<String> = peer.addrs.iter().map(ToString::to_string).collect();
+        if !addrs.is_empty() {
+            print!(" - {}", addrs.join(", ").dimmed());
+        }
+        match hosts.iter().find(|h| h.id == peer.node_id) {
+            Some(host) => println!(" (saved as {})", host.name.green()),
+            None => {
+                println!();
+                new_peers.push(DiscoveredChoice(peer));
+            }
+        }
+    }
+
+    if new_peers.is_empty() || !prompt.is_interactive() {
+        return Ok(());
+    }
+
+    let selected = inquire::MultiSelect::new("Save as hosts:", new_peers).prompt()?;
+    for DiscoveredChoice(peer) in selected {
+        let default = peer
+            .name
+            .clone()
+            .filter(|name| !hosts.iter().any(|h| &h.name == name))
+            .unwrap_or_else(|| peer.node_id.fmt_short());
+        let name = inquire::Text::new(&format!("Name for {}:", peer.node_id.fmt_short()))
+            .with_default(&default)
+            .prompt()?;
+
+        match host_manager
+            .add_host(Host::new(name.clone(), peer.node_id))
+            .await
+        {
+            Ok(()) => punch::success!("Added host: {} ({})", name, reduced_node_id(&peer.node_id)),
+            Err(e) => punch::warning!("Failed to add host {}: {}", name, e),
+        }
+    }
+    Ok(())
+}
+
+/// How a discovered peer shows up when picking the ones to save.
+struct DiscoveredChoice(DiscoveredPeer);
+
+impl std::fmt::Display for DiscoveredChoice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let peer = &self.0;
+        write!(f, "{}", peer.node_id.fmt_short())?;
+        if let Some(name) = &peer.name {
+            write!(f, " ({})", name)?;
+        }
+        Ok(())
+    }
+}
+
+fn print_host_stats(stats: &HostStats) {
+    if stats.is_empty() {
+        println!("  {}", "No sessions yet".dimmed());
+        return;
+    }
+    println!(
+        "  {} session(s), {} up, {} down, {} on average",
+        stats.connections,
+        format_bytes(stats.bytes_up).green(),
+        format_bytes(stats.bytes_down).green(),
+        format_elapsed(stats.average_session_secs().unwrap_or(0))
+    );
+}
+
+/// The known host a client command targets, picked by the user for `connect` without a host.
+fn target_host(
+    command: &Command,
+    config: &ClientConfig,
+    prompt: PromptMode,
+) -> punch::Result<Option<Host>> {
+    let to = match command {
+        Command::Connect { to, .. } => return pick_host(config, to.as_deref(), prompt).map(Some),
+        Command::Client { to: Some(to), .. }
+        | Command::Run { to, .. }
+        | Command::Stdio { to, .. }
+        | Command::Bench { to, .. }
+        | Command::Send { to, .. }
+        | Command::Probe { to, .. }
+        | Command::Vpn { to, .. }
+        | Command::Sync {
+            command: SyncCommand::Push { to, .. } | SyncCommand::Pull { to, .. },
+        } => to,
+        _ => return Ok(None),
+    };
+    Ok(config
+        .hosts
+        .iter()
+        .find(|h| &h.name == to || &h.id.to_string() == to)
+        .cloned())
+}
+
+/// Finds the host to connect to, letting the user pick one when none is given.
+fn pick_host(
+    config: &ClientConfig,
+    identifier: Option<&str>,
+    prompt: PromptMode,
+) -> punch::Result<Host> {
+    if let Some(identifier) = identifier {
+        return config
+            .hosts
+            .iter()
+            .find(|h| h.name == identifier || h.id.to_string() == identifier)
+            .cloned()
+            .ok_or_else(|| punch::error!("Unknown host: {}", identifier));
+    }
+
+    if config.hosts.is_empty() {
+        return Err(punch::error!(
+            "No hosts configured, add one with `punch hosts add`"
+        ));
+    }
+    if !prompt.is_interactive() {
+        return Err(punch::error!("No host given and no terminal to pick one"));
+    }
+
+    // Most recently used first
+    let mut hosts = config.hosts.clone();
+    hosts.sort_by_key(|h| std::cmp::Reverse(h.last_connected));
+    let choices = hosts.into_iter().map(HostChoice).collect();
+
+    Ok(inquire::Select::new("Host:", choices)
+        .with_page_size(10)
+        .prompt()?
+        .0)
+}
+
+/// The host's saved mapping, or one the user types in.
+fn host_mapping(
+    host: &Host,
+    mapping: Option<Mapping>,
+    prompt: PromptMode,
+) -> punch::Result<(Mapping, Protocol)> {
+    let protocol = host.protocol.unwrap_or_default();
+    if let Some(mapping) = mapping.or(host.mapping) {
+        return Ok((mapping, protocol));
+    }
+    if !prompt.is_interactive() {
+        return Err(punch::error!(
+            "Host {} has no saved mapping, pass one explicitly",
+            host.name
+        ));
+    }
+
+    let mapping = inquire::Text::new("Mapping ([bind:]local:remote or port):")
+        .with_validator(|input: &str| {
+            Ok(match input.parse::<Mapping>() {
+                Ok(_) => Validation::Valid,
+                Err(e) => Validation::Invalid(e.into()),
+            })
+        })
+        .prompt()?;
+    let mapping = mapping
+        .parse()
+        .map_err(|e: String| punch::error!("{}", e))?;
+    Ok((mapping, protocol))
+}
+
+/// How a host shows up in the picker, which also fuzzy-matches on this text.
+struct HostChoice(Host);
+
+impl std::fmt::Display for HostChoice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let host = &self.0;
+        // Kept uncolored, escape codes would get in the way of the matching
+        write!(f, "{}", host.name)?;
+        if let Some(description) = &host.description {
+            write!(f, " - {}", description)?;
+        }
+        if let Some(mapping) = host.mapping {
+            write!(f, " [{}]", mapping)?;
+        }
+        if let Some(ago) = host.last_connected_ago() {
+            write!(f, " (last connected: {})", format_duration(ago))?;
+        }
+        Ok(())
+    }
+}
+
+/// Authorizes the keys of a file or GitHub user, once the summary is confirmed.
+async fn import_keys(
+    source: &ImportSource,
+    auth_manager: &AuthorizationManager,
+    our_key: iroh::PublicKey,
+    prompt: PromptMode,
+) -> punch::Result<()> {
+    let (keys, skipped) = parse_keys(&source.read().await?);
+
+    let authorized = auth_manager.list_authorized().await?;
+    let mut new_keys: Vec<ImportedKey> = Vec::new();
+    let mut known = 0;
+    for imported in keys {
+        if authorized.contains(&imported.key) || imported.key == our_key {
+            known += 1;
+        } else if !new_keys.iter().any(|k| k.key == imported.key) {
+            new_keys.push(imported);
+        }
+    }
+
+    for line in &skipped {
+        punch::warning!("Skipping line {}: {}", line.line, line.reason);
+    }
+    if new_keys.is_empty() {
+        punch::info!(
+            "No new keys in {} ({} already authorized)",
+            source.to_string().bold(),
+            known
+        );
+        return Ok(());
+    }
+
+    println!("Keys to authorize from {}:", source.to_string().bold());
+    for imported in &new_keys {
+        let origin = match (&imported.comment, imported.from_ssh) {
+            (Some(comment), true) => format!(" (SSH key {})", comment),
+            (None, true) => " (SSH key)".to_string(),
+            (Some(comment), false) => format!(" ({})", comment),
+            (None, false) => String::new(),
+        };
+        println!("  {}{}", imported.key.to_string().blue(), origin.dimmed());
+    }
+    if known > 0 {
+        println!("  {} already authorized", known);
+    }
+
+    if !prompt.confirm(&format!("Authorize {} key(s)?", new_keys.len()), false)? {
+        return Err(punch::error!(
+            "Not importing the keys, pass {} to skip the confirmation",
+            styled("--yes", Style::new().bold())
+        ));
+    }
+    let keys: Vec<_> = new_keys.iter().map(|imported| imported.key).collect();
+    let added = auth_manager.authorize_all(&keys).await?;
+    punch::success!("Authorized {} key(s) from {}", added, source);
+    Ok(())
+}
+
 async fn handle_auth_command(
-    command: punch::cli::AuthCommand,
+    command: AuthCommand,
     auth_manager: AuthorizationManager,
+    control_socket: PathBuf,
     our_key: iroh::PublicKey,
 ) -> punch::Result<()> {
-    use punch::cli::AuthCommand;
-
     match command {
-        AuthCommand::List => {
+        AuthCommand::List { unused: Some(age) } => {
+            let last_seen = auth_manager.last_seen()?.ok_or_else(|| {
+                punch::error!(
+                    "Only the SQLite store keeps track of when keys were used, see `punch config --store sqlite`"
+                )
+            })?;
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+
+            let unused: Vec<_> = auth_manager
+                .list_authorized()
+                .await?
+                .into_iter()
+                .map(|key| (key, last_seen.get(&key).copied()))
+                .filter(|(_, seen)| seen.is_none_or(|seen| now.saturating_sub(seen) >= age))
+                .collect();
+            if unused.is_empty() {
+                println!("Every key was used in the last {}.", format_elapsed(age));
+                return Ok(());
+            }
+
+            println!("Keys unused for {}:", format_elapsed(age));
+            for (key, seen) in unused {
+                let seen = match seen {
+                    Some(seen) => format_duration(now.saturating_sub(seen)),
+                    None => "never".to_string(),
+                };
+                println!(
+                    "  {} {}",
+                    key.to_string().blue(),
+                    format!("(last seen: {})", seen).dimmed()
+                );
+            }
+        }
+        AuthCommand::List { unused: None } => {
             let keys = auth_manager.list_authorized().await?;
             if keys.is_empty() {
                 println!("No authorized keys configured.");
@@ -163,23 +1870,53 @@ async fn handle_auth_command(
                 } else {
                     "".to_string()
                 };
-                println!("  {}. {}{}", i + 1, key.to_string().blue(), marker);
+                let role = match auth_manager.role_of(key).await? {
+                    Role::User => String::new(),
+                    role => format!(" [{}]", role).yellow().to_string(),
+                };
+                println!("  {}. {}{}{}", i + 1, key.to_string().blue(), role, marker);
             }
         }
-        AuthCommand::Add { key } => {
+        AuthCommand::Add { key, role } => {
             let public_key = key
                 .parse()
                 .map_err(|_| anyhow::anyhow!("Invalid public key format."))?;
 
             auth_manager.authorize(public_key).await?;
+            if let Some(role) = role {
+                auth_manager.set_role(public_key, role).await?;
+            }
             punch::success!("Added authorized key: {}", key.blue());
         }
+        AuthCommand::Role { key, role } => {
+            let public_key: iroh::PublicKey = key
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid public key format."))?;
+            if !auth_manager.is_authorized(&public_key).await? {
+                punch::warning!("Key is not authorized, the role applies once it is");
+            }
+
+            auth_manager.set_role(public_key, role).await?;
+            punch::success!("Key {} is now {}", key.blue(), role.bold());
+        }
         AuthCommand::Remove { key } => {
-            let public_key = key
+            let public_key: iroh::PublicKey = key
                 .parse()
                 .map_err(|_| anyhow::anyhow!("Invalid public key format."))?;
 
-            if auth_manager.revoke(&public_key).await? {
+            // A running server revokes the key itself, to also close its open tunnels
+            let request = ControlRequest::Revoke {
+                node_id: public_key.to_string(),
+            };
+            let removed = match control::request(&control_socket, &request).await {
+                Ok(ControlResponse::Revoked { removed }) => removed,
+                Ok(ControlResponse::Error { message }) => {
+                    return Err(anyhow::anyhow!(message).into());
+                }
+                _ => auth_manager.revoke(&public_key).await?,
+            };
+
+            if removed {
                 punch::success!("Removed authorized key: {}", key.blue());
             } else {
                 punch::warning!("Key not found in authorized list");
@@ -189,6 +1926,71 @@ async fn handle_auth_command(
             println!("Your public key: {}", our_key.to_string().blue().bold());
             println!("\nShare this key with server administrators to get access.");
         }
+        AuthCommand::Request { .. } | AuthCommand::Requests { .. } | AuthCommand::Import { .. } => {
+            unreachable!()
+        }
+    }
+    Ok(())
+}
+
+async fn handle_access_request_command(
+    command: AccessRequestCommand,
+    config_manager: ConfigManager,
+) -> punch::Result<()> {
+    let requests = AccessRequests::new(config_manager.access_requests_path());
+    let auth_manager = AuthorizationManager::new(config_manager);
+
+    match command {
+        AccessRequestCommand::List => {
+            let pending = requests.list().await?;
+            if pending.is_empty() {
+                println!("No pending access requests.");
+                return Ok(());
+            }
+
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            println!("Pending access requests:");
+            for request in pending {
+                println!(
+                    "  {} {}",
+                    request.node_id.to_string().blue(),
+                    format_duration(now.saturating_sub(request.timestamp)).dimmed()
+                );
+                if !request.ports.is_empty() {
+                    let ports: Vec<String> = request.ports.iter().map(u16::to_string).collect();
+                    println!("    ports:  {}", ports.join(", "));
+                }
+                if let Some(reason) = &request.reason {
+                    println!("    reason: {}", reason);
+                }
+            }
+        }
+        AccessRequestCommand::Approve { key } => {
+            let public_key = key
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid public key format."))?;
+
+            if requests.take(&public_key).await?.is_none() {
+                punch::warning!("No pending request from this key");
+                return Ok(());
+            }
+            auth_manager.authorize(public_key).await?;
+            punch::success!("Authorized key: {}", key.blue());
+        }
+        AccessRequestCommand::Deny { key } => {
+            let public_key = key
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid public key format."))?;
+
+            if requests.take(&public_key).await?.is_some() {
+                punch::success!("Denied access request from: {}", key.blue());
+            } else {
+                punch::warning!("No pending request from this key");
+            }
+        }
     }
     Ok(())
 }
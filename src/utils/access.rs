@@ -0,0 +1,199 @@
+use crate::Result;
+use crate::utils::constants::MAX_ACCESS_REQUESTS;
+use iroh::PublicKey;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// A request from an unauthorized node to be added to `authorized_keys`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessRequest {
+    pub timestamp: u64,
+    /// Taken from the connection the request came in on, so it can't be forged
+    pub node_id: PublicKey,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ports: Vec<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubmitOutcome {
+    Stored,
+    /// The node already had a pending request, which was replaced
+    Updated,
+    /// The oldest pending request was dropped to make room for this one
+    Evicted,
+}
+
+/// Pending access requests, kept as a JSON array next to the server config. The server adds
+/// to it while `punch auth requests` takes from it, so changes hold a lock on the file like
+/// config updates do.
+#[derive(Debug)]
+pub struct AccessRequests {
+    path: PathBuf,
+}
+
+impl AccessRequests {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn list(&self) -> Result<Vec<AccessRequest>> {
+        self.read().await
+    }
+
+    pub async fn submit(&self, request: AccessRequest) -> Result<SubmitOutcome> {
+        let _lock = self.lock().await?;
+        let mut requests = self.read().await?;
+        let pending = requests.len();
+
+        let outcome = match requests.iter_mut().find(|r| r.node_id == request.node_id) {
+            Some(existing) => {
+                *existing = request;
+                SubmitOutcome::Updated
+            }
+            // Turning new requests away would let whoever filled the list first keep it
+            None if pending >= MAX_ACCESS_REQUESTS => {
+                if let Some(oldest) = (0..pending).min_by_key(|&i| requests[i].timestamp) {
+                    requests.remove(oldest);
+                }
+                requests.push(request);
+                SubmitOutcome::Evicted
+            }
+            None => {
+                requests.push(request);
+                SubmitOutcome::Stored
+            }
+        };
+
+        self.write(&requests).await?;
+        Ok(outcome)
+    }
+
+    /// Removes and returns the pending request from `node_id`, if any.
+    pub async fn take(&self, node_id: &PublicKey) -> Result<Option<AccessRequest>> {
+        let _lock = self.lock().await?;
+        let mut requests = self.read().await?;
+
+        let Some(index) = requests.iter().position(|r| &r.node_id == node_id) else {
+            return Ok(None);
+        };
+        let request = requests.remove(index);
+        self.write(&requests).await?;
+
+        Ok(Some(request))
+    }
+
+    async fn read(&self) -> Result<Vec<AccessRequest>> {
+        match tokio::fs::read(&self.path).await {
+            Ok(content) => serde_json::from_slice(&content).map_err(|e| {
+                crate::error!(
+                    "Invalid access requests file {}: {}",
+                    self.path.display(),
+                    e
+                )
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Holds an exclusive lock on the requests until the guard is dropped, in this process
+    /// and in others.
+    async fn lock(&self) -> Result<std::fs::File> {
+        let filename = self.path.file_name().unwrap_or_default().to_string_lossy();
+        let path = self.path.with_file_name(format!(".{}.lock", filename));
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)?;
+            file.lock()?;
+            Ok(file)
+        })
+        .await
+        .map_err(|e| crate::error!(source = e, "Failed to lock {}", self.path.display()))?
+    }
+
+    async fn write(&self, requests: &[AccessRequest]) -> Result<()> {
+        let content = serde_json::to_vec_pretty(requests).map_err(|e| crate::error!("{}", e))?;
+        // Written aside first so that readers and a crash never see half of the requests
+        let tmp = self.path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, content).await?;
+        tokio::fs::rename(&tmp, &self.path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrent_submits_are_all_kept() {
+        let dir = std::env::temp_dir().join(format!("punch-access-{}", std::process::id()));
+        let path = dir.join("access_requests.json");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // Separate instances, as the server and `punch auth requests` are
+        let submits = (0..16).map(|timestamp| {
+            let requests = AccessRequests::new(path.clone());
+            tokio::spawn(async move {
+                let node_id = iroh::SecretKey::generate(&mut rand::rngs::OsRng).public();
+                let request = AccessRequest {
+                    timestamp,
+                    node_id,
+                    ports: Vec::new(),
+                    reason: None,
+                };
+                requests.submit(request).await.unwrap()
+            })
+        });
+        for outcome in n0_future::join_all(submits).await {
+            assert_eq!(outcome.unwrap(), SubmitOutcome::Stored);
+        }
+
+        let requests = AccessRequests::new(path);
+        let pending = requests.list().await.unwrap();
+        assert_eq!(pending.len(), 16);
+        assert!(requests.take(&pending[0].node_id).await.unwrap().is_some());
+        assert_eq!(requests.list().await.unwrap().len(), 15);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn full_list_drops_the_oldest_request() {
+        let dir = std::env::temp_dir().join(format!("punch-access-full-{}", std::process::id()));
+        let requests = AccessRequests::new(dir.join("access_requests.json"));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let request = |timestamp| AccessRequest {
+            timestamp,
+            node_id: iroh::SecretKey::generate(&mut rand::rngs::OsRng).public(),
+            ports: Vec::new(),
+            reason: None,
+        };
+        // Submitted out of order, the oldest one being in the middle
+        for timestamp in (1..=MAX_ACCESS_REQUESTS as u64).rev() {
+            let outcome = requests.submit(request(timestamp)).await.unwrap();
+            assert_eq!(outcome, SubmitOutcome::Stored);
+        }
+        let outcome = requests.submit(request(1000)).await.unwrap();
+        assert_eq!(outcome, SubmitOutcome::Evicted);
+
+        let pending = requests.list().await.unwrap();
+        assert_eq!(pending.len(), MAX_ACCESS_REQUESTS);
+        assert!(pending.iter().all(|r| r.timestamp != 1));
+        assert!(pending.iter().any(|r| r.timestamp == 1000));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    Accepted,
+    Rejected,
+    Closed,
+}
+
+impl std::fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuditEvent::Accepted => write!(f, "accepted"),
+            AuditEvent::Rejected => write!(f, "rejected"),
+            AuditEvent::Closed => write!(f, "closed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub timestamp: u64,
+    pub event: AuditEvent,
+    pub node_id: String,
+    /// ID of the tunnel, as shown by `punch server connections` and in the logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tunnel: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Session length in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes_in: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes_out: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(event: AuditEvent, node_id: &iroh::NodeId) -> Self {
+        Self {
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            event,
+            node_id: node_id.to_string(),
+            tunnel: None,
+            protocol: None,
+            port: None,
+            target: None,
+            duration: None,
+            bytes_in: None,
+            bytes_out: None,
+            reason: None,
+        }
+    }
+}
+
+/// Append-only JSONL log of connection attempts and sessions.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn append(&self, record: &AuditRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record).map_err(|e| crate::error!("{}", e))?;
+        line.push(b'\n');
+
+        let _guard = self.lock.lock().await;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(&line).await?;
+
+        Ok(())
+    }
+
+    /// Reads the records written after byte `offset`, skipping lines that fail to parse,
+    /// and returns them along with the offset to resume from.
+    pub async fn read_since(&self, offset: u64) -> Result<(Vec<AuditRecord>, u64)> {
+        let content = match tokio::fs::read(&self.path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+            Err(e) => return Err(e.into()),
+        };
+
+        // The file was truncated or rotated, start over
+        let offset = if offset as usize > content.len() {
+            0
+        } else {
+            offset as usize
+        };
+        let complete = content[offset..]
+            .iter()
+            .rposition(|b| *b == b'\n')
+            .map_or(offset, |end| offset + end + 1);
+
+        let records = String::from_utf8_lossy(&content[offset..complete])
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect();
+
+        Ok((records, complete as u64))
+    }
+}
@@ -0,0 +1,67 @@
+use crate::utils::config::ClientSettings;
+use std::time::{Duration, Instant};
+
+/// Exponential backoff with jitter between connection attempts, bounded by a number of
+/// retries and by the total time spent retrying.
+#[derive(Debug)]
+pub struct Backoff {
+    initial_delay: Duration,
+    max_delay: Duration,
+    max_elapsed: Duration,
+    max_retries: usize,
+    retries: usize,
+    started: Instant,
+}
+
+impl Backoff {
+    /// Retries for as long as `max_elapsed` allows.
+    pub fn new(initial_delay: Duration, max_delay: Duration, max_elapsed: Duration) -> Self {
+        Self {
+            initial_delay,
+            max_delay,
+            max_elapsed,
+            max_retries: usize::MAX,
+            retries: 0,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn with_max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn from_settings(settings: &ClientSettings) -> Self {
+        Self::new(
+            Duration::from_millis(settings.retry_initial_delay_ms),
+            Duration::from_millis(settings.retry_max_delay_ms),
+            Duration::from_secs(settings.retry_max_elapsed),
+        )
+        .with_max_retries(settings.max_retries)
+    }
+
+    pub fn retries(&self) -> usize {
+        self.retries
+    }
+
+    /// Delay before the next attempt, `None` once we should give up.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        let exponent = u32::try_from(self.retries).unwrap_or(u32::MAX);
+        let base = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(exponent))
+            .min(self.max_delay);
+        // Half of the delay is random, so that clients dropped at the same time spread out
+        let delay = base / 2 + base.mul_f64(rand::random::<f64>() / 2.0);
+        self.next_after(delay)
+    }
+
+    /// Counts a retry after a delay chosen by the server, within the same limits.
+    pub fn next_after(&mut self, delay: Duration) -> Option<Duration> {
+        if self.retries >= self.max_retries || self.started.elapsed() + delay > self.max_elapsed {
+            return None;
+        }
+        self.retries += 1;
+        Some(delay)
+    }
+}
@@ -0,0 +1,142 @@
+use crate::Result;
+use crate::utils::config::{ClientConfig, ConfigManager, Configuration, ServerConfig};
+use crate::utils::constants::PRIVATE_KEY_PATH;
+use crate::utils::crypto::{parse_secret_key, write_secret_key};
+use age::secrecy::SecretString;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bumped when the content of a backup changes in a way older versions cannot restore
+const BACKUP_VERSION: u32 = 1;
+
+/// The node key and both configs, moved to another machine as one passphrase-encrypted
+/// age file.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Backup {
+    version: u32,
+    created_at: u64,
+    /// Contents by file name, as found in the config directory
+    files: BTreeMap<String, Vec<u8>>,
+}
+
+impl Backup {
+    /// Gathers whatever exists of the key and configs, from the files or the SQLite store.
+    pub async fn collect(config_manager: &ConfigManager) -> Result<Self> {
+        let mut files = BTreeMap::new();
+
+        let key_path = config_manager.config_path(PRIVATE_KEY_PATH);
+        match tokio::fs::read(&key_path).await {
+            Ok(key) => {
+                files.insert(PRIVATE_KEY_PATH.to_string(), key);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        for name in [ClientConfig::filename(), ServerConfig::filename()] {
+            if let Some(content) = config_manager.read_raw(name).await? {
+                files.insert(name.to_string(), content.into_bytes());
+            }
+        }
+
+        Ok(Self {
+            version: BACKUP_VERSION,
+            created_at: now(),
+            files,
+        })
+    }
+
+    /// Seconds since the backup was made.
+    pub fn age(&self) -> u64 {
+        now().saturating_sub(self.created_at)
+    }
+
+    /// Names of the files in the backup.
+    pub fn files(&self) -> impl Iterator<Item = &str> {
+        self.files.keys().map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn encrypt(&self, passphrase: &str) -> Result<Vec<u8>> {
+        let plaintext = postcard::to_stdvec(self).map_err(|e| crate::error!("{}", e))?;
+        let recipient = age::scrypt::Recipient::new(SecretString::from(passphrase));
+        age::encrypt(&recipient, &plaintext)
+            .map_err(|e| crate::error!(source = e, "Failed to encrypt the backup"))
+    }
+
+    pub fn decrypt(ciphertext: &[u8], passphrase: &str) -> Result<Self> {
+        let identity = age::scrypt::Identity::new(SecretString::from(passphrase));
+        let plaintext = age::decrypt(&identity, ciphertext)
+            .map_err(|e| crate::error!(source = e, "Failed to decrypt the backup"))?;
+        let backup: Self = postcard::from_bytes(&plaintext)
+            .map_err(|e| crate::error!(source = e, "Not a punch backup"))?;
+
+        if backup.version > BACKUP_VERSION {
+            return Err(crate::error!(
+                "The backup was made by a newer version of punch, upgrade to restore it"
+            ));
+        }
+        Ok(backup)
+    }
+
+    /// Files of the backup that would replace existing ones.
+    pub async fn conflicts(&self, config_manager: &ConfigManager) -> Result<Vec<&str>> {
+        let mut conflicts = Vec::new();
+        for name in self.files() {
+            let exists = if name == PRIVATE_KEY_PATH {
+                config_manager.config_path(name).exists()
+            } else {
+                config_manager.read_raw(name).await?.is_some()
+            };
+            if exists {
+                conflicts.push(name);
+            }
+        }
+        Ok(conflicts)
+    }
+
+    /// Writes every file of the backup, configs going to the store in use. Configs are
+    /// checked before anything is written, so a backup that does not parse changes nothing.
+    pub async fn restore(&self, config_manager: &ConfigManager) -> Result<()> {
+        let text = |name: &str| -> Result<Option<&str>> {
+            self.files
+                .get(name)
+                .map(|content| {
+                    std::str::from_utf8(content)
+                        .map_err(|e| crate::error!(source = e, "{} is not text", name))
+                })
+                .transpose()
+        };
+        let client = text(ClientConfig::filename())?;
+        let server = text(ServerConfig::filename())?;
+        if let Some(content) = client {
+            toml::from_str::<ClientConfig>(content)?.validate()?;
+        }
+        if let Some(content) = server {
+            toml::from_str::<ServerConfig>(content)?.validate()?;
+        }
+
+        if let Some(key) = self.files.get(PRIVATE_KEY_PATH) {
+            let key = parse_secret_key(key)?;
+            write_secret_key(&config_manager.config_path(PRIVATE_KEY_PATH), &key).await?;
+        }
+        if let Some(content) = client {
+            config_manager.write_raw::<ClientConfig>(content).await?;
+        }
+        if let Some(content) = server {
+            config_manager.write_raw::<ServerConfig>(content).await?;
+        }
+
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
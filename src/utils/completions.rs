@@ -0,0 +1,124 @@
+use clap::{Command, ValueHint};
+use clap_complete::Shell;
+
+/// Lists the saved hosts for the completion scripts.
+const LIST_HOSTS: &str = "punch hosts list --plain 2>/dev/null";
+
+/// Writes the completion script of `shell`, where the arguments hinted as host names
+/// complete to the saved hosts, listed when completing. PowerShell and Elvish only get the
+/// static completions.
+pub fn generate(
+    shell: Shell,
+    command: &mut Command,
+    out: &mut impl std::io::Write,
+) -> std::io::Result<()> {
+    let mut script = Vec::new();
+    clap_complete::generate(shell, command, "punch", &mut script);
+    let script = String::from_utf8_lossy(&script);
+
+    let script = match shell {
+        Shell::Zsh => zsh(&script),
+        Shell::Fish => fish(&script, command),
+        Shell::Bash => bash(&script, command),
+        _ => script.into_owned(),
+    };
+    out.write_all(script.as_bytes())
+}
+
+fn zsh(script: &str) -> String {
+    let helper = format!(
+        "_punch_hosts() {{\n    local -a hosts\n    hosts=(${{(f)\"$({})\"}})\n    _describe 'host' hosts\n}}\n",
+        LIST_HOSTS
+    );
+    let script = script.replace(":_hosts'", ":_punch_hosts'");
+    // Defined ahead of `_punch`, which the file calls when autoloaded
+    match script.split_once('\n') {
+        Some((compdef, rest)) => format!("{}\n\n{}\n{}", compdef, helper, rest),
+        None => script,
+    }
+}
+
+/// Fish only uses value hints for options, the positional arguments get their own lines.
+fn fish(script: &str, command: &Command) -> String {
+    let mut script = script.replace("(__fish_print_hostnames)", &format!("({})", LIST_HOSTS));
+    for path in host_subcommands(command) {
+        let mut condition = format!("__fish_punch_using_subcommand {}", path[0].join(" "));
+        for names in &path[1..] {
+            condition.push_str(&format!(
+                "; and __fish_seen_subcommand_from {}",
+                names.join(" ")
+            ));
+        }
+        script.push_str(&format!(
+            "complete -c punch -n \"{}\" -f -a \"({})\"\n",
+            condition, LIST_HOSTS
+        ));
+    }
+    script
+}
+
+/// Bash completions know nothing of value hints, so the first positional argument of the
+/// subcommands taking a host is completed by a wrapper around the generated function.
+fn bash(script: &str, command: &Command) -> String {
+    let mut patterns = Vec::new();
+    for path in host_subcommands(command) {
+        let mut words = vec![String::new()];
+        for names in path {
+            words = words
+                .iter()
+                .flat_map(|prefix| names.iter().map(move |name| format!("{} {}", prefix, name)))
+                .collect();
+        }
+        patterns.extend(
+            words
+                .iter()
+                .map(|words| format!("\"{}\"", words.trim_start())),
+        );
+    }
+
+    let registration = "complete -F _punch";
+    let script = script.replace(registration, "complete -F _punch_with_hosts");
+    format!(
+        r#"{script}
+_punch_with_hosts() {{
+    _punch "$@"
+    local cur="${{COMP_WORDS[COMP_CWORD]}}" word
+    local -a words=()
+    [[ "$cur" == -* ]] && return
+    for word in "${{COMP_WORDS[@]:1:COMP_CWORD-1}}"; do
+        [[ "$word" != -* ]] && words+=("$word")
+    done
+    case "${{words[*]}}" in
+        {patterns})
+            COMPREPLY=($(compgen -W "$({LIST_HOSTS})" -- "$cur"))
+            ;;
+    esac
+}}
+"#,
+        patterns = patterns.join("|"),
+    )
+}
+
+/// The subcommands whose first positional argument is a host, as the names leading to them,
+/// each with its aliases.
+fn host_subcommands(command: &Command) -> Vec<Vec<Vec<&str>>> {
+    let mut paths = Vec::new();
+    for subcommand in command.get_subcommands() {
+        let names: Vec<&str> = std::iter::once(subcommand.get_name())
+            .chain(subcommand.get_visible_aliases())
+            .collect();
+
+        let takes_host = subcommand
+            .get_positionals()
+            .next()
+            .is_some_and(|arg| arg.get_value_hint() == ValueHint::Hostname);
+        if takes_host {
+            paths.push(vec![names.clone()]);
+        }
+        for mut path in host_subcommands(subcommand) {
+            path.insert(0, names.clone());
+            paths.push(path);
+        }
+    }
+    paths
+}
@@ -1,11 +1,32 @@
 use crate::Result;
+use crate::core::{Protocol, discovery, mapping::Mapping};
 use crate::utils::constants::{
-    DEFAULT_ALLOWED_PORT_RANGE, DEFAULT_MAX_CONNECTIONS, DEFAULT_RETRIES, DEFAULT_TIMEOUT,
+    ACCESS_REQUESTS_PATH, CONFIG_RELOAD_DELAY, CONTROL_SOCKET_PATH, DEFAULT_ACCEPTORS,
+    DEFAULT_ALLOWED_PORT_RANGE, DEFAULT_CONNECTIONS, DEFAULT_GUEST_BANDWIDTH,
+    DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_CONNECTIONS_PER_KEY, DEFAULT_MAX_STREAMS_PER_CONNECTION,
+    DEFAULT_RETRIES, DEFAULT_RETRY_INITIAL_DELAY_MS, DEFAULT_RETRY_MAX_DELAY_MS,
+    DEFAULT_RETRY_MAX_ELAPSED, DEFAULT_STREAM_MEMORY_PER_CONNECTION, DEFAULT_TIMEOUT,
+    DEFAULT_VPN_INTERFACE, DEFAULT_VPN_MTU, ENV_ALLOWED_PORTS, ENV_AUTHORIZED_KEYS,
+    GRPC_TOKEN_PATH, HISTORY_PATH, STATE_DB_PATH, USAGE_PATH,
 };
-use iroh::{NodeId, PublicKey};
+use crate::utils::policy::{TargetPolicy, TargetRule};
+use crate::utils::ports::{PortRange, PortRanges};
+use crate::utils::reduced_node_id;
+use crate::utils::schedule::Schedule;
+#[cfg(feature = "sqlite")]
+use crate::utils::store::SqliteStore;
+use arc_swap::ArcSwap;
+use ipnet::Ipv4Net;
+use iroh::{NodeId, PublicKey, RelayUrl, dns::DnsResolver};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::broadcast;
 
 pub trait Configuration: Serialize + DeserializeOwned + Debug {
     fn filename() -> &'static str;
@@ -14,110 +35,1365 @@ pub trait Configuration: Serialize + DeserializeOwned + Debug {
         Ok(())
     }
 
+    /// Overrides settings from `PUNCH_*` environment variables. Only applied to the config
+    /// a running server holds, so the values never end up in the stored config.
+    fn apply_env(&mut self) -> Result<()> {
+        Ok(())
+    }
+
     fn default() -> Self;
 }
 
+/// The last valid version of a config file, kept in memory and optionally reloaded when the
+/// file changes on disk.
+#[derive(Debug)]
+pub struct ConfigCache<C> {
+    manager: ConfigManager,
+    current: ArcSwap<C>,
+    watcher: std::sync::Mutex<Option<RecommendedWatcher>>,
+}
+
+impl<C: Configuration + Send + Sync + 'static> ConfigCache<C> {
+    pub async fn load(manager: ConfigManager) -> Result<Arc<Self>> {
+        let mut config: C = manager.load().await?;
+        config.apply_env()?;
+
+        Ok(Arc::new(Self {
+            manager,
+            current: ArcSwap::from_pointee(config),
+            watcher: std::sync::Mutex::new(None),
+        }))
+    }
+
+    pub fn get(&self) -> Arc<C> {
+        self.current.load_full()
+    }
+
+    pub fn manager(&self) -> &ConfigManager {
+        &self.manager
+    }
+
+    /// Applies `f` to the stored config, see [`ConfigManager::update`].
+    pub async fn update<T>(&self, f: impl FnOnce(&mut C) -> Result<T>) -> Result<T>
+    where
+        C: Clone,
+    {
+        let (config, value) = self
+            .manager
+            .update(|config: &mut C| {
+                let value = f(config)?;
+                Ok((config.clone(), value))
+            })
+            .await?;
+        let mut config = config;
+        config.apply_env()?;
+        self.current.store(Arc::new(config));
+        Ok(value)
+    }
+
+    /// Re-reads the config, keeping the current one if it went missing.
+    pub async fn reload(&self) -> Result<()> {
+        if let Some(mut config) = self.manager.read::<C>().await? {
+            config.apply_env()?;
+            self.current.store(Arc::new(config));
+        }
+        Ok(())
+    }
+
+    /// Reloads the config whenever its file changes, for as long as the cache lives. Invalid
+    /// edits are reported and ignored.
+    pub fn watch(self: &Arc<Self>) -> Result<()> {
+        let filenames = self.manager.watched_files::<C>();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            if let Ok(event) = event
+                && !event.kind.is_access()
+                && event.paths.iter().any(|path| {
+                    path.file_name()
+                        .is_some_and(|name| filenames.iter().any(|watched| watched == name))
+                })
+            {
+                let _ = tx.send(());
+            }
+        })
+        .map_err(|e| crate::error!(source = e, "Failed to watch the config directory"))?;
+
+        // Editors often replace the file instead of writing to it, so watch its directory
+        watcher
+            .watch(&self.manager.base_path, RecursiveMode::NonRecursive)
+            .map_err(|e| crate::error!(source = e, "Failed to watch the config directory"))?;
+        *self.watcher.lock().unwrap() = Some(watcher);
+
+        let cache = Arc::downgrade(self);
+        tokio::spawn(async move {
+            while rx.recv().await.is_some() {
+                // Let multi-step writes settle before reading the file
+                tokio::time::sleep(CONFIG_RELOAD_DELAY).await;
+                while rx.try_recv().is_ok() {}
+
+                let Some(cache) = cache.upgrade() else {
+                    break;
+                };
+                match cache.reload().await {
+                    Ok(()) => tracing::debug!("Reloaded {}", C::filename()),
+                    Err(e) => crate::warning!("Ignoring invalid {}: {}", C::filename(), e),
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+/// Where the config files are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreKind {
+    /// One TOML file per config in the config directory
+    Toml,
+    /// A single SQLite database, for servers whose keys and hosts change often
+    Sqlite,
+}
+
+impl std::str::FromStr for StoreKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "toml" => Ok(StoreKind::Toml),
+            "sqlite" => Ok(StoreKind::Sqlite),
+            _ => Err("Invalid store. Use 'toml' or 'sqlite'.".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for StoreKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreKind::Toml => write!(f, "toml"),
+            StoreKind::Sqlite => write!(f, "sqlite"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+enum Backend {
+    #[default]
+    Toml,
+    #[cfg(feature = "sqlite")]
+    Sqlite(Arc<SqliteStore>),
+}
+
+impl Backend {
+    /// The SQLite store is used as soon as its database exists.
+    fn detect(base_path: &Path) -> Result<Self> {
+        let path = base_path.join(STATE_DB_PATH);
+        if !path.exists() {
+            return Ok(Backend::Toml);
+        }
+
+        #[cfg(feature = "sqlite")]
+        {
+            Ok(Backend::Sqlite(Arc::new(SqliteStore::open(path)?)))
+        }
+        #[cfg(not(feature = "sqlite"))]
+        {
+            Err(crate::error!(
+                "{} holds the config, but punch was built without the sqlite feature",
+                path.display()
+            ))
+        }
+    }
+}
+
+/// Directory given with `--config-dir` or `PUNCH_CONFIG_DIR`, used by every
+/// [`ConfigManager`] of the process.
+static CONFIG_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Keeps everything in `path` instead of the default directories. Only the first call has
+/// an effect, and it must come before the first [`ConfigManager::new`].
+pub fn set_config_dir(path: PathBuf) {
+    let _ = CONFIG_DIR.set(path);
+}
+
+/// Where punch keeps its files.
+#[derive(Clone, Debug)]
+struct Dirs {
+    /// Configs and the secret key
+    config: PathBuf,
+    /// History, usage counters and access requests
+    state: PathBuf,
+    /// Control socket of the running server
+    runtime: PathBuf,
+}
+
+impl Dirs {
+    fn single(path: PathBuf) -> Self {
+        Self {
+            config: path.clone(),
+            state: path.clone(),
+            runtime: path,
+        }
+    }
+
+    /// An explicit directory wins, then an existing `~/.punch`. Otherwise the XDG base
+    /// directories are used where the platform has them.
+    fn resolve() -> Result<Self> {
+        if let Some(path) = CONFIG_DIR.get() {
+            return Ok(Self::single(path.clone()));
+        }
+
+        let home = dirs::home_dir().ok_or_else(|| crate::error!("Home directory not found"))?;
+        let legacy = home.join(".punch");
+        if legacy.exists() {
+            return Ok(Self::single(legacy));
+        }
+
+        let Some(config) = xdg_dir("XDG_CONFIG_HOME", dirs::config_dir) else {
+            return Ok(Self::single(legacy));
+        };
+        let state = xdg_dir("XDG_STATE_HOME", dirs::state_dir).unwrap_or_else(|| config.clone());
+        let runtime =
+            xdg_dir("XDG_RUNTIME_DIR", dirs::runtime_dir).unwrap_or_else(|| state.clone());
+        Ok(Self {
+            config,
+            state,
+            runtime,
+        })
+    }
+}
+
+/// `punch` under the directory named by `var`, or under the platform default on Linux
+/// and the BSDs. Elsewhere the platform defaults aren't XDG ones, so they are skipped.
+fn xdg_dir(var: &str, default: fn() -> Option<PathBuf>) -> Option<PathBuf> {
+    let base = std::env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| {
+            if cfg!(any(target_os = "macos", target_os = "ios", windows)) {
+                None
+            } else {
+                default()
+            }
+        })?;
+    Some(base.join("punch"))
+}
+
 #[derive(Clone, Debug)]
 pub struct ConfigManager {
     base_path: PathBuf,
+    state_path: PathBuf,
+    runtime_path: PathBuf,
+    backend: Backend,
 }
 
-impl ConfigManager {
-    pub fn new() -> Result<Self> {
-        let base_path = dirs::home_dir()
-            .ok_or_else(|| crate::error!("Home directory not found"))?
-            .join(".punch");
+impl ConfigManager {
+    pub fn new() -> Result<Self> {
+        let dirs = Dirs::resolve()?;
+        let backend = Backend::detect(&dirs.config)?;
+
+        Ok(Self {
+            base_path: dirs.config,
+            state_path: dirs.state,
+            runtime_path: dirs.runtime,
+            backend,
+        })
+    }
+
+    pub fn with_base_path(base_path: PathBuf) -> Self {
+        let dirs = Dirs::single(base_path);
+        Self {
+            base_path: dirs.config,
+            state_path: dirs.state,
+            runtime_path: dirs.runtime,
+            backend: Backend::Toml,
+        }
+    }
+
+    /// Directory holding the configs.
+    pub fn base_path(&self) -> &Path {
+        &self.base_path
+    }
+
+    pub fn store_kind(&self) -> StoreKind {
+        match self.backend {
+            Backend::Toml => StoreKind::Toml,
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(_) => StoreKind::Sqlite,
+        }
+    }
+
+    pub async fn load<C: Configuration>(&self) -> Result<C> {
+        match self.read().await? {
+            Some(config) => Ok(config),
+            None => {
+                let config = C::default();
+                // Nowhere to save it is fine, e.g. in a container configured through the
+                // environment
+                if let Err(e) = self.save(&config).await {
+                    tracing::debug!("Not saving the default {}: {}", C::filename(), e);
+                }
+                Ok(config)
+            }
+        }
+    }
+
+    /// Reads a config, `None` if it was never saved.
+    pub async fn read<C: Configuration>(&self) -> Result<Option<C>> {
+        match &self.backend {
+            Backend::Toml => {
+                let path = self.config_path(C::filename());
+                if !path.exists() {
+                    return Ok(None);
+                }
+                Ok(Some(self.load_from_file(&path).await?))
+            }
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(store) => store
+                .get(C::filename())?
+                .map(|content| parse_config(&content))
+                .transpose(),
+        }
+    }
+
+    /// The text of a config as stored, `None` if it was never saved.
+    pub async fn read_raw(&self, filename: &str) -> Result<Option<String>> {
+        match &self.backend {
+            Backend::Toml => match tokio::fs::read_to_string(self.config_path(filename)).await {
+                Ok(content) => Ok(Some(content)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(store) => store.get(filename),
+        }
+    }
+
+    pub async fn save<C: Configuration>(&self, config: &C) -> Result<()> {
+        config.validate()?;
+        let content = toml::to_string_pretty(config)?;
+
+        match &self.backend {
+            Backend::Toml => {
+                let _lock = self.lock(C::filename()).await?;
+                self.write_file(&self.config_path(C::filename()), &content)
+                    .await
+            }
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(store) => store.put(C::filename(), &content),
+        }
+    }
+
+    /// Applies `f` to the stored config and saves the result, returning what `f` returned.
+    /// Nothing else can write the config in between, so concurrent updates from other
+    /// processes are applied one after the other instead of overwriting each other.
+    pub async fn update<C: Configuration, T>(
+        &self,
+        f: impl FnOnce(&mut C) -> Result<T>,
+    ) -> Result<T> {
+        match &self.backend {
+            Backend::Toml => {
+                let _lock = self.lock(C::filename()).await?;
+                let mut config: C = self.read().await?.unwrap_or_else(C::default);
+                let value = f(&mut config)?;
+                config.validate()?;
+                self.write_file(
+                    &self.config_path(C::filename()),
+                    &toml::to_string_pretty(&config)?,
+                )
+                .await?;
+                Ok(value)
+            }
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(store) => store.update(C::filename(), |content| {
+                let mut config = match content {
+                    Some(content) => parse_config(&content)?,
+                    None => C::default(),
+                };
+                let value = f(&mut config)?;
+                config.validate()?;
+                Ok((toml::to_string_pretty(&config)?, value))
+            }),
+        }
+    }
+
+    /// Copies a config to a temporary file to be edited by hand, returning its path and the
+    /// text it started from.
+    pub async fn edit_copy<C: Configuration>(&self) -> Result<(PathBuf, String)> {
+        let original = match self.read_raw(C::filename()).await? {
+            Some(content) => content,
+            None => toml::to_string_pretty(&C::default())?,
+        };
+        // Keeps the extension so that editors highlight it as TOML
+        let path = self.base_path.join(format!(".edit.{}", C::filename()));
+        self.ensure_directory(&path).await?;
+        tokio::fs::write(&path, &original)
+            .await
+            .map_err(|e| crate::PunchError::ConfigError {
+                path: path.clone(),
+                source: Box::new(e),
+            })?;
+
+        Ok((path, original))
+    }
+
+    /// Replaces a config with text edited by hand, keeping its comments and layout. Nothing
+    /// is written if the text is not a valid config, or if the stored one is no longer
+    /// `original`.
+    pub async fn replace_raw<C: Configuration>(&self, content: &str, original: &str) -> Result<()> {
+        parse_config::<C>(content)?;
+        let check = |current: Option<&str>| {
+            if current.is_some_and(|current| current != original) {
+                return Err(crate::error!(
+                    "{} changed while it was being edited",
+                    C::filename()
+                ));
+            }
+            Ok(())
+        };
+
+        match &self.backend {
+            Backend::Toml => {
+                let _lock = self.lock(C::filename()).await?;
+                check(self.read_raw(C::filename()).await?.as_deref())?;
+                self.write_file(&self.config_path(C::filename()), content)
+                    .await
+            }
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(store) => store.update(C::filename(), |current| {
+                check(current.as_deref())?;
+                Ok((content.to_string(), ()))
+            }),
+        }
+    }
+
+    /// Saves the text of a config as is, once it parsed as a valid config.
+    pub async fn write_raw<C: Configuration>(&self, content: &str) -> Result<()> {
+        parse_config::<C>(content)?;
+
+        match &self.backend {
+            Backend::Toml => {
+                let _lock = self.lock(C::filename()).await?;
+                self.write_file(&self.config_path(C::filename()), content)
+                    .await
+            }
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(store) => store.put(C::filename(), content),
+        }
+    }
+
+    /// Moves every config to another store. The files or database left behind are kept
+    /// with a `.bak` extension.
+    pub async fn migrate(&mut self, to: StoreKind) -> Result<()> {
+        if self.store_kind() == to {
+            return Ok(());
+        }
+
+        match to {
+            StoreKind::Toml => {
+                #[cfg(feature = "sqlite")]
+                if let Backend::Sqlite(store) = std::mem::take(&mut self.backend) {
+                    for name in [ClientConfig::filename(), ServerConfig::filename()] {
+                        if let Some(content) = store.get(name)? {
+                            self.write_file(&self.config_path(name), &content).await?;
+                        }
+                    }
+                    let path = store.path().to_path_buf();
+                    // Closes the database so that its journal is folded back in
+                    drop(store);
+                    tokio::fs::rename(&path, path.with_extension("db.bak")).await?;
+                }
+                Ok(())
+            }
+            #[cfg(feature = "sqlite")]
+            StoreKind::Sqlite => {
+                let store = SqliteStore::open(self.base_path.join(STATE_DB_PATH))?;
+                for name in [ClientConfig::filename(), ServerConfig::filename()] {
+                    let path = self.config_path(name);
+                    if !path.exists() {
+                        continue;
+                    }
+                    let content = tokio::fs::read_to_string(&path).await?;
+                    store.put(name, &content)?;
+                    tokio::fs::rename(&path, path.with_extension("toml.bak")).await?;
+                }
+                self.backend = Backend::Sqlite(Arc::new(store));
+                Ok(())
+            }
+            #[cfg(not(feature = "sqlite"))]
+            StoreKind::Sqlite => Err(crate::error!("punch was built without the sqlite feature")),
+        }
+    }
+
+    /// Notes that a key opened a session, for stores that keep track of it.
+    pub fn record_seen(&self, node_id: &NodeId) -> Result<()> {
+        match &self.backend {
+            Backend::Toml => {
+                let _ = node_id;
+                Ok(())
+            }
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(store) => {
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                store.record_seen(&node_id.to_string(), now)
+            }
+        }
+    }
+
+    /// When each key last opened a session, `None` when the store doesn't keep track.
+    pub fn last_seen(&self) -> Result<Option<HashMap<NodeId, u64>>> {
+        match &self.backend {
+            Backend::Toml => Ok(None),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(store) => Ok(Some(
+                store
+                    .last_seen()?
+                    .into_iter()
+                    .filter_map(|(node_id, seen)| Some((node_id.parse().ok()?, seen)))
+                    .collect(),
+            )),
+        }
+    }
+
+    /// Names of the files whose changes may change the config `C`.
+    fn watched_files<C: Configuration>(&self) -> Vec<std::ffi::OsString> {
+        match &self.backend {
+            Backend::Toml => vec![C::filename().into()],
+            // Writes land in the write-ahead log first
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(_) => vec![
+                STATE_DB_PATH.into(),
+                format!("{}-wal", STATE_DB_PATH).into(),
+            ],
+        }
+    }
+
+    /// Holds an exclusive lock on a config file until the guard is dropped. Other punch
+    /// processes wait for it before writing the file.
+    async fn lock(&self, filename: &str) -> Result<std::fs::File> {
+        let path = self.base_path.join(format!(".{}.lock", filename));
+        self.ensure_directory(&path).await?;
+
+        tokio::task::spawn_blocking(move || {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)?;
+            file.lock()?;
+            Ok(file)
+        })
+        .await
+        .map_err(|e| crate::error!(source = e, "Failed to lock {}", filename))?
+    }
+
+    /// Writes the whole file aside and renames it over the old one, so that readers and a
+    /// crash never see half of it.
+    async fn write_file(&self, path: &Path, content: &str) -> Result<()> {
+        self.ensure_directory(path).await?;
+        let tmp = path.with_extension("toml.tmp");
+        let result = async {
+            tokio::fs::write(&tmp, content).await?;
+            tokio::fs::rename(&tmp, path).await
+        };
+        result.await.map_err(|e| crate::PunchError::ConfigError {
+            path: path.to_path_buf(),
+            source: Box::new(e),
+        })?;
+
+        Ok(())
+    }
+
+    async fn load_from_file<C: Configuration>(&self, path: &Path) -> Result<C> {
+        let content =
+            tokio::fs::read_to_string(path)
+                .await
+                .map_err(|e| crate::PunchError::ConfigError {
+                    path: path.to_path_buf(),
+                    source: Box::new(e),
+                })?;
+
+        parse_config(&content).map_err(|e| crate::PunchError::ConfigError {
+            path: path.to_path_buf(),
+            source: Box::new(e),
+        })
+    }
+
+    /// Resolves a path from a config file, relative paths being taken from the config directory.
+    pub fn resolve_path(&self, path: &Path) -> PathBuf {
+        self.base_path.join(path)
+    }
+
+    pub fn control_socket_path(&self) -> PathBuf {
+        self.runtime_path.join(CONTROL_SOCKET_PATH)
+    }
+
+    pub fn grpc_token_path(&self) -> PathBuf {
+        self.state_path.join(GRPC_TOKEN_PATH)
+    }
+
+    pub fn access_requests_path(&self) -> PathBuf {
+        self.state_path.join(ACCESS_REQUESTS_PATH)
+    }
+
+    pub fn history_path(&self) -> PathBuf {
+        self.state_path.join(HISTORY_PATH)
+    }
+
+    pub fn usage_path(&self) -> PathBuf {
+        self.state_path.join(USAGE_PATH)
+    }
+
+    pub fn config_path(&self, filename: &str) -> PathBuf {
+        self.base_path.join(filename)
+    }
+
+    async fn ensure_directory(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await.map_err(|e| {
+                crate::PunchError::ConfigError {
+                    path: parent.to_path_buf(),
+                    source: Box::new(e),
+                }
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ServerConfig {
+    pub authorized_keys: Vec<PublicKey>,
+
+    /// Keys the operator approved once with `punch server --confirm --remember`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub confirmed_keys: Vec<PublicKey>,
+
+    #[serde(default)]
+    pub settings: ServerSettings,
+
+    #[serde(default)]
+    pub network: NetworkSettings,
+
+    #[serde(default, skip_serializing_if = "HookSettings::is_empty")]
+    pub hooks: HookSettings,
+
+    #[serde(default, skip_serializing_if = "NotificationSettings::is_empty")]
+    pub notifications: NotificationSettings,
+
+    #[serde(default, skip_serializing_if = "LoggingSettings::is_empty")]
+    pub logging: LoggingSettings,
+
+    #[serde(default, skip_serializing_if = "TelemetrySettings::is_empty")]
+    pub telemetry: TelemetrySettings,
+
+    #[serde(default, skip_serializing_if = "RuntimeSettings::is_empty")]
+    pub runtime: RuntimeSettings,
+
+    #[serde(default, skip_serializing_if = "VpnSettings::is_empty")]
+    pub vpn: VpnSettings,
+
+    /// Named targets clients can ask for with `--service` instead of a port
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub services: BTreeMap<String, ServiceDefinition>,
+
+    /// Additional per-key policies, applied on top of the global settings
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub keys: BTreeMap<PublicKey, KeyPolicy>,
+}
+
+impl ServerConfig {
+    pub fn max_connections_for(&self, key: &PublicKey) -> usize {
+        self.keys
+            .get(key)
+            .and_then(|policy| policy.max_connections)
+            .unwrap_or(self.settings.max_connections_per_key)
+    }
+
+    /// The role of `key`, which only counts while it is authorized: a revoked key keeps its
+    /// policy, in case it gets authorized again.
+    pub fn role_of(&self, key: &PublicKey) -> Role {
+        if !self.authorized_keys.contains(key) {
+            return Role::default();
+        }
+        self.keys
+            .get(key)
+            .map(|policy| policy.role)
+            .unwrap_or_default()
+    }
+
+    /// Whether `key` may forward to `port`, services aside.
+    pub fn is_port_allowed(&self, key: &PublicKey, port: u16) -> bool {
+        if self.settings.is_port_denied(port) {
+            return false;
+        }
+        match self.role_of(key) {
+            Role::Admin => true,
+            Role::User => self.settings.allowed_ports.contains(port),
+            Role::Guest => self.settings.guest_ports.contains(&port),
+        }
+    }
+
+    pub fn schedule_of(&self, key: &PublicKey) -> Option<&Schedule> {
+        self.keys
+            .get(key)
+            .and_then(|policy| policy.schedule.as_ref())
+    }
+
+    pub fn max_session_for(&self, key: &PublicKey) -> Option<Duration> {
+        self.keys
+            .get(key)
+            .and_then(|policy| policy.max_session)
+            .map(Duration::from_secs)
+    }
+
+    /// Bytes per second a tunnel of `key` may carry, `None` for no cap.
+    pub fn bandwidth_for(&self, key: &PublicKey) -> Option<u64> {
+        (self.role_of(key) == Role::Guest && self.settings.guest_bandwidth > 0)
+            .then_some(self.settings.guest_bandwidth)
+    }
+
+    pub fn target_policy(&self, key: &PublicKey) -> TargetPolicy<'_> {
+        let policy = TargetPolicy::new().with_rules(
+            &self.settings.allowed_targets,
+            &self.settings.denied_targets,
+        );
+
+        match self.keys.get(key) {
+            Some(key_policy) => {
+                policy.with_rules(&key_policy.allowed_targets, &key_policy.denied_targets)
+            }
+            None => policy,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct KeyPolicy {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_targets: Vec<TargetRule>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub denied_targets: Vec<TargetRule>,
+
+    /// Overrides `settings.max_connections_per_key` for this key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+
+    #[serde(default, skip_serializing_if = "Role::is_user")]
+    pub role: Role,
+
+    /// When the key may connect, sessions are closed as the window ends
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Schedule>,
+
+    /// Seconds after which a session of this key is closed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_session: Option<u64>,
+}
+
+/// What an authorized key may do besides the per-key policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Not held to the allowed ports, and may use `punch sync` with this node
+    Admin,
+    /// Held to the allowed ports and targets
+    #[default]
+    User,
+    /// Only reaches services and `settings.guest_ports`, at `settings.guest_bandwidth`
+    Guest,
+}
+
+impl Role {
+    fn is_user(&self) -> bool {
+        *self == Role::User
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "admin" => Ok(Role::Admin),
+            "user" => Ok(Role::User),
+            "guest" => Ok(Role::Guest),
+            _ => Err("Invalid role. Use 'admin', 'user' or 'guest'.".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::Admin => write!(f, "admin"),
+            Role::User => write!(f, "user"),
+            Role::Guest => write!(f, "guest"),
+        }
+    }
+}
+
+/// A port published under a name, e.g. `web = { port = 8080, protocol = "tcp" }`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ServiceDefinition {
+    pub port: u16,
+
+    #[serde(default)]
+    pub protocol: Protocol,
+
+    /// Host to forward to, defaults to the server's loopback interface
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+
+    /// Shown to clients listing the services with `--list-services`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct NetworkSettings {
+    #[serde(default)]
+    pub transport: TransportSettings,
+
+    #[serde(default)]
+    pub buffers: BufferSettings,
+
+    #[serde(default, skip_serializing_if = "TcpSettings::is_empty")]
+    pub tcp: TcpSettings,
+
+    /// Name shown by `punch discover` on the local network. Like the node ID, it is also
+    /// published through n0's DNS discovery.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub discovery_name: Option<String>,
+
+    /// Only relay through this server instead of n0's public relays
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay_url: Option<RelayUrl>,
+}
+
+impl NetworkSettings {
+    fn validate(&self) -> Result<()> {
+        if let Some(name) = &self.discovery_name
+            && discovery::user_data(Some(name)).is_none()
+        {
+            return Err(crate::error!("network.discovery_name is too long"));
+        }
+
+        let t = &self.transport;
+        let values = [
+            ("stream_receive_window", t.stream_receive_window),
+            ("receive_window", t.receive_window),
+            ("send_window", t.send_window),
+            ("max_concurrent_bidi_streams", t.max_concurrent_bidi_streams),
+            ("max_concurrent_uni_streams", t.max_concurrent_uni_streams),
+        ];
+
+        for (name, value) in values {
+            if value.is_some_and(|v| v > MAX_VARINT) {
+                return Err(crate::error!("network.transport.{} is too large", name));
+            }
+        }
+
+        if t.stream_receive_window == Some(0) || t.receive_window == Some(0) {
+            return Err(crate::error!("Receive windows must be greater than 0"));
+        }
+
+        if self.buffers.size == Some(0) {
+            return Err(crate::error!("network.buffers.size must be greater than 0"));
+        }
+
+        self.tcp.validate()?;
+
+        Ok(())
+    }
+}
+
+/// QUIC transport parameters, left to iroh's defaults when unset.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct TransportSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub congestion_controller: Option<CongestionController>,
+
+    /// Initial flow control window of each stream, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_receive_window: Option<u64>,
+
+    /// Maximum data in flight across all streams of a connection, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receive_window: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub send_window: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_bidi_streams: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_uni_streams: Option<u64>,
+}
+
+/// Shell commands run in the background on tunnel lifecycle events. They receive
+/// `PUNCH_EVENT`, `PUNCH_PEER`, `PUNCH_PORT` and `PUNCH_PROTOCOL` in their environment,
+/// plus `PUNCH_TARGET` on the server.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct HookSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_connect: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_disconnect: Option<String>,
+
+    /// Run instead of `on_connect` when a peer connects again: on the client after failed
+    /// attempts, on the server when the node already had a session since it started
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_reconnect: Option<String>,
+}
+
+impl HookSettings {
+    pub fn is_empty(&self) -> bool {
+        self.on_connect.is_none() && self.on_disconnect.is_none() && self.on_reconnect.is_none()
+    }
+}
+
+/// Desktop notifications, for builds with the `notifications` feature.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct NotificationSettings {
+    /// When a tunnel is up, or back up after dropping
+    #[serde(default)]
+    pub connect: bool,
+
+    /// When a tunnel drops
+    #[serde(default)]
+    pub disconnect: bool,
+
+    /// On the server, when a key it doesn't know tries to connect or asks for access
+    #[serde(default)]
+    pub new_key: bool,
+}
+
+impl NotificationSettings {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Where log lines go once the config is read.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogOutput {
+    #[default]
+    Stderr,
+    /// The systemd journal, with span and event fields as journal fields (Linux only)
+    Journald,
+    /// The Windows Event Log, under the `punch` source
+    EventLog,
+}
+
+impl LogOutput {
+    fn is_stderr(&self) -> bool {
+        *self == LogOutput::Stderr
+    }
+}
+
+impl std::fmt::Display for LogOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogOutput::Stderr => write!(f, "stderr"),
+            LogOutput::Journald => write!(f, "journald"),
+            LogOutput::EventLog => write!(f, "event_log"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct LoggingSettings {
+    #[serde(default, skip_serializing_if = "LogOutput::is_stderr")]
+    pub output: LogOutput,
+
+    /// Least severe lines logged when `PUNCH_LOG` isn't set. Nothing goes to stderr by default,
+    /// while the journal and the Event Log get `info`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<String>,
+}
+
+impl LoggingSettings {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(level) = &self.level
+            && level
+                .parse::<tracing::level_filters::LevelFilter>()
+                .is_err()
+        {
+            return Err(crate::error!("Invalid logging.level: {}", level));
+        }
+
+        let supported = match self.output {
+            LogOutput::Stderr => true,
+            LogOutput::Journald => cfg!(target_os = "linux"),
+            LogOutput::EventLog => cfg!(windows),
+        };
+        if !supported {
+            return Err(crate::error!(
+                "logging.output = \"{}\" is not supported on this platform",
+                self.output
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Export of traces and metrics over OTLP/HTTP, for builds with the `otel` feature.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct TelemetrySettings {
+    /// Collector to export to, e.g. `http://localhost:4318`. Nothing is exported without it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+
+    /// Reported as `service.name`, defaults to `punch`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_name: Option<String>,
+
+    /// Least severe spans exported, defaults to `info`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<String>,
+
+    /// Seconds between two exports of the metrics, defaults to 60
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics_interval: Option<u64>,
+}
+
+impl TelemetrySettings {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(endpoint) = &self.endpoint
+            && !(endpoint.starts_with("http://") || endpoint.starts_with("https://"))
+        {
+            return Err(crate::error!(
+                "telemetry.endpoint must be an http:// or https:// URL"
+            ));
+        }
+
+        if let Some(level) = &self.level
+            && level
+                .parse::<tracing::level_filters::LevelFilter>()
+                .is_err()
+        {
+            return Err(crate::error!("Invalid telemetry.level: {}", level));
+        }
+
+        if self.metrics_interval == Some(0) {
+            return Err(crate::error!(
+                "telemetry.metrics_interval must be greater than 0"
+            ));
+        }
 
-        Ok(Self { base_path })
+        Ok(())
     }
+}
 
-    pub fn with_base_path(base_path: PathBuf) -> Self {
-        Self { base_path }
+/// The Tokio runtime punch runs on, e.g. a couple of threads on a small relay box. Read
+/// before anything else, so only applied on the next start.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct RuntimeSettings {
+    /// Threads running the tunnels, defaults to one per CPU core
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worker_threads: Option<usize>,
+
+    /// Threads for blocking work such as file access, defaults to Tokio's 512
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_blocking_threads: Option<usize>,
+
+    /// Run everything on the main thread instead of a pool of workers
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub current_thread: bool,
+}
+
+impl RuntimeSettings {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
     }
 
-    pub async fn load<C: Configuration>(&self) -> Result<C> {
-        let path = self.config_path(C::filename());
+    fn validate(&self) -> Result<()> {
+        if self.worker_threads == Some(0) {
+            return Err(crate::error!(
+                "runtime.worker_threads must be greater than 0"
+            ));
+        }
 
-        if path.exists() {
-            self.load_from_file(&path).await
-        } else {
-            let config = C::default();
-            self.save(&config).await?;
-            Ok(config)
+        if self.max_blocking_threads == Some(0) {
+            return Err(crate::error!(
+                "runtime.max_blocking_threads must be greater than 0"
+            ));
         }
+
+        Ok(())
     }
+}
 
-    pub async fn save<C: Configuration>(&self, config: &C) -> Result<()> {
-        config.validate()?;
+/// The layer-3 network `punch vpn` joins, for builds with the `vpn` feature. Only admin keys
+/// may join it, as it isn't held to the allowed ports or targets.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct VpnSettings {
+    /// IPv4 network addresses are leased from, e.g. `10.77.0.0/24`. The server takes the first
+    /// one and no VPN is offered without it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subnet: Option<Ipv4Net>,
+
+    /// Other networks clients route through the server, which must forward them itself
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<Ipv4Net>,
+
+    /// Name of the interface on the server, defaults to `punch0`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+
+    /// MTU of the interfaces on both ends, defaults to 1200
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u16>,
+}
 
-        let path = self.config_path(C::filename());
-        self.ensure_directory(&path).await?;
+impl VpnSettings {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
 
-        let content = toml::to_string_pretty(config)?;
-        tokio::fs::write(&path, content)
-            .await
-            .map_err(|e| crate::PunchError::ConfigError {
-                path: path.to_path_buf(),
-                source: Box::new(e),
-            })?;
+    pub fn mtu(&self) -> u16 {
+        self.mtu.unwrap_or(DEFAULT_VPN_MTU)
+    }
+
+    pub fn interface(&self) -> &str {
+        self.interface.as_deref().unwrap_or(DEFAULT_VPN_INTERFACE)
+    }
 
+    fn validate(&self) -> Result<()> {
+        if self.subnet.is_none() && !self.is_empty() {
+            return Err(crate::error!("vpn.subnet is required to offer a VPN"));
+        }
+        if let Some(subnet) = &self.subnet
+            && subnet.prefix_len() > 30
+        {
+            return Err(crate::error!(
+                "vpn.subnet {} is too small, use a /30 or larger",
+                subnet
+            ));
+        }
+        // Below the 576 bytes every IPv4 host must accept
+        if self.mtu.is_some_and(|mtu| mtu < 576) {
+            return Err(crate::error!("vpn.mtu must be at least 576"));
+        }
         Ok(())
     }
+}
 
-    async fn load_from_file<C: Configuration>(&self, path: &Path) -> Result<C> {
-        let content =
-            tokio::fs::read_to_string(path)
-                .await
-                .map_err(|e| crate::PunchError::ConfigError {
-                    path: path.to_path_buf(),
-                    source: Box::new(e),
-                })?;
+/// Sizing of the buffers used to copy data between the tunnel and local sockets.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct BufferSettings {
+    /// Size of each buffer copying from local sockets to the tunnel, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<usize>,
 
-        let config: C = toml::from_str(&content)?;
-        config.validate()?;
+    /// Number of idle buffers kept around for reuse
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_capacity: Option<usize>,
+}
 
-        Ok(config)
+/// Options of the TCP sockets on either end of the tunnel: the local connections a client
+/// accepts, and the connections a server opens to the target. Unset values keep the
+/// system's defaults.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TcpSettings {
+    /// Send small writes right away (`TCP_NODELAY`), for interactive protocols like SSH
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodelay: Option<bool>,
+
+    /// Seconds a connection stays idle before keepalive probes are sent. Setting any of the
+    /// keepalive values turns them on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keepalive_time: Option<u64>,
+
+    /// Seconds between two keepalive probes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keepalive_interval: Option<u64>,
+
+    /// Unanswered keepalive probes before the connection is dropped, not on Windows
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keepalive_retries: Option<u32>,
+
+    /// Milliseconds sent data may stay unacknowledged before the connection is dropped
+    /// (`TCP_USER_TIMEOUT`, Linux only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_timeout_ms: Option<u64>,
+}
+
+impl TcpSettings {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
     }
 
-    fn config_path(&self, filename: &str) -> PathBuf {
-        self.base_path.join(filename)
+    pub fn keepalive(&self) -> bool {
+        self.keepalive_time.is_some()
+            || self.keepalive_interval.is_some()
+            || self.keepalive_retries.is_some()
     }
 
-    async fn ensure_directory(&self, path: &Path) -> Result<()> {
-        if let Some(parent) = path.parent() {
-            tokio::fs::create_dir_all(parent).await.map_err(|e| {
-                crate::PunchError::ConfigError {
-                    path: parent.to_path_buf(),
-                    source: Box::new(e),
-                }
-            })?;
+    fn validate(&self) -> Result<()> {
+        let values = [
+            ("keepalive_time", self.keepalive_time),
+            ("keepalive_interval", self.keepalive_interval),
+            ("keepalive_retries", self.keepalive_retries.map(u64::from)),
+        ];
+        for (name, value) in values {
+            if value == Some(0) {
+                return Err(crate::error!("network.tcp.{} must be greater than 0", name));
+            }
         }
+
         Ok(())
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct ServerConfig {
-    pub authorized_keys: Vec<PublicKey>,
-
-    #[serde(default)]
-    pub settings: ServerSettings,
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CongestionController {
+    Cubic,
+    Bbr,
+    NewReno,
 }
 
+const MAX_VARINT: u64 = (1 << 62) - 1;
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ServerSettings {
     #[serde(default = "default_max_connections")]
     pub max_connections: usize,
 
+    /// Concurrent connections allowed for a single key
+    #[serde(default = "default_max_connections_per_key")]
+    pub max_connections_per_key: usize,
+
+    /// Streams a single connection may have open at once, further ones wait for one to end
+    #[serde(default = "default_max_streams_per_connection")]
+    pub max_streams_per_connection: usize,
+
+    /// Bytes of buffers the streams of a single connection may hold at once, each stream
+    /// holding up to two of `network.buffers.size`. `0` for no cap.
+    #[serde(default = "default_stream_memory_per_connection")]
+    pub stream_memory_per_connection: u64,
+
+    /// Ports keys may forward to, e.g. `["1024-5999", "7000-65535"]`
     #[serde(default = "default_port_range")]
-    pub allowed_ports: (u16, u16),
+    pub allowed_ports: PortRanges,
+
+    /// Let `allowed_ports` include ports below 1024, e.g. to reach a web server on port 80
+    #[serde(default)]
+    pub allow_privileged_ports: bool,
+
+    /// Ports no key may forward to whatever its role, e.g. `[6379, "9000-9100"]`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub denied_ports: Vec<PortRange>,
+
+    /// Hosts, addresses or CIDR blocks clients may forward to besides the loopback interface
+    #[serde(default)]
+    pub allowed_targets: Vec<TargetRule>,
+
+    /// Hosts, addresses or CIDR blocks no client may forward to, overriding any allow rule
+    #[serde(default)]
+    pub denied_targets: Vec<TargetRule>,
+
+    /// Prepend a PROXY protocol v2 header to TCP connections made to the target
+    #[serde(default)]
+    pub proxy_protocol: bool,
+
+    /// JSONL file recording every connection attempt, relative to the config directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit_log: Option<PathBuf>,
+
+    /// Let unauthorized nodes submit access requests with `punch auth request`
+    #[serde(default = "default_true")]
+    pub access_requests: bool,
+
+    /// Seconds to keep dialing a target that refuses connections, e.g. while it restarts,
+    /// before failing the stream. `0` fails right away.
+    #[serde(default)]
+    pub wait_for_service: u64,
+
+    /// Count the sessions and bytes of every key, as reported by `punch server usage`
+    #[serde(default = "default_true")]
+    pub track_usage: bool,
+
+    /// Your other nodes, allowed to exchange hosts and authorized keys with `punch sync`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sync_peers: Vec<PublicKey>,
+
+    /// Ports keys with the guest role may forward to, besides services
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub guest_ports: Vec<u16>,
+
+    /// Bytes per second a tunnel of a guest may carry, `0` for no cap
+    #[serde(default = "default_guest_bandwidth")]
+    pub guest_bandwidth: u64,
+
+    /// User to switch to once the server is listening, as `user` or `user:group`, when it
+    /// is started as root
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_as: Option<String>,
+
+    /// Nameserver `punch client --dns` reaches, defaults to the first one in
+    /// `/etc/resolv.conf`. Held to the allowed targets like any other host.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns_resolver: Option<String>,
+
+    /// Address to serve the gRPC control API on, loopback only. Clients authenticate with the
+    /// bearer token of the `grpc_token` file in the state directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grpc_listen: Option<SocketAddr>,
+}
+
+impl ServerSettings {
+    pub fn is_port_denied(&self, port: u16) -> bool {
+        self.denied_ports.iter().any(|range| range.contains(port))
+    }
+
+    /// The ports keys with the user role may forward to once the denied ones are taken out.
+    pub fn effective_ports(&self) -> Vec<PortRange> {
+        self.allowed_ports.without(&self.denied_ports)
+    }
 }
 
 impl Default for ServerSettings {
     fn default() -> Self {
         Self {
             max_connections: default_max_connections(),
+            max_connections_per_key: default_max_connections_per_key(),
+            max_streams_per_connection: default_max_streams_per_connection(),
+            stream_memory_per_connection: default_stream_memory_per_connection(),
             allowed_ports: default_port_range(),
+            allow_privileged_ports: false,
+            denied_ports: Vec::new(),
+            allowed_targets: Vec::new(),
+            denied_targets: Vec::new(),
+            proxy_protocol: false,
+            audit_log: None,
+            access_requests: true,
+            wait_for_service: 0,
+            track_usage: true,
+            sync_peers: Vec::new(),
+            guest_ports: Vec::new(),
+            guest_bandwidth: default_guest_bandwidth(),
+            run_as: None,
+            dns_resolver: None,
+            grpc_listen: None,
         }
     }
 }
@@ -125,8 +1401,26 @@ impl Default for ServerSettings {
 fn default_max_connections() -> usize {
     DEFAULT_MAX_CONNECTIONS
 }
-fn default_port_range() -> (u16, u16) {
-    DEFAULT_ALLOWED_PORT_RANGE
+fn default_max_connections_per_key() -> usize {
+    DEFAULT_MAX_CONNECTIONS_PER_KEY
+}
+fn default_max_streams_per_connection() -> usize {
+    DEFAULT_MAX_STREAMS_PER_CONNECTION
+}
+fn default_stream_memory_per_connection() -> u64 {
+    DEFAULT_STREAM_MEMORY_PER_CONNECTION
+}
+fn default_guest_bandwidth() -> u64 {
+    DEFAULT_GUEST_BANDWIDTH
+}
+
+fn default_port_range() -> PortRanges {
+    let (min, max) = DEFAULT_ALLOWED_PORT_RANGE;
+    PortRanges::from(vec![PortRange::new(min, max)])
+}
+
+fn default_true() -> bool {
+    true
 }
 
 fn default_timeout() -> u64 {
@@ -137,6 +1431,26 @@ fn default_retries() -> usize {
     DEFAULT_RETRIES
 }
 
+fn default_retry_initial_delay() -> u64 {
+    DEFAULT_RETRY_INITIAL_DELAY_MS
+}
+
+fn default_retry_max_delay() -> u64 {
+    DEFAULT_RETRY_MAX_DELAY_MS
+}
+
+fn default_retry_max_elapsed() -> u64 {
+    DEFAULT_RETRY_MAX_ELAPSED
+}
+
+fn default_connections() -> usize {
+    DEFAULT_CONNECTIONS
+}
+
+fn default_acceptors() -> usize {
+    DEFAULT_ACCEPTORS
+}
+
 impl Configuration for ServerConfig {
     fn filename() -> &'static str {
         "server.toml"
@@ -145,21 +1459,95 @@ impl Configuration for ServerConfig {
     fn default() -> Self {
         Self {
             authorized_keys: Vec::new(),
+            confirmed_keys: Vec::new(),
             settings: ServerSettings::default(),
+            network: NetworkSettings::default(),
+            hooks: HookSettings::default(),
+            notifications: NotificationSettings::default(),
+            logging: LoggingSettings::default(),
+            telemetry: TelemetrySettings::default(),
+            runtime: RuntimeSettings::default(),
+            vpn: VpnSettings::default(),
+            services: BTreeMap::new(),
+            keys: BTreeMap::new(),
         }
     }
 
     fn validate(&self) -> Result<()> {
-        if self.settings.allowed_ports.0 > self.settings.allowed_ports.1 {
-            return Err(crate::error!("Invalid port range: min > max"));
+        if !self.settings.allow_privileged_ports
+            && let Some(range) = self
+                .settings
+                .allowed_ports
+                .ranges()
+                .iter()
+                .find(|range| range.min < 1024)
+        {
+            return Err(crate::error!(
+                "Allowed port range {} includes ports below 1024, set settings.allow_privileged_ports = true to allow them",
+                range
+            ));
+        }
+
+        if let Some(key) = self
+            .keys
+            .iter()
+            .find_map(|(key, policy)| (policy.max_session == Some(0)).then_some(key))
+        {
+            return Err(crate::error!(
+                "keys.{}.max_session must be greater than 0",
+                key
+            ));
+        }
+
+        if self.settings.max_streams_per_connection == 0 {
+            return Err(crate::error!(
+                "settings.max_streams_per_connection must be greater than 0"
+            ));
         }
 
-        if self.settings.allowed_ports.0 < 1024 {
-            return Err(crate::error!("Minimum allowed port must be >= 1024"));
+        if let Some(address) = self.settings.grpc_listen
+            && !address.ip().is_loopback()
+        {
+            return Err(crate::error!(
+                "settings.grpc_listen must be a loopback address, got {}",
+                address
+            ));
         }
 
+        self.network.validate()?;
+        self.logging.validate()?;
+        self.telemetry.validate()?;
+        self.runtime.validate()?;
+        self.vpn.validate()?;
+
         Ok(())
     }
+
+    fn apply_env(&mut self) -> Result<()> {
+        if let Ok(keys) = std::env::var(ENV_AUTHORIZED_KEYS) {
+            for key in keys.split([',', ' ', '\n']).filter(|key| !key.is_empty()) {
+                let key: PublicKey = key.parse().map_err(|e| {
+                    crate::error!(
+                        source = e,
+                        "Invalid key in {}: {}",
+                        ENV_AUTHORIZED_KEYS,
+                        key
+                    )
+                })?;
+                if !self.authorized_keys.contains(&key) {
+                    self.authorized_keys.push(key);
+                }
+            }
+        }
+
+        if let Ok(ports) = std::env::var(ENV_ALLOWED_PORTS) {
+            self.settings.allowed_ports = ports
+                .parse()
+                .map_err(|e| crate::error!("Invalid {}: {}", ENV_ALLOWED_PORTS, e))?;
+        }
+
+        self.validate()
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -169,6 +1557,27 @@ pub struct ClientSettings {
 
     #[serde(default = "default_retries")]
     pub max_retries: usize,
+
+    /// Delay before the first retry, doubled on every following one
+    #[serde(default = "default_retry_initial_delay")]
+    pub retry_initial_delay_ms: u64,
+
+    #[serde(default = "default_retry_max_delay")]
+    pub retry_max_delay_ms: u64,
+
+    /// Seconds after which a client stops retrying, whatever `max_retries` says
+    #[serde(default = "default_retry_max_elapsed")]
+    pub retry_max_elapsed: u64,
+
+    /// Parallel connections TCP tunnels spread their streams over, for fast links where a
+    /// single connection's flow control or congestion window is the bottleneck
+    #[serde(default = "default_connections")]
+    pub connections: usize,
+
+    /// Listeners sharing each local TCP port through `SO_REUSEPORT`, each accepting from
+    /// its own task, for ports taking in many connections (Linux only)
+    #[serde(default = "default_acceptors")]
+    pub acceptors: usize,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -177,6 +1586,69 @@ pub struct ClientConfig {
 
     #[serde(default)]
     pub settings: ClientSettings,
+
+    #[serde(default)]
+    pub network: NetworkSettings,
+
+    #[serde(default, skip_serializing_if = "HookSettings::is_empty")]
+    pub hooks: HookSettings,
+
+    #[serde(default, skip_serializing_if = "NotificationSettings::is_empty")]
+    pub notifications: NotificationSettings,
+
+    #[serde(default, skip_serializing_if = "LoggingSettings::is_empty")]
+    pub logging: LoggingSettings,
+
+    #[serde(default, skip_serializing_if = "TelemetrySettings::is_empty")]
+    pub telemetry: TelemetrySettings,
+
+    #[serde(default, skip_serializing_if = "RuntimeSettings::is_empty")]
+    pub runtime: RuntimeSettings,
+}
+
+impl ClientConfig {
+    /// Resolves a known host name, or parses `identifier` as a Node ID. Hosts with a domain
+    /// have their node ID refreshed from DNS, the cached one being used when the lookup fails.
+    pub async fn resolve_host(
+        &mut self,
+        resolver: &DnsResolver,
+        identifier: &str,
+    ) -> Result<NodeId> {
+        let Some(host) = self.hosts.iter().find(|h| h.name == identifier) else {
+            return identifier
+                .parse()
+                .map_err(|_| crate::error!("Unknown host: {}", identifier));
+        };
+        let Some(dns) = host.dns.clone() else {
+            return Ok(host.id);
+        };
+
+        let node_id = match discovery::resolve_dns(resolver, &dns).await {
+            Ok(node_id) => node_id,
+            Err(e) => {
+                crate::warning!("{}, using the last known node ID", e);
+                return Ok(host.id);
+            }
+        };
+
+        if node_id != host.id {
+            crate::info!(
+                "Node ID of {} changed to {}",
+                host.name.bold(),
+                reduced_node_id(&node_id)
+            );
+            let name = host.name.clone();
+            *self = ConfigManager::new()?
+                .update(|config: &mut ClientConfig| {
+                    if let Some(host) = config.hosts.iter_mut().find(|h| h.name == name) {
+                        host.id = node_id;
+                    }
+                    Ok(config.clone())
+                })
+                .await?;
+        }
+        Ok(node_id)
+    }
 }
 
 impl Default for ClientSettings {
@@ -184,6 +1656,11 @@ impl Default for ClientSettings {
         Self {
             connection_timeout: DEFAULT_TIMEOUT,
             max_retries: DEFAULT_RETRIES,
+            retry_initial_delay_ms: DEFAULT_RETRY_INITIAL_DELAY_MS,
+            retry_max_delay_ms: DEFAULT_RETRY_MAX_DELAY_MS,
+            retry_max_elapsed: DEFAULT_RETRY_MAX_ELAPSED,
+            connections: DEFAULT_CONNECTIONS,
+            acceptors: DEFAULT_ACCEPTORS,
         }
     }
 }
@@ -202,6 +1679,28 @@ pub struct Host {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub last_connected: Option<u64>,
+
+    /// Relay to use for this host, when it is the only one the network lets through
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay_url: Option<RelayUrl>,
+
+    /// Domain whose `_punch` TXT record gives the node ID, `id` then caches the last answer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns: Option<String>,
+
+    /// Other identities of the same server, tried in order when `id` can't be reached
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub backups: Vec<NodeId>,
+
+    #[serde(default, skip_serializing_if = "HostStats::is_empty")]
+    pub stats: HostStats,
+
+    /// Mapping used by `punch connect` when none is given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mapping: Option<Mapping>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<Protocol>,
 }
 
 fn current_timestamp() -> u64 {
@@ -211,6 +1710,42 @@ fn current_timestamp() -> u64 {
         .as_secs()
 }
 
+/// Totals over the tunnels opened to a host, updated when they close.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct HostStats {
+    #[serde(default)]
+    pub connections: u64,
+    #[serde(default)]
+    pub bytes_up: u64,
+    #[serde(default)]
+    pub bytes_down: u64,
+    /// Seconds spent connected, across all sessions
+    #[serde(default)]
+    pub connected_secs: u64,
+}
+
+impl HostStats {
+    pub fn is_empty(&self) -> bool {
+        self.connections == 0
+    }
+
+    pub fn record_session(
+        &mut self,
+        duration: std::time::Duration,
+        bytes_up: u64,
+        bytes_down: u64,
+    ) {
+        self.connections += 1;
+        self.bytes_up = self.bytes_up.saturating_add(bytes_up);
+        self.bytes_down = self.bytes_down.saturating_add(bytes_down);
+        self.connected_secs = self.connected_secs.saturating_add(duration.as_secs());
+    }
+
+    pub fn average_session_secs(&self) -> Option<u64> {
+        self.connected_secs.checked_div(self.connections)
+    }
+}
+
 impl Host {
     pub fn new(name: String, id: NodeId) -> Self {
         Self {
@@ -219,9 +1754,52 @@ impl Host {
             description: None,
             added_at: current_timestamp(),
             last_connected: None,
+            relay_url: None,
+            stats: HostStats::default(),
+            dns: None,
+            backups: Vec::new(),
+            mapping: None,
+            protocol: None,
         }
     }
 
+    pub fn with_backups(mut self, backups: Vec<NodeId>) -> Self {
+        self.backups = backups;
+        self
+    }
+
+    /// Whether `node_id` is this host's primary or one of its backup identities.
+    pub fn has_node(&self, node_id: &NodeId) -> bool {
+        &self.id == node_id || self.backups.contains(node_id)
+    }
+
+    pub fn with_description(mut self, description: Option<String>) -> Self {
+        self.description = description;
+        self
+    }
+
+    pub fn with_relay_url(mut self, relay_url: Option<RelayUrl>) -> Self {
+        self.relay_url = relay_url;
+        self
+    }
+
+    pub fn with_dns(mut self, dns: Option<String>) -> Self {
+        self.dns = dns;
+        self
+    }
+
+    pub fn with_mapping(mut self, mapping: Option<Mapping>, protocol: Option<Protocol>) -> Self {
+        self.mapping = mapping;
+        self.protocol = protocol;
+        self
+    }
+
+    /// Seconds since we last connected to this host
+    pub fn last_connected_ago(&self) -> Option<u64> {
+        self.last_connected
+            .map(|last| current_timestamp().saturating_sub(last))
+    }
+
     pub fn mark_connected(&mut self) {
         self.last_connected = Some(current_timestamp());
     }
@@ -236,6 +1814,12 @@ impl Configuration for ClientConfig {
         Self {
             hosts: Vec::new(),
             settings: ClientSettings::default(),
+            network: NetworkSettings::default(),
+            hooks: HookSettings::default(),
+            notifications: NotificationSettings::default(),
+            logging: LoggingSettings::default(),
+            telemetry: TelemetrySettings::default(),
+            runtime: RuntimeSettings::default(),
         }
     }
 
@@ -247,6 +1831,11 @@ impl Configuration for ClientConfig {
             }
         }
 
+        self.network.validate()?;
+        self.logging.validate()?;
+        self.telemetry.validate()?;
+        self.runtime.validate()?;
+
         Ok(())
     }
 }
@@ -260,58 +1849,89 @@ impl HostManager {
         Self { config_manager }
     }
 
-    pub async fn add_host(
-        &self,
-        name: String,
-        id: NodeId,
-        description: Option<String>,
-    ) -> Result<()> {
-        let mut config: ClientConfig = self.config_manager.load().await?;
-
-        if config.hosts.iter().any(|h| h.name == name) {
-            return Err(crate::error!("Host with name '{}' already exists", name));
-        }
-
-        if let Some(existing) = config.hosts.iter().find(|h| h.id == id) {
-            return Err(crate::error!(
-                "Node ID already exists with name '{}'",
-                existing.name
-            ));
-        }
-
-        let mut host = Host::new(name, id);
-        host.description = description;
+    pub async fn add_host(&self, host: Host) -> Result<()> {
+        self.config_manager
+            .update(|config: &mut ClientConfig| {
+                if config.hosts.iter().any(|h| h.name == host.name) {
+                    return Err(crate::error!(
+                        "Host with name '{}' already exists",
+                        host.name
+                    ));
+                }
 
-        config.hosts.push(host);
-        self.config_manager.save(&config).await?;
+                if let Some(existing) = config.hosts.iter().find(|h| h.id == host.id) {
+                    return Err(crate::error!(
+                        "Node ID already exists with name '{}'",
+                        existing.name
+                    ));
+                }
 
-        Ok(())
+                config.hosts.push(host);
+                Ok(())
+            })
+            .await
     }
 
     pub async fn remove_host(&self, identifier: &str) -> Result<Host> {
-        let mut config: ClientConfig = self.config_manager.load().await?;
-
-        let position = config
-            .hosts
-            .iter()
-            .position(|h| h.name == identifier || h.id.to_string() == identifier)
-            .ok_or_else(|| crate::error!("Host not found: {}", identifier))?;
-
-        let removed = config.hosts.remove(position);
-        self.config_manager.save(&config).await?;
-
-        Ok(removed)
+        self.config_manager
+            .update(|config: &mut ClientConfig| {
+                let position = config
+                    .hosts
+                    .iter()
+                    .position(|h| h.name == identifier || h.id.to_string() == identifier)
+                    .ok_or_else(|| crate::error!("Host not found: {}", identifier))?;
+
+                Ok(config.hosts.remove(position))
+            })
+            .await
     }
 
     pub async fn mark_host_connected(&self, node_id: &NodeId) -> Result<()> {
-        let mut config: ClientConfig = self.config_manager.load().await?;
-
-        if let Some(host) = config.hosts.iter_mut().find(|h| &h.id == node_id) {
-            host.mark_connected();
-            self.config_manager.save(&config).await?;
-        }
+        self.config_manager
+            .update(|config: &mut ClientConfig| {
+                if let Some(host) = config.hosts.iter_mut().find(|h| h.has_node(node_id)) {
+                    host.mark_connected();
+                }
+                Ok(())
+            })
+            .await
+    }
 
-        Ok(())
+    /// Adds the hosts another node knows about, skipping `own` and nodes already known under
+    /// another name. A host known under the same name is replaced if the other one was
+    /// added later, keeping its local stats. Returns how many were added and replaced.
+    pub async fn merge_hosts(&self, hosts: Vec<Host>, own: &NodeId) -> Result<(usize, usize)> {
+        self.config_manager
+            .update(|config: &mut ClientConfig| {
+                let (mut added, mut replaced) = (0, 0);
+                for host in hosts {
+                    if host.has_node(own) {
+                        continue;
+                    }
+                    let known = config.hosts.iter().any(|h| h.id == host.id);
+                    match config.hosts.iter_mut().find(|h| h.name == host.name) {
+                        Some(existing)
+                            if host.added_at > existing.added_at
+                                && (existing.id == host.id || !known) =>
+                        {
+                            *existing = Host {
+                                stats: std::mem::take(&mut existing.stats),
+                                last_connected: existing.last_connected,
+                                ..host
+                            };
+                            replaced += 1;
+                        }
+                        Some(_) => {}
+                        None if known => {}
+                        None => {
+                            config.hosts.push(host);
+                            added += 1;
+                        }
+                    }
+                }
+                Ok((added, replaced))
+            })
+            .await
     }
 
     pub async fn find_host(&self, identifier: &str) -> Result<Option<Host>> {
@@ -332,52 +1952,153 @@ impl HostManager {
 #[derive(Clone, Debug)]
 pub struct AuthorizationManager {
     config_manager: ConfigManager,
+    cache: Option<Arc<ConfigCache<ServerConfig>>>,
+    /// Keys revoked through this manager, so that their open connections can be closed
+    revocations: broadcast::Sender<PublicKey>,
 }
 
 impl AuthorizationManager {
     pub fn new(config_manager: ConfigManager) -> Self {
-        Self { config_manager }
+        Self {
+            config_manager,
+            cache: None,
+            revocations: broadcast::channel(16).0,
+        }
+    }
+
+    /// Reads and writes the server config through `cache` instead of the file.
+    pub fn with_cache(mut self, cache: Arc<ConfigCache<ServerConfig>>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    async fn config(&self) -> Result<Arc<ServerConfig>> {
+        match &self.cache {
+            Some(cache) => Ok(cache.get()),
+            None => Ok(Arc::new(self.config_manager.load().await?)),
+        }
+    }
+
+    async fn update<T>(&self, f: impl FnOnce(&mut ServerConfig) -> Result<T>) -> Result<T> {
+        match &self.cache {
+            Some(cache) => cache.update(f).await,
+            None => self.config_manager.update(f).await,
+        }
+    }
+
+    pub fn subscribe_revocations(&self) -> broadcast::Receiver<PublicKey> {
+        self.revocations.subscribe()
     }
 
     pub async fn is_authorized(&self, node_id: &PublicKey) -> Result<bool> {
-        let config: ServerConfig = self.config_manager.load().await?;
+        let config = self.config().await?;
         Ok(config.authorized_keys.contains(node_id))
     }
 
     pub async fn authorize(&self, key: PublicKey) -> Result<()> {
-        let mut config: ServerConfig = self.config_manager.load().await?;
-
-        if !config.authorized_keys.contains(&key) {
-            config.authorized_keys.push(key);
-            self.config_manager.save(&config).await?;
-        }
+        self.update(|config| {
+            if !config.authorized_keys.contains(&key) {
+                config.authorized_keys.push(key);
+            }
+            Ok(())
+        })
+        .await
+    }
 
-        Ok(())
+    /// Authorizes every key of `keys` in a single write, returning how many were new.
+    pub async fn authorize_all(&self, keys: &[PublicKey]) -> Result<usize> {
+        self.update(|config| {
+            let before = config.authorized_keys.len();
+            for key in keys {
+                if !config.authorized_keys.contains(key) {
+                    config.authorized_keys.push(*key);
+                }
+            }
+            Ok(config.authorized_keys.len() - before)
+        })
+        .await
     }
 
     pub async fn revoke(&self, key: &PublicKey) -> Result<bool> {
-        let mut config: ServerConfig = self.config_manager.load().await?;
+        let revoked = self
+            .update(|config| {
+                let original_len = config.authorized_keys.len();
+                config.authorized_keys.retain(|k| k != key);
+                config.confirmed_keys.retain(|k| k != key);
+                Ok(config.authorized_keys.len() < original_len)
+            })
+            .await?;
+
+        if revoked {
+            // Nobody listening just means no server runs in this process
+            let _ = self.revocations.send(*key);
+        }
+        Ok(revoked)
+    }
 
-        let original_len = config.authorized_keys.len();
-        config.authorized_keys.retain(|k| k != key);
+    pub async fn is_confirmed(&self, node_id: &PublicKey) -> Result<bool> {
+        let config = self.config().await?;
+        Ok(config.confirmed_keys.contains(node_id))
+    }
 
-        if config.authorized_keys.len() < original_len {
-            self.config_manager.save(&config).await?;
-            Ok(true)
-        } else {
-            Ok(false)
-        }
+    pub async fn remember_confirmed(&self, key: PublicKey) -> Result<()> {
+        self.update(|config| {
+            if !config.confirmed_keys.contains(&key) {
+                config.confirmed_keys.push(key);
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// When each key last opened a session, `None` when the store doesn't keep track.
+    pub fn last_seen(&self) -> Result<Option<HashMap<NodeId, u64>>> {
+        self.config_manager.last_seen()
     }
 
     pub async fn list_authorized(&self) -> Result<Vec<PublicKey>> {
-        let config: ServerConfig = self.config_manager.load().await?;
-        Ok(config.authorized_keys)
+        let config = self.config().await?;
+        Ok(config.authorized_keys.clone())
+    }
+
+    pub async fn is_port_allowed(&self, key: &PublicKey, port: u16) -> Result<bool> {
+        let config = self.config().await?;
+        Ok(config.is_port_allowed(key, port))
     }
 
-    pub async fn is_port_allowed(&self, port: u16) -> Result<bool> {
-        let config: ServerConfig = self.config_manager.load().await?;
-        let (min, max) = config.settings.allowed_ports;
-        Ok(port >= min && port <= max)
+    pub async fn role_of(&self, key: &PublicKey) -> Result<Role> {
+        let config = self.config().await?;
+        Ok(config.role_of(key))
+    }
+
+    /// Gives `key` a role, which only takes effect while it is authorized.
+    pub async fn set_role(&self, key: PublicKey, role: Role) -> Result<()> {
+        self.update(|config| {
+            let policy = config.keys.entry(key).or_default();
+            policy.role = role;
+            // Leave no empty policy behind for keys back to the default role
+            if role == Role::User
+                && policy.allowed_targets.is_empty()
+                && policy.denied_targets.is_empty()
+                && policy.max_connections.is_none()
+                && policy.schedule.is_none()
+                && policy.max_session.is_none()
+            {
+                config.keys.remove(&key);
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn is_target_allowed(
+        &self,
+        key: &PublicKey,
+        host: &str,
+        addrs: &[IpAddr],
+    ) -> Result<bool> {
+        let config = self.config().await?;
+        Ok(config.target_policy(key).allows(host, addrs))
     }
 }
 
@@ -392,3 +2113,37 @@ pub async fn save_config<C: Configuration>(config: &C) -> Result<()> {
     let manager = ConfigManager::new()?;
     manager.save(config).await
 }
+
+fn parse_config<C: Configuration>(content: &str) -> Result<C> {
+    let config: C = toml::from_str(content)?;
+    config.validate()?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admin_config() -> (ServerConfig, PublicKey) {
+        let key = iroh::SecretKey::generate(&mut rand::rngs::OsRng).public();
+        let mut config = <ServerConfig as Configuration>::default();
+        config.authorized_keys.push(key);
+        config.keys.entry(key).or_default().role = Role::Admin;
+        (config, key)
+    }
+
+    #[test]
+    fn authorized_keys_keep_their_role() {
+        let (config, key) = admin_config();
+        assert_eq!(config.role_of(&key), Role::Admin);
+        assert!(config.is_port_allowed(&key, 22));
+    }
+
+    #[test]
+    fn revoked_keys_lose_their_role() {
+        let (mut config, key) = admin_config();
+        config.authorized_keys.clear();
+        assert_eq!(config.role_of(&key), Role::default());
+        assert!(config.keys.contains_key(&key));
+    }
+}
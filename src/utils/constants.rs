@@ -1,9 +1,143 @@
 pub const ALPN: &[u8] = b"punch/0";
-pub const MAX_RETRIES: usize = 5;
+pub const BENCH_ALPN: &[u8] = b"punch/bench/0";
+pub const ACCESS_ALPN: &[u8] = b"punch/access/0";
+pub const SERVICES_ALPN: &[u8] = b"punch/services/0";
+pub const SYNC_ALPN: &[u8] = b"punch/sync/0";
+pub const TRANSFER_ALPN: &[u8] = b"punch/transfer/0";
+pub const PROBE_ALPN: &[u8] = b"punch/probe/0";
+pub const VPN_ALPN: &[u8] = b"punch/vpn/0";
 
 pub const PRIVATE_KEY_PATH: &str = "private_key";
+pub const CONTROL_SOCKET_PATH: &str = "server.sock";
+pub const ACCESS_REQUESTS_PATH: &str = "access_requests.json";
+pub const HISTORY_PATH: &str = "history.jsonl";
+pub const USAGE_PATH: &str = "usage.bin";
+pub const GRPC_TOKEN_PATH: &str = "grpc_token";
+pub const STATE_DB_PATH: &str = "state.db";
+
+/// Server settings that can be given through the environment, e.g. in a container
+pub const ENV_AUTHORIZED_KEYS: &str = "PUNCH_AUTHORIZED_KEYS";
+pub const ENV_ALLOWED_PORTS: &str = "PUNCH_ALLOWED_PORTS";
+pub const ENV_SECRET_KEY: &str = "PUNCH_SECRET_KEY";
+pub const ENV_SECRET_KEY_FILE: &str = "PUNCH_SECRET_KEY_FILE";
+
+/// Exit codes of failures that scripts may want to react to, anything else exits with 1
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_UNAUTHORIZED: i32 = 3;
+pub const EXIT_PORT_NOT_ALLOWED: i32 = 4;
+pub const EXIT_CONNECT_TIMEOUT: i32 = 5;
+pub const EXIT_CONFIG_ERROR: i32 = 6;
 
 pub const DEFAULT_TIMEOUT: u64 = 30; // seconds
 pub const DEFAULT_RETRIES: usize = 5;
+pub const DEFAULT_CONNECTIONS: usize = 1;
+pub const DEFAULT_ACCEPTORS: usize = 1;
+pub const DEFAULT_RETRY_INITIAL_DELAY_MS: u64 = 500;
+pub const DEFAULT_RETRY_MAX_DELAY_MS: u64 = 30_000;
+pub const DEFAULT_RETRY_MAX_ELAPSED: u64 = 120; // seconds
 pub const DEFAULT_MAX_CONNECTIONS: usize = 100;
+pub const DEFAULT_MAX_CONNECTIONS_PER_KEY: usize = 10;
+/// Bytes per second a tunnel of a guest key may carry
+pub const DEFAULT_GUEST_BANDWIDTH: u64 = 1024 * 1024;
+pub const DEFAULT_TELEMETRY_SERVICE_NAME: &str = "punch";
+pub const DEFAULT_TELEMETRY_LEVEL: &str = "info";
+pub const DEFAULT_METRICS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
 pub const DEFAULT_ALLOWED_PORT_RANGE: (u16, u16) = (1024, 65535);
+pub const DEFAULT_TARGET_HOST: &str = "127.0.0.1";
+/// Local UDP peers tracked per tunnel, packets from new peers are dropped past this
+pub const MAX_UDP_SESSIONS: usize = 1024;
+/// UDP peers that neither sent nor received a packet for this long are forgotten, releasing
+/// their stream or socket
+pub const UDP_SESSION_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+/// Packets taken in or sent by a single system call in the UDP forwarding loops (Linux)
+pub const UDP_BATCH_SIZE: usize = 16;
+pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+/// Chunks received from the tunnel that are written to a local socket at once, a chunk being
+/// at most a packet's worth of data
+pub const MAX_WRITE_CHUNKS: usize = 64;
+pub const DEFAULT_BUFFER_POOL_CAPACITY: usize = 256;
+
+/// Longest download a client can ask the bench service for
+pub const MAX_BENCH_DURATION: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// How long the server waits for a probed port to accept a connection before calling it filtered
+pub const PROBE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// How long `punch server --confirm` waits for the operator before rejecting a node
+pub const CONFIRM_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// How long clients are told to wait when the server has no free connection slots
+pub const CONNECTION_LIMIT_RETRY_AFTER: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// How long a peer has to send its tunnel request, or the request of any other protocol,
+/// before the server gives up on it
+pub const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+/// Largest tunnel request, enough for a host name and a service name of 255 bytes each
+pub const MAX_HANDSHAKE_SIZE: usize = 1024;
+/// Highest socket priority a client may ask for, the ones above need `CAP_NET_ADMIN`
+pub const MAX_CLIENT_SOCKET_PRIORITY: u8 = 6;
+/// Streams of a tunnel bridged at once, further ones wait until one of them ends
+pub const DEFAULT_MAX_STREAMS_PER_CONNECTION: usize = 256;
+/// Bytes of buffers the streams of a tunnel hold at once, enough for the default number of
+/// streams with the default buffer size
+pub const DEFAULT_STREAM_MEMORY_PER_CONNECTION: u64 =
+    (DEFAULT_MAX_STREAMS_PER_CONNECTION * 2 * DEFAULT_BUFFER_SIZE) as u64;
+
+/// Pending access requests kept, the oldest one making room for a new one past that
+pub const MAX_ACCESS_REQUESTS: usize = 100;
+/// Access requests taken at once before the server only takes one per interval
+pub const ACCESS_REQUEST_BURST: u32 = 10;
+pub const ACCESS_REQUEST_INTERVAL: std::time::Duration = std::time::Duration::from_secs(6);
+pub const MAX_ACCESS_REQUEST_SIZE: usize = 4096;
+pub const MAX_ACCESS_REASON_LEN: usize = 512;
+
+/// Largest hosts and keys lists exchanged by `punch sync`
+pub const MAX_SYNC_SIZE: usize = 4 * 1024 * 1024;
+
+/// Largest file description sent ahead of each file by `punch send`
+pub const MAX_TRANSFER_OFFER_SIZE: usize = 4096;
+/// How often `punch send` redraws its progress line
+pub const TRANSFER_PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// Built-in service `punch client --dns` asks for, forwarding to the server's resolver
+pub const DNS_SERVICE: &str = "dns";
+pub const DEFAULT_DNS_PORT: u16 = 53;
+pub const RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+
+/// Interface `punch vpn` brings up on both ends, unless named otherwise
+pub const DEFAULT_VPN_INTERFACE: &str = "punch0";
+/// Small enough for a packet to fit in a QUIC datagram on most paths
+pub const DEFAULT_VPN_MTU: u16 = 1200;
+/// Largest address lease a `punch vpn` client reads
+pub const MAX_VPN_LEASE_SIZE: usize = 4096;
+
+/// Connection events buffered for each gRPC subscriber, slower ones miss the oldest
+pub const CONNECTION_EVENTS_CAPACITY: usize = 256;
+
+/// Time given to an edit of a config file to complete before it is reloaded
+pub const CONFIG_RELOAD_DELAY: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// Delays between dials of a target that refuses connections, with `wait_for_service` set
+pub const DIAL_RETRY_INITIAL_DELAY: std::time::Duration = std::time::Duration::from_millis(100);
+pub const DIAL_RETRY_MAX_DELAY: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// How long to wait for the TXT record of a host resolved through DNS
+pub const DNS_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Editor used by `punch config edit` when neither `$VISUAL` nor `$EDITOR` is set
+#[cfg(unix)]
+pub const DEFAULT_EDITOR: &str = "vi";
+#[cfg(not(unix))]
+pub const DEFAULT_EDITOR: &str = "notepad";
+
+/// Passphrase of `punch config backup` and `restore`, for scripts that cannot prompt
+pub const ENV_BACKUP_PASSPHRASE: &str = "PUNCH_BACKUP_PASSPHRASE";
+
+/// Where `punch auth import gh:<user>` fetches the published SSH keys of a user
+pub const GITHUB_URL: &str = "https://github.com";
+pub const IMPORT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Where `punch upgrade` finds the latest release and downloads its archives
+pub const LATEST_RELEASE_URL: &str = "https://api.github.com/repos/cestef/punch/releases/latest";
+pub const RELEASE_DOWNLOAD_URL: &str = "https://github.com/cestef/punch/releases/download";
+pub const UPGRADE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
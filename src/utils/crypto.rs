@@ -1,33 +1,60 @@
 use anyhow::Result;
 use iroh::SecretKey;
-use owo_colors::OwoColorize;
+use owo_colors::{OwoColorize, Style};
 use rand::rngs::OsRng;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
 
-use crate::{cli::Opts, utils::constants::PRIVATE_KEY_PATH};
+use crate::{
+    cli::{Command, Opts},
+    utils::{
+        config::ConfigManager,
+        constants::{ENV_SECRET_KEY, ENV_SECRET_KEY_FILE, PRIVATE_KEY_PATH},
+        styled,
+    },
+};
 
 pub async fn load_secret_key(opts: &Opts) -> Result<SecretKey> {
-    let path = opts.private_key.clone().unwrap_or_else(|| {
-        dirs::home_dir()
-            .expect("Could not find home directory")
-            .join(".punch")
-            .join(PRIVATE_KEY_PATH)
-    });
+    if !opts.ephemeral
+        && let Some(sk) = injected_secret_key(opts).await?
+    {
+        if opts.regenerate {
+            return Err(anyhow::anyhow!(
+                "Cannot use {} with a key given through stdin, the environment or a credential",
+                styled("--regenerate", Style::new().bold())
+            ));
+        }
+        return Ok(sk);
+    }
+
+    let path = match &opts.private_key {
+        Some(path) => path.clone(),
+        None => ConfigManager::new()?.config_path(PRIVATE_KEY_PATH),
+    };
 
     if opts.regenerate {
         if opts.ephemeral {
             return Err(anyhow::anyhow!(
                 "Cannot use {} with {}",
-                "--regenerate".bold(),
-                "--ephemeral".bold()
+                styled("--regenerate", Style::new().bold()),
+                styled("--ephemeral", Style::new().bold())
             ));
         }
 
-        inquire::Confirm::new(&format!(
-            "Regenerate secret key at {} ? This will overwrite the existing key.",
-            path.display().purple()
-        ))
-        .with_default(false)
-        .prompt()?;
+        let confirmed = opts.prompt_mode().confirm(
+            &format!(
+                "Regenerate secret key at {} ? This will overwrite the existing key.",
+                path.display().purple()
+            ),
+            false,
+        )?;
+        if !confirmed {
+            return Err(anyhow::anyhow!(
+                "Not regenerating the secret key, pass {} to skip the confirmation",
+                styled("--yes", Style::new().bold())
+            ));
+        }
         if !path.exists() {
             tokio::fs::create_dir_all(path.parent().unwrap()).await?;
         }
@@ -38,11 +65,7 @@ pub async fn load_secret_key(opts: &Opts) -> Result<SecretKey> {
     }
 
     if path.exists() && !opts.ephemeral {
-        let contents = tokio::fs::read(&path).await?;
-        let bytes: [u8; 32] = contents
-            .try_into()
-            .map_err(|_| anyhow::anyhow!("Invalid key length"))?;
-        return Ok(SecretKey::from_bytes(&bytes));
+        return parse_secret_key(&tokio::fs::read(&path).await?);
     }
 
     let sk = SecretKey::generate(&mut OsRng);
@@ -53,7 +76,182 @@ pub async fn load_secret_key(opts: &Opts) -> Result<SecretKey> {
     Ok(sk)
 }
 
-async fn write_secret_key(path: &std::path::Path, sk: &SecretKey) -> Result<()> {
+/// Where an imported key is saved: `--private-key`, or else the config directory.
+fn import_path(opts: &Opts) -> Result<PathBuf> {
+    match &opts.private_key {
+        Some(path) if path.as_os_str() == "-" => Err(anyhow::anyhow!(
+            "Cannot save a key read from stdin, pass the path of the key file instead"
+        )),
+        Some(path) => Ok(path.clone()),
+        None => Ok(ConfigManager::new()?.config_path(PRIVATE_KEY_PATH)),
+    }
+}
+
+/// Replaces our key with the one of an OpenSSH ed25519 private key, after confirming and
+/// keeping the old one next to it, so that the node ID becomes the SSH public key.
+pub async fn import_ssh_key(opts: &Opts, source: &Path) -> Result<SecretKey> {
+    let contents = tokio::fs::read_to_string(source)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", source.display(), e))?;
+    let sk = secret_key_from_openssh(&contents)
+        .map_err(|e| anyhow::anyhow!("Cannot import {}: {}", source.display(), e))?;
+
+    let path = import_path(opts)?;
+    let previous = match path.exists() {
+        true => Some(parse_secret_key(&tokio::fs::read(&path).await?)?),
+        false => None,
+    };
+    if let Some(previous) = &previous {
+        if previous.public() == sk.public() {
+            crate::info!(
+                "{} is already the key at {}",
+                source.display(),
+                path.display()
+            );
+            return Ok(sk);
+        }
+
+        let confirmed = opts.prompt_mode().confirm(
+            &format!(
+                "Replace the key at {} ? Your Node ID changes from {} to {}, servers and peers that know the old one will need the new one.",
+                path.display().purple(),
+                previous.public().fmt_short(),
+                sk.public().fmt_short()
+            ),
+            false,
+        )?;
+        if !confirmed {
+            return Err(anyhow::anyhow!(
+                "Not replacing the secret key, pass {} to skip the confirmation",
+                styled("--yes", Style::new().bold())
+            ));
+        }
+
+        let secs = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut backup = path.clone().into_os_string();
+        backup.push(format!(".{}.bak", secs));
+        tokio::fs::copy(&path, &backup).await?;
+        crate::info!(
+            "Kept the previous key at {}",
+            Path::new(&backup).display().purple()
+        );
+    }
+
+    write_secret_key(&path, &sk).await?;
+    crate::success!(
+        "Imported {} into {}, your Node ID is now {}",
+        source.display(),
+        path.display().purple(),
+        sk.public().to_string().blue().bold()
+    );
+    Ok(sk)
+}
+
+/// Takes the key out of an OpenSSH ed25519 private key, asking for its passphrase if it is
+/// encrypted.
+fn secret_key_from_openssh(contents: &str) -> Result<SecretKey> {
+    let mut key = ssh_key::PrivateKey::from_openssh(contents)?;
+    if key.is_encrypted() {
+        let passphrase = inquire::Password::new("Passphrase of the SSH key:")
+            .without_confirmation()
+            .prompt()?;
+        key = key
+            .decrypt(passphrase)
+            .map_err(|_| anyhow::anyhow!("Wrong passphrase"))?;
+    }
+
+    let keypair = key.key_data().ed25519().ok_or_else(|| {
+        anyhow::anyhow!(
+            "it is a {} key, only ed25519 keys can be used as a Node ID",
+            key.algorithm()
+        )
+    })?;
+    Ok(SecretKey::from_bytes(&keypair.private.to_bytes()))
+}
+
+/// Takes `PUNCH_SECRET_KEY` out of the environment, so that hooks and the commands of
+/// `punch run` don't inherit the key.
+///
+/// # Safety
+///
+/// No other thread may be running, as they could be reading the environment meanwhile.
+pub unsafe fn take_env_secret_key() -> Option<String> {
+    let key = std::env::var(ENV_SECRET_KEY).ok()?;
+    // SAFETY: the caller guarantees that no other thread is running
+    unsafe { std::env::remove_var(ENV_SECRET_KEY) };
+    Some(key)
+}
+
+/// A key handed over by whoever started punch rather than kept in the config directory:
+/// `--private-key -` reads it from stdin, then come `PUNCH_SECRET_KEY`, the file named by
+/// `PUNCH_SECRET_KEY_FILE` and the `private_key` systemd credential. It is never written
+/// anywhere.
+async fn injected_secret_key(opts: &Opts) -> Result<Option<SecretKey>> {
+    match &opts.private_key {
+        Some(path) if path.as_os_str() == "-" => {
+            if matches!(opts.command, Command::Stdio { .. }) {
+                return Err(anyhow::anyhow!(
+                    "Cannot read the key from stdin, {} uses it for the connection",
+                    styled("punch stdio", Style::new().bold())
+                ));
+            }
+            return secret_key_from_stdin().await.map(Some);
+        }
+        Some(_) => return Ok(None),
+        None => {}
+    }
+
+    if let Some(key) = &opts.env_secret_key {
+        return key
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("Invalid {}: {}", ENV_SECRET_KEY, e));
+    }
+    if let Some(path) = std::env::var_os(ENV_SECRET_KEY_FILE) {
+        let contents = tokio::fs::read(&path).await.map_err(|e| {
+            anyhow::anyhow!("Failed to read {} {:?}: {}", ENV_SECRET_KEY_FILE, path, e)
+        })?;
+        return parse_secret_key(&contents).map(Some);
+    }
+    // Set by systemd for services with `LoadCredential=private_key:<path>`
+    if let Some(dir) = std::env::var_os("CREDENTIALS_DIRECTORY") {
+        let path = std::path::Path::new(&dir).join(PRIVATE_KEY_PATH);
+        if path.exists() {
+            return parse_secret_key(&tokio::fs::read(&path).await?).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+async fn secret_key_from_stdin() -> Result<SecretKey> {
+    if std::io::stdin().is_terminal() {
+        let key = inquire::Password::new("Secret key:")
+            .without_confirmation()
+            .prompt()?;
+        return parse_secret_key(key.as_bytes());
+    }
+
+    let mut contents = Vec::new();
+    tokio::io::stdin().read_to_end(&mut contents).await?;
+    parse_secret_key(&contents)
+}
+
+/// Reads a key saved by punch, or one written out as hex or base32.
+pub fn parse_secret_key(contents: &[u8]) -> Result<SecretKey> {
+    if let Ok(bytes) = <[u8; 32]>::try_from(contents) {
+        return Ok(SecretKey::from_bytes(&bytes));
+    }
+    std::str::from_utf8(contents)
+        .ok()
+        .and_then(|text| text.trim().parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("Invalid key length"))
+}
+
+pub async fn write_secret_key(path: &std::path::Path, sk: &SecretKey) -> Result<()> {
     tokio::fs::create_dir_all(path.parent().unwrap()).await?;
     tokio::fs::write(path, sk.to_bytes()).await?;
     Ok(())
@@ -1,7 +1,14 @@
 use std::path::PathBuf;
 
-use iroh::endpoint::{Connection, VarInt};
+use crate::utils::constants::{
+    EXIT_CONFIG_ERROR, EXIT_CONNECT_TIMEOUT, EXIT_FAILURE, EXIT_PORT_NOT_ALLOWED, EXIT_UNAUTHORIZED,
+};
+
+use iroh::endpoint::{
+    ApplicationClose, Connection, ConnectionError, ReadError, VarInt, WriteError,
+};
 use miette::Diagnostic;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 #[derive(Debug, Error, Diagnostic)]
@@ -30,13 +37,35 @@ pub enum PunchError {
     #[diagnostic(code(punch::toml::ser))]
     TomlSer(#[from] toml::ser::Error),
 
+    #[cfg(feature = "sqlite")]
+    #[error(transparent)]
+    #[diagnostic(code(punch::sqlite))]
+    Sqlite(#[from] rusqlite::Error),
+
     #[error(transparent)]
     #[diagnostic(code(punch::connection))]
     Connection(#[from] iroh::endpoint::ConnectionError),
 
-    #[error("Connection closed by remote peer: {reason}")]
+    #[error("Connection closed by remote peer: {}", .details.describe(.reason))]
     #[diagnostic(code(punch::connection_closed))]
-    ConnectionClosed { reason: CloseReason },
+    ConnectionClosed {
+        reason: CloseReason,
+        details: CloseDetails,
+    },
+
+    #[error("Remote service {reason}{}", .port.map(|port| format!(" on port {}", port)).unwrap_or_default())]
+    #[diagnostic(code(punch::stream_reset))]
+    StreamReset {
+        reason: ResetReason,
+        port: Option<u16>,
+    },
+
+    #[error("No answer from {node} after {timeout:?}")]
+    #[diagnostic(code(punch::connect_timeout))]
+    ConnectTimeout {
+        node: String,
+        timeout: std::time::Duration,
+    },
 
     #[error(transparent)]
     Inquire(#[from] inquire::InquireError),
@@ -58,15 +87,38 @@ pub enum CloseReason {
     Unauthorized,
     InvalidPort,
     InvalidProtocol,
+    ForbiddenTarget,
+    TooManyConnections,
+    UnknownService,
+    Kicked,
+    /// The key's schedule doesn't allow it to connect right now
+    OutsideSchedule,
+    /// The session outlived its key's time limit or schedule
+    SessionExpired,
+    /// The tunnel request couldn't be parsed
+    InvalidHandshake,
+    /// No tunnel request came in time
+    HandshakeTimeout,
+    /// A code this version doesn't know about, likely from a newer server
+    Other(u64),
     Unknown,
 }
 
-impl Into<VarInt> for &CloseReason {
-    fn into(self) -> VarInt {
-        match self {
-            CloseReason::Unauthorized => VarInt::from(0x01 as u8),
-            CloseReason::InvalidPort => VarInt::from(0x02 as u8),
-            CloseReason::InvalidProtocol => VarInt::from(0x03 as u8),
+impl From<&CloseReason> for VarInt {
+    fn from(reason: &CloseReason) -> Self {
+        match reason {
+            CloseReason::Unauthorized => VarInt::from(0x01u8),
+            CloseReason::InvalidPort => VarInt::from(0x02u8),
+            CloseReason::InvalidProtocol => VarInt::from(0x03u8),
+            CloseReason::ForbiddenTarget => VarInt::from(0x04u8),
+            CloseReason::TooManyConnections => VarInt::from(0x05u8),
+            CloseReason::UnknownService => VarInt::from(0x06u8),
+            CloseReason::Kicked => VarInt::from(0x07u8),
+            CloseReason::OutsideSchedule => VarInt::from(0x08u8),
+            CloseReason::SessionExpired => VarInt::from(0x09u8),
+            CloseReason::InvalidHandshake => VarInt::from(0x0au8),
+            CloseReason::HandshakeTimeout => VarInt::from(0x0bu8),
+            CloseReason::Other(code) => VarInt::from_u64(*code).unwrap_or(VarInt::MAX),
             CloseReason::Unknown => VarInt::from(u8::MAX), // Use a sentinel value for unknown
         }
     }
@@ -78,7 +130,16 @@ impl From<VarInt> for CloseReason {
             0x01 => CloseReason::Unauthorized,
             0x02 => CloseReason::InvalidPort,
             0x03 => CloseReason::InvalidProtocol,
-            _ => panic!("Unknown CloseReason: {}", value),
+            0x04 => CloseReason::ForbiddenTarget,
+            0x05 => CloseReason::TooManyConnections,
+            0x06 => CloseReason::UnknownService,
+            0x07 => CloseReason::Kicked,
+            0x08 => CloseReason::OutsideSchedule,
+            0x09 => CloseReason::SessionExpired,
+            0x0a => CloseReason::InvalidHandshake,
+            0x0b => CloseReason::HandshakeTimeout,
+            // Codes added by newer servers, the message in the details still explains them
+            code => CloseReason::Other(code),
         }
     }
 }
@@ -87,12 +148,25 @@ impl std::fmt::Display for CloseReason {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             CloseReason::Unauthorized => write!(f, "Unauthorized connection attempt"),
-            CloseReason::InvalidPort => {
-                write!(f, "Invalid port requested, must be between 1024 and 65535")
-            }
+            CloseReason::InvalidPort => write!(f, "The requested port is not allowed"),
             CloseReason::InvalidProtocol => {
                 write!(f, "Invalid protocol requested, must be TCP or UDP")
             }
+            CloseReason::ForbiddenTarget => {
+                write!(f, "Forwarding to the requested target host is not allowed")
+            }
+            CloseReason::TooManyConnections => {
+                write!(f, "Too many concurrent connections, try again later")
+            }
+            CloseReason::UnknownService => write!(f, "The requested service doesn't exist"),
+            CloseReason::Kicked => write!(f, "Disconnected by the server administrator"),
+            CloseReason::OutsideSchedule => write!(f, "Connections aren't allowed at this time"),
+            CloseReason::SessionExpired => write!(f, "The session reached its time limit"),
+            CloseReason::InvalidHandshake => write!(f, "Malformed tunnel request"),
+            CloseReason::HandshakeTimeout => {
+                write!(f, "Timed out waiting for the tunnel request")
+            }
+            CloseReason::Other(code) => write!(f, "Closed with unrecognized code {:#x}", code),
             CloseReason::Unknown => write!(f, "Unknown close reason"),
         }
     }
@@ -100,17 +174,286 @@ impl std::fmt::Display for CloseReason {
 
 impl CloseReason {
     pub fn execute(&self, connection: &Connection) {
-        connection.close(self.into(), self.to_string().as_bytes())
+        self.execute_with(connection, CloseDetails::default())
+    }
+
+    /// Closes the connection with extra details for the peer, defaulting the message to our
+    /// description of the reason.
+    pub fn execute_with(&self, connection: &Connection, mut details: CloseDetails) {
+        details.message.get_or_insert_with(|| self.to_string());
+        connection.close(self.into(), &details.encode())
+    }
+}
+
+/// Why a tunnel stream was reset, sent as the stream's error code so that the client can
+/// tell a failure from a connection the service closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetReason {
+    /// The other end of the bridge failed midway
+    Aborted,
+    Refused,
+    Unreachable,
+    TimedOut,
+    /// The server doesn't let the client reach the target named by the stream
+    Forbidden,
+    /// A code this version doesn't know about, likely from a newer server
+    Other(u64),
+}
+
+impl From<&ResetReason> for VarInt {
+    fn from(reason: &ResetReason) -> Self {
+        match reason {
+            ResetReason::Aborted => VarInt::from(0x01u8),
+            ResetReason::Refused => VarInt::from(0x02u8),
+            ResetReason::Unreachable => VarInt::from(0x03u8),
+            ResetReason::TimedOut => VarInt::from(0x04u8),
+            ResetReason::Forbidden => VarInt::from(0x05u8),
+            ResetReason::Other(code) => VarInt::from_u64(*code).unwrap_or(VarInt::MAX),
+        }
+    }
+}
+
+impl From<VarInt> for ResetReason {
+    fn from(value: VarInt) -> Self {
+        match value.into_inner() {
+            0x01 => ResetReason::Aborted,
+            0x02 => ResetReason::Refused,
+            0x03 => ResetReason::Unreachable,
+            0x04 => ResetReason::TimedOut,
+            0x05 => ResetReason::Forbidden,
+            code => ResetReason::Other(code),
+        }
+    }
+}
+
+impl std::fmt::Display for ResetReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResetReason::Aborted => write!(f, "aborted the connection"),
+            ResetReason::Refused => write!(f, "refused connection"),
+            ResetReason::Unreachable => write!(f, "is unreachable"),
+            ResetReason::TimedOut => write!(f, "timed out"),
+            ResetReason::Forbidden => write!(f, "is not allowed"),
+            ResetReason::Other(code) => write!(f, "reset the stream with code {:#x}", code),
+        }
+    }
+}
+
+impl ResetReason {
+    /// Classifies a failure to connect to the target of a stream.
+    pub fn from_dial_error(error: &std::io::Error) -> Self {
+        match error.kind() {
+            std::io::ErrorKind::ConnectionRefused => ResetReason::Refused,
+            std::io::ErrorKind::TimedOut => ResetReason::TimedOut,
+            std::io::ErrorKind::HostUnreachable | std::io::ErrorKind::NetworkUnreachable => {
+                ResetReason::Unreachable
+            }
+            _ => ResetReason::Aborted,
+        }
+    }
+
+    /// The code the peer reset or stopped a stream with, if that's what `error` comes from.
+    pub fn from_io_error(error: &std::io::Error) -> Option<Self> {
+        let inner = error.get_ref()?;
+        let code = match (inner.downcast_ref(), inner.downcast_ref()) {
+            (Some(ReadError::Reset(code)), _) => *code,
+            (_, Some(WriteError::Stopped(code))) => *code,
+            _ => return None,
+        };
+        Some(code.into())
+    }
+}
+
+/// What a server sends along with a close code, as JSON in the close reason. Every field is
+/// optional so that both sides can ignore the ones they don't know about.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CloseDetails {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Seconds to wait before trying again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_ports: Option<(u16, u16)>,
+    /// Every range the key may forward to, `allowed_ports` being only set for a single one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_port_ranges: Option<Vec<(u16, u16)>>,
+}
+
+impl CloseDetails {
+    pub fn with_message(mut self, message: impl Into<String>) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+
+    pub fn with_retry_after(mut self, retry_after: std::time::Duration) -> Self {
+        self.retry_after = Some(retry_after.as_secs());
+        self
+    }
+
+    pub fn with_allowed_ports(mut self, allowed_ports: (u16, u16)) -> Self {
+        self.allowed_ports = Some(allowed_ports);
+        self
+    }
+
+    /// Sets the ranges, keeping `allowed_ports` for older clients when there is only one.
+    pub fn with_allowed_port_ranges(mut self, ranges: Vec<(u16, u16)>) -> Self {
+        if let [range] = ranges.as_slice() {
+            self.allowed_ports = Some(*range);
+        }
+        self.allowed_port_ranges = Some(ranges);
+        self
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap_or_default()
+    }
+
+    /// Parses a close reason, never failing: older servers send the message as plain text and
+    /// anything else is ignored.
+    pub fn decode(bytes: &[u8]) -> Self {
+        let mut details = serde_json::from_slice::<Self>(bytes).unwrap_or_else(|_| {
+            let message = String::from_utf8_lossy(bytes);
+            Self {
+                message: (!message.trim().is_empty()).then(|| message.into_owned()),
+                ..Default::default()
+            }
+        });
+        // The message ends up in our terminal, don't let the peer control it
+        details.message = details
+            .message
+            .map(|m| m.chars().filter(|c| !c.is_control()).collect());
+        details
+    }
+
+    /// Describes why the connection was closed, preferring the peer's own message.
+    pub fn describe(&self, reason: &CloseReason) -> String {
+        let mut description = match (&self.message, reason) {
+            (Some(message), CloseReason::Other(code)) => format!("{} (code {:#x})", message, code),
+            (Some(message), _) => message.clone(),
+            (None, reason) => reason.to_string(),
+        };
+        match (&self.allowed_port_ranges, self.allowed_ports) {
+            (Some(ranges), _) if ranges.is_empty() => {
+                description.push_str(" (no ports allowed, only services)")
+            }
+            (Some(ranges), _) => {
+                let ranges: Vec<String> = ranges
+                    .iter()
+                    .map(|(min, max)| match min == max {
+                        true => min.to_string(),
+                        false => format!("{}-{}", min, max),
+                    })
+                    .collect();
+                description.push_str(&format!(" (allowed ports: {})", ranges.join(", ")));
+            }
+            (None, Some((min, max))) => {
+                description.push_str(&format!(" (allowed ports: {}-{})", min, max))
+            }
+            (None, None) => {}
+        }
+        if let Some(retry_after) = self.retry_after {
+            description.push_str(&format!(" (retry in {}s)", retry_after));
+        }
+        description
+    }
+}
+
+impl From<&ApplicationClose> for PunchError {
+    fn from(close: &ApplicationClose) -> Self {
+        PunchError::ConnectionClosed {
+            reason: close.error_code.into(),
+            details: CloseDetails::decode(&close.reason),
+        }
+    }
+}
+
+impl PunchError {
+    /// Whether the failure may go away by itself, like a timeout or a relay hiccup, as opposed
+    /// to the server refusing us.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            PunchError::ConnectionClosed { reason, details } => {
+                *reason == CloseReason::TooManyConnections || details.retry_after.is_some()
+            }
+            PunchError::Connection(e) => matches!(
+                e,
+                ConnectionError::TimedOut
+                    | ConnectionError::Reset
+                    | ConnectionError::ConnectionClosed(_)
+            ),
+            // Failing to dial, usually because discovery or the relay didn't answer in time
+            PunchError::Other(_) | PunchError::Io(_) | PunchError::Datagram(_) => true,
+            _ => false,
+        }
     }
 }
 
+impl PunchError {
+    /// The process exit code for this error, stable so that scripts can tell failures apart.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            PunchError::ConnectionClosed {
+                reason: CloseReason::Unauthorized,
+                ..
+            } => EXIT_UNAUTHORIZED,
+            PunchError::ConnectionClosed {
+                reason: CloseReason::InvalidPort,
+                ..
+            } => EXIT_PORT_NOT_ALLOWED,
+            PunchError::ConnectTimeout { .. }
+            | PunchError::Connection(ConnectionError::TimedOut) => EXIT_CONNECT_TIMEOUT,
+            PunchError::ConfigError { .. } | PunchError::TomlDe(_) | PunchError::TomlSer(_) => {
+                EXIT_CONFIG_ERROR
+            }
+            _ => EXIT_FAILURE,
+        }
+    }
+
+    /// The error as printed on stderr with `--json`.
+    pub fn to_json(&self) -> String {
+        let mut causes = Vec::new();
+        let mut source = std::error::Error::source(self);
+        while let Some(error) = source {
+            causes.push(error.to_string());
+            source = error.source();
+        }
+        let details = match self {
+            PunchError::ConnectionClosed { details, .. } => Some(details.clone()),
+            _ => None,
+        };
+
+        let envelope = ErrorEnvelope {
+            error: self.to_string(),
+            code: self.code().map(|code| code.to_string()),
+            exit_code: self.exit_code(),
+            causes,
+            details,
+        };
+        serde_json::to_string(&envelope).unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorEnvelope {
+    error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+    exit_code: i32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    causes: Vec<String>,
+    /// What the server sent along when it closed the connection
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<CloseDetails>,
+}
+
 pub type Result<T, E = PunchError> = std::result::Result<T, E>;
 
 #[macro_export]
 macro_rules! error {
     (source = $source:expr, $($arg:tt)*) => {
         {
-            crate::utils::error::PunchError::Error {
+            $crate::utils::error::PunchError::Error {
                 message: format!($($arg)*),
                 source: Some(Box::new($source)),
             }
@@ -118,10 +461,161 @@ macro_rules! error {
     };
     ($($arg:tt)*) => {
         {
-            crate::utils::error::PunchError::Error {
+            $crate::utils::error::PunchError::Error {
                 message: format!($($arg)*),
                 source: None,
             }
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn closed(code: u64, reason: &[u8]) -> PunchError {
+        let close = ApplicationClose {
+            error_code: VarInt::from_u64(code).unwrap(),
+            reason: reason.to_vec().into(),
+        };
+        PunchError::from(&close)
+    }
+
+    #[test]
+    fn known_codes_round_trip() {
+        for reason in [
+            CloseReason::Unauthorized,
+            CloseReason::InvalidPort,
+            CloseReason::InvalidProtocol,
+            CloseReason::ForbiddenTarget,
+            CloseReason::TooManyConnections,
+            CloseReason::UnknownService,
+            CloseReason::Kicked,
+            CloseReason::OutsideSchedule,
+            CloseReason::SessionExpired,
+            CloseReason::InvalidHandshake,
+            CloseReason::HandshakeTimeout,
+        ] {
+            assert_eq!(CloseReason::from(VarInt::from(&reason)), reason);
+        }
+    }
+
+    #[test]
+    fn reset_codes_round_trip() {
+        for reason in [
+            ResetReason::Aborted,
+            ResetReason::Refused,
+            ResetReason::Unreachable,
+            ResetReason::TimedOut,
+            ResetReason::Forbidden,
+            ResetReason::Other(0x42),
+        ] {
+            assert_eq!(ResetReason::from(VarInt::from(&reason)), reason);
+        }
+    }
+
+    #[test]
+    fn reset_streams_name_the_port() {
+        let error = PunchError::StreamReset {
+            reason: ResetReason::from_dial_error(&std::io::ErrorKind::ConnectionRefused.into()),
+            port: Some(5432),
+        };
+        assert_eq!(
+            error.to_string(),
+            "Remote service refused connection on port 5432"
+        );
+    }
+
+    #[test]
+    fn future_codes_map_to_other() {
+        assert_eq!(
+            CloseReason::from(VarInt::from(0x0cu8)),
+            CloseReason::Other(0x0c)
+        );
+        assert_eq!(
+            CloseReason::from(VarInt::from_u64(0x1234).unwrap()),
+            CloseReason::Other(0x1234)
+        );
+        let reason = CloseReason::Other(0x1234);
+        assert_eq!(CloseReason::from(VarInt::from(&reason)), reason);
+    }
+
+    #[test]
+    fn future_code_keeps_server_message() {
+        let details = CloseDetails::default()
+            .with_message("Maintenance in progress")
+            .with_retry_after(std::time::Duration::from_secs(30));
+        let error = closed(0x42, &details.encode());
+
+        assert!(matches!(
+            &error,
+            PunchError::ConnectionClosed { reason: CloseReason::Other(0x42), details: d } if d == &details
+        ));
+        assert_eq!(
+            error.to_string(),
+            "Connection closed by remote peer: Maintenance in progress (code 0x42) (retry in 30s)"
+        );
+    }
+
+    #[test]
+    fn future_code_without_message() {
+        let error = closed(0x42, b"");
+        assert_eq!(
+            error.to_string(),
+            "Connection closed by remote peer: Closed with unrecognized code 0x42"
+        );
+    }
+
+    #[test]
+    fn plain_text_reason_from_older_servers() {
+        let details = CloseDetails::decode(b"Unauthorized connection attempt");
+        assert_eq!(
+            details.message.as_deref(),
+            Some("Unauthorized connection attempt")
+        );
+        assert_eq!(details.retry_after, None);
+    }
+
+    #[test]
+    fn unknown_fields_are_ignored() {
+        let details = CloseDetails::decode(
+            br#"{"message":"Nope","allowed_ports":[2000,3000],"new_field":1}"#,
+        );
+        assert_eq!(details.message.as_deref(), Some("Nope"));
+        assert_eq!(details.allowed_ports, Some((2000, 3000)));
+    }
+
+    #[test]
+    fn port_ranges_are_described() {
+        let details = CloseDetails::default()
+            .with_allowed_port_ranges(vec![(1024, 6378), (6380, 65535)])
+            .with_message("Nope");
+        assert_eq!(details.allowed_ports, None);
+        assert_eq!(
+            details.describe(&CloseReason::InvalidPort),
+            "Nope (allowed ports: 1024-6378, 6380-65535)"
+        );
+
+        let details = CloseDetails::default().with_allowed_port_ranges(vec![(8000, 8100)]);
+        assert_eq!(details.allowed_ports, Some((8000, 8100)));
+    }
+
+    #[test]
+    fn control_characters_are_stripped() {
+        let details = CloseDetails::decode(b"bad\x1b[31mred\n");
+        assert_eq!(details.message.as_deref(), Some("bad[31mred"));
+    }
+
+    #[test]
+    fn exit_codes_follow_the_close_reason() {
+        assert_eq!(closed(0x01, b"").exit_code(), EXIT_UNAUTHORIZED);
+        assert_eq!(closed(0x02, b"").exit_code(), EXIT_PORT_NOT_ALLOWED);
+        assert_eq!(closed(0x05, b"").exit_code(), EXIT_FAILURE);
+
+        let json: serde_json::Value =
+            serde_json::from_str(&closed(0x01, br#"{"message":"Nope"}"#).to_json()).unwrap();
+        assert_eq!(json["exit_code"], EXIT_UNAUTHORIZED);
+        assert_eq!(json["code"], "punch::connection_closed");
+        assert_eq!(json["details"]["message"], "Nope");
+    }
+}
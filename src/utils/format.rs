@@ -1,3 +1,7 @@
+use crate::utils::styled;
+use iroh::{RelayUrl, endpoint::ConnectionType};
+use owo_colors::Style;
+
 pub fn format_duration(seconds: u64) -> String {
     if seconds < 60 {
         format!("{} seconds ago", seconds)
@@ -9,3 +13,95 @@ pub fn format_duration(seconds: u64) -> String {
         format!("{} days ago", seconds / 86400)
     }
 }
+
+pub fn format_bitrate(bits_per_second: f64) -> String {
+    const UNITS: [&str; 4] = ["bit/s", "Kbit/s", "Mbit/s", "Gbit/s"];
+
+    let mut value = bits_per_second;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", value, UNITS[unit])
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
+
+/// Formats a length of time, e.g. `3m 12s`, keeping the two largest units.
+pub fn format_elapsed(seconds: u64) -> String {
+    match seconds {
+        0..60 => format!("{}s", seconds),
+        60..3600 => format!("{}m {}s", seconds / 60, seconds % 60),
+        3600..86400 => format!("{}h {}m", seconds / 3600, seconds % 3600 / 60),
+        _ => format!("{}d {}h", seconds / 86400, seconds % 86400 / 3600),
+    }
+}
+
+/// Describes how packets reach a peer, e.g. `DIRECT via 1.2.3.4:5000` or `RELAY via euw1-1`.
+pub fn format_path(path: &ConnectionType) -> String {
+    match path {
+        ConnectionType::Direct(addr) => {
+            format!(
+                "{} via {}",
+                styled("DIRECT", Style::new().green().bold()),
+                addr
+            )
+        }
+        ConnectionType::Relay(url) => format!(
+            "{} via {}",
+            styled("RELAY", Style::new().yellow().bold()),
+            relay_name(url)
+        ),
+        ConnectionType::Mixed(addr, url) => format!(
+            "{} via {} and {}",
+            styled("MIXED", Style::new().yellow().bold()),
+            addr,
+            relay_name(url)
+        ),
+        ConnectionType::None => styled("no path yet", Style::new().red()),
+    }
+}
+
+/// First label of the relay's hostname, which is enough to tell iroh's relays apart.
+fn relay_name(url: &RelayUrl) -> String {
+    url.host_str()
+        .and_then(|host| host.split('.').next())
+        .map_or_else(|| url.to_string(), ToString::to_string)
+}
+
+/// Parses a length of time such as `90s`, `30m`, `12h`, `7d` or `2w` into seconds, bare
+/// numbers being seconds.
+pub fn parse_duration(s: &str) -> std::result::Result<u64, String> {
+    let s = s.trim();
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => s.split_at(index),
+        None => (s, "s"),
+    };
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("Invalid duration '{}', expected e.g. 30m, 12h or 7d", s))?;
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("Unknown unit '{}', use s, m, h, d or w", unit)),
+    };
+    Ok(value * multiplier)
+}
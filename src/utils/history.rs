@@ -0,0 +1,100 @@
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+/// Sessions kept in the history, older ones are dropped as new ones come in
+const MAX_ENTRIES: usize = 1000;
+
+/// A finished client session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryRecord {
+    /// Unix timestamps in seconds
+    pub started: u64,
+    pub ended: u64,
+    pub node_id: String,
+    /// Name of the host in the client config, if it was a known one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    pub protocol: String,
+    pub mapping: String,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub reason: String,
+}
+
+impl HistoryRecord {
+    pub fn duration(&self) -> u64 {
+        self.ended.saturating_sub(self.started)
+    }
+
+    /// Whether the record matches a host name or a node ID prefix.
+    pub fn matches_host(&self, host: &str) -> bool {
+        self.host.as_deref() == Some(host) || self.node_id.starts_with(host)
+    }
+}
+
+/// Rolling JSONL log of the sessions opened by the client.
+#[derive(Debug)]
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn append(&self, record: &HistoryRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record).map_err(|e| crate::error!("{}", e))?;
+        line.push(b'\n');
+
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(&line).await?;
+
+        self.trim().await
+    }
+
+    /// Reads every record, oldest first, skipping lines that fail to parse.
+    pub async fn read(&self) -> Result<Vec<HistoryRecord>> {
+        let content = match tokio::fs::read_to_string(&self.path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(content
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+
+    /// Drops the oldest lines once the history grows past [`MAX_ENTRIES`].
+    async fn trim(&self) -> Result<()> {
+        let content = tokio::fs::read_to_string(&self.path).await?;
+        let lines: Vec<&str> = content.lines().collect();
+        if lines.len() <= MAX_ENTRIES {
+            return Ok(());
+        }
+
+        let mut kept = lines[lines.len() - MAX_ENTRIES..].join("\n");
+        kept.push('\n');
+        // Written aside first so that a crash never leaves a half-written history
+        let tmp = self.path.with_extension("jsonl.tmp");
+        tokio::fs::write(&tmp, kept).await?;
+        tokio::fs::rename(&tmp, &self.path).await?;
+
+        Ok(())
+    }
+}
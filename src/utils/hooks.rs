@@ -0,0 +1,97 @@
+use crate::utils::config::HookSettings;
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+    Connect,
+    Disconnect,
+    Reconnect,
+}
+
+impl std::fmt::Display for HookEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HookEvent::Connect => write!(f, "connect"),
+            HookEvent::Disconnect => write!(f, "disconnect"),
+            HookEvent::Reconnect => write!(f, "reconnect"),
+        }
+    }
+}
+
+/// What a hook is told about the tunnel that triggered it.
+#[derive(Debug, Clone)]
+pub struct HookContext {
+    pub peer: NodeId,
+    pub protocol: String,
+    pub port: u16,
+    pub target: Option<String>,
+}
+
+impl HookSettings {
+    fn command(&self, event: HookEvent) -> Option<&str> {
+        match event {
+            HookEvent::Connect => self.on_connect.as_deref(),
+            HookEvent::Disconnect => self.on_disconnect.as_deref(),
+            HookEvent::Reconnect => self.on_reconnect.as_deref(),
+        }
+    }
+}
+
+/// Runs the hook configured for `event` in the background, if any. Failures are logged,
+/// never propagated to the tunnel.
+pub fn trigger(hooks: &HookSettings, event: HookEvent, context: &HookContext) {
+    let Some(command) = hooks.command(event) else {
+        return;
+    };
+
+    let mut process = shell(command);
+    process
+        .env("PUNCH_EVENT", event.to_string())
+        .env("PUNCH_PEER", context.peer.to_string())
+        .env("PUNCH_PORT", context.port.to_string())
+        .env("PUNCH_PROTOCOL", &context.protocol)
+        .stdin(std::process::Stdio::null());
+    if let Some(target) = &context.target {
+        process.env("PUNCH_TARGET", target);
+    }
+    // Under `punch stdio` our stdout carries the connection, the hook's output would corrupt it
+    if crate::utils::output::stdout_is_data() {
+        process.stdout(std::io::stderr());
+    }
+
+    // Spawned right away so that the hook still runs if we exit before it finishes
+    let mut child = match process.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            tracing::warn!("Failed to run on_{} hook `{}`: {}", event, command, e);
+            return;
+        }
+    };
+
+    let command = command.to_string();
+    tokio::spawn(async move {
+        match child.wait().await {
+            Ok(status) if status.success() => {
+                tracing::debug!("on_{} hook finished", event);
+            }
+            Ok(status) => tracing::warn!("on_{} hook `{}` exited with {}", event, command, status),
+            Err(e) => tracing::warn!("Failed to wait for on_{} hook `{}`: {}", event, command, e),
+        }
+    });
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> tokio::process::Command {
+    let mut process = tokio::process::Command::new("sh");
+    process.arg("-c").arg(command);
+    process
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> tokio::process::Command {
+    let mut process = tokio::process::Command::new("cmd");
+    process.arg("/C").arg(command);
+    process
+}
@@ -0,0 +1,163 @@
+use crate::Result;
+use crate::utils::constants::{GITHUB_URL, IMPORT_TIMEOUT};
+use iroh::PublicKey;
+use std::path::PathBuf;
+
+const SSH_ED25519: &str = "ssh-ed25519";
+
+/// Where `punch auth import` reads keys from.
+#[derive(Debug, Clone)]
+pub enum ImportSource {
+    /// A file with one key per line
+    File(PathBuf),
+    /// The SSH keys a GitHub user published, written `gh:<username>`
+    GitHub(String),
+}
+
+impl std::str::FromStr for ImportSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let Some(user) = s.strip_prefix("gh:").or_else(|| s.strip_prefix("github:")) else {
+            return Ok(ImportSource::File(PathBuf::from(s)));
+        };
+        // Same rules as GitHub usernames, which also keeps the URL well-formed
+        if user.is_empty()
+            || user.len() > 39
+            || !user.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            return Err(format!("Invalid GitHub username: {}", user));
+        }
+        Ok(ImportSource::GitHub(user.to_string()))
+    }
+}
+
+impl std::fmt::Display for ImportSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportSource::File(path) => write!(f, "{}", path.display()),
+            ImportSource::GitHub(user) => write!(f, "gh:{}", user),
+        }
+    }
+}
+
+/// A key found in an import source.
+#[derive(Debug, Clone)]
+pub struct ImportedKey {
+    pub key: PublicKey,
+    /// Text following the key on its line, e.g. `user@host` for SSH keys
+    pub comment: Option<String>,
+    /// Whether the key was mapped from an ed25519 SSH key
+    pub from_ssh: bool,
+}
+
+/// A line of an import source that holds no usable key.
+#[derive(Debug, Clone)]
+pub struct SkippedLine {
+    pub line: usize,
+    pub reason: String,
+}
+
+impl ImportSource {
+    pub async fn read(&self) -> Result<String> {
+        match self {
+            ImportSource::File(path) => tokio::fs::read_to_string(path)
+                .await
+                .map_err(|e| crate::error!(source = e, "Failed to read {}", path.display())),
+            ImportSource::GitHub(user) => {
+                let url = format!("{}/{}.keys", GITHUB_URL, user);
+                let response = reqwest::Client::new()
+                    .get(&url)
+                    .timeout(IMPORT_TIMEOUT)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|e| crate::error!(source = e, "Failed to fetch {}", url))?;
+                response
+                    .text()
+                    .await
+                    .map_err(|e| crate::error!(source = e, "Failed to fetch {}", url))
+            }
+        }
+    }
+}
+
+/// Finds the keys in `text`: node IDs, optionally followed by a comment, and ed25519 SSH
+/// public keys, which are the same kind of key as node IDs. Blank lines and `#` comments
+/// are ignored, anything else is reported as skipped.
+pub fn parse_keys(text: &str) -> (Vec<ImportedKey>, Vec<SkippedLine>) {
+    let mut keys = Vec::new();
+    let mut skipped = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut words = line.split_whitespace();
+        let first = words.next().unwrap_or_default();
+        let parsed = if first == SSH_ED25519 {
+            words
+                .next()
+                .ok_or_else(|| "SSH key without its base64 part".to_string())
+                .and_then(ssh_ed25519_key)
+                .map(|key| (key, true))
+        } else if first.starts_with("ssh-")
+            || first.starts_with("ecdsa-")
+            || first.starts_with("sk-")
+        {
+            Err(format!(
+                "{} keys can't be used as punch keys, only {} ones",
+                first, SSH_ED25519
+            ))
+        } else {
+            first
+                .parse::<PublicKey>()
+                .map(|key| (key, false))
+                .map_err(|_| "not a node ID".to_string())
+        };
+
+        match parsed {
+            Ok((key, from_ssh)) => {
+                let comment = words.collect::<Vec<_>>().join(" ");
+                keys.push(ImportedKey {
+                    key,
+                    comment: (!comment.is_empty()).then_some(comment),
+                    from_ssh,
+                });
+            }
+            Err(reason) => skipped.push(SkippedLine {
+                line: index + 1,
+                reason,
+            }),
+        }
+    }
+
+    (keys, skipped)
+}
+
+/// Extracts the ed25519 key of an SSH public key blob: the key type then the 32 key bytes,
+/// each prefixed with its length.
+fn ssh_ed25519_key(base64: &str) -> std::result::Result<PublicKey, String> {
+    let blob = data_encoding::BASE64
+        .decode(base64.as_bytes())
+        .map_err(|_| "SSH key is not valid base64".to_string())?;
+
+    let mut rest = blob.as_slice();
+    let mut field = || -> Option<&[u8]> {
+        let (len, tail) = rest.split_first_chunk::<4>()?;
+        let len = u32::from_be_bytes(*len) as usize;
+        let (value, tail) = tail.split_at_checked(len)?;
+        rest = tail;
+        Some(value)
+    };
+
+    if field() != Some(SSH_ED25519.as_bytes()) {
+        return Err("SSH key type doesn't match its content".to_string());
+    }
+    let bytes: &[u8; 32] = field()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| "Truncated SSH key".to_string())?;
+    PublicKey::from_bytes(bytes).map_err(|_| "SSH key is not a valid ed25519 point".to_string())
+}
@@ -1,28 +1,125 @@
+use crate::utils::config::{LogOutput, LoggingSettings};
+use crate::utils::output;
+use std::sync::OnceLock;
 use tracing::level_filters::LevelFilter;
-use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{
+    EnvFilter, Layer, Registry, layer::SubscriberExt, reload, util::SubscriberInitExt,
+};
+
+/// Layer set once the config is read, like the OpenTelemetry export
+pub type ExtraLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// A layer that can be swapped after `init`. A per-layer filter can't be swapped in later, so
+/// the slot comes with its own.
+struct Slot {
+    layer: reload::Handle<Option<ExtraLayer>, Registry>,
+    filter: reload::Handle<EnvFilter, Registry>,
+}
+
+impl Slot {
+    fn new(layer: Option<ExtraLayer>, filter: EnvFilter) -> (ExtraLayer, Self) {
+        let (layer, layer_handle) = reload::Layer::new(layer);
+        let (filter, filter_handle) = reload::Layer::new(filter);
+        let slot = Self {
+            layer: layer_handle,
+            filter: filter_handle,
+        };
+        (Box::new(layer.with_filter(filter)), slot)
+    }
+
+    fn set(&self, layer: ExtraLayer, filter: EnvFilter) -> anyhow::Result<()> {
+        self.layer.reload(Some(layer))?;
+        self.filter.reload(filter)?;
+        Ok(())
+    }
+}
+
+struct Slots {
+    /// Where log lines go, stderr until the config says otherwise
+    output: Slot,
+    extra: Slot,
+}
+
+static SLOTS: OnceLock<Slots> = OnceLock::new();
 
 pub fn init() -> anyhow::Result<()> {
+    let (output, output_slot) = Slot::new(Some(stderr_layer()), env_filter("off")?);
+    let (extra, extra_slot) = Slot::new(None, EnvFilter::new("off"));
+    let _ = SLOTS.set(Slots {
+        output: output_slot,
+        extra: extra_slot,
+    });
+
     tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::fmt::layer()
-                .compact()
-                .without_time()
-                .with_target(false),
-        )
-        .with({
-            EnvFilter::builder()
-                .with_default_directive(LevelFilter::OFF.into())
-                .from_env()?
-                .add_directive(
-                    format!(
-                        "{}={}",
-                        env!("CARGO_PKG_NAME"),
-                        std::env::var(format!("{}_LOG", env!("CARGO_PKG_NAME").to_uppercase()))
-                            .unwrap_or_else(|_| "off".to_string())
-                    )
-                    .parse()?,
-                )
-        })
+        .with(vec![extra, output])
         .init();
     Ok(())
 }
+
+fn stderr_layer() -> ExtraLayer {
+    // Not compact, which would list the fields of spans apart from their names, while
+    // `tunnel{id=3}:stream{id=8}` tells interleaved streams apart at a glance
+    Box::new(
+        tracing_subscriber::fmt::layer()
+            .without_time()
+            .with_target(false)
+            .with_ansi(crate::utils::colors_enabled(&std::io::stderr()))
+            .with_writer(std::io::stderr),
+    )
+}
+
+/// `-v` or `PUNCH_LOG` for our own lines, falling back to `level`, and `RUST_LOG` for
+/// everything else.
+fn env_filter(level: &str) -> anyhow::Result<EnvFilter> {
+    let level = match output::verbosity().log_level() {
+        Some(level) => level.to_string(),
+        None => std::env::var(format!("{}_LOG", env!("CARGO_PKG_NAME").to_uppercase()))
+            .unwrap_or_else(|_| level.to_string()),
+    };
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::OFF.into())
+        .from_env()?
+        .add_directive(format!("{}={}", env!("CARGO_PKG_NAME"), level).parse()?);
+    Ok(filter)
+}
+
+fn slots() -> anyhow::Result<&'static Slots> {
+    SLOTS
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("Logging is not initialized"))
+}
+
+/// Switches to the output and level of the `[logging]` section.
+pub fn configure(settings: &LoggingSettings) -> anyhow::Result<()> {
+    if settings.is_empty() {
+        return Ok(());
+    }
+
+    // A service logs nothing otherwise, unlike a terminal where `PUNCH_LOG` is at hand
+    let level = settings.level.as_deref().unwrap_or(match settings.output {
+        LogOutput::Stderr => "off",
+        _ => "info",
+    });
+    let layer: ExtraLayer = match settings.output {
+        LogOutput::Stderr => stderr_layer(),
+        #[cfg(target_os = "linux")]
+        LogOutput::Journald => Box::new(
+            tracing_journald::layer()
+                .map_err(|e| anyhow::anyhow!("Failed to connect to journald: {}", e))?
+                .with_syslog_identifier(env!("CARGO_PKG_NAME").to_string()),
+        ),
+        #[cfg(windows)]
+        LogOutput::EventLog => Box::new(
+            tracing_layer_win_eventlog::EventLogLayer::new(env!("CARGO_PKG_NAME"))
+                .map_err(|e| anyhow::anyhow!("Failed to open the Event Log: {}", e))?,
+        ),
+        #[allow(unreachable_patterns)]
+        output => anyhow::bail!("Logging to {} is not supported on this platform", output),
+    };
+    slots()?.output.set(layer, env_filter(level)?)
+}
+
+/// Adds `layer` next to the log output, seeing what `filter` lets through.
+pub fn add_layer(layer: ExtraLayer, filter: EnvFilter) -> anyhow::Result<()> {
+    slots()?.extra.set(layer, filter)
+}
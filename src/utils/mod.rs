@@ -1,18 +1,41 @@
-use owo_colors::OwoColorize;
+use owo_colors::{OwoColorize, Stream, Style};
 
+pub mod access;
+pub mod audit;
+pub mod backoff;
+pub mod backup;
+pub mod completions;
 pub mod config;
 pub mod constants;
 pub mod crypto;
 pub mod error;
 pub mod format;
+pub mod history;
+pub mod hooks;
+pub mod import;
 pub mod logging;
+pub mod notifications;
+pub mod output;
+pub mod policy;
+pub mod ports;
+pub mod privileges;
+pub mod prompt;
+pub mod schedule;
+#[cfg(feature = "sqlite")]
+pub mod store;
+pub mod telemetry;
+pub mod upgrade;
+pub mod usage;
+pub mod validate;
 
 #[macro_export]
 macro_rules! success {
     ($($arg:tt)*) => {
         {
             use owo_colors::OwoColorize;
-            println!("{} {}", "✓".green(), format!($($arg)*))
+            if $crate::utils::output::shows_progress() {
+                $crate::utils::output::message(format_args!("{} {}", "✓".green(), format!($($arg)*)))
+            }
         }
     };
 }
@@ -22,7 +45,7 @@ macro_rules! warning {
     ($($arg:tt)*) => {
         {
             use owo_colors::OwoColorize;
-            println!("{} {}", "⚠".yellow(), format!($($arg)*))
+            $crate::utils::output::message(format_args!("{} {}", "⚠".yellow(), format!($($arg)*)))
         }
     };
 }
@@ -32,17 +55,44 @@ macro_rules! info {
     ($($arg:tt)*) => {
        {
             use owo_colors::OwoColorize;
-            println!("{} {}", "ℹ".blue(), format!($($arg)*))
+            if $crate::utils::output::shows_progress() {
+                $crate::utils::output::message(format_args!("{} {}", "ℹ".blue(), format!($($arg)*)))
+            }
        }
     };
 }
 
+/// Settles whether output is colored: not with `--no-color`, `NO_COLOR` or `CLICOLOR=0`, nor
+/// when stdout isn't a terminal. Printing goes through `anstream`, which strips what was styled
+/// anyway, while [`styled`] leaves text kept in strings plain.
+pub fn init_colors(no_color: bool) {
+    if no_color {
+        anstream::ColorChoice::Never.write_global();
+        // Errors are reported by miette, which only knows about `NO_COLOR`
+        let _ = miette::set_hook(Box::new(|_| {
+            Box::new(miette::MietteHandlerOpts::new().color(false).build())
+        }));
+    }
+    owo_colors::set_override(colors_enabled(&std::io::stdout()));
+}
+
+pub fn colors_enabled<S: anstream::stream::RawStream>(stream: &S) -> bool {
+    anstream::AutoStream::choice(stream) != anstream::ColorChoice::Never
+}
+
+/// `text` in `style` if colors are enabled, for strings that end up in logs or errors.
+pub fn styled(text: impl std::fmt::Display, style: Style) -> String {
+    text.if_supports_color(Stream::Stdout, |text| text.style(style))
+        .to_string()
+}
+
 pub fn reduced_node_id(node_id: &iroh::NodeId) -> String {
     let id_str = node_id.to_string();
+    let style = Style::new().bold().blue();
     format!(
         "{}{}{}",
-        (&id_str[..6]).bold().blue(),
-        "...".dimmed(),
-        (&id_str[id_str.len() - 6..]).bold().blue()
+        styled(&id_str[..6], style),
+        styled("...", Style::new().dimmed()),
+        styled(&id_str[id_str.len() - 6..], style)
     )
 }
@@ -0,0 +1,73 @@
+use crate::utils::config::NotificationSettings;
+use crate::utils::hooks::HookEvent;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationEvent {
+    Connect,
+    Disconnect,
+    NewKey,
+}
+
+impl NotificationEvent {
+    #[cfg(feature = "notifications")]
+    fn summary(&self) -> &'static str {
+        match self {
+            NotificationEvent::Connect => "Tunnel connected",
+            NotificationEvent::Disconnect => "Tunnel disconnected",
+            NotificationEvent::NewKey => "Unknown key",
+        }
+    }
+}
+
+impl From<HookEvent> for NotificationEvent {
+    fn from(event: HookEvent) -> Self {
+        match event {
+            HookEvent::Connect | HookEvent::Reconnect => NotificationEvent::Connect,
+            HookEvent::Disconnect => NotificationEvent::Disconnect,
+        }
+    }
+}
+
+impl NotificationSettings {
+    fn enabled(&self, event: NotificationEvent) -> bool {
+        match event {
+            NotificationEvent::Connect => self.connect,
+            NotificationEvent::Disconnect => self.disconnect,
+            NotificationEvent::NewKey => self.new_key,
+        }
+    }
+}
+
+/// Shows a desktop notification for `event` if `[notifications]` asks for it. Failures, e.g. a
+/// session without a notification daemon, are logged and never propagated to the tunnel.
+pub fn notify(settings: &NotificationSettings, event: NotificationEvent, body: String) {
+    if !settings.enabled(event) {
+        return;
+    }
+
+    #[cfg(feature = "notifications")]
+    {
+        // Talking to the notification daemon blocks
+        tokio::task::spawn_blocking(move || {
+            let result = notify_rust::Notification::new()
+                .appname(env!("CARGO_PKG_NAME"))
+                .summary(event.summary())
+                .body(&body)
+                .show();
+            if let Err(e) = result {
+                tracing::warn!("Failed to show a notification: {}", e);
+            }
+        });
+    }
+
+    #[cfg(not(feature = "notifications"))]
+    {
+        static WARNED: std::sync::Once = std::sync::Once::new();
+        WARNED.call_once(|| {
+            crate::warning!(
+                "Ignoring [notifications], punch was built without the notifications feature"
+            )
+        });
+        let _ = body;
+    }
+}
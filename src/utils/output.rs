@@ -0,0 +1,105 @@
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use tracing::level_filters::LevelFilter;
+
+/// How much punch tells about what it does, from `-q` to `-vvv`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Verbosity {
+    /// Only warnings and errors
+    Quiet,
+    /// Progress messages, but no log lines unless `PUNCH_LOG` or the config asks for them
+    #[default]
+    Normal,
+    Verbose,
+    Debug,
+    Trace,
+}
+
+impl Verbosity {
+    pub fn from_flags(quiet: bool, verbose: u8) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Verbose,
+            (false, 2) => Verbosity::Debug,
+            (false, _) => Verbosity::Trace,
+        }
+    }
+
+    /// Level of punch's own log lines, ahead of `PUNCH_LOG` and `[logging]`.
+    pub fn log_level(self) -> Option<LevelFilter> {
+        match self {
+            Verbosity::Quiet | Verbosity::Normal => None,
+            Verbosity::Verbose => Some(LevelFilter::INFO),
+            Verbosity::Debug => Some(LevelFilter::DEBUG),
+            Verbosity::Trace => Some(LevelFilter::TRACE),
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Verbosity::Quiet,
+            1 => Verbosity::Normal,
+            2 => Verbosity::Verbose,
+            3 => Verbosity::Debug,
+            _ => Verbosity::Trace,
+        }
+    }
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+/// Sets the verbosity of the whole process, before logging is initialized.
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+pub fn verbosity() -> Verbosity {
+    Verbosity::from_u8(VERBOSITY.load(Ordering::Relaxed))
+}
+
+/// Whether `success!` and `info!` print anything.
+pub fn shows_progress() -> bool {
+    verbosity() > Verbosity::Quiet
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Makes punch report errors and listening addresses as JSON, for `--json`.
+pub fn set_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+
+pub fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Prints an address a tunnel listens on as a JSON line on stdout with `--json`, the local
+/// port being the one picked when the mapping asked for 0.
+pub fn report_listening(protocol: &str, local_addr: std::net::SocketAddr) {
+    if json() {
+        let line = serde_json::json!({ "listening": local_addr, "protocol": protocol });
+        anstream::println!("{}", line);
+    }
+}
+
+static STDOUT_IS_DATA: AtomicBool = AtomicBool::new(false);
+
+/// Sends what `success!`, `info!` and `warning!` print to stderr, for `punch stdio` whose stdout
+/// is the tunnel itself.
+pub fn set_stdout_is_data(stdout_is_data: bool) {
+    STDOUT_IS_DATA.store(stdout_is_data, Ordering::Relaxed);
+}
+
+pub fn stdout_is_data() -> bool {
+    STDOUT_IS_DATA.load(Ordering::Relaxed)
+}
+
+/// Prints a message of the `success!`, `info!` and `warning!` macros.
+pub fn message(args: std::fmt::Arguments) {
+    if stdout_is_data() {
+        anstream::eprintln!("{}", args);
+    } else {
+        anstream::println!("{}", args);
+    }
+}
@@ -0,0 +1,113 @@
+use crate::core::mapping::parse_network;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+/// A forwarding destination rule: a CIDR block, a single address, a hostname, or a
+/// `*.domain` wildcard.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum TargetRule {
+    Network(IpNet),
+    Host(String),
+}
+
+impl TargetRule {
+    fn matches_host(&self, host: &str) -> bool {
+        match self {
+            TargetRule::Network(_) => false,
+            TargetRule::Host(pattern) => match pattern.strip_prefix("*.") {
+                Some(domain) => host
+                    .to_ascii_lowercase()
+                    .strip_suffix(&domain.to_ascii_lowercase())
+                    .is_some_and(|sub| sub.ends_with('.')),
+                None => pattern.eq_ignore_ascii_case(host),
+            },
+        }
+    }
+
+    fn matches_addr(&self, addr: &IpAddr) -> bool {
+        match self {
+            TargetRule::Network(network) => network.contains(addr),
+            TargetRule::Host(_) => false,
+        }
+    }
+}
+
+impl TryFrom<String> for TargetRule {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if let Ok(network) = parse_network(&value) {
+            return Ok(TargetRule::Network(network));
+        }
+
+        let name = value.strip_prefix("*.").unwrap_or(&value);
+        let valid = !name.is_empty()
+            && name.split('.').all(|label| {
+                !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            });
+
+        if valid {
+            Ok(TargetRule::Host(value))
+        } else {
+            Err(format!("Invalid target rule: {}", value))
+        }
+    }
+}
+
+impl From<TargetRule> for String {
+    fn from(rule: TargetRule) -> Self {
+        match rule {
+            TargetRule::Network(network) => network.to_string(),
+            TargetRule::Host(host) => host,
+        }
+    }
+}
+
+/// Allow and deny lists for forwarding destinations. Deny rules always win; the
+/// loopback interface is allowed unless explicitly denied.
+#[derive(Debug, Clone, Default)]
+pub struct TargetPolicy<'a> {
+    allow: Vec<&'a TargetRule>,
+    deny: Vec<&'a TargetRule>,
+}
+
+impl<'a> TargetPolicy<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_rules(
+        mut self,
+        allow: impl IntoIterator<Item = &'a TargetRule>,
+        deny: impl IntoIterator<Item = &'a TargetRule>,
+    ) -> Self {
+        self.allow.extend(allow);
+        self.deny.extend(deny);
+        self
+    }
+
+    /// Decides whether `host`, which resolved to `addrs`, may be dialed.
+    pub fn allows(&self, host: &str, addrs: &[IpAddr]) -> bool {
+        if addrs.is_empty() {
+            return false;
+        }
+
+        let denied = self
+            .deny
+            .iter()
+            .any(|rule| rule.matches_host(host) || addrs.iter().any(|a| rule.matches_addr(a)));
+        if denied {
+            return false;
+        }
+
+        if self.allow.iter().any(|rule| rule.matches_host(host)) {
+            return true;
+        }
+
+        addrs
+            .iter()
+            .all(|addr| addr.is_loopback() || self.allow.iter().any(|rule| rule.matches_addr(addr)))
+    }
+}
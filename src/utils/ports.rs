@@ -0,0 +1,322 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// An inclusive range of ports, written `8000-8100`, or a single port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PortRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+impl PortRange {
+    pub fn new(min: u16, max: u16) -> Self {
+        Self { min, max }
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        (self.min..=self.max).contains(&port)
+    }
+
+    /// What is left of the range once `other` is taken out of it.
+    fn subtract(&self, other: &PortRange) -> Vec<PortRange> {
+        if other.max < self.min || other.min > self.max {
+            return vec![*self];
+        }
+
+        let mut left = Vec::new();
+        if other.min > self.min {
+            left.push(PortRange::new(self.min, other.min - 1));
+        }
+        if other.max < self.max {
+            left.push(PortRange::new(other.max + 1, self.max));
+        }
+        left
+    }
+}
+
+impl std::str::FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("Invalid port: {}", port.trim()))
+        };
+
+        let range = match s.split_once('-') {
+            Some((min, max)) => PortRange::new(parse(min)?, parse(max)?),
+            None => {
+                let port = parse(s)?;
+                PortRange::new(port, port)
+            }
+        };
+
+        if range.min > range.max {
+            return Err(format!("Invalid port range {}: min > max", s.trim()));
+        }
+        Ok(range)
+    }
+}
+
+impl std::fmt::Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.min == self.max {
+            write!(f, "{}", self.min)
+        } else {
+            write!(f, "{}-{}", self.min, self.max)
+        }
+    }
+}
+
+impl From<PortRange> for (u16, u16) {
+    fn from(range: PortRange) -> Self {
+        (range.min, range.max)
+    }
+}
+
+impl Serialize for PortRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.min == self.max {
+            serializer.serialize_u16(self.min)
+        } else {
+            serializer.collect_str(self)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Port(u16),
+            Range(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Port(port) => Ok(PortRange::new(port, port)),
+            Raw::Range(range) => range.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// The ports keys may forward to: a list like `["1024-5999", "7000-65535"]`, or the
+/// `[min, max]` pair of older configs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortRanges(Vec<PortRange>);
+
+impl PortRanges {
+    pub fn ranges(&self) -> &[PortRange] {
+        &self.0
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        self.0.iter().any(|range| range.contains(port))
+    }
+
+    /// The ranges left once `denied` is taken out, sorted and merged.
+    pub fn without(&self, denied: &[PortRange]) -> Vec<PortRange> {
+        let mut ranges = self.0.clone();
+        ranges.sort();
+
+        let mut merged: Vec<PortRange> = Vec::new();
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.min <= last.max.saturating_add(1) => {
+                    last.max = last.max.max(range.max)
+                }
+                _ => merged.push(range),
+            }
+        }
+
+        denied.iter().fold(merged, |ranges, denied| {
+            ranges
+                .iter()
+                .flat_map(|range| range.subtract(denied))
+                .collect()
+        })
+    }
+}
+
+impl From<Vec<PortRange>> for PortRanges {
+    fn from(ranges: Vec<PortRange>) -> Self {
+        Self(ranges)
+    }
+}
+
+impl std::str::FromStr for PortRanges {
+    type Err = String;
+
+    /// Parses a comma separated list, e.g. `8000-8100,9000`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|range| !range.trim().is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl Serialize for PortRanges {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // A single range keeps the pair older versions expect
+        match self.0.as_slice() {
+            [range] => (range.min, range.max).serialize(serializer),
+            ranges => ranges.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRanges {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Pair(u16, u16),
+            Ranges(Vec<PortRange>),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Pair(min, max) if min > max => Err(serde::de::Error::custom(format!(
+                "Invalid port range {}-{}: min > max",
+                min, max
+            ))),
+            Raw::Pair(min, max) => Ok(Self(vec![PortRange::new(min, max)])),
+            Raw::Ranges(ranges) => Ok(Self(ranges)),
+        }
+    }
+}
+
+/// Lists ranges for humans, e.g. `1024-6378, 6380-65535`.
+pub fn format_port_ranges(ranges: &[PortRange]) -> String {
+    if ranges.is_empty() {
+        return "none".to_string();
+    }
+    ranges
+        .iter()
+        .map(PortRange::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_port_ranges() {
+        let cases = [
+            ("22", Some(PortRange::new(22, 22))),
+            ("8000-8100", Some(PortRange::new(8000, 8100))),
+            (" 8000 - 8100 ", Some(PortRange::new(8000, 8100))),
+            ("0-65535", Some(PortRange::new(0, 65535))),
+            ("8100-8000", None),
+            ("8000-", None),
+            ("-8000", None),
+            ("8000-9000-10000", None),
+            ("65536", None),
+            ("http", None),
+            ("", None),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(input.parse::<PortRange>().ok(), expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn parses_port_range_lists() {
+        let cases = [
+            ("", Some(vec![])),
+            ("22", Some(vec![PortRange::new(22, 22)])),
+            (
+                "8000-8100,9000",
+                Some(vec![PortRange::new(8000, 8100), PortRange::new(9000, 9000)]),
+            ),
+            (
+                "8000-8100, ,9000,",
+                Some(vec![PortRange::new(8000, 8100), PortRange::new(9000, 9000)]),
+            ),
+            ("8000-8100,x", None),
+            ("9000-8000", None),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(
+                input.parse::<PortRanges>().ok(),
+                expected.map(PortRanges::from),
+                "{:?}",
+                input
+            );
+        }
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Policy {
+        ports: PortRanges,
+    }
+
+    #[test]
+    fn deserializes_port_ranges() {
+        let cases = [
+            (
+                "ports = [1024, 65535]",
+                Some(vec![PortRange::new(1024, 65535)]),
+            ),
+            ("ports = [22, 22]", Some(vec![PortRange::new(22, 22)])),
+            (
+                "ports = [\"1024-5999\", 7000]",
+                Some(vec![PortRange::new(1024, 5999), PortRange::new(7000, 7000)]),
+            ),
+            (
+                "ports = [22, 80, 443]",
+                Some(vec![
+                    PortRange::new(22, 22),
+                    PortRange::new(80, 80),
+                    PortRange::new(443, 443),
+                ]),
+            ),
+            ("ports = [65535, 1024]", None),
+            ("ports = [\"6000-5000\"]", None),
+            ("ports = \"1024-65535\"", None),
+        ];
+
+        for (input, expected) in cases {
+            let parsed = toml::from_str::<Policy>(input).ok().map(|p| p.ports);
+            assert_eq!(parsed, expected.map(PortRanges::from), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn serializes_single_ranges_as_pairs() {
+        let policy = Policy {
+            ports: vec![PortRange::new(1024, 65535)].into(),
+        };
+        assert_eq!(
+            toml::to_string(&policy).unwrap().trim(),
+            "ports = [1024, 65535]"
+        );
+
+        let policy = Policy {
+            ports: vec![PortRange::new(22, 22), PortRange::new(8000, 8100)].into(),
+        };
+        let serialized = toml::to_string(&policy).unwrap();
+        assert_eq!(
+            toml::from_str::<Policy>(&serialized).unwrap().ports,
+            policy.ports
+        );
+    }
+
+    #[test]
+    fn removes_denied_ranges() {
+        let allowed = PortRanges::from(vec![
+            PortRange::new(1024, 5999),
+            PortRange::new(5000, 65535),
+        ]);
+        assert_eq!(
+            allowed.without(&[PortRange::new(6379, 6379), PortRange::new(65000, 65535)]),
+            [PortRange::new(1024, 6378), PortRange::new(6380, 64999)]
+        );
+        assert!(allowed.without(&[PortRange::new(0, 65535)]).is_empty());
+    }
+}
@@ -0,0 +1,90 @@
+use crate::Result;
+use std::path::{Path, PathBuf};
+
+/// Switches the process to `run_as`, written `user` or `user:group`, once the server holds
+/// everything it needs root for. `owned` are files created as root that the server still
+/// uses afterwards, like its control socket, and are handed over to the user first.
+#[cfg(unix)]
+pub fn drop_privileges(run_as: &str, owned: &[PathBuf]) -> Result<()> {
+    use nix::unistd::{Group, Uid, User, chown, geteuid, setgid, setuid};
+
+    let (user_name, group_name) = match run_as.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (run_as, None),
+    };
+    let user = User::from_name(user_name)
+        .map_err(|e| crate::error!(source = e, "Failed to look up user {}", user_name))?
+        .ok_or_else(|| crate::error!("settings.run_as: no user named {}", user_name))?;
+    let gid = match group_name {
+        Some(name) => {
+            Group::from_name(name)
+                .map_err(|e| crate::error!(source = e, "Failed to look up group {}", name))?
+                .ok_or_else(|| crate::error!("settings.run_as: no group named {}", name))?
+                .gid
+        }
+        None => user.gid,
+    };
+
+    if !geteuid().is_root() {
+        if geteuid() == user.uid {
+            return Ok(());
+        }
+        return Err(crate::error!(
+            "settings.run_as needs the server to start as root to switch to {}",
+            run_as
+        ));
+    }
+
+    for path in owned {
+        chown(path, Some(user.uid), Some(gid))
+            .map_err(|e| crate::error!(source = e, "Failed to hand over {}", path.display()))?;
+    }
+
+    // Groups first, as only root may change them
+    set_groups(gid)?;
+    setgid(gid).map_err(|e| crate::error!(source = e, "Failed to switch to group {}", gid))?;
+    setuid(user.uid)
+        .map_err(|e| crate::error!(source = e, "Failed to switch to user {}", user_name))?;
+
+    // Make sure there is no way back, e.g. from a saved set-user-ID
+    if !user.uid.is_root() && setuid(Uid::from_raw(0)).is_ok() {
+        return Err(crate::error!(
+            "Still able to regain root after switching users"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Drops every supplementary group root had, keeping only `gid`.
+#[cfg(unix)]
+fn set_groups(gid: nix::unistd::Gid) -> Result<()> {
+    #[cfg(not(target_vendor = "apple"))]
+    let result = nix::unistd::setgroups(&[gid]);
+
+    // Not exposed by nix on Apple platforms
+    #[cfg(target_vendor = "apple")]
+    let result = nix::errno::Errno::result(unsafe { nix::libc::setgroups(1, &gid.as_raw()) });
+
+    result
+        .map(drop)
+        .map_err(|e| crate::error!(source = e, "Failed to drop supplementary groups"))
+}
+
+/// Whether the current user may create files in `dir`.
+#[cfg(unix)]
+pub fn is_writable(dir: &Path) -> bool {
+    nix::unistd::access(dir, nix::unistd::AccessFlags::W_OK).is_ok()
+}
+
+#[cfg(not(unix))]
+pub fn is_writable(_dir: &Path) -> bool {
+    true
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(_run_as: &str, _owned: &[PathBuf]) -> Result<()> {
+    Err(crate::error!(
+        "settings.run_as is not supported on this platform"
+    ))
+}
@@ -0,0 +1,41 @@
+use crate::Result;
+use std::io::IsTerminal;
+
+/// How prompts get answered, decided once from the command line and the terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PromptMode {
+    #[default]
+    Interactive,
+    /// Never prompt, every question takes its default answer
+    Defaults,
+    /// Never prompt, confirmations are accepted
+    Yes,
+}
+
+impl PromptMode {
+    /// Falls back to [`PromptMode::Defaults`] when there is no terminal to prompt on, so that
+    /// scripts and containers never hang.
+    pub fn new(non_interactive: bool, yes: bool) -> Self {
+        if yes {
+            PromptMode::Yes
+        } else if non_interactive || !std::io::stdin().is_terminal() {
+            PromptMode::Defaults
+        } else {
+            PromptMode::Interactive
+        }
+    }
+
+    pub fn is_interactive(self) -> bool {
+        self == PromptMode::Interactive
+    }
+
+    pub fn confirm(self, message: &str, default: bool) -> Result<bool> {
+        match self {
+            PromptMode::Interactive => Ok(inquire::Confirm::new(message)
+                .with_default(default)
+                .prompt()?),
+            PromptMode::Defaults => Ok(default),
+            PromptMode::Yes => Ok(true),
+        }
+    }
+}
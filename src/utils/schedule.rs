@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// How many back to back windows a single session may span, e.g. a week of whole days
+const MAX_CHAINED_WINDOWS: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Weekday {
+    /// The weekday of a day counted since the Unix epoch, which was a Thursday.
+    fn of_day(day: u64) -> Self {
+        const DAYS: [Weekday; 7] = [
+            Weekday::Mon,
+            Weekday::Tue,
+            Weekday::Wed,
+            Weekday::Thu,
+            Weekday::Fri,
+            Weekday::Sat,
+            Weekday::Sun,
+        ];
+        DAYS[((day + 3) % 7) as usize]
+    }
+}
+
+impl std::fmt::Display for Weekday {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Weekday::Mon => "mon",
+            Weekday::Tue => "tue",
+            Weekday::Wed => "wed",
+            Weekday::Thu => "thu",
+            Weekday::Fri => "fri",
+            Weekday::Sat => "sat",
+            Weekday::Sun => "sun",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A time of day in UTC, written `HH:MM`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay {
+    minutes: u16,
+}
+
+impl TimeOfDay {
+    fn secs(&self) -> u64 {
+        self.minutes as u64 * 60
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let parsed = value.split_once(':').and_then(|(hours, minutes)| {
+            let hours = hours.parse::<u16>().ok().filter(|h| *h < 24)?;
+            let minutes = minutes.parse::<u16>().ok().filter(|m| *m < 60)?;
+            Some(hours * 60 + minutes)
+        });
+
+        match parsed {
+            Some(minutes) => Ok(Self { minutes }),
+            None => Err(format!("Invalid time of day: {}, expected HH:MM", value)),
+        }
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> Self {
+        time.to_string()
+    }
+}
+
+impl std::fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02}:{:02}", self.minutes / 60, self.minutes % 60)
+    }
+}
+
+/// When a key may connect, in UTC, e.g. `{ days = ["mon", "fri"], from = "09:00", to = "18:00" }`.
+/// A window ending before it starts runs past midnight, and one ending where it starts lasts the
+/// whole day.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    /// Days the window opens on, every day if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<Weekday>,
+
+    #[serde(default)]
+    pub from: TimeOfDay,
+
+    #[serde(default)]
+    pub to: TimeOfDay,
+}
+
+impl Schedule {
+    fn opens_on(&self, day: u64) -> bool {
+        self.days.is_empty() || self.days.contains(&Weekday::of_day(day))
+    }
+
+    /// When the window open at `now` closes, both as Unix timestamps.
+    fn window_end(&self, now: u64) -> Option<u64> {
+        let (day, secs) = (now / DAY, now % DAY);
+        let (from, to) = (self.from.secs(), self.to.secs());
+
+        if from < to {
+            (self.opens_on(day) && (from..to).contains(&secs)).then_some(day * DAY + to)
+        } else if secs >= from && self.opens_on(day) {
+            Some((day + 1) * DAY + to)
+        } else if secs < to && day > 0 && self.opens_on(day - 1) {
+            Some(day * DAY + to)
+        } else {
+            None
+        }
+    }
+
+    /// How long until the window closes, or `None` if it isn't open at `now`.
+    pub fn remaining(&self, now: SystemTime) -> Option<Duration> {
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut end = self.window_end(now)?;
+
+        // Windows that follow each other, like whole days, make up a single one
+        for _ in 0..MAX_CHAINED_WINDOWS {
+            match self.window_end(end) {
+                Some(next) if next > end => end = next,
+                _ => break,
+            }
+        }
+
+        Some(Duration::from_secs(end - now))
+    }
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{} UTC", self.from, self.to)?;
+        if !self.days.is_empty() {
+            let days: Vec<String> = self.days.iter().map(Weekday::to_string).collect();
+            write!(f, " on {}", days.join(", "))?;
+        }
+        Ok(())
+    }
+}
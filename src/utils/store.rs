@@ -0,0 +1,127 @@
+use crate::Result;
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How long a write waits for another process holding the database
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS documents (
+    name TEXT PRIMARY KEY,
+    content TEXT NOT NULL,
+    updated INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS key_activity (
+    node_id TEXT PRIMARY KEY,
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL
+);
+";
+
+/// Config files kept in a single SQLite database instead of TOML files.
+///
+/// Every config is stored as its TOML text, so `validate()` and the migration back to
+/// files work the same. Updates run in a write transaction, which keeps concurrent
+/// `punch auth add` or a running server from losing each other's changes. Queries are
+/// small enough to run in place rather than on a blocking thread.
+#[derive(Debug)]
+pub struct SqliteStore {
+    path: PathBuf,
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: PathBuf) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let conn = Connection::open(&path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        // Readers then never block the writer, e.g. the server while `punch auth add` runs
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA)?;
+
+        Ok(Self {
+            path,
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn
+            .query_row(
+                "SELECT content FROM documents WHERE name = ?1",
+                [name],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    pub fn put(&self, name: &str, content: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        write_document(&conn, name, content)?;
+        Ok(())
+    }
+
+    /// Replaces a document with what `f` makes of its current content, without letting
+    /// another writer in between.
+    pub fn update<T>(
+        &self,
+        name: &str,
+        f: impl FnOnce(Option<String>) -> Result<(String, T)>,
+    ) -> Result<T> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let current = tx
+            .query_row(
+                "SELECT content FROM documents WHERE name = ?1",
+                [name],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let (content, value) = f(current)?;
+        write_document(&tx, name, &content)?;
+        tx.commit()?;
+
+        Ok(value)
+    }
+
+    /// Records that a key just opened a session.
+    pub fn record_seen(&self, node_id: &str, now: u64) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO key_activity (node_id, first_seen, last_seen) VALUES (?1, ?2, ?2)
+             ON CONFLICT(node_id) DO UPDATE SET last_seen = excluded.last_seen",
+            rusqlite::params![node_id, now as i64],
+        )?;
+        Ok(())
+    }
+
+    /// When each key last opened a session, as Unix timestamps.
+    pub fn last_seen(&self) -> Result<HashMap<String, u64>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT node_id, last_seen FROM key_activity")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as u64))
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+}
+
+fn write_document(conn: &Connection, name: &str, content: &str) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO documents (name, content, updated) VALUES (?1, ?2, unixepoch())
+         ON CONFLICT(name) DO UPDATE SET content = excluded.content, updated = excluded.updated",
+        [name, content],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,192 @@
+use crate::Result;
+use crate::utils::config::TelemetrySettings;
+use std::time::Duration;
+
+/// Exports spans and metrics until dropped, flushing what is left on the way out.
+#[derive(Debug)]
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    tracer: opentelemetry_sdk::trace::SdkTracerProvider,
+    #[cfg(feature = "otel")]
+    meter: opentelemetry_sdk::metrics::SdkMeterProvider,
+}
+
+/// Starts exporting to the collector in `settings`, if there is one.
+#[cfg(feature = "otel")]
+pub fn init(settings: &TelemetrySettings) -> Result<Option<Telemetry>> {
+    use crate::utils::constants::{
+        DEFAULT_METRICS_INTERVAL, DEFAULT_TELEMETRY_LEVEL, DEFAULT_TELEMETRY_SERVICE_NAME,
+    };
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::{
+        Resource, metrics::PeriodicReader, metrics::SdkMeterProvider, trace::SdkTracerProvider,
+    };
+    use tracing_subscriber::EnvFilter;
+
+    let Some(endpoint) = &settings.endpoint else {
+        return Ok(None);
+    };
+    let endpoint = endpoint.trim_end_matches('/');
+    let resource = Resource::builder()
+        .with_service_name(
+            settings
+                .service_name
+                .clone()
+                .unwrap_or_else(|| DEFAULT_TELEMETRY_SERVICE_NAME.to_string()),
+        )
+        .build();
+    let interval = settings
+        .metrics_interval
+        .map_or(DEFAULT_METRICS_INTERVAL, Duration::from_secs);
+
+    // The blocking HTTP client can't be created from within the async runtime
+    let (spans, metrics) = std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                let spans = SpanExporter::builder()
+                    .with_http()
+                    .with_endpoint(format!("{}/v1/traces", endpoint))
+                    .build();
+                let metrics = MetricExporter::builder()
+                    .with_http()
+                    .with_endpoint(format!("{}/v1/metrics", endpoint))
+                    .build();
+                (spans, metrics)
+            })
+            .join()
+    })
+    .map_err(|_| crate::error!("Failed to set up the OTLP exporters"))?;
+    let spans = spans.map_err(|e| crate::error!(source = e, "Invalid telemetry settings"))?;
+    let metrics = metrics.map_err(|e| crate::error!(source = e, "Invalid telemetry settings"))?;
+
+    let tracer = SdkTracerProvider::builder()
+        .with_batch_exporter(spans)
+        .with_resource(resource.clone())
+        .build();
+    let meter = SdkMeterProvider::builder()
+        .with_reader(
+            PeriodicReader::builder(metrics)
+                .with_interval(interval)
+                .build(),
+        )
+        .with_resource(resource)
+        .build();
+    opentelemetry::global::set_meter_provider(meter.clone());
+
+    // Only our own spans, iroh's would drown them
+    let level = settings.level.as_deref().unwrap_or(DEFAULT_TELEMETRY_LEVEL);
+    let filter = EnvFilter::new(format!("{}={}", env!("CARGO_PKG_NAME"), level));
+    let layer = tracing_opentelemetry::layer().with_tracer(tracer.tracer(env!("CARGO_PKG_NAME")));
+    crate::utils::logging::add_layer(Box::new(layer), filter)?;
+
+    Ok(Some(Telemetry { tracer, meter }))
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn init(settings: &TelemetrySettings) -> Result<Option<Telemetry>> {
+    if settings.endpoint.is_some() {
+        crate::warning!("Ignoring [telemetry], punch was built without the otel feature");
+    }
+    Ok(None)
+}
+
+#[cfg(feature = "otel")]
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Err(e) = self.tracer.shutdown() {
+            tracing::warn!("Failed to flush spans: {}", e);
+        }
+        if let Err(e) = self.meter.shutdown() {
+            tracing::warn!("Failed to flush metrics: {}", e);
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+mod metrics {
+    use opentelemetry::metrics::{Counter, Histogram, UpDownCounter};
+    use std::sync::LazyLock;
+
+    pub struct Metrics {
+        pub handshakes: Counter<u64>,
+        pub handshake_duration: Histogram<f64>,
+        pub tunnels: Counter<u64>,
+        pub active_tunnels: UpDownCounter<i64>,
+        pub streams: Counter<u64>,
+        pub bytes: Counter<u64>,
+    }
+
+    /// Created on first use, which comes after `init` has set the meter provider
+    pub static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+        Metrics {
+            handshakes: meter
+                .u64_counter("punch.handshakes")
+                .with_description("Tunnel handshakes, by outcome")
+                .build(),
+            handshake_duration: meter
+                .f64_histogram("punch.handshake.duration")
+                .with_unit("s")
+                .build(),
+            tunnels: meter.u64_counter("punch.tunnels").build(),
+            active_tunnels: meter.i64_up_down_counter("punch.tunnels.active").build(),
+            streams: meter
+                .u64_counter("punch.streams")
+                .with_description("Streams bridged with a target")
+                .build(),
+            bytes: meter
+                .u64_counter("punch.bytes")
+                .with_unit("By")
+                .with_description("Bytes carried by finished tunnels, by direction")
+                .build(),
+        }
+    });
+}
+
+/// Records a handshake of the server, accepted or not.
+pub fn handshake(accepted: bool, duration: Duration) {
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::KeyValue;
+        let outcome = [KeyValue::new(
+            "outcome",
+            if accepted { "accepted" } else { "rejected" },
+        )];
+        metrics::METRICS.handshakes.add(1, &outcome);
+        metrics::METRICS
+            .handshake_duration
+            .record(duration.as_secs_f64(), &outcome);
+    }
+    #[cfg(not(feature = "otel"))]
+    let _ = (accepted, duration);
+}
+
+pub fn tunnel_opened() {
+    #[cfg(feature = "otel")]
+    {
+        metrics::METRICS.tunnels.add(1, &[]);
+        metrics::METRICS.active_tunnels.add(1, &[]);
+    }
+}
+
+pub fn tunnel_closed(bytes_in: u64, bytes_out: u64) {
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::KeyValue;
+        metrics::METRICS.active_tunnels.add(-1, &[]);
+        metrics::METRICS
+            .bytes
+            .add(bytes_in, &[KeyValue::new("direction", "in")]);
+        metrics::METRICS
+            .bytes
+            .add(bytes_out, &[KeyValue::new("direction", "out")]);
+    }
+    #[cfg(not(feature = "otel"))]
+    let _ = (bytes_in, bytes_out);
+}
+
+pub fn stream_opened() {
+    #[cfg(feature = "otel")]
+    metrics::METRICS.streams.add(1, &[]);
+}
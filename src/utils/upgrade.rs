@@ -0,0 +1,228 @@
+use crate::Result;
+use crate::utils::constants::{LATEST_RELEASE_URL, RELEASE_DOWNLOAD_URL, UPGRADE_TIMEOUT};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+
+#[cfg(windows)]
+const BINARY_NAME: &str = "punch.exe";
+#[cfg(not(windows))]
+const BINARY_NAME: &str = "punch";
+
+/// A published version of punch.
+#[derive(Debug, Clone)]
+pub struct Release {
+    pub tag: String,
+    pub version: semver::Version,
+}
+
+#[derive(Debug, Deserialize)]
+struct GitHubRelease {
+    tag_name: String,
+}
+
+impl Release {
+    pub fn is_newer(&self) -> bool {
+        self.version > current_version()
+    }
+
+    fn archive_url(&self, target: &str) -> String {
+        format!(
+            "{}/{}/{}",
+            RELEASE_DOWNLOAD_URL,
+            self.tag,
+            archive_name(target)
+        )
+    }
+}
+
+pub fn current_version() -> semver::Version {
+    env!("CARGO_PKG_VERSION")
+        .parse()
+        .expect("The package version is valid semver")
+}
+
+/// Target triple of the release archives matching this build, `None` for platforms without
+/// released binaries.
+pub fn target() -> Option<&'static str> {
+    match (std::env::consts::OS, std::env::consts::ARCH) {
+        ("linux", "x86_64") if cfg!(target_env = "musl") => Some("x86_64-unknown-linux-musl"),
+        ("linux", "x86_64") => Some("x86_64-unknown-linux-gnu"),
+        ("linux", "aarch64") => Some("aarch64-unknown-linux-gnu"),
+        ("macos", "x86_64") => Some("x86_64-apple-darwin"),
+        ("macos", "aarch64") => Some("aarch64-apple-darwin"),
+        ("windows", "x86_64") => Some("x86_64-pc-windows-msvc"),
+        _ => None,
+    }
+}
+
+/// Cargo features this build has, which the released binaries are built without.
+pub fn enabled_features() -> Vec<&'static str> {
+    [
+        ("sqlite", cfg!(feature = "sqlite")),
+        ("otel", cfg!(feature = "otel")),
+        ("notifications", cfg!(feature = "notifications")),
+        ("vpn", cfg!(feature = "vpn")),
+        ("grpc", cfg!(feature = "grpc")),
+    ]
+    .into_iter()
+    .filter_map(|(feature, enabled)| enabled.then_some(feature))
+    .collect()
+}
+
+fn archive_name(target: &str) -> String {
+    let extension = if target.contains("windows") {
+        "zip"
+    } else {
+        "tar.xz"
+    };
+    format!("punch-{}.{}", target, extension)
+}
+
+fn client() -> Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .user_agent(concat!("punch/", env!("CARGO_PKG_VERSION")))
+        .timeout(UPGRADE_TIMEOUT)
+        .build()
+        .map_err(|e| crate::error!(source = e, "Failed to create the HTTP client"))
+}
+
+async fn fetch(client: &reqwest::Client, url: &str) -> Result<bytes::Bytes> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| crate::error!(source = e, "Failed to fetch {}", url))?;
+    response
+        .bytes()
+        .await
+        .map_err(|e| crate::error!(source = e, "Failed to fetch {}", url))
+}
+
+/// Asks GitHub for the latest release.
+pub async fn latest() -> Result<Release> {
+    let body = fetch(&client()?, LATEST_RELEASE_URL).await?;
+    let release: GitHubRelease = serde_json::from_slice(&body)
+        .map_err(|e| crate::error!(source = e, "Invalid release from {}", LATEST_RELEASE_URL))?;
+    let version = release
+        .tag_name
+        .trim_start_matches('v')
+        .parse()
+        .map_err(|e| crate::error!(source = e, "Invalid release tag {}", release.tag_name))?;
+
+    Ok(Release {
+        tag: release.tag_name,
+        version,
+    })
+}
+
+/// Downloads the binary of `release` for this platform, checked against the checksum
+/// published next to its archive.
+pub async fn download(release: &Release) -> Result<Vec<u8>> {
+    let target = target().ok_or_else(|| {
+        crate::error!(
+            "No released binary for {}-{}, build it with `cargo install punch`",
+            std::env::consts::ARCH,
+            std::env::consts::OS
+        )
+    })?;
+    let url = release.archive_url(target);
+
+    let client = client()?;
+    let archive = fetch(&client, &url).await?;
+    let checksum = fetch(&client, &format!("{}.sha256", url)).await?;
+    verify(&archive, &String::from_utf8_lossy(&checksum), &url)?;
+
+    extract(&archive)
+}
+
+/// Checks `archive` against a `sha256sum` line, the digest coming first.
+fn verify(archive: &[u8], checksum: &str, url: &str) -> Result<()> {
+    let expected = checksum
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| crate::error!("Empty checksum for {}", url))?;
+    let actual = data_encoding::HEXLOWER.encode(&Sha256::digest(archive));
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(crate::error!(
+            "Checksum mismatch for {}: expected {}, got {}",
+            url,
+            expected,
+            actual
+        ));
+    }
+    Ok(())
+}
+
+/// Takes the binary out of a release archive, wherever it sits in it.
+#[cfg(not(windows))]
+fn extract(archive: &[u8]) -> Result<Vec<u8>> {
+    let mut tar = Vec::new();
+    lzma_rs::xz_decompress(&mut Cursor::new(archive), &mut tar)
+        .map_err(|e| crate::error!(source = e, "Failed to decompress the release archive"))?;
+
+    let mut archive = tar::Archive::new(Cursor::new(tar));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.file_name() == Some(BINARY_NAME.as_ref()) {
+            let mut binary = Vec::new();
+            entry.read_to_end(&mut binary)?;
+            return Ok(binary);
+        }
+    }
+    Err(crate::error!("No {} in the release archive", BINARY_NAME))
+}
+
+#[cfg(windows)]
+fn extract(archive: &[u8]) -> Result<Vec<u8>> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(archive))
+        .map_err(|e| crate::error!(source = e, "Invalid release archive"))?;
+    for index in 0..archive.len() {
+        let mut file = archive
+            .by_index(index)
+            .map_err(|e| crate::error!(source = e, "Invalid release archive"))?;
+        if Path::new(file.name()).file_name() == Some(BINARY_NAME.as_ref()) {
+            let mut binary = Vec::new();
+            file.read_to_end(&mut binary)?;
+            return Ok(binary);
+        }
+    }
+    Err(crate::error!("No {} in the release archive", BINARY_NAME))
+}
+
+/// Replaces the running executable with `binary`, returning its path. The new binary is
+/// written next to it and renamed over it, so that it is never left half written.
+pub fn install(binary: &[u8]) -> Result<PathBuf> {
+    let path = std::env::current_exe()?.canonicalize()?;
+    let dir = path
+        .parent()
+        .ok_or_else(|| crate::error!("{} has no parent directory", path.display()))?;
+    let staged = dir.join(format!(".{}.new", BINARY_NAME));
+
+    let write = |staged: &Path| -> std::io::Result<()> {
+        std::fs::write(staged, binary)?;
+        std::fs::set_permissions(staged, std::fs::metadata(&path)?.permissions())
+    };
+    if let Err(e) = write(&staged) {
+        let _ = std::fs::remove_file(&staged);
+        return Err(crate::error!(
+            source = e,
+            "Failed to write to {}, run the upgrade as a user who can",
+            dir.display()
+        ));
+    }
+
+    // A running executable can't be replaced on Windows, but it can be moved out of the way
+    #[cfg(windows)]
+    {
+        let old = path.with_extension("old.exe");
+        let _ = std::fs::remove_file(&old);
+        std::fs::rename(&path, &old)?;
+    }
+    std::fs::rename(&staged, &path)
+        .map_err(|e| crate::error!(source = e, "Failed to replace {}", path.display()))?;
+
+    Ok(path)
+}
@@ -0,0 +1,142 @@
+use crate::Result;
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// Days of counters kept, older ones are dropped on the next write
+const RETENTION_DAYS: u32 = 400;
+
+/// Traffic of a key over some period, bytes counted from the server's side.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub sessions: u64,
+    /// Bytes received from the client and written to the target
+    pub bytes_in: u64,
+    /// Bytes read from the target and sent to the client
+    pub bytes_out: u64,
+    /// Seconds spent connected
+    pub duration: u64,
+}
+
+impl Usage {
+    pub fn session(bytes_in: u64, bytes_out: u64, duration: u64) -> Self {
+        Self {
+            sessions: 1,
+            bytes_in,
+            bytes_out,
+            duration,
+        }
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.bytes_in + self.bytes_out
+    }
+
+    fn add(&mut self, other: &Usage) {
+        self.sessions += other.sessions;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.duration += other.duration;
+    }
+}
+
+/// Counters of every key, bucketed by day since the Unix epoch.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Buckets {
+    days: BTreeMap<(u32, NodeId), Usage>,
+}
+
+/// Per-key traffic counters of the server, kept as a compact postcard file.
+#[derive(Debug)]
+pub struct UsageLedger {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl UsageLedger {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Adds a finished session to today's counters of `node_id`.
+    pub async fn record(&self, node_id: NodeId, usage: Usage) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        let mut buckets = self.load().await?;
+
+        let today = day_of(SystemTime::now());
+        buckets
+            .days
+            .entry((today, node_id))
+            .or_default()
+            .add(&usage);
+        buckets
+            .days
+            .retain(|(day, _), _| today.saturating_sub(*day) < RETENTION_DAYS);
+
+        let content = postcard::to_stdvec(&buckets).map_err(|e| crate::error!("{}", e))?;
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Written aside first so that a crash never leaves half of the counters behind
+        let tmp = self.path.with_extension("bin.tmp");
+        tokio::fs::write(&tmp, content).await?;
+        tokio::fs::rename(&tmp, &self.path).await?;
+
+        Ok(())
+    }
+
+    /// Sums the usage of every key over the last `since` seconds, heaviest keys first.
+    /// Counters are kept by day, so the period is rounded up to whole days.
+    pub async fn report(&self, since: u64) -> Result<Vec<(NodeId, Usage)>> {
+        let buckets = self.load().await?;
+        let first_day = SystemTime::now()
+            .checked_sub(Duration::from_secs(since))
+            .map_or(0, day_of);
+
+        let mut totals: HashMap<NodeId, Usage> = HashMap::new();
+        for ((day, node_id), usage) in &buckets.days {
+            if *day >= first_day {
+                totals.entry(*node_id).or_default().add(usage);
+            }
+        }
+
+        let mut totals: Vec<_> = totals.into_iter().collect();
+        totals.sort_by_key(|(_, usage)| std::cmp::Reverse(usage.total_bytes()));
+        Ok(totals)
+    }
+
+    async fn load(&self) -> Result<Buckets> {
+        let content = match tokio::fs::read(&self.path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Buckets::default()),
+            Err(e) => return Err(e.into()),
+        };
+        postcard::from_bytes(&content).map_err(|e| {
+            crate::error!(
+                "Failed to read usage counters {}: {}",
+                self.path.display(),
+                e
+            )
+        })
+    }
+}
+
+fn day_of(time: SystemTime) -> u32 {
+    (time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / DAY) as u32
+}
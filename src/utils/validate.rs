@@ -0,0 +1,325 @@
+use crate::utils::config::{ClientConfig, Configuration, ServerConfig};
+use crate::utils::ports::PortRange;
+use iroh::{PublicKey, RelayUrl};
+use miette::{Diagnostic, LabeledSpan, NamedSource};
+use std::collections::HashMap;
+use std::ops::Range;
+use thiserror::Error;
+use toml_edit::{ImDocument, Item, TableLike};
+
+/// Something wrong in a config file, pointing at the lines at fault when it can.
+#[derive(Debug, Error, Diagnostic)]
+#[error("{message}")]
+pub struct Problem {
+    message: String,
+    #[label(collection)]
+    labels: Vec<LabeledSpan>,
+    #[help]
+    help: Option<String>,
+}
+
+impl Problem {
+    fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            labels: Vec::new(),
+            help: None,
+        }
+    }
+
+    fn with_label(mut self, span: Option<Range<usize>>, label: impl Into<String>) -> Self {
+        if let Some(span) = span {
+            self.labels
+                .push(LabeledSpan::new_with_span(Some(label.into()), span));
+        }
+        self
+    }
+
+    fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+}
+
+/// Every problem found in one config file.
+#[derive(Debug, Error, Diagnostic)]
+#[error("{name} has {} problem(s)", problems.len())]
+#[diagnostic(code(punch::invalid_config))]
+pub struct ConfigReport {
+    name: String,
+    #[source_code]
+    src: NamedSource<String>,
+    #[related]
+    problems: Vec<Problem>,
+}
+
+impl ConfigReport {
+    pub fn problems(&self) -> usize {
+        self.problems.len()
+    }
+}
+
+/// Checks a client or server config, collecting every problem instead of stopping at the
+/// first one. `name` is shown in front of the offending lines.
+pub fn validate<C: Configuration>(name: &str, content: &str) -> Option<ConfigReport> {
+    let problems = match ImDocument::parse(content) {
+        Ok(document) => {
+            let root = document.as_table();
+            let mut problems = if C::filename() == ServerConfig::filename() {
+                check_server(root)
+            } else if C::filename() == ClientConfig::filename() {
+                check_client(root)
+            } else {
+                Vec::new()
+            };
+
+            // Type errors would repeat what was found above, so only look for them after
+            if problems.is_empty() {
+                problems.extend(check_types::<C>(content));
+            }
+            problems
+        }
+        Err(e) => vec![Problem::new(e.message().trim()).with_label(e.span(), "here")],
+    };
+
+    (!problems.is_empty()).then(|| ConfigReport {
+        name: name.to_string(),
+        src: NamedSource::new(name, content.to_string()).with_language("TOML"),
+        problems,
+    })
+}
+
+/// Deserializes the whole config, then runs the checks done when it is loaded.
+fn check_types<C: Configuration>(content: &str) -> Option<Problem> {
+    match toml::from_str::<C>(content) {
+        Ok(config) => config.validate().err().map(|e| Problem::new(e.to_string())),
+        Err(e) => Some(Problem::new(e.message().trim()).with_label(e.span(), "here")),
+    }
+}
+
+fn check_server(root: &toml_edit::Table) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    for field in ["authorized_keys", "confirmed_keys"] {
+        if let Some(keys) = root.get(field).and_then(Item::as_array) {
+            for key in keys.iter() {
+                check_key(&mut problems, key.as_str(), key.span(), field);
+            }
+        }
+    }
+
+    if let Some(keys) = root.get("keys").and_then(Item::as_table_like) {
+        for (key, _) in keys.iter() {
+            let span = keys.key(key).and_then(|key| key.span());
+            check_key(&mut problems, Some(key), span, "keys");
+        }
+    }
+
+    let settings = root.get("settings").and_then(Item::as_table_like);
+    if let Some(peers) = settings
+        .and_then(|settings| settings.get("sync_peers"))
+        .and_then(Item::as_array)
+    {
+        for peer in peers.iter() {
+            check_key(
+                &mut problems,
+                peer.as_str(),
+                peer.span(),
+                "settings.sync_peers",
+            );
+        }
+    }
+    let privileged = settings
+        .and_then(|settings| settings.get("allow_privileged_ports"))
+        .and_then(Item::as_bool)
+        .unwrap_or(false);
+    if let Some(ports) = settings.and_then(|settings| settings.get("allowed_ports"))
+        && let Some(array) = ports.as_array()
+    {
+        let pair = [0, 1].map(|i| array.get(i).and_then(|v| v.as_integer()));
+        match pair {
+            // The `[min, max]` pair of older configs
+            [Some(min), Some(max)] if array.len() == 2 => {
+                if min > max {
+                    problems.push(
+                        Problem::new("settings.allowed_ports starts after it ends")
+                            .with_label(ports.span(), "min is greater than max"),
+                    );
+                } else if min < 1024 && !privileged {
+                    problems.push(privileged_ports(ports.span()));
+                }
+            }
+            _ => {
+                for item in array.iter() {
+                    if check_port_range(&mut problems, item, "settings.allowed_ports")
+                        .is_some_and(|range| range.min < 1024 && !privileged)
+                    {
+                        problems.push(privileged_ports(item.span()));
+                    }
+                }
+            }
+        }
+    }
+    if let Some(ports) = settings
+        .and_then(|settings| settings.get("denied_ports"))
+        .and_then(Item::as_array)
+    {
+        for range in ports.iter() {
+            check_port_range(&mut problems, range, "settings.denied_ports");
+        }
+    }
+
+    if let Some(services) = root.get("services").and_then(Item::as_table_like) {
+        let mut targets: HashMap<(String, i64, String), &str> = HashMap::new();
+        for (name, service) in services.iter() {
+            let Some(service) = service.as_table_like() else {
+                continue;
+            };
+            let field = |field: &str| service.get(field).and_then(|item| item.as_str());
+            let Some(port) = service.get("port").and_then(|item| item.as_integer()) else {
+                continue;
+            };
+            let target = (
+                field("host").unwrap_or("127.0.0.1").to_string(),
+                port,
+                field("protocol").unwrap_or("tcp").to_lowercase(),
+            );
+            if let Some(first) = targets.insert(target, name) {
+                let span = services.key(name).and_then(|key| key.span());
+                problems.push(
+                    Problem::new(format!(
+                        "Services {} and {} forward to the same target",
+                        first, name
+                    ))
+                    .with_label(span, "same host, port and protocol")
+                    .with_help("Remove one of them or point it at another port"),
+                );
+            }
+        }
+    }
+
+    problems
+}
+
+fn check_client(root: &toml_edit::Table) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut names: HashMap<String, Option<Range<usize>>> = HashMap::new();
+    let mut ids: HashMap<String, Option<Range<usize>>> = HashMap::new();
+
+    for host in hosts(root) {
+        let name = host.get("name");
+        if let Some(value) = name.and_then(|name| name.as_str()) {
+            let span = name.and_then(Item::span);
+            if let Some(first) = names.insert(value.to_string(), span.clone()) {
+                problems.push(
+                    Problem::new(format!("Duplicate host name: {}", value))
+                        .with_label(first, "first used here")
+                        .with_label(span, "used again here"),
+                );
+            }
+        }
+
+        let id = host.get("id");
+        if let Some(value) = id.and_then(|id| id.as_str()) {
+            let span = id.and_then(Item::span);
+            if check_key(&mut problems, Some(value), span.clone(), "hosts.id")
+                && let Some(first) = ids.insert(value.to_string(), span.clone())
+            {
+                problems.push(
+                    Problem::new("Two hosts share the same node ID")
+                        .with_label(first, "first used here")
+                        .with_label(span, "used again here"),
+                );
+            }
+        }
+
+        if let Some(backups) = host.get("backups").and_then(Item::as_array) {
+            for backup in backups.iter() {
+                check_key(
+                    &mut problems,
+                    backup.as_str(),
+                    backup.span(),
+                    "hosts.backups",
+                );
+            }
+        }
+
+        if let Some(relay_url) = host.get("relay_url")
+            && let Some(value) = relay_url.as_str()
+            && value.parse::<RelayUrl>().is_err()
+        {
+            problems.push(
+                Problem::new(format!("Invalid relay URL: {}", value))
+                    .with_label(relay_url.span(), "not a URL"),
+            );
+        }
+    }
+
+    problems
+}
+
+/// Hosts written as `[[hosts]]` tables or as an inline array.
+fn hosts(root: &toml_edit::Table) -> Vec<&dyn TableLike> {
+    match root.get("hosts") {
+        Some(Item::ArrayOfTables(tables)) => {
+            tables.iter().map(|table| table as &dyn TableLike).collect()
+        }
+        Some(Item::Value(value)) => value
+            .as_array()
+            .into_iter()
+            .flat_map(|array| array.iter())
+            .filter_map(|value| value.as_inline_table())
+            .map(|table| table as &dyn TableLike)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Reports `value` unless it is a port or a `min-max` range, returning the range if it is.
+fn check_port_range(
+    problems: &mut Vec<Problem>,
+    value: &toml_edit::Value,
+    field: &str,
+) -> Option<PortRange> {
+    let range = match value {
+        toml_edit::Value::Integer(port) => u16::try_from(*port.value())
+            .map(|port| PortRange::new(port, port))
+            .map_err(|_| format!("Invalid port: {}", port.value())),
+        toml_edit::Value::String(range) => range.value().parse(),
+        _ => Err("Expected a port or a range".to_string()),
+    };
+
+    range
+        .inspect_err(|e| {
+            problems.push(
+                Problem::new(format!("Invalid port range in {}", field))
+                    .with_label(value.span(), e.clone())
+                    .with_help("Write ports as `8000` and ranges as \"8000-8100\""),
+            )
+        })
+        .ok()
+}
+
+fn privileged_ports(span: Option<Range<usize>>) -> Problem {
+    Problem::new("settings.allowed_ports includes privileged ports")
+        .with_label(span, "below 1024")
+        .with_help("Set settings.allow_privileged_ports = true to allow ports below 1024")
+}
+
+/// Reports `value` unless it is a valid node ID, returning whether it was.
+fn check_key(
+    problems: &mut Vec<Problem>,
+    value: Option<&str>,
+    span: Option<Range<usize>>,
+    field: &str,
+) -> bool {
+    if value.is_some_and(|value| value.parse::<PublicKey>().is_ok()) {
+        return true;
+    }
+    problems.push(
+        Problem::new(format!("Invalid node ID in {}", field))
+            .with_label(span, "not a node ID")
+            .with_help("Node IDs are 64 hex characters, as printed by `punch id`"),
+    );
+    false
+}
@@ -0,0 +1,33 @@
+use std::process::{Command, Stdio};
+
+/// `punch stdio` is used as an SSH `ProxyCommand`, where anything on stdout besides the
+/// tunneled bytes corrupts the stream. A fresh config dir makes punch announce the key it
+/// generates, and an unknown host fails before any byte is tunneled.
+#[test]
+fn stdio_keeps_stdout_for_the_tunnel() {
+    let config_dir = std::env::temp_dir().join(format!("punch-stdio-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&config_dir);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_punch"))
+        .arg("--config-dir")
+        .arg(&config_dir)
+        .args(["stdio", "unknown-host", "22"])
+        .stdin(Stdio::null())
+        .output()
+        .expect("Failed to run punch");
+    let _ = std::fs::remove_dir_all(&config_dir);
+
+    assert!(!output.status.success());
+    assert!(
+        output.stdout.is_empty(),
+        "stdout: {}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Generating new secret key"),
+        "stderr: {}",
+        stderr
+    );
+    assert!(stderr.contains("Unknown host"), "stderr: {}", stderr);
+}